@@ -0,0 +1,79 @@
+//! The same chunks through the proxy, streamed by one session and
+//! interleaved across 100, with the flush task firing throughout.
+//!
+//! Run with `cargo bench --bench concurrent_sessions`. There is no harness:
+//! each mode streams the chunks a few times on its own transport and the
+//! best run is reported. Sessions lock individually, so buffering a chunk
+//! never waits on another session's flush and 100 sessions should cost
+//! about what one does per chunk; work shared across sessions shows up as
+//! the 100 session figure pulling away.
+
+use std::time::{Duration, Instant};
+
+use decaf_mod::Decaf;
+use futures::StreamExt;
+use sacp::jsonrpcmsg::Message;
+use sacp::schema::{ContentBlock, ContentChunk, SessionNotification, SessionUpdate};
+
+const CHUNKS: usize = 100_000;
+const RUNS: usize = 5;
+
+/// `CHUNKS` chunks from the agent, round-robin over `sessions` sessions.
+fn chunks(sessions: usize) -> Vec<Message> {
+    (0..CHUNKS)
+        .map(|n| {
+            let notification = SessionNotification::new(
+                format!("session-{}", n % sessions),
+                SessionUpdate::AgentMessageChunk(ContentChunk::new(ContentBlock::from("word "))),
+            );
+            serde_json::from_value(serde_json::json!({
+                "jsonrpc": "2.0",
+                "method": "_proxy/successor",
+                "params": { "method": "session/update", "params": notification },
+            }))
+            .unwrap()
+        })
+        .collect()
+}
+
+/// Time until the proxy has buffered every one of `chunks`, reading what it
+/// flushes meanwhile.
+async fn stream(chunks: &[Message]) -> Duration {
+    let decaf = Decaf::new(Duration::from_millis(5));
+    let stats = decaf.stats_handle();
+    let (channel, transport) = sacp::Channel::duplex();
+    let proxy = tokio::spawn(decaf.run(transport));
+    let mut output = channel.rx;
+    let reader = tokio::spawn(async move { while output.next().await.is_some() {} });
+
+    let start = Instant::now();
+    for chunk in chunks {
+        channel.tx.unbounded_send(Ok(chunk.clone())).unwrap();
+    }
+    while stats.chunks_received() < CHUNKS as u64 {
+        tokio::task::yield_now().await;
+    }
+    let elapsed = start.elapsed();
+
+    proxy.abort();
+    reader.abort();
+    elapsed
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    for sessions in [1, 100] {
+        let chunks = chunks(sessions);
+        let best = (0..RUNS)
+            .map(|_| runtime.block_on(stream(&chunks)))
+            .min()
+            .unwrap();
+        println!(
+            "{sessions:>4} sessions: {:>7.1} ns/chunk ({CHUNKS} chunks, best of {RUNS})",
+            best.as_nanos() as f64 / CHUNKS as f64
+        );
+    }
+}
@@ -0,0 +1,61 @@
+//! One session's stream through a `Coalescer`, with sessions in a map and
+//! with `single_session`.
+//!
+//! Run with `cargo bench --bench single_session`. There is no harness: each
+//! mode streams the same chunks a few times on a mock clock and the best
+//! run is reported, which is enough to compare the two.
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use decaf_mod::{Coalescer, Decaf, MockClock};
+use sacp::schema::{ContentBlock, ContentChunk, SessionNotification, SessionUpdate};
+
+const CHUNKS: usize = 200_000;
+const RUNS: usize = 5;
+
+/// Stream `chunks` a millisecond apart, ticking after each, and end the
+/// turn every 100 chunks as a session taking prompts would.
+fn stream(single_session: bool, chunks: &[SessionNotification]) -> Duration {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = Coalescer::new(
+        Decaf::builder()
+            .interval(Duration::from_millis(20))
+            .single_session(single_session)
+            .with_clock(clock.clone())
+            .build(),
+    );
+    let start = Instant::now();
+    for (n, chunk) in chunks.iter().enumerate() {
+        black_box(coalescer.push(chunk.clone()).unwrap());
+        clock.advance(Duration::from_millis(1));
+        black_box(coalescer.tick().unwrap());
+        if n % 100 == 99 {
+            black_box(coalescer.end_turn(&chunk.session_id).unwrap());
+        }
+    }
+    black_box(coalescer.flush().unwrap());
+    start.elapsed()
+}
+
+fn main() {
+    let chunks: Vec<_> = (0..CHUNKS)
+        .map(|_| {
+            SessionNotification::new(
+                "a-session-id-as-long-as-a-uuid-0000",
+                SessionUpdate::AgentMessageChunk(ContentChunk::new(ContentBlock::from("word "))),
+            )
+        })
+        .collect();
+    for (name, single_session) in [("map", false), ("single_session", true)] {
+        let best = (0..RUNS)
+            .map(|_| stream(single_session, &chunks))
+            .min()
+            .unwrap();
+        println!(
+            "{name:>14}: {:>7.1} ns/chunk ({CHUNKS} chunks, best of {RUNS})",
+            best.as_nanos() as f64 / CHUNKS as f64
+        );
+    }
+}
@@ -0,0 +1,1322 @@
+//! Configuration for [`Decaf`].
+
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use regex::Regex;
+use sacp::schema::{ContentChunk, SessionId, SessionNotification, ToolKind};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::clock::{Clock, TokioClock, WallClock};
+use crate::control::{DRAIN_QUEUE, LiveInterval};
+use crate::events::FlushReports;
+use crate::history::FlushHistory;
+use crate::rate::EmitBudget;
+use crate::{
+    ConcatFn, DEFAULT_SENTENCE_TERMINATORS, Decaf, DecafControl, DecafStats, ErrorFn, FlushFn,
+    IntervalFn, MergeFn, PassthroughFn, PriorityFn, SessionMapFn, TokenCountFn, TransformFn,
+};
+
+/// The proxy name used when [`DecafBuilder::named`] is not called.
+const DEFAULT_NAME: &str = "decaf";
+
+/// The prefix used when [`DecafBuilder::metrics_prefix`] is not called.
+const DEFAULT_METRICS_PREFIX: &str = "decaf_";
+
+/// The interval used when [`DecafBuilder::interval`] is not called.
+const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The floor used when [`DecafBuilder::with_min_interval`] is not called.
+const DEFAULT_MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The capacity used when [`DecafBuilder::initial_buffer_capacity`] is not
+/// called.
+const DEFAULT_BUFFER_CAPACITY: usize = 1024;
+
+/// How [`coalesce_tool_calls`](DecafBuilder::coalesce_tool_calls) merges
+/// one tool call's updates, chosen per [`ToolKind`] with
+/// [`DecafBuilder::tool_call_mode`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CoalesceMode {
+    /// Keep the latest value of every field but `content`, and append each
+    /// update's `content` to the content pending before it, for agents that
+    /// stream a call's output as deltas.
+    #[default]
+    Concat,
+
+    /// Keep the latest value of every field, `content` included, and drop
+    /// the ones before it, as ACP specifies: a status or progress update
+    /// replaces the last.
+    LatestWins,
+}
+
+/// How Decaf reacts when the text it is holding exceeds
+/// [`DecafBuilder::max_total_bytes`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Flush every session at once, so the limit is exceeded by at most
+    /// the chunk that crossed it.
+    #[default]
+    Flush,
+
+    /// Stop reading from the sender until deadline flushes bring the total
+    /// back under the limit. This holds up every message from that peer
+    /// while it waits, and needs the timer: combined with
+    /// [`with_flush_signal`](DecafBuilder::with_flush_signal) the handler
+    /// waits for the next signal.
+    Block,
+}
+
+/// What Decaf does with a new session once
+/// [`DecafBuilder::max_sessions`] sessions are already buffered.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SessionLimitPolicy {
+    /// Flush the least recently updated session and drop its entry to make
+    /// room, so the new session is buffered as usual.
+    #[default]
+    EvictLeastRecent,
+
+    /// Leave the buffered sessions alone and forward the new session's
+    /// updates untouched until room frees up.
+    PassThrough,
+}
+
+/// Builder for a [`Decaf`] proxy, obtained from [`Decaf::builder`].
+pub struct DecafBuilder {
+    name: String,
+    metrics_prefix: String,
+    enabled: bool,
+    interval: Duration,
+    min_interval: Duration,
+    adaptive: Option<(Duration, Duration)>,
+    max_latency: Option<Duration>,
+    jitter: Duration,
+    quiet_period: Option<Duration>,
+    leading_edge: bool,
+    coalesce_thoughts: bool,
+    thought_interval: Option<Duration>,
+    first_flush_after: Option<Duration>,
+    coalesce_user_echo: bool,
+    coalesce_tool_calls: bool,
+    tool_call_modes: Vec<(ToolKind, CoalesceMode)>,
+    flush_on_sentence: bool,
+    sentence_terminators: Vec<char>,
+    flush_on_newline: bool,
+    flush_on_paragraph: bool,
+    flush_on_pattern: Option<Regex>,
+    max_buffer_bytes: Option<usize>,
+    passthrough_large: Option<usize>,
+    auto_passthrough_above: Option<usize>,
+    flush_every_chunks: Option<usize>,
+    flush_every_tokens: Option<(usize, TokenCountFn)>,
+    join_with: String,
+    skip_empty_chunks: bool,
+    skip_whitespace_chunks: bool,
+    dedupe_repeats: bool,
+    split_on_word_boundary: bool,
+    max_total_bytes: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    max_sessions: Option<usize>,
+    session_limit_policy: SessionLimitPolicy,
+    single_session: bool,
+    session_ttl: Option<Duration>,
+    initial_buffer_capacity: usize,
+    interval_for: Option<IntervalFn>,
+    passthrough_sessions: Option<PassthroughFn>,
+    session_priority: Option<PriorityFn>,
+    session_alias: Option<SessionMapFn>,
+    map_session_id: Option<SessionMapFn>,
+    debounce_client_to_agent: bool,
+    flush_before_response: bool,
+    flush_on_stop_reason: bool,
+    coalesce_across_prompts: bool,
+    mark_coalesced: bool,
+    stamp_timestamps: bool,
+    trim_trailing_on_flush: bool,
+    transform: Option<TransformFn>,
+    can_merge: Option<MergeFn>,
+    merge_fn: Option<ConcatFn>,
+    on_flush: Option<FlushFn>,
+    on_error: Option<ErrorFn>,
+    flush_signal: Option<mpsc::Receiver<()>>,
+    tap: Option<mpsc::Sender<SessionNotification>>,
+    max_emit_rate: Option<u32>,
+    max_emit_bytes: Option<usize>,
+    history: Option<usize>,
+    clock: Arc<dyn Clock>,
+    shutdown: CancellationToken,
+}
+
+impl Default for DecafBuilder {
+    fn default() -> Self {
+        DecafBuilder {
+            name: DEFAULT_NAME.to_string(),
+            metrics_prefix: DEFAULT_METRICS_PREFIX.to_string(),
+            enabled: true,
+            interval: DEFAULT_INTERVAL,
+            min_interval: DEFAULT_MIN_INTERVAL,
+            adaptive: None,
+            max_latency: None,
+            jitter: Duration::ZERO,
+            quiet_period: None,
+            leading_edge: false,
+            coalesce_thoughts: true,
+            thought_interval: None,
+            first_flush_after: None,
+            coalesce_user_echo: false,
+            coalesce_tool_calls: false,
+            tool_call_modes: Vec::new(),
+            flush_on_sentence: false,
+            sentence_terminators: DEFAULT_SENTENCE_TERMINATORS.to_vec(),
+            flush_on_newline: false,
+            flush_on_paragraph: false,
+            flush_on_pattern: None,
+            max_buffer_bytes: None,
+            passthrough_large: None,
+            auto_passthrough_above: None,
+            flush_every_chunks: None,
+            flush_every_tokens: None,
+            join_with: String::new(),
+            skip_empty_chunks: false,
+            skip_whitespace_chunks: false,
+            dedupe_repeats: false,
+            split_on_word_boundary: false,
+            max_total_bytes: None,
+            overflow_policy: OverflowPolicy::default(),
+            max_sessions: None,
+            session_limit_policy: SessionLimitPolicy::default(),
+            single_session: false,
+            session_ttl: None,
+            initial_buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+            interval_for: None,
+            passthrough_sessions: None,
+            session_priority: None,
+            session_alias: None,
+            map_session_id: None,
+            debounce_client_to_agent: false,
+            flush_before_response: true,
+            flush_on_stop_reason: true,
+            coalesce_across_prompts: false,
+            mark_coalesced: false,
+            stamp_timestamps: false,
+            trim_trailing_on_flush: false,
+            transform: None,
+            can_merge: None,
+            merge_fn: None,
+            on_flush: None,
+            on_error: None,
+            flush_signal: None,
+            tap: None,
+            max_emit_rate: None,
+            max_emit_bytes: None,
+            history: None,
+            clock: Arc::new(TokioClock),
+            shutdown: CancellationToken::new(),
+        }
+    }
+}
+
+impl DecafBuilder {
+    /// Name this proxy instance (default: `"decaf"`).
+    ///
+    /// The name is given to the sacp connection, recorded as the `proxy`
+    /// field of every session span, and reported by
+    /// [`DecafStats::name`](crate::DecafStats::name), so two instances in one
+    /// pipeline can be told apart.
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// Start the metric names of
+    /// [`DecafStats::render_prometheus`](crate::DecafStats::render_prometheus)
+    /// with `prefix` (default: `"decaf_"`), e.g. `"myapp_decaf_"`. It is
+    /// used as-is, so include any trailing underscore.
+    pub fn metrics_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.metrics_prefix = prefix.into();
+        self
+    }
+
+    /// Coalesce at all (default: on).
+    ///
+    /// A disabled proxy registers no handlers and runs no flush task: sacp
+    /// forwards every message untouched, as if Decaf were not in the chain,
+    /// and [`DecafStats`](crate::DecafStats) counts nothing. Every other
+    /// option is ignored, apart from the name, the cancellation token and
+    /// [`DecafControl::drain`](crate::DecafControl::drain), which returns
+    /// at once.
+    pub fn enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    /// How long to coalesce chunks before flushing (default: 100ms).
+    /// [`DecafControl::set_interval`](crate::DecafControl::set_interval)
+    /// changes it while the proxy runs.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Scale each session's interval with its chunk rate, between
+    /// `min_interval` and `max_interval`, instead of using a fixed one.
+    ///
+    /// The session keeps an exponentially weighted moving average of the
+    /// gap between its chunks (each new gap weighted 1/4). The window is
+    /// `max_interval - (max_interval - min_interval) * min(gap / max_interval, 1)`:
+    /// a rapid burst coalesces for close to `max_interval`, while chunks
+    /// arriving `max_interval` or more apart, too slowly for coalescing to
+    /// gain anything, are flushed after `min_interval`. A session's first
+    /// chunk, with no gap measured yet, uses `min_interval`. The estimate
+    /// starts over with each turn, when the session's entry is freed.
+    ///
+    /// This replaces [`interval`](Self::interval) and
+    /// [`interval_for`](Self::interval_for);
+    /// [`max_latency`](Self::max_latency) and
+    /// [`quiet_period`](Self::quiet_period) still apply on top.
+    ///
+    /// # Panics
+    ///
+    /// [`build`](Self::build) panics if `min_interval` is zero or greater
+    /// than `max_interval`.
+    pub fn adaptive_interval(mut self, min_interval: Duration, max_interval: Duration) -> Self {
+        self.adaptive = Some((min_interval, max_interval));
+        self
+    }
+
+    /// Bound how long buffered text may wait before it is flushed.
+    ///
+    /// Each session is stamped with the arrival time of its first un-flushed
+    /// chunk; once that age reaches `max_latency` the session is flushed even
+    /// if its interval has not elapsed yet. This caps every session no matter
+    /// what interval [`interval_for`](Self::interval_for) picks for it.
+    pub fn max_latency(mut self, max_latency: Duration) -> Self {
+        self.max_latency = Some(max_latency);
+        self
+    }
+
+    /// Stagger each session's deadline by a random offset of up to
+    /// `jitter`, so sessions started together don't all flush at once
+    /// (default: zero, every session flushes exactly on its interval).
+    ///
+    /// The offset is drawn uniformly from `[0, jitter)` when the session's
+    /// entry is created, so it holds for the rest of the turn and is drawn
+    /// again for the next one. It only ever delays a deadline, and
+    /// [`max_latency`](Self::max_latency) still caps the result;
+    /// [`quiet_period`](Self::quiet_period) and the early flushes are not
+    /// affected.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Flush a session early once its stream has gone quiet.
+    ///
+    /// If no chunk has been buffered for a session for `quiet_period`, it is
+    /// flushed without waiting for the rest of its interval, so a short burst
+    /// followed by silence shows up promptly. A steady stream still flushes
+    /// when its interval is reached.
+    pub fn quiet_period(mut self, quiet_period: Duration) -> Self {
+        self.quiet_period = Some(quiet_period);
+        self
+    }
+
+    /// Forward the first chunk of each reply immediately.
+    ///
+    /// When a session has no buffer yet for a chunk kind, its first chunk goes
+    /// straight to the client and only the chunks that follow are coalesced.
+    /// The edge re-arms when a `PromptRequest` response flushes and frees the
+    /// session, so every reply in a multi-prompt session gets an instant
+    /// first token.
+    /// Timer-driven flushes do *not* re-arm it: mid-reply pauses keep
+    /// coalescing as usual.
+    pub fn leading_edge(mut self, leading_edge: bool) -> Self {
+        self.leading_edge = leading_edge;
+        self
+    }
+
+    /// Flush each reply's first coalesced text after `first_flush_after`
+    /// instead of the full interval, e.g. 30ms then every 150ms, to cut the
+    /// time to first token.
+    ///
+    /// Unlike [`leading_edge`](Self::leading_edge), the first chunks are
+    /// still coalesced, just over a shorter window. Every stream of the
+    /// session uses it (when shorter than its own interval) until the
+    /// session first sends coalesced text, early splits included; after
+    /// that the usual intervals apply. Like the leading edge, it re-arms
+    /// when a `PromptRequest` response frees the session. Jitter and
+    /// [`max_latency`](Self::max_latency) apply as usual.
+    ///
+    /// # Panics
+    ///
+    /// [`build`](Self::build) panics if `first_flush_after` is below
+    /// [`with_min_interval`](Self::with_min_interval).
+    pub fn first_flush_after(mut self, first_flush_after: Duration) -> Self {
+        self.first_flush_after = Some(first_flush_after);
+        self
+    }
+
+    /// Also coalesce `AgentThoughtChunk` text (default: `true`).
+    ///
+    /// Thoughts are buffered separately from message text and flushed as
+    /// their own `AgentThoughtChunk` notifications. When disabled, thought
+    /// chunks are forwarded untouched like any other update.
+    pub fn coalesce_thoughts(mut self, coalesce_thoughts: bool) -> Self {
+        self.coalesce_thoughts = coalesce_thoughts;
+        self
+    }
+
+    /// Coalesce thought text over `thought_interval` instead of the
+    /// session's interval (default: the same interval as messages).
+    ///
+    /// Reasoning is usually less urgent than the answer, so a longer window
+    /// cuts its notifications further. Jitter and
+    /// [`max_latency`](Self::max_latency) apply as usual. A message chunk
+    /// still flushes pending thoughts ahead of it at once, so the two
+    /// cadences never hold each other up.
+    ///
+    /// # Panics
+    ///
+    /// [`build`](Self::build) panics if `thought_interval` is below
+    /// [`with_min_interval`](Self::with_min_interval).
+    pub fn thought_interval(mut self, thought_interval: Duration) -> Self {
+        self.thought_interval = Some(thought_interval);
+        self
+    }
+
+    /// Also coalesce `UserMessageChunk` text the agent sends (default:
+    /// `false`).
+    ///
+    /// Some agents echo the user's message back while they reflect on it,
+    /// a chunk at a time. The echo gets a buffer of its own, flushed on the
+    /// same timer as `UserMessageChunk` notifications, and switching between
+    /// it and message or thought text flushes the stream being left, as for
+    /// thoughts. When disabled, the echo is forwarded untouched.
+    pub fn coalesce_user_echo(mut self, coalesce_user_echo: bool) -> Self {
+        self.coalesce_user_echo = coalesce_user_echo;
+        self
+    }
+
+    /// Also coalesce `ToolCallUpdate` notifications (default: `false`).
+    ///
+    /// Updates are held per session *and* tool call, so several calls in
+    /// flight never mix, and each call's updates merge into one carrying
+    /// the latest value of each field that was set, with the `content` of
+    /// every update appended in turn (see [`CoalesceMode`] to replace it
+    /// instead, per [`ToolKind`]). They flush with the session's text, on its
+    /// deadline, before any other notification for the session and at the
+    /// prompt response. A flush emits the session's text and tool calls in
+    /// the order their first un-flushed update arrived, and text sent early
+    /// by another trigger takes any older tool call along ahead of it, so
+    /// the client sees the true interleaving of concurrent streams.
+    pub fn coalesce_tool_calls(mut self, coalesce_tool_calls: bool) -> Self {
+        self.coalesce_tool_calls = coalesce_tool_calls;
+        self
+    }
+
+    /// How updates to tool calls of `kind` merge under
+    /// [`coalesce_tool_calls`](Self::coalesce_tool_calls) (default:
+    /// [`CoalesceMode::Concat`] for every kind). Call once per kind; the
+    /// last call for a kind wins.
+    ///
+    /// A call's kind is the one its `ToolCall` announced, or failing that
+    /// the latest one its updates set. Once a mode is set, `ToolCall`
+    /// notifications are watched for their kind too, flushing the session
+    /// ahead of them as any other notification does.
+    pub fn tool_call_mode(mut self, kind: ToolKind, mode: CoalesceMode) -> Self {
+        self.tool_call_modes.push((kind, mode));
+        self
+    }
+
+    /// Flush complete sentences as soon as they are buffered.
+    ///
+    /// A sentence ends at `.`, `!` or `?` followed by whitespace *and* more
+    /// text, so `e.g.` mid-word never splits and a trailing terminator waits
+    /// for the next chunk to confirm the boundary. Non-ASCII terminators,
+    /// by default `。`, `！` and `？`, need no whitespace, only more text that
+    /// doesn't start with another terminator (so `！？` stays together).
+    /// Each complete sentence is emitted as its own notification (including
+    /// the whitespace after its terminator); the trailing partial sentence
+    /// stays buffered and is flushed by the timer as usual.
+    pub fn flush_on_sentence(mut self, flush_on_sentence: bool) -> Self {
+        self.flush_on_sentence = flush_on_sentence;
+        self
+    }
+
+    /// The characters that end a sentence for
+    /// [`flush_on_sentence`](Self::flush_on_sentence) (default:
+    /// [`DEFAULT_SENTENCE_TERMINATORS`]).
+    ///
+    /// Text is scanned by `char`, so any Unicode scalar value works and
+    /// splits always land on a character boundary. To add to the defaults,
+    /// extend a copy of [`DEFAULT_SENTENCE_TERMINATORS`].
+    pub fn sentence_terminators(mut self, terminators: &[char]) -> Self {
+        self.sentence_terminators = terminators.to_vec();
+        self
+    }
+
+    /// Flush completed lines as soon as they are buffered.
+    ///
+    /// Everything up to and including the last `\n` in the buffer is emitted
+    /// as one notification, so several lines arriving in one chunk still
+    /// coalesce; the trailing partial line stays buffered and is flushed by
+    /// the timer as usual. A `\r\n` ending splits after the `\n`.
+    pub fn flush_on_newline(mut self, flush_on_newline: bool) -> Self {
+        self.flush_on_newline = flush_on_newline;
+        self
+    }
+
+    /// Flush completed paragraphs as soon as they are buffered, for clients
+    /// that render markdown incrementally.
+    ///
+    /// A paragraph ends at a blank line: a `\n` followed by a line holding
+    /// only whitespace (`\n\n`, `\n \n`, `\r\n\r\n`...) and its own `\n`.
+    /// Everything up to and including the last blank line is emitted as one
+    /// notification, and the partial paragraph after it stays buffered.
+    /// Unlike [`flush_on_newline`](Self::flush_on_newline), the lines of a
+    /// paragraph are kept together; with both on, the newline split runs
+    /// second and takes the remaining complete lines.
+    pub fn flush_on_paragraph(mut self, flush_on_paragraph: bool) -> Self {
+        self.flush_on_paragraph = flush_on_paragraph;
+        self
+    }
+
+    /// Flush whenever newly buffered text completes a match of `pattern`.
+    ///
+    /// Everything up to and including the last such match is emitted as one
+    /// notification; the rest stays buffered. This runs before the other
+    /// early-flush modes.
+    ///
+    /// To keep buffering linear, only the appended chunk plus the 256 bytes
+    /// buffered before it are searched (anchors still see the whole buffer).
+    /// A match that starts before that window is missed, so keep patterns
+    /// short, e.g. `(?m)^```` for fenced code blocks.
+    pub fn flush_on_pattern(mut self, pattern: Regex) -> Self {
+        self.flush_on_pattern = Some(pattern);
+        self
+    }
+
+    /// Flush as soon as a buffer holds more than `max_buffer_bytes` of text
+    /// (default: unlimited).
+    ///
+    /// The flushed notification carries at most `max_buffer_bytes`, cut on a
+    /// UTF-8 character boundary; any overflow stays buffered. A single
+    /// character wider than the cap is emitted whole rather than split.
+    pub fn max_buffer_bytes(mut self, max_buffer_bytes: usize) -> Self {
+        self.max_buffer_bytes = Some(max_buffer_bytes);
+        self
+    }
+
+    /// Forward text chunks longer than `threshold` bytes as they are, with
+    /// no coalescing (default: every chunk is buffered).
+    ///
+    /// A chunk that big, such as a whole paragraph sent at once, gains
+    /// nothing from waiting. Whatever the session has buffered is flushed
+    /// first, so the large chunk never overtakes earlier text; smaller
+    /// chunks go on coalescing as usual.
+    pub fn passthrough_large(mut self, threshold: usize) -> Self {
+        self.passthrough_large = Some(threshold);
+        self
+    }
+
+    /// Forward a session's text chunks as they are while their average
+    /// size is over `avg_bytes` (default: every chunk is buffered).
+    ///
+    /// Where [`passthrough_large`](Self::passthrough_large) looks at one
+    /// chunk, this looks at the stream: an agent that already sends few,
+    /// large chunks gains nothing from coalescing but the latency. The
+    /// average is smoothed over the session's recent chunks (each new one
+    /// weighs a quarter), so a stream that turns back to small chunks is
+    /// coalesced again. As with `passthrough_large`, text buffered before
+    /// the switch is flushed first.
+    pub fn auto_passthrough_above(mut self, avg_bytes: usize) -> Self {
+        self.auto_passthrough_above = Some(avg_bytes);
+        self
+    }
+
+    /// Flush a session once it has buffered `chunks` text chunks since its
+    /// last flush (default: off).
+    ///
+    /// This runs alongside the timer: whichever comes first flushes, and
+    /// any flush starts the count again. Chunks forwarded without being
+    /// buffered (leading edge, passthrough) are not counted.
+    ///
+    /// # Panics
+    ///
+    /// [`build`](Self::build) panics if `chunks` is zero.
+    pub fn flush_every_chunks(mut self, chunks: usize) -> Self {
+        self.flush_every_chunks = Some(chunks);
+        self
+    }
+
+    /// Flush a session once the text chunks it buffered since its last
+    /// flush hold `tokens` tokens, as counted by `count_tokens` (default:
+    /// off).
+    ///
+    /// Plug in a real tokenizer for LLM-paced output, or a whitespace split
+    /// for a cheap approximation. `count_tokens` is called on each chunk's
+    /// text once, as it arrives, and the counts are summed, so the buffer is
+    /// never re-tokenized; a token split across two chunks may be counted
+    /// twice. Like [`flush_every_chunks`](Self::flush_every_chunks), this
+    /// runs alongside the timer and any flush starts the count again.
+    ///
+    /// # Panics
+    ///
+    /// [`build`](Self::build) panics if `tokens` is zero.
+    pub fn flush_every_tokens(
+        mut self,
+        tokens: usize,
+        count_tokens: impl Fn(&str) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        self.flush_every_tokens = Some((tokens, Box::new(count_tokens)));
+        self
+    }
+
+    /// Insert `separator` between consecutive text chunks as they are
+    /// coalesced (default: `""`, joining them as they are).
+    ///
+    /// For agents that send bare tokens and leave spacing to the client,
+    /// e.g. `join_with(" ")`. The separator only goes between two chunks
+    /// buffered together, never before the first chunk after a flush, and
+    /// empty chunks add none: text in separate notifications is joined by
+    /// the client, as it would have been without Decaf.
+    pub fn join_with(mut self, separator: impl Into<String>) -> Self {
+        self.join_with = separator.into();
+        self
+    }
+
+    /// Drop text chunks from the agent whose text is empty (default: off).
+    ///
+    /// They are dropped before anything else sees them, so they open no
+    /// session, reset no timer and count towards no stats, and the text
+    /// around them coalesces as if they were never sent. Chunks of other
+    /// content, such as images, are never dropped, and neither are
+    /// annotation-only chunks, whose annotations are merged into the text.
+    pub fn skip_empty_chunks(mut self, skip_empty_chunks: bool) -> Self {
+        self.skip_empty_chunks = skip_empty_chunks;
+        self
+    }
+
+    /// Drop text chunks from the agent whose text is only whitespace,
+    /// empty ones included, like [`skip_empty_chunks`](Self::skip_empty_chunks)
+    /// (default: off).
+    ///
+    /// Kept separate because a lone `" "` or `"\n"` is often meaningful:
+    /// agents that stream a space or a line break as its own token would
+    /// lose it. Turn it on only for agents whose whitespace-only chunks are
+    /// strays.
+    pub fn skip_whitespace_chunks(mut self, skip_whitespace_chunks: bool) -> Self {
+        self.skip_whitespace_chunks = skip_whitespace_chunks;
+        self
+    }
+
+    /// Drop a text chunk that repeats the one just before it (default: off).
+    ///
+    /// For agents that resend a chunk on retry, which would otherwise be
+    /// coalesced into "thethe". A chunk is only dropped if its text is
+    /// identical to the session's previous chunk of the same stream, nothing
+    /// else arrived for the session in between, and it came within
+    /// [`REPEAT_WINDOW`](crate::REPEAT_WINDOW) of it. Repeated words in prose
+    /// usually differ by their whitespace ("that", " that") or arrive inside
+    /// one chunk, so they are kept.
+    pub fn dedupe_repeats(mut self, dedupe_repeats: bool) -> Self {
+        self.dedupe_repeats = dedupe_repeats;
+        self
+    }
+
+    /// Keep a trailing partial word buffered when a flush cuts text short
+    /// (default: off).
+    ///
+    /// Timer flushes (the interval, [`max_latency`](Self::max_latency) and
+    /// [`quiet_period`](Self::quiet_period)) and the
+    /// [`max_buffer_bytes`](Self::max_buffer_bytes) cap then emit text only
+    /// through its last whitespace; the rest gets a fresh window, so a
+    /// partially held word may wait up to one more interval. Text without
+    /// any whitespace is flushed whole, so a long token is never held
+    /// indefinitely.
+    ///
+    /// The byte cap takes precedence: each piece still fits within
+    /// `max_buffer_bytes`, backing off to the last whitespace inside it, and
+    /// a word longer than the cap is cut at the cap as before. Turn ends,
+    /// drains, flush signals and the flush ahead of a non-text update send
+    /// everything, partial word included.
+    pub fn split_on_word_boundary(mut self, split_on_word_boundary: bool) -> Self {
+        self.split_on_word_boundary = split_on_word_boundary;
+        self
+    }
+
+    /// Send the client at most `per_second` timer flushes a second across
+    /// all sessions (default: unlimited), smoothing bursts such as many
+    /// sessions coming due together.
+    ///
+    /// A token bucket holding a single token, refilled every
+    /// `1 / per_second`, is checked before each timer flush. While it is
+    /// empty, due sessions stay buffered, taking in new chunks, and are
+    /// flushed oldest deadline first as tokens come in. Other notifications
+    /// still go out at once to keep their order (the flush ahead of a
+    /// non-text update, turn ends, early splits like
+    /// [`flush_on_sentence`](Self::flush_on_sentence), drains), but every
+    /// coalesced or passed-through chunk sent to the client spends a token,
+    /// so the timer makes up for them afterwards: over any window of `t`
+    /// seconds the timer sends at most `1 + per_second * t` minus whatever
+    /// else was sent. Text bound for the agent is not limited.
+    ///
+    /// # Panics
+    ///
+    /// [`build`](Self::build) panics if `per_second` is zero.
+    pub fn max_emit_rate(mut self, per_second: u32) -> Self {
+        self.max_emit_rate = Some(per_second);
+        self
+    }
+
+    /// Send no text notification carrying more than `max_bytes` of text
+    /// (default: unlimited), for transports with a maximum frame size.
+    ///
+    /// Unlike [`max_buffer_bytes`](Self::max_buffer_bytes), this changes
+    /// nothing about when text is flushed; it applies as each notification
+    /// goes out, after any [`transform`](Self::transform). A larger one is
+    /// sent as consecutive copies of itself, `meta` included, each carrying
+    /// the next piece of its text: cut after the last whitespace that fits,
+    /// or on a UTF-8 character boundary when there is none. The pieces
+    /// concatenate back to the original text, and a single character wider
+    /// than `max_bytes` is sent whole rather than split. Each piece counts
+    /// as a notification toward [`max_emit_rate`](Self::max_emit_rate) and
+    /// the stats.
+    ///
+    /// # Panics
+    ///
+    /// [`build`](Self::build) panics if `max_bytes` is zero.
+    pub fn max_emit_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_emit_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Keep the last `capacity` notifications sent to the client, for
+    /// [`DecafControl::history`](crate::DecafControl::history) and
+    /// [`DecafControl::recent`](crate::DecafControl::recent) to answer
+    /// "what went out lately?" (default: off).
+    ///
+    /// Purely diagnostic: each one is cloned as it is sent and the oldest
+    /// is dropped once `capacity` are held, so memory stays bounded by
+    /// `capacity` notifications.
+    ///
+    /// # Panics
+    ///
+    /// [`build`](Self::build) panics if `capacity` is zero.
+    pub fn with_history(mut self, capacity: usize) -> Self {
+        self.history = Some(capacity);
+        self
+    }
+
+    /// Bound the text buffered across all sessions (default: unlimited).
+    ///
+    /// The total is kept per direction (text bound for the client, and with
+    /// [`debounce_client_to_agent`](Self::debounce_client_to_agent) text
+    /// bound for the agent, each get `max_total_bytes`). What happens once a
+    /// chunk takes it over the limit is chosen by
+    /// [`overflow_policy`](Self::overflow_policy).
+    pub fn max_total_bytes(mut self, max_total_bytes: usize) -> Self {
+        self.max_total_bytes = Some(max_total_bytes);
+        self
+    }
+
+    /// What to do when [`max_total_bytes`](Self::max_total_bytes) is exceeded
+    /// (default: [`OverflowPolicy::Flush`]).
+    pub fn overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Bound how many sessions are buffered at once (default: unlimited), to
+    /// guard against an agent opening thousands of sessions.
+    ///
+    /// The limit applies per direction. What happens to a session arriving
+    /// while it is reached is chosen by
+    /// [`session_limit_policy`](Self::session_limit_policy); either way no
+    /// text is dropped.
+    ///
+    /// # Panics
+    ///
+    /// [`build`](Self::build) panics if `max_sessions` is zero.
+    pub fn max_sessions(mut self, max_sessions: usize) -> Self {
+        self.max_sessions = Some(max_sessions);
+        self
+    }
+
+    /// What to do with a new session once
+    /// [`max_sessions`](Self::max_sessions) are buffered (default:
+    /// [`SessionLimitPolicy::EvictLeastRecent`]).
+    pub fn session_limit_policy(mut self, session_limit_policy: SessionLimitPolicy) -> Self {
+        self.session_limit_policy = session_limit_policy;
+        self
+    }
+
+    /// Keep sessions in a single slot instead of a map (default: `false`),
+    /// for embeddings that only ever stream one session at a time.
+    ///
+    /// The slot is never hashed into or grown, and is emptied at each turn
+    /// end like a map entry, so a new session after that reuses it. If a
+    /// second session arrives while the first still has an entry, both move
+    /// into a map (logged at `DEBUG`) for the rest of the run; nothing is
+    /// flushed or lost.
+    pub fn single_session(mut self, single_session: bool) -> Self {
+        self.single_session = single_session;
+        self
+    }
+
+    /// Flush and drop a session's entry once nothing has arrived for it in
+    /// `session_ttl` (default: never).
+    ///
+    /// Entries are normally freed when a turn ends. One whose turn never
+    /// ends (an agent that stopped mid-stream, or updates outside any
+    /// prompt) would otherwise stay until shutdown; with a TTL the flush
+    /// task flushes what it holds and removes it (logged with reason
+    /// `session_ttl`). A later
+    /// update for the session starts a fresh entry, without the tool call
+    /// kinds or [`first_flush_after`](Self::first_flush_after) state the old
+    /// one had.
+    ///
+    /// # Panics
+    ///
+    /// [`build`](Self::build) panics if `session_ttl` is zero.
+    pub fn session_ttl(mut self, session_ttl: Duration) -> Self {
+        self.session_ttl = Some(session_ttl);
+        self
+    }
+
+    /// Bytes of text capacity to reserve for each new buffer (default: 1024).
+    ///
+    /// Flushing copies the text out and keeps the buffer's allocation, so a
+    /// long stream only reallocates when a single flush window outgrows it.
+    pub fn initial_buffer_capacity(mut self, initial_buffer_capacity: usize) -> Self {
+        self.initial_buffer_capacity = initial_buffer_capacity;
+        self
+    }
+
+    /// Choose the coalescing interval per session.
+    ///
+    /// The closure runs when a session's buffer entry is created (its first
+    /// chunk of a turn, since entries are freed when a turn ends) and the
+    /// result is stored with the session, raised to
+    /// [`with_min_interval`](Self::with_min_interval) if it is shorter.
+    /// Without it every session uses [`interval`](Self::interval).
+    pub fn interval_for(
+        mut self,
+        interval_for: impl Fn(&SessionId) -> Duration + Send + Sync + 'static,
+    ) -> Self {
+        self.interval_for = Some(Box::new(interval_for));
+        self
+    }
+
+    /// Forward chunks for the sessions `passthrough` selects immediately,
+    /// without buffering.
+    ///
+    /// Like [`interval_for`](Self::interval_for), the predicate runs once
+    /// when a session's buffer entry is created and the answer is cached
+    /// with it. If anything is still buffered for a session when its chunks
+    /// start passing through, that text is flushed first.
+    pub fn passthrough_sessions(
+        mut self,
+        passthrough: impl Fn(&SessionId) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.passthrough_sessions = Some(Box::new(passthrough));
+        self
+    }
+
+    /// Rank sessions for flushing, highest first (default: all equal).
+    ///
+    /// Wherever several sessions are flushed in one go, higher-priority
+    /// ones go first: timer flushes, drains, flush signals and shutdown.
+    /// It matters most with [`max_emit_rate`](Self::max_emit_rate), which
+    /// sends due sessions by priority and only then oldest deadline first,
+    /// so the chat a user is looking at isn't kept waiting behind
+    /// background ones. Unlike [`interval_for`](Self::interval_for), the
+    /// closure is asked every time sessions are ordered, so a priority can
+    /// follow focus as it moves; keep it cheap.
+    pub fn session_priority(
+        mut self,
+        priority: impl Fn(&SessionId) -> u8 + Send + Sync + 'static,
+    ) -> Self {
+        self.session_priority = Some(Box::new(priority));
+        self
+    }
+
+    /// Merge streams that arrive under more than one session id back into
+    /// one (default: take every id as it is), for conductors that, by a
+    /// bug, send part of a session's stream under another id.
+    ///
+    /// `alias` returns the canonical id for each id, itself for ids that
+    /// are already canonical. It is applied as each notification from the
+    /// agent arrives, before buffering, so chunks under an alias join the
+    /// canonical session's buffer in arrival order, and everything sent on
+    /// carries the canonical id. Each rewrite is logged at `DEBUG`. Ids
+    /// from the client (in prompts and cancellations) are normalized the
+    /// same way to find their session. Unlike
+    /// [`map_session_id`](Self::map_session_id), which chooses the ids the
+    /// client sees, this only repairs the input; with both set, ids are
+    /// normalized first and the canonical id is then mapped. Like it,
+    /// this leaves text coalesced toward the agent and a tapped or disabled
+    /// proxy alone.
+    pub fn session_alias(
+        mut self,
+        alias: impl Fn(&SessionId) -> SessionId + Send + Sync + 'static,
+    ) -> Self {
+        self.session_alias = Some(Box::new(alias));
+        self
+    }
+
+    /// Rewrite the session id of every notification from the agent
+    /// (default: leave them alone), e.g. to namespace sessions or map
+    /// internal ids to external ones.
+    ///
+    /// The id is mapped as each notification arrives, so buffers are kept
+    /// under the mapped id and everything sent on, coalesced or not,
+    /// carries it. Ids coming from the client (in prompts and
+    /// cancellations) are taken to be unmapped, as the agent handed them
+    /// out, and are mapped the same way to find their session; the
+    /// messages themselves go to the agent unchanged.
+    /// [`interval_for`](Self::interval_for),
+    /// [`passthrough_sessions`](Self::passthrough_sessions),
+    /// [`session_priority`](Self::session_priority) and the
+    /// [`DecafControl`](crate::DecafControl) queries see mapped ids.
+    /// Text coalesced toward the agent, and a [`tap`](Self::tap)ped or
+    /// disabled proxy, which coalesce nothing, are left unmapped.
+    pub fn map_session_id(
+        mut self,
+        map: impl Fn(&SessionId) -> SessionId + Send + Sync + 'static,
+    ) -> Self {
+        self.map_session_id = Some(Box::new(map));
+        self
+    }
+
+    /// Also coalesce `UserMessageChunk` notifications the client streams
+    /// toward the agent (default: `false`).
+    ///
+    /// The client side keeps its own session state and flushes the same way
+    /// as the agent side: on its deadline, and before forwarding any other
+    /// message from the client, so a prompt never overtakes the text typed
+    /// ahead of it.
+    pub fn debounce_client_to_agent(mut self, debounce_client_to_agent: bool) -> Self {
+        self.debounce_client_to_agent = debounce_client_to_agent;
+        self
+    }
+
+    /// Send a turn's remaining text before its `PromptRequest` response
+    /// (default: `true`).
+    ///
+    /// When disabled the response is forwarded first and the final
+    /// coalesced chunks follow it, so a client can show the turn as done
+    /// before the tail of the text lands. The buffers are taken as the
+    /// response arrives, so no later chunk can be mixed in, and the text is
+    /// queued behind the response on the proxy's transport. A conductor
+    /// forwarding responses through a different task than notifications
+    /// (sacp's does) can still deliver them to the client swapped.
+    pub fn flush_before_response(mut self, flush_before_response: bool) -> Self {
+        self.flush_before_response = flush_before_response;
+        self
+    }
+
+    /// Send a turn's remaining text before its response whenever the turn
+    /// stops for any reason but `EndTurn`, even with
+    /// [`flush_before_response(false)`](Self::flush_before_response)
+    /// (default: `true`).
+    ///
+    /// A client showing a `MaxTokens`, `Refusal` or `Cancelled` stop (or an
+    /// error) is likely to render the partial text with it, so the tail
+    /// shouldn't land after the indicator. `EndTurn` still follows
+    /// `flush_before_response`.
+    pub fn flush_on_stop_reason(mut self, flush_on_stop_reason: bool) -> Self {
+        self.flush_on_stop_reason = flush_on_stop_reason;
+        self
+    }
+
+    /// Keep coalescing a session's text across prompts sent before the
+    /// previous one was answered (default: `false`).
+    ///
+    /// A client firing several prompts in a row (a multi-turn macro, say)
+    /// would otherwise have each response flush the session, leaving
+    /// little to coalesce. With this on, a response only ends the turn once
+    /// it answers the session's last outstanding prompt: until then text
+    /// goes out on the timer (or an early trigger), and the last response
+    /// flushes what is left as usual. Prompts sent one after another's
+    /// response are unaffected, as nothing is outstanding when each is
+    /// answered.
+    pub fn coalesce_across_prompts(mut self, coalesce_across_prompts: bool) -> Self {
+        self.coalesce_across_prompts = coalesce_across_prompts;
+        self
+    }
+
+    /// Keep trailing whitespace off the end of coalesced notifications
+    /// (default: `false`), for clients that render it, in code blocks say.
+    ///
+    /// The whitespace isn't lost: it is held back and leads the next
+    /// notification of the same stream, so the text put back together is
+    /// unchanged and words flushed apart stay apart. Text that is only
+    /// whitespace is held back whole. Whitespace still held when the
+    /// session's turn ends (or it is evicted or shut down) is dropped, as it
+    /// has nothing left to separate. Chunks forwarded as they are keep
+    /// theirs.
+    pub fn trim_trailing_on_flush(mut self, trim_trailing_on_flush: bool) -> Self {
+        self.trim_trailing_on_flush = trim_trailing_on_flush;
+        self
+    }
+
+    /// Tag every coalesced notification in its `meta` (default: `false`).
+    ///
+    /// Each notification Decaf builds from buffered text gets two
+    /// notification-level keys, overwriting any chunk meta of the same name:
+    ///
+    /// - `"decaf.coalesced"` ([`COALESCED_META_KEY`](crate::COALESCED_META_KEY)):
+    ///   `true`
+    /// - `"decaf.chunk_count"` ([`CHUNK_COUNT_META_KEY`](crate::CHUNK_COUNT_META_KEY)):
+    ///   how many text chunks the notification was built from. A chunk split
+    ///   across several notifications (by sentence, line, pattern or byte
+    ///   cap) counts towards each of them.
+    ///
+    /// Chunks forwarded as they are (leading-edge and passthrough chunks, and
+    /// non-text blocks) are left unmarked, so clients can tell a batch from a
+    /// raw chunk.
+    pub fn mark_coalesced(mut self, mark_coalesced: bool) -> Self {
+        self.mark_coalesced = mark_coalesced;
+        self
+    }
+
+    /// Record in every coalesced notification's `meta` when its first and
+    /// last chunks arrived (default: `false`), for latency analysis
+    /// downstream.
+    ///
+    /// Both are milliseconds since the Unix epoch, under
+    /// `"decaf.first_chunk_at"` ([`FIRST_CHUNK_AT_META_KEY`](crate::FIRST_CHUNK_AT_META_KEY))
+    /// and `"decaf.last_chunk_at"` ([`LAST_CHUNK_AT_META_KEY`](crate::LAST_CHUNK_AT_META_KEY)),
+    /// overwriting any chunk meta of the same name. They are read from the
+    /// proxy's [`Clock`](crate::Clock), set against the system time once
+    /// when the proxy is built, so a [`MockClock`](crate::MockClock) moves
+    /// them too. A notification split off the front of a longer chunk
+    /// (by sentence, line, pattern or byte cap) and the rest after it both
+    /// carry that chunk's arrival. Chunks forwarded as they are carry none.
+    pub fn stamp_timestamps(mut self, stamp_timestamps: bool) -> Self {
+        self.stamp_timestamps = stamp_timestamps;
+        self
+    }
+
+    /// Rewrite coalesced text just before it is sent, e.g. to normalize
+    /// whitespace or strip zero-width characters.
+    ///
+    /// `transform` sees the text of each outgoing notification, already
+    /// coalesced from its chunks, never the individual chunks, so a pattern
+    /// split across chunks is still matched whole. It is not called for
+    /// empty text, nor for chunks forwarded as they are (leading-edge and
+    /// passthrough chunks, non-text blocks). It runs under the session's
+    /// lock, in both directions, so keep it cheap.
+    pub fn transform(mut self, transform: impl Fn(&str) -> String + Send + Sync + 'static) -> Self {
+        self.transform = Some(Arc::new(transform));
+        self
+    }
+
+    /// Only merge a text chunk into the one before it if
+    /// `can_merge(previous, next)` agrees (default: always, as long as the
+    /// annotations match).
+    ///
+    /// Both chunks are passed as they arrived, `meta` and text included.
+    /// When the predicate says no, the text buffered so far becomes a
+    /// notification of its own, still sent in order at the next flush, and
+    /// `next` starts a new run and template. Chunks differing in their
+    /// [annotations](sacp::schema::Annotations) are never merged, whatever
+    /// the predicate says.
+    pub fn can_merge(
+        mut self,
+        can_merge: impl Fn(&ContentChunk, &ContentChunk) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.can_merge = Some(Arc::new(can_merge));
+        self
+    }
+
+    /// Append each text chunk to the buffered text with
+    /// `merge_fn(buffered, chunk)` (default: [`String::push_str`]), for
+    /// content that needs smarter joining, such as JSON fragments to
+    /// validate or normalize as they stream.
+    ///
+    /// It is called for every text chunk buffered, empty ones included,
+    /// after any [`join_with`](Self::join_with) separator has been pushed,
+    /// with the text of the current run so far: nothing flushed, and
+    /// nothing before a non-text block or a new run. What it leaves in
+    /// `buffered` is what is sent; an early trigger (sentence, line, byte
+    /// cap...) looks at it afterwards. It runs under the session's lock
+    /// for every chunk, in both directions, so it must be fast.
+    ///
+    /// It should only append to `buffered`: inline annotations record
+    /// offsets into the text already there. If it shortens the text anyway,
+    /// offsets past the new end are moved back to the end.
+    pub fn merge_fn(
+        mut self,
+        merge_fn: impl Fn(&mut String, &str) + Send + Sync + 'static,
+    ) -> Self {
+        self.merge_fn = Some(Arc::new(merge_fn));
+        self
+    }
+
+    /// Call `on_flush` for every coalesced notification sent, with its
+    /// session, how many text chunks it merged and its text's size in bytes
+    /// (after any [`transform`](Self::transform)).
+    ///
+    /// The chunk counts are those of
+    /// [`mark_coalesced`](Self::mark_coalesced): summed over a turn they
+    /// give the chunks buffered, with a chunk split across notifications
+    /// counted for each. Chunks forwarded as they are aren't reported.
+    /// `on_flush` runs on the task that sent the notification, once it holds
+    /// no session lock, so a slow callback cannot deadlock the proxy, though
+    /// it does hold up that task.
+    pub fn on_flush(
+        mut self,
+        on_flush: impl Fn(&SessionId, usize, usize) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_flush = Some(Box::new(on_flush));
+        self
+    }
+
+    /// Call `on_error` whenever the flush task fails to flush or send
+    /// buffered text (default: log it at `ERROR`).
+    ///
+    /// The flush task keeps running either way: the text it was flushing is
+    /// lost, but every later deadline, drain and flush signal is served as
+    /// usual. Errors from the handlers themselves are returned to sacp as
+    /// before and never reach `on_error`.
+    pub fn on_error(mut self, on_error: impl Fn(&sacp::Error) + Send + Sync + 'static) -> Self {
+        self.on_error = Some(Box::new(on_error));
+        self
+    }
+
+    /// Flush on demand instead of on a timer.
+    ///
+    /// Every `()` received on `flush_signal` flushes everything buffered in
+    /// every session, and the interval, [`max_latency`](Self::max_latency)
+    /// and [`quiet_period`](Self::quiet_period) deadlines are never used.
+    /// The other triggers (non-text notifications, prompt responses,
+    /// shutdown and the early-flush modes) still apply. Once every sender
+    /// is dropped, buffers are only flushed by those.
+    ///
+    /// Meant for tests that need exact coalescing without racing a clock.
+    pub fn with_flush_signal(mut self, flush_signal: mpsc::Receiver<()>) -> Self {
+        self.flush_signal = Some(flush_signal);
+        self
+    }
+
+    /// Record every `SessionNotification` from the agent into `tap`, for
+    /// replaying a session later, and forward them all unchanged.
+    ///
+    /// A tapped proxy does not coalesce, whatever else is configured: like
+    /// a [disabled](Self::enabled) one it only forwards, the recording being
+    /// the one side effect. The forward path never waits on the tap. When
+    /// the channel is full the copy is dropped from the recording (counted
+    /// by [`DecafStats::tap_dropped`](crate::DecafStats::tap_dropped)) and
+    /// the notification still goes out; once the receiver is dropped,
+    /// recording stops. Size the channel for the bursts you expect.
+    pub fn tap(mut self, tap: mpsc::Sender<SessionNotification>) -> Self {
+        self.tap = Some(tap);
+        self
+    }
+
+    /// Allow intervals down to `min_interval` (default: 1ms).
+    ///
+    /// Below a millisecond almost every chunk gets its own notification, so
+    /// coalescing costs a timer wake-up per chunk for nothing, and
+    /// [`build`](Self::build) rejects such an [`interval`](Self::interval)
+    /// or [`adaptive_interval`](Self::adaptive_interval) minimum. Lower the
+    /// floor here if you really want tighter windows; it must stay non-zero.
+    /// Intervals chosen by [`interval_for`](Self::interval_for) are raised
+    /// to the floor instead, since they are only known at run time.
+    pub fn with_min_interval(mut self, min_interval: Duration) -> Self {
+        self.min_interval = min_interval;
+        self
+    }
+
+    /// Read the time and wait for deadlines through `clock` (default:
+    /// [`TokioClock`]).
+    ///
+    /// With a [`MockClock`](crate::MockClock) the timer only fires when the
+    /// test advances it, whatever tokio's own clock does.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Stop the proxy when `shutdown` is cancelled.
+    ///
+    /// On cancellation every pending buffer is flushed to the client one
+    /// final time and [`run`](crate::Decaf::run) returns `Ok(())`. Buffers are
+    /// drained under their session locks, so text the flush task already
+    /// sent is never sent again.
+    ///
+    /// sacp does not tell the proxy when its transport reaches EOF, so this
+    /// token is the way to get a clean exit with nothing left buffered.
+    pub fn with_cancellation(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Finish configuring the proxy.
+    ///
+    /// # Panics
+    ///
+    /// If the interval is zero: a zero window would flush on every chunk,
+    /// which is what running without Decaf already does. Likewise if it is
+    /// below [`with_min_interval`](Self::with_min_interval) (1ms by
+    /// default), if
+    /// [`max_sessions`](Self::max_sessions),
+    /// [`session_ttl`](Self::session_ttl),
+    /// [`flush_every_chunks`](Self::flush_every_chunks),
+    /// [`flush_every_tokens`](Self::flush_every_tokens),
+    /// [`max_emit_rate`](Self::max_emit_rate) or
+    /// [`max_emit_bytes`](Self::max_emit_bytes) or the
+    /// [`with_history`](Self::with_history) capacity is zero, or if the
+    /// [`metrics_prefix`](Self::metrics_prefix) is not a valid start of a
+    /// Prometheus metric name.
+    pub fn build(self) -> Decaf {
+        assert!(!self.interval.is_zero(), "Decaf interval must be non-zero");
+        if let Some((min_interval, max_interval)) = self.adaptive {
+            assert!(
+                !min_interval.is_zero() && min_interval <= max_interval,
+                "Decaf adaptive interval needs 0 < min_interval <= max_interval"
+            );
+        }
+        assert!(
+            self.max_sessions != Some(0),
+            "Decaf max_sessions must be non-zero"
+        );
+        assert!(
+            self.session_ttl != Some(Duration::ZERO),
+            "Decaf session_ttl must be non-zero"
+        );
+        assert!(
+            self.flush_every_chunks != Some(0),
+            "Decaf flush_every_chunks must be non-zero"
+        );
+        assert!(
+            !matches!(self.flush_every_tokens, Some((0, _))),
+            "Decaf flush_every_tokens must be non-zero"
+        );
+        assert!(
+            !self.min_interval.is_zero(),
+            "Decaf min_interval must be non-zero"
+        );
+        assert!(
+            self.max_emit_rate != Some(0),
+            "Decaf max_emit_rate must be non-zero"
+        );
+        assert!(
+            self.max_emit_bytes != Some(0),
+            "Decaf max_emit_bytes must be non-zero"
+        );
+        assert!(
+            self.history != Some(0),
+            "Decaf history capacity must be non-zero"
+        );
+        let shortest = match self.adaptive {
+            Some((min_interval, _)) => min_interval,
+            None => self.interval,
+        };
+        let shortest = [self.thought_interval, self.first_flush_after]
+            .into_iter()
+            .flatten()
+            .fold(shortest, Duration::min);
+        assert!(
+            shortest >= self.min_interval,
+            "Decaf interval must be at least min_interval (1ms unless set with with_min_interval)"
+        );
+        assert!(
+            valid_metrics_prefix(&self.metrics_prefix),
+            "Decaf metrics_prefix must match [a-zA-Z_:][a-zA-Z0-9_:]*"
+        );
+        let (drains, drain_requests) = mpsc::channel(DRAIN_QUEUE);
+        let history = self
+            .history
+            .map(|capacity| Arc::new(FlushHistory::new(capacity, self.clock.clone())));
+        let interval = Arc::new(LiveInterval::new(self.interval, self.min_interval));
+        let paused = Arc::new(AtomicBool::new(false));
+        Decaf {
+            stats: Arc::new(DecafStats::new(self.name.clone(), self.metrics_prefix)),
+            name: self.name,
+            enabled: self.enabled,
+            interval: interval.clone(),
+            min_interval: self.min_interval,
+            adaptive: self.adaptive,
+            max_latency: self.max_latency,
+            jitter: self.jitter,
+            quiet_period: self.quiet_period,
+            leading_edge: self.leading_edge,
+            coalesce_thoughts: self.coalesce_thoughts,
+            thought_interval: self.thought_interval,
+            first_flush_after: self.first_flush_after,
+            coalesce_user_echo: self.coalesce_user_echo,
+            coalesce_tool_calls: self.coalesce_tool_calls,
+            tool_call_modes: self.tool_call_modes,
+            flush_on_sentence: self.flush_on_sentence,
+            sentence_terminators: self.sentence_terminators,
+            flush_on_newline: self.flush_on_newline,
+            flush_on_paragraph: self.flush_on_paragraph,
+            flush_on_pattern: self.flush_on_pattern,
+            max_buffer_bytes: self.max_buffer_bytes,
+            passthrough_large: self.passthrough_large,
+            auto_passthrough_above: self.auto_passthrough_above,
+            flush_every_chunks: self.flush_every_chunks,
+            flush_every_tokens: self.flush_every_tokens,
+            join_with: self.join_with.into(),
+            skip_empty_chunks: self.skip_empty_chunks,
+            skip_whitespace_chunks: self.skip_whitespace_chunks,
+            dedupe_repeats: self.dedupe_repeats,
+            split_on_word_boundary: self.split_on_word_boundary,
+            max_total_bytes: self.max_total_bytes,
+            overflow_policy: self.overflow_policy,
+            max_sessions: self.max_sessions,
+            session_limit_policy: self.session_limit_policy,
+            single_session: self.single_session,
+            session_ttl: self.session_ttl,
+            initial_buffer_capacity: self.initial_buffer_capacity,
+            interval_for: self.interval_for,
+            passthrough_sessions: self.passthrough_sessions,
+            paused: paused.clone(),
+            session_priority: self.session_priority,
+            session_alias: self.session_alias,
+            map_session_id: self.map_session_id,
+            debounce_client_to_agent: self.debounce_client_to_agent,
+            flush_before_response: self.flush_before_response,
+            flush_on_stop_reason: self.flush_on_stop_reason,
+            coalesce_across_prompts: self.coalesce_across_prompts,
+            mark_coalesced: self.mark_coalesced,
+            wall_clock: self
+                .stamp_timestamps
+                .then(|| WallClock::anchor(self.clock.as_ref())),
+            trim_trailing_on_flush: self.trim_trailing_on_flush,
+            transform: self.transform,
+            can_merge: self.can_merge,
+            merge_fn: self.merge_fn,
+            flush_reports: Arc::new(FlushReports::new(self.on_flush.is_some())),
+            on_flush: self.on_flush,
+            on_error: self.on_error,
+            flush_signal: self.flush_signal,
+            tap: self.tap,
+            emit_budget: self.max_emit_rate.map(EmitBudget::new),
+            max_emit_bytes: self.max_emit_bytes,
+            clock: self.clock,
+            history: history.clone(),
+            control: DecafControl {
+                drains,
+                interval,
+                paused,
+                history,
+                sessions: Arc::default(),
+            },
+            drains: Some(drain_requests),
+            shutdown: self.shutdown,
+        }
+    }
+}
+
+/// Whether metric names may start with `prefix`; an empty prefix is fine.
+fn valid_metrics_prefix(prefix: &str) -> bool {
+    prefix.chars().enumerate().all(|(i, c)| {
+        c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit())
+    })
+}
@@ -0,0 +1,120 @@
+//! The time source behind flush deadlines.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Where Decaf reads the time and waits for deadlines, set with
+/// [`DecafBuilder::with_clock`](crate::DecafBuilder::with_clock).
+///
+/// Every timestamp a proxy takes (chunk arrival, deadlines, flush latency)
+/// comes from its clock, so a [`MockClock`] controls exactly when the timer
+/// fires. The default, [`TokioClock`], is the tokio timer.
+pub trait Clock: Send + Sync + 'static {
+    /// The current time.
+    fn now(&self) -> Instant;
+
+    /// Wait until [`now`](Self::now) reaches `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send + '_>>;
+}
+
+/// The tokio timer, following `tokio::time::pause` and `advance` in tests.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(tokio::time::sleep_until(deadline))
+    }
+}
+
+/// A clock that only moves when [`advance`](Self::advance) is called.
+///
+/// It starts at the tokio time it was created and is otherwise independent
+/// of it, so real or paused tokio sleeps elsewhere don't move it.
+#[derive(Debug)]
+pub struct MockClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+    advanced: Notify,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        MockClock::new()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        MockClock {
+            start: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+            advanced: Notify::new(),
+        }
+    }
+
+    /// Move the clock forward by `by`, waking anything whose deadline has
+    /// now passed.
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap_or_else(|e| e.into_inner()) += by;
+        self.advanced.notify_waiters();
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send + '_>> {
+        Box::pin(async move {
+            loop {
+                // Registered before the check, so an advance in between
+                // still wakes us.
+                let advanced = self.advanced.notified();
+                if self.now() >= deadline {
+                    return;
+                }
+                advanced.await;
+            }
+        })
+    }
+}
+
+/// Wall-clock time for instants read from a [`Clock`], anchored once, so
+/// timestamps follow a [`MockClock`] or paused tokio time as deadlines do.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct WallClock {
+    at: Instant,
+    unix_ms: u64,
+}
+
+impl WallClock {
+    pub(crate) fn anchor(clock: &dyn Clock) -> Self {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        WallClock {
+            at: clock.now(),
+            unix_ms: u64::try_from(since_epoch.as_millis()).unwrap_or(u64::MAX),
+        }
+    }
+
+    /// `instant` as milliseconds since the Unix epoch.
+    pub(crate) fn unix_ms(&self, instant: Instant) -> u64 {
+        let ms = |d: Duration| u64::try_from(d.as_millis()).unwrap_or(u64::MAX);
+        match instant.checked_duration_since(self.at) {
+            Some(after) => self.unix_ms.saturating_add(ms(after)),
+            None => self.unix_ms.saturating_sub(ms(self.at - instant)),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! Coalescing without a transport, for embedding Decaf as a library.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use sacp::schema::{SessionId, SessionNotification};
+use tokio::time::Instant;
+
+use crate::flush_log::FlushReason;
+use crate::sessions::Sessions;
+use crate::{
+    BufferedSession, Decaf, DecafError, Route, SessionLimitPolicy, buffer_chunk, report_flushes,
+};
+
+/// Decaf's buffering on its own: push the agent's notifications in, get
+/// the coalesced ones out.
+///
+/// A `Coalescer` is configured like a proxy, with
+/// [`Decaf::builder`], and buffers each session exactly as
+/// [`Decaf::run`] does toward the client, sharing the same per-session
+/// code. Nothing runs in the background and nothing is sent anywhere, so
+/// the caller decides when time passes: call [`tick`](Self::tick) at (or
+/// after) [`next_deadline`](Self::next_deadline), and
+/// [`end_turn`](Self::end_turn) when a prompt is answered. Time is read
+/// from the configured [`Clock`](crate::Clock); with a
+/// [`MockClock`](crate::MockClock) no tokio runtime is needed at all.
+///
+/// Options that only make sense for a proxy in a pipeline are ignored:
+/// [`tap`](crate::DecafBuilder::tap),
+/// [`with_flush_signal`](crate::DecafBuilder::with_flush_signal),
+/// [`with_cancellation`](crate::DecafBuilder::with_cancellation),
+/// [`debounce_client_to_agent`](crate::DecafBuilder::debounce_client_to_agent),
+/// [`flush_before_response`](crate::DecafBuilder::flush_before_response)
+/// and [`max_total_bytes`](crate::DecafBuilder::max_total_bytes).
+/// Drains and [`DecafStats::peak_pending_bytes`](crate::DecafStats::peak_pending_bytes)
+/// don't apply either.
+pub struct Coalescer {
+    pub(crate) decaf: Decaf,
+    sessions: Sessions<BufferedSession>,
+}
+
+impl Coalescer {
+    pub fn new(decaf: Decaf) -> Self {
+        Coalescer {
+            sessions: Sessions::new(decaf.single_session),
+            decaf,
+        }
+    }
+
+    /// Take in one notification from the agent, returning whatever must be
+    /// sent on right away, in order.
+    ///
+    /// Usually that is nothing: the chunk is buffered. An early flush (a
+    /// sentence, the byte cap, a stream switch, an evicted session...)
+    /// returns text ahead of its deadline, and any other notification
+    /// returns its session's buffered text followed by the notification
+    /// itself.
+    pub fn push(
+        &mut self,
+        mut notification: SessionNotification,
+    ) -> Result<Vec<SessionNotification>, DecafError> {
+        if !self.decaf.enabled {
+            return Ok(vec![notification]);
+        }
+        self.decaf.map_session(&mut notification.session_id);
+        let Coalescer { decaf, sessions } = self;
+        let mut out = Vec::new();
+        match Route::of(&notification, decaf) {
+            Route::Chunk(kind) => match admit(sessions, decaf, &notification.session_id, &mut out)?
+            {
+                Some(session) => out.extend(buffer_chunk(session, kind, notification, decaf)?),
+                None => out.push(notification),
+            },
+            Route::ToolCall => match admit(sessions, decaf, &notification.session_id, &mut out)? {
+                Some(session) => out.extend(session.buffer_tool_call(notification, decaf)?),
+                None => out.push(notification),
+            },
+            Route::Forward => {
+                if let Some(session) = sessions.get_mut(&notification.session_id) {
+                    // The update goes on even if its session fails to flush.
+                    match session.take_for(
+                        FlushReason::NonChunkUpdate,
+                        BufferedSession::take_before_update,
+                    ) {
+                        Ok(flushed) => out.extend(flushed),
+                        Err(error) => decaf.flush_failed(Err(error.into())),
+                    }
+                }
+                let mut out = decaf.frame(out);
+                self.report(&out);
+                out.push(notification);
+                return Ok(out);
+            }
+            Route::Skip => {}
+        }
+        let out = decaf.frame(out);
+        self.report(&out);
+        Ok(out)
+    }
+
+    /// Flush every session whose deadline has passed, earliest first, then
+    /// flush and forget those idle past
+    /// [`session_ttl`](crate::DecafBuilder::session_ttl).
+    ///
+    /// With [`max_emit_rate`](crate::DecafBuilder::max_emit_rate) the
+    /// deadline flushes stop once the rate is reached; the sessions left
+    /// stay buffered for a later tick.
+    pub fn tick(&mut self) -> Result<Vec<SessionNotification>, DecafError> {
+        let now = self.decaf.clock.now();
+        let decaf = &self.decaf;
+        let mut due: Vec<_> = self
+            .sessions
+            .iter_mut()
+            .filter_map(|(id, session)| Some((id.clone(), (session.deadline(decaf)?, session))))
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .collect();
+        due.sort_by_key(|(_, (deadline, _))| *deadline);
+        decaf.by_priority(&mut due);
+        let mut out = Vec::new();
+        for (_, (_, session)) in due {
+            if let Some(budget) = &decaf.emit_budget {
+                if budget.ready_at(now) > now {
+                    break;
+                }
+            }
+            let flushed = decaf.frame(session.take_timed_flush(decaf)?);
+            spend(decaf, &flushed, now);
+            out.extend(flushed);
+        }
+        let expired: Vec<_> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.expires_at(decaf).is_some_and(|at| at <= now))
+            .map(|(id, _)| id.clone())
+            .collect();
+        let mut idle: Vec<_> = expired
+            .iter()
+            .filter_map(|id| self.sessions.remove(id))
+            .collect();
+        if !idle.is_empty() {
+            self.decaf.stats.record_sessions_closed(idle.len());
+        }
+        idle.sort_by_key(|session| session.oldest_chunk_at());
+        for mut session in idle {
+            let flushed = self.decaf.frame(session.retire(FlushReason::SessionTtl)?);
+            spend(&self.decaf, &flushed, now);
+            out.extend(flushed);
+        }
+        self.announce(&out);
+        Ok(out)
+    }
+
+    /// When [`tick`](Self::tick) next has something to flush or a session
+    /// to forget, or `None` while nothing is buffered and no
+    /// [`session_ttl`](crate::DecafBuilder::session_ttl) is running.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let deadline = self
+            .sessions
+            .values()
+            .filter_map(|session| session.deadline(&self.decaf))
+            .min()
+            .map(|deadline| self.decaf.emit_deadline(deadline));
+        let expiry = self
+            .sessions
+            .values()
+            .filter_map(|session| session.expires_at(&self.decaf))
+            .min();
+        deadline.into_iter().chain(expiry).min()
+    }
+
+    /// End `session_id`'s turn, returning its remaining text and forgetting
+    /// the session. Call it before passing on the prompt's response. With
+    /// [`session_alias`](crate::DecafBuilder::session_alias) or
+    /// [`map_session_id`](crate::DecafBuilder::map_session_id), pass the id
+    /// as normalized and mapped.
+    /// Unlike the proxy, which forwards chunks arriving after the response
+    /// as they are, a `Coalescer` can't tell them from the next turn's and
+    /// buffers them into a fresh session.
+    pub fn end_turn(
+        &mut self,
+        session_id: &SessionId,
+    ) -> Result<Vec<SessionNotification>, DecafError> {
+        let out = match self.sessions.remove(session_id) {
+            Some(mut session) => {
+                self.decaf.stats.record_sessions_closed(1);
+                session.retire(FlushReason::PromptResponse)?
+            }
+            None => Vec::new(),
+        };
+        let out = self.decaf.frame(out);
+        self.report(&out);
+        Ok(out)
+    }
+
+    /// Flush and forget every session, e.g. before shutting down.
+    pub fn flush(&mut self) -> Result<Vec<SessionNotification>, DecafError> {
+        let mut sessions: Vec<_> = self.sessions.drain().collect();
+        self.decaf.stats.record_sessions_closed(sessions.len());
+        sessions.sort_by_key(|(_, session)| session.oldest_chunk_at());
+        self.decaf.by_priority(&mut sessions);
+        let mut out = Vec::new();
+        for (_, mut session) in sessions {
+            out.extend(session.retire(FlushReason::Shutdown)?);
+        }
+        let out = self.decaf.frame(out);
+        self.report(&out);
+        Ok(out)
+    }
+
+    /// Coalesce `input` into a stream of the notifications to send on, in
+    /// order, driving [`tick`](Self::tick) from the configured
+    /// [`Clock`](crate::Clock) in between.
+    ///
+    /// A stream carries no prompt responses, so there are no turn ends:
+    /// text goes out on its deadline or an early trigger, and whatever is
+    /// still buffered when `input` ends is flushed before the output ends.
+    /// A failure to coalesce, a bug in the buffering itself, is reported to
+    /// [`on_error`](crate::DecafBuilder::on_error) (or logged) and the
+    /// stream goes on without that text.
+    pub fn into_stream(
+        self,
+        input: impl Stream<Item = SessionNotification>,
+    ) -> impl Stream<Item = SessionNotification> {
+        let state = (self, Box::pin(input), VecDeque::new(), false);
+        futures::stream::unfold(
+            state,
+            async |(mut coalescer, mut input, mut ready, mut done)| {
+                loop {
+                    if let Some(notification) = ready.pop_front() {
+                        return Some((notification, (coalescer, input, ready, done)));
+                    }
+                    if done {
+                        return None;
+                    }
+                    let clock = coalescer.decaf.clock.clone();
+                    let next_deadline = coalescer.next_deadline();
+                    let deadline = async {
+                        match next_deadline {
+                            Some(deadline) => clock.sleep_until(deadline).await,
+                            None => std::future::pending().await,
+                        }
+                    };
+                    let out = tokio::select! {
+                        next = input.next() => match next {
+                            Some(notification) => coalescer.push(notification),
+                            None => {
+                                done = true;
+                                coalescer.flush()
+                            }
+                        },
+                        () = deadline => coalescer.tick(),
+                    };
+                    match out {
+                        Ok(out) => ready.extend(out),
+                        Err(error) => coalescer.decaf.flush_failed(Err(error.into())),
+                    }
+                }
+            },
+        )
+    }
+
+    /// Count text handed back, spend `max_emit_rate` tokens on it and tell
+    /// `on_flush` about it, as sending it would in the proxy.
+    fn report(&self, out: &[SessionNotification]) {
+        spend(&self.decaf, out, self.decaf.clock.now());
+        self.announce(out);
+    }
+
+    /// [`report`](Self::report), for text whose tokens are already spent.
+    fn announce(&self, out: &[SessionNotification]) {
+        if let Some(history) = &self.decaf.history {
+            history.record(out);
+        }
+        self.decaf.stats.record_forwarded(out.len());
+        report_flushes(&self.decaf);
+    }
+}
+
+/// Coalesce `input`, a stream of the agent's notifications, into a stream
+/// of coalesced ones, flushing each session `interval` after its oldest
+/// buffered chunk.
+///
+/// This is [`Coalescer::into_stream`] for a [`Decaf::new`] proxy; build a
+/// [`Coalescer`] yourself for any other option.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use futures::StreamExt;
+/// # use sacp::schema::{ContentBlock, ContentChunk, SessionNotification, SessionUpdate};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let chunk = |text: &str| {
+///     SessionNotification::new(
+///         "session",
+///         SessionUpdate::AgentMessageChunk(ContentChunk::new(ContentBlock::from(text))),
+///     )
+/// };
+/// let input = futures::stream::iter([chunk("Hello, "), chunk("world")]);
+/// let out: Vec<_> = decaf_mod::into_stream(input, Duration::from_millis(100))
+///     .collect()
+///     .await;
+/// assert_eq!(out.len(), 1);
+/// # }
+/// ```
+pub fn into_stream(
+    input: impl Stream<Item = SessionNotification>,
+    interval: Duration,
+) -> impl Stream<Item = SessionNotification> {
+    Coalescer::new(Decaf::new(interval)).into_stream(input)
+}
+
+/// The session to buffer into, created on first sight. `None` when
+/// [`max_sessions`](crate::DecafBuilder::max_sessions) is reached under
+/// [`SessionLimitPolicy::PassThrough`]; under the default policy the least
+/// recently updated session is evicted, its text going to `out`.
+fn admit<'a>(
+    sessions: &'a mut Sessions<BufferedSession>,
+    decaf: &Decaf,
+    session_id: &SessionId,
+    out: &mut Vec<SessionNotification>,
+) -> Result<Option<&'a mut BufferedSession>, DecafError> {
+    if !sessions.contains_key(session_id) {
+        if let Some(max_sessions) = decaf.max_sessions {
+            if sessions.len() >= max_sessions {
+                if decaf.session_limit_policy == SessionLimitPolicy::PassThrough {
+                    return Ok(None);
+                }
+                let least_recent = sessions
+                    .iter()
+                    .min_by_key(|(_, session)| session.last_chunk_at)
+                    .map(|(id, _)| id.clone());
+                if let Some(mut evicted) = least_recent.and_then(|id| sessions.remove(&id)) {
+                    decaf.stats.record_sessions_closed(1);
+                    out.extend(evicted.retire(FlushReason::Eviction)?);
+                }
+            }
+        }
+        decaf.stats.record_sessions_opened(1);
+        sessions.insert(session_id.clone(), BufferedSession::new(session_id, decaf));
+    }
+    let session = sessions.get_mut(session_id);
+    if let Some(session) = session {
+        session.updated_at = decaf.clock.now();
+        return Ok(Some(session));
+    }
+    Ok(None)
+}
+
+/// Spend a `max_emit_rate` token on each of `sent`.
+fn spend(decaf: &Decaf, sent: &[SessionNotification], now: Instant) {
+    if let Some(budget) = &decaf.emit_budget {
+        for _ in sent {
+            budget.spend(now);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! Configuration from a file, with the `serde` feature.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Decaf, DecafBuilder, DecafError};
+
+/// The options of [`DecafBuilder`] that can be written down, for operators
+/// who ship a config file rather than code.
+///
+/// Every field is optional and defaults to the builder's default, so
+/// `{}` configures the same proxy as `Decaf::builder().build()`. Durations
+/// are whole milliseconds. Unknown fields are rejected, so a misspelled
+/// option fails to load instead of being ignored.
+///
+/// ```
+/// # use decaf_mod::{Decaf, DecafConfig};
+/// let config: DecafConfig =
+///     serde_json::from_str(r#"{ "interval_ms": 50, "flush_on_sentence": true }"#)?;
+/// let decaf = Decaf::from_config(config)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DecafConfig {
+    /// [`named`](DecafBuilder::named).
+    pub name: Option<String>,
+    /// [`enabled`](DecafBuilder::enabled).
+    pub enabled: Option<bool>,
+    /// [`interval`](DecafBuilder::interval).
+    pub interval_ms: Option<u64>,
+    /// [`max_latency`](DecafBuilder::max_latency).
+    pub max_latency_ms: Option<u64>,
+    /// [`quiet_period`](DecafBuilder::quiet_period).
+    pub quiet_period_ms: Option<u64>,
+    /// [`thought_interval`](DecafBuilder::thought_interval).
+    pub thought_interval_ms: Option<u64>,
+    /// [`first_flush_after`](DecafBuilder::first_flush_after).
+    pub first_flush_after_ms: Option<u64>,
+    /// [`max_buffer_bytes`](DecafBuilder::max_buffer_bytes).
+    pub max_buffer_bytes: Option<usize>,
+    /// [`max_total_bytes`](DecafBuilder::max_total_bytes).
+    pub max_total_bytes: Option<usize>,
+    /// [`max_emit_bytes`](DecafBuilder::max_emit_bytes).
+    pub max_emit_bytes: Option<usize>,
+    /// [`passthrough_large`](DecafBuilder::passthrough_large).
+    pub passthrough_large: Option<usize>,
+    /// [`auto_passthrough_above`](DecafBuilder::auto_passthrough_above).
+    pub auto_passthrough_above: Option<usize>,
+    /// [`max_sessions`](DecafBuilder::max_sessions).
+    pub max_sessions: Option<usize>,
+    /// [`session_ttl`](DecafBuilder::session_ttl).
+    pub session_ttl_ms: Option<u64>,
+    /// [`flush_every_chunks`](DecafBuilder::flush_every_chunks).
+    pub flush_every_chunks: Option<usize>,
+    /// [`max_emit_rate`](DecafBuilder::max_emit_rate).
+    pub max_emit_rate: Option<u32>,
+    /// [`join_with`](DecafBuilder::join_with).
+    pub join_with: Option<String>,
+    /// [`flush_on_paragraph`](DecafBuilder::flush_on_paragraph).
+    pub flush_on_paragraph: Option<bool>,
+    /// [`flush_on_sentence`](DecafBuilder::flush_on_sentence).
+    pub flush_on_sentence: Option<bool>,
+    /// [`flush_on_newline`](DecafBuilder::flush_on_newline).
+    pub flush_on_newline: Option<bool>,
+    /// [`split_on_word_boundary`](DecafBuilder::split_on_word_boundary).
+    pub split_on_word_boundary: Option<bool>,
+    /// [`leading_edge`](DecafBuilder::leading_edge).
+    pub leading_edge: Option<bool>,
+    /// [`coalesce_tool_calls`](DecafBuilder::coalesce_tool_calls).
+    pub coalesce_tool_calls: Option<bool>,
+    /// [`coalesce_user_echo`](DecafBuilder::coalesce_user_echo).
+    pub coalesce_user_echo: Option<bool>,
+    /// [`debounce_client_to_agent`](DecafBuilder::debounce_client_to_agent).
+    pub debounce_client_to_agent: Option<bool>,
+    /// [`flush_before_response`](DecafBuilder::flush_before_response).
+    pub flush_before_response: Option<bool>,
+    /// [`coalesce_across_prompts`](DecafBuilder::coalesce_across_prompts).
+    pub coalesce_across_prompts: Option<bool>,
+    /// [`mark_coalesced`](DecafBuilder::mark_coalesced).
+    pub mark_coalesced: Option<bool>,
+    /// [`stamp_timestamps`](DecafBuilder::stamp_timestamps).
+    pub stamp_timestamps: Option<bool>,
+    /// [`trim_trailing_on_flush`](DecafBuilder::trim_trailing_on_flush).
+    pub trim_trailing_on_flush: Option<bool>,
+    /// [`dedupe_repeats`](DecafBuilder::dedupe_repeats).
+    pub dedupe_repeats: Option<bool>,
+    /// [`skip_empty_chunks`](DecafBuilder::skip_empty_chunks).
+    pub skip_empty_chunks: Option<bool>,
+    /// [`skip_whitespace_chunks`](DecafBuilder::skip_whitespace_chunks).
+    pub skip_whitespace_chunks: Option<bool>,
+    /// [`single_session`](DecafBuilder::single_session).
+    pub single_session: Option<bool>,
+}
+
+impl DecafConfig {
+    /// Check every value [`build`](DecafBuilder::build) would panic on,
+    /// plus a per-buffer cap larger than the total cap, which could
+    /// never be reached.
+    fn validate(&self) -> Result<(), DecafError> {
+        let zero = [
+            ("interval_ms", self.interval_ms == Some(0)),
+            ("max_latency_ms", self.max_latency_ms == Some(0)),
+            ("thought_interval_ms", self.thought_interval_ms == Some(0)),
+            ("first_flush_after_ms", self.first_flush_after_ms == Some(0)),
+            ("max_buffer_bytes", self.max_buffer_bytes == Some(0)),
+            ("max_total_bytes", self.max_total_bytes == Some(0)),
+            ("max_emit_bytes", self.max_emit_bytes == Some(0)),
+            ("max_sessions", self.max_sessions == Some(0)),
+            ("session_ttl_ms", self.session_ttl_ms == Some(0)),
+            ("flush_every_chunks", self.flush_every_chunks == Some(0)),
+            ("max_emit_rate", self.max_emit_rate == Some(0)),
+        ];
+        if let Some((field, _)) = zero.iter().find(|(_, zero)| *zero) {
+            return Err(invalid(field, "must be non-zero"));
+        }
+        if let (Some(buffer), Some(total)) = (self.max_buffer_bytes, self.max_total_bytes) {
+            if buffer > total {
+                return Err(invalid(
+                    "max_buffer_bytes",
+                    "must not exceed max_total_bytes",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A builder method taking a `bool`, such as [`DecafBuilder::leading_edge`].
+type SetFlag = fn(DecafBuilder, bool) -> DecafBuilder;
+
+fn invalid(field: &'static str, reason: &'static str) -> DecafError {
+    DecafError::InvalidConfig { field, reason }
+}
+
+impl Decaf {
+    /// Build a proxy from a [`DecafConfig`], typically deserialized from a
+    /// file.
+    ///
+    /// Unlike [`DecafBuilder::build`], which panics on a bad value, this
+    /// fails with [`DecafError::InvalidConfig`] naming the offending field: a zero
+    /// interval, latency, byte cap, session limit or TTL, chunk count or rate, or
+    /// a `max_buffer_bytes` above `max_total_bytes`.
+    pub fn from_config(config: DecafConfig) -> Result<Decaf, DecafError> {
+        config.validate()?;
+        let mut builder = Decaf::builder();
+        if let Some(name) = config.name {
+            builder = builder.named(name);
+        }
+        if let Some(enabled) = config.enabled {
+            builder = builder.enabled(enabled);
+        }
+        if let Some(ms) = config.interval_ms {
+            builder = builder.interval(Duration::from_millis(ms));
+        }
+        if let Some(ms) = config.max_latency_ms {
+            builder = builder.max_latency(Duration::from_millis(ms));
+        }
+        if let Some(ms) = config.quiet_period_ms {
+            builder = builder.quiet_period(Duration::from_millis(ms));
+        }
+        if let Some(ms) = config.session_ttl_ms {
+            builder = builder.session_ttl(Duration::from_millis(ms));
+        }
+        if let Some(ms) = config.thought_interval_ms {
+            builder = builder.thought_interval(Duration::from_millis(ms));
+        }
+        if let Some(ms) = config.first_flush_after_ms {
+            builder = builder.first_flush_after(Duration::from_millis(ms));
+        }
+        if let Some(bytes) = config.max_buffer_bytes {
+            builder = builder.max_buffer_bytes(bytes);
+        }
+        if let Some(bytes) = config.max_total_bytes {
+            builder = builder.max_total_bytes(bytes);
+        }
+        if let Some(bytes) = config.max_emit_bytes {
+            builder = builder.max_emit_bytes(bytes);
+        }
+        if let Some(threshold) = config.passthrough_large {
+            builder = builder.passthrough_large(threshold);
+        }
+        if let Some(avg_bytes) = config.auto_passthrough_above {
+            builder = builder.auto_passthrough_above(avg_bytes);
+        }
+        if let Some(max_sessions) = config.max_sessions {
+            builder = builder.max_sessions(max_sessions);
+        }
+        if let Some(chunks) = config.flush_every_chunks {
+            builder = builder.flush_every_chunks(chunks);
+        }
+        if let Some(per_second) = config.max_emit_rate {
+            builder = builder.max_emit_rate(per_second);
+        }
+        if let Some(separator) = config.join_with {
+            builder = builder.join_with(separator);
+        }
+        let flags: [(Option<bool>, SetFlag); 17] = [
+            (config.flush_on_sentence, DecafBuilder::flush_on_sentence),
+            (config.flush_on_newline, DecafBuilder::flush_on_newline),
+            (config.flush_on_paragraph, DecafBuilder::flush_on_paragraph),
+            (
+                config.split_on_word_boundary,
+                DecafBuilder::split_on_word_boundary,
+            ),
+            (config.leading_edge, DecafBuilder::leading_edge),
+            (
+                config.coalesce_tool_calls,
+                DecafBuilder::coalesce_tool_calls,
+            ),
+            (config.coalesce_user_echo, DecafBuilder::coalesce_user_echo),
+            (
+                config.debounce_client_to_agent,
+                DecafBuilder::debounce_client_to_agent,
+            ),
+            (
+                config.flush_before_response,
+                DecafBuilder::flush_before_response,
+            ),
+            (
+                config.coalesce_across_prompts,
+                DecafBuilder::coalesce_across_prompts,
+            ),
+            (config.mark_coalesced, DecafBuilder::mark_coalesced),
+            (config.stamp_timestamps, DecafBuilder::stamp_timestamps),
+            (
+                config.trim_trailing_on_flush,
+                DecafBuilder::trim_trailing_on_flush,
+            ),
+            (config.dedupe_repeats, DecafBuilder::dedupe_repeats),
+            (config.skip_empty_chunks, DecafBuilder::skip_empty_chunks),
+            (
+                config.skip_whitespace_chunks,
+                DecafBuilder::skip_whitespace_chunks,
+            ),
+            (config.single_session, DecafBuilder::single_session),
+        ];
+        for (value, set) in flags {
+            if let Some(value) = value {
+                builder = set(builder, value);
+            }
+        }
+        Ok(builder.build())
+    }
+}
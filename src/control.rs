@@ -0,0 +1,186 @@
+//! Flushing a running proxy on the embedder's own events.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock, Weak};
+use std::time::Duration;
+
+use sacp::schema::SessionId;
+use tokio::sync::{Notify, mpsc, oneshot};
+
+use crate::history::{FlushHistory, FlushRecord};
+use crate::{DecafError, Shared};
+
+/// A drain request: the flush task answers once everything is sent.
+pub(crate) type DrainRequest = oneshot::Sender<()>;
+
+/// How many drains may be queued before [`DecafControl::drain`] waits to
+/// queue its own.
+pub(crate) const DRAIN_QUEUE: usize = 16;
+
+/// The coalescing interval, shared by a proxy and its [`DecafControl`]s so
+/// it can change while the proxy runs. Kept in nanoseconds, since
+/// [`with_min_interval`](crate::DecafBuilder::with_min_interval) allows
+/// sub-millisecond intervals.
+#[derive(Debug)]
+pub(crate) struct LiveInterval {
+    nanos: AtomicU64,
+    min: Duration,
+    /// Wakes the flush task to recompute its deadline.
+    pub(crate) changed: Notify,
+}
+
+impl LiveInterval {
+    pub(crate) fn new(interval: Duration, min: Duration) -> Self {
+        LiveInterval {
+            nanos: AtomicU64::new(nanos(interval)),
+            min,
+            changed: Notify::new(),
+        }
+    }
+
+    pub(crate) fn get(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Acquire))
+    }
+}
+
+/// `interval` in nanoseconds, saturating at about 584 years.
+fn nanos(interval: Duration) -> u64 {
+    u64::try_from(interval.as_nanos()).unwrap_or(u64::MAX)
+}
+
+/// A handle for flushing and retuning a [`Decaf`](crate::Decaf) from
+/// outside, obtained with
+/// [`Decaf::control_handle`](crate::Decaf::control_handle) before the proxy
+/// runs.
+#[derive(Clone, Debug)]
+pub struct DecafControl {
+    pub(crate) drains: mpsc::Sender<DrainRequest>,
+    pub(crate) interval: Arc<LiveInterval>,
+    /// Shared with the proxy, which passes every chunk through while set.
+    pub(crate) paused: Arc<AtomicBool>,
+    pub(crate) history: Option<Arc<FlushHistory>>,
+    /// The client-bound sessions, set once the proxy runs. Held weakly so
+    /// a handle outliving the proxy doesn't keep its buffers alive.
+    pub(crate) sessions: Arc<OnceLock<Weak<Shared>>>,
+}
+
+impl DecafControl {
+    /// Flush everything buffered in every session, returning once it has
+    /// been sent on.
+    ///
+    /// Drains are served by the same task as the timer, one at a time and
+    /// under the session locks, so a drain racing a deadline never sends
+    /// the same text twice. A drain requested before the proxy starts waits
+    /// for it; one requested after it stopped fails with
+    /// [`DecafError::Stopped`].
+    pub async fn drain(&self) -> Result<(), DecafError> {
+        let (done, drained) = oneshot::channel();
+        self.drains
+            .send(done)
+            .await
+            .map_err(|_| DecafError::Stopped)?;
+        drained.await.map_err(|_| DecafError::Stopped)
+    }
+
+    /// Flush everything, then stop buffering until [`resume`](Self::resume):
+    /// every chunk and tool call update is forwarded as it arrives, so a
+    /// proxy further down the pipeline sees a clean stream.
+    ///
+    /// Buffering stops before the flush, and a session's first chunk after
+    /// it flushes that session's text ahead of itself, so nothing buffered
+    /// is overtaken. Returns once the flush is sent, failing like
+    /// [`drain`](Self::drain) (the proxy stays paused either way).
+    pub async fn pause(&self) -> Result<(), DecafError> {
+        self.paused.store(true, Ordering::Release);
+        self.drain().await
+    }
+
+    /// Buffer again after [`pause`](Self::pause), starting with the next
+    /// chunk. Does nothing if the proxy isn't paused.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Release);
+    }
+
+    /// Whether the proxy is [paused](Self::pause).
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    /// Change the coalescing [`interval`](crate::DecafBuilder::interval).
+    ///
+    /// Every session's deadline moves at once, buffered text included: the
+    /// flush task wakes to recompute it, so a shorter interval flushes
+    /// anything already older than it right away. Sessions whose interval
+    /// [`interval_for`](crate::DecafBuilder::interval_for) picked keep
+    /// theirs, and with
+    /// [`adaptive_interval`](crate::DecafBuilder::adaptive_interval) the
+    /// interval isn't used at all. Fails with
+    /// [`DecafError::InvalidInterval`] below
+    /// [`with_min_interval`](crate::DecafBuilder::with_min_interval) (1ms
+    /// by default), leaving the interval as it was.
+    pub fn set_interval(&self, interval: Duration) -> Result<(), DecafError> {
+        let live = &self.interval;
+        if interval < live.min {
+            return Err(DecafError::InvalidInterval {
+                interval,
+                min_interval: live.min,
+            });
+        }
+        live.nanos.store(nanos(interval), Ordering::Release);
+        live.changed.notify_one();
+        Ok(())
+    }
+
+    /// The coalescing interval currently in use.
+    pub fn interval(&self) -> Duration {
+        self.interval.get()
+    }
+
+    /// Whether `session_id` has anything buffered toward the client, text
+    /// or tool calls, without flushing it.
+    ///
+    /// A momentary snapshot for coordinating with other components: the
+    /// answer may be stale as soon as it is returned, since chunks and
+    /// flushes go on meanwhile. Only the session's own lock is taken, and
+    /// only long enough to look, so this may wait for a flush of that
+    /// session already in progress but never holds one up for long.
+    /// `false` before the proxy runs, after it stops, and for a
+    /// [`Coalescer`](crate::Coalescer)'s sessions, which no handle sees.
+    pub async fn has_pending(&self, session_id: &SessionId) -> bool {
+        match self.pending(session_id).await {
+            Some((has_pending, _)) => has_pending,
+            None => false,
+        }
+    }
+
+    /// The bytes of text `session_id` has buffered toward the client, as
+    /// [`has_pending`](Self::has_pending) sees them, so 0 while only tool
+    /// calls are waiting.
+    pub async fn pending_bytes(&self, session_id: &SessionId) -> usize {
+        match self.pending(session_id).await {
+            Some((_, bytes)) => bytes,
+            None => 0,
+        }
+    }
+
+    async fn pending(&self, session_id: &SessionId) -> Option<(bool, usize)> {
+        let state = self.sessions.get()?.upgrade()?;
+        state.pending(session_id).await
+    }
+
+    /// Every notification kept by
+    /// [`with_history`](crate::DecafBuilder::with_history), oldest first;
+    /// empty without it.
+    pub fn history(&self) -> Vec<FlushRecord> {
+        self.history.as_ref().map_or_else(Vec::new, |h| h.all())
+    }
+
+    /// The kept notifications for `session_id` sent in the last `within`,
+    /// oldest first: what went out for a session in the last 30 seconds,
+    /// say, as far back as the history reaches.
+    pub fn recent(&self, session_id: &SessionId, within: Duration) -> Vec<FlushRecord> {
+        self.history
+            .as_ref()
+            .map_or_else(Vec::new, |h| h.recent(session_id, within))
+    }
+}
@@ -0,0 +1,104 @@
+//! Errors raised inside the proxy.
+//!
+//! Handlers still return `sacp::Error`; a [`DecafError`] is logged with its
+//! context when it is converted, so internal inconsistencies show up in the
+//! logs instead of silently producing no output.
+
+use std::fmt;
+use std::time::Duration;
+
+use sacp::schema::SessionId;
+
+/// Something went wrong while buffering or flushing.
+#[derive(Debug)]
+pub enum DecafError {
+    /// Sending to a peer failed.
+    Sacp(sacp::Error),
+
+    /// A buffer held text but no template chunk to carry it.
+    TemplateMissing { session_id: SessionId },
+
+    /// A buffer's template was not a text content chunk, so the coalesced
+    /// text had nowhere to go.
+    TemplateNotChunk { session_id: SessionId },
+
+    /// The proxy a [`DecafControl`](crate::DecafControl) belongs to is no
+    /// longer running.
+    Stopped,
+
+    /// [`DecafControl::set_interval`](crate::DecafControl::set_interval)
+    /// was given an interval below the proxy's minimum.
+    InvalidInterval {
+        interval: Duration,
+        min_interval: Duration,
+    },
+
+    /// [`Decaf::from_config`](crate::Decaf::from_config) was given a value
+    /// out of range.
+    InvalidConfig {
+        field: &'static str,
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for DecafError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecafError::Sacp(error) => write!(f, "{error}"),
+            DecafError::TemplateMissing { session_id } => {
+                write!(
+                    f,
+                    "session {} buffered text without a template",
+                    session_id.0
+                )
+            }
+            DecafError::TemplateNotChunk { session_id } => {
+                write!(
+                    f,
+                    "session {} has a template that is not a text chunk",
+                    session_id.0
+                )
+            }
+            DecafError::Stopped => write!(f, "the proxy is not running"),
+            DecafError::InvalidInterval {
+                interval,
+                min_interval,
+            } => {
+                write!(
+                    f,
+                    "interval {interval:?} is below the minimum of {min_interval:?}"
+                )
+            }
+            DecafError::InvalidConfig { field, reason } => {
+                write!(f, "invalid config: {field} {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecafError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecafError::Sacp(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<sacp::Error> for DecafError {
+    fn from(error: sacp::Error) -> Self {
+        DecafError::Sacp(error)
+    }
+}
+
+impl From<DecafError> for sacp::Error {
+    fn from(error: DecafError) -> Self {
+        match error {
+            DecafError::Sacp(error) => error,
+            error => {
+                tracing::error!(%error, "decaf internal error");
+                sacp::Error::into_internal_error(error)
+            }
+        }
+    }
+}
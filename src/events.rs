@@ -0,0 +1,67 @@
+//! Reporting each coalesced notification to `on_flush` and subscribers.
+
+use std::sync::Mutex;
+
+use sacp::schema::SessionId;
+use tokio::sync::broadcast;
+
+/// How many [`FlushEvent`]s a subscriber may fall behind before it lags.
+pub(crate) const FLUSH_EVENT_CAPACITY: usize = 256;
+
+/// One coalesced notification sent for a session, as published to
+/// [`Decaf::event_broadcast`](crate::Decaf::event_broadcast) subscribers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlushEvent {
+    pub session_id: SessionId,
+    /// Size of the notification's text in bytes, after any
+    /// [`transform`](crate::DecafBuilder::transform).
+    pub byte_len: usize,
+    /// How many text chunks it was built from, as counted by
+    /// [`mark_coalesced`](crate::DecafBuilder::mark_coalesced).
+    pub chunk_count: usize,
+}
+
+/// Flushes waiting to be reported, queued under the session locks and
+/// handed out once they are released.
+#[derive(Debug)]
+pub(crate) struct FlushReports {
+    pending: Mutex<Vec<FlushEvent>>,
+    events: broadcast::Sender<FlushEvent>,
+    on_flush: bool,
+}
+
+impl FlushReports {
+    pub(crate) fn new(on_flush: bool) -> Self {
+        FlushReports {
+            pending: Mutex::new(Vec::new()),
+            events: broadcast::channel(FLUSH_EVENT_CAPACITY).0,
+            on_flush,
+        }
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<FlushEvent> {
+        self.events.subscribe()
+    }
+
+    /// Queue `event`, unless nobody would see it.
+    pub(crate) fn record(&self, event: impl FnOnce() -> FlushEvent) {
+        if self.on_flush || self.events.receiver_count() > 0 {
+            self.pending
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .push(event());
+        }
+    }
+
+    /// Take the queued events, oldest first.
+    pub(crate) fn take(&self) -> Vec<FlushEvent> {
+        std::mem::take(&mut *self.pending.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    /// Publish `event` to every subscriber. Never waits: a subscriber that
+    /// has fallen [`FLUSH_EVENT_CAPACITY`] events behind loses the oldest.
+    pub(crate) fn publish(&self, event: FlushEvent) {
+        // Only fails when nobody is subscribed.
+        let _ = self.events.send(event);
+    }
+}
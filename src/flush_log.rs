@@ -0,0 +1,104 @@
+//! Logging why each flush happened, for offline analysis.
+
+use sacp::schema::SessionNotification;
+
+/// The `tracing` target flush decisions are logged under, one `DEBUG` event
+/// per flush. Enable it with `RUST_LOG=decaf_mod::flush=debug`.
+pub(crate) const FLUSH_TARGET: &str = "decaf_mod::flush";
+
+/// What triggered a flush.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FlushReason {
+    /// The session's coalescing window closed.
+    Timer,
+    /// The buffer grew past `max_buffer_bytes`.
+    ByteCap,
+    /// A `flush_on_newline` line ended.
+    Newline,
+    /// A `flush_on_paragraph` paragraph ended.
+    Paragraph,
+    /// A `flush_on_sentence` sentence ended.
+    Sentence,
+    /// `flush_on_pattern` matched.
+    Pattern,
+    /// `flush_every_chunks` chunks were buffered.
+    ChunkCount,
+    /// `flush_every_tokens` tokens were buffered.
+    TokenCount,
+    /// A chunk of another kind arrived, e.g. a thought after message text.
+    StreamSwitch,
+    /// A non-chunk update arrived and must not overtake the text.
+    NonChunkUpdate,
+    /// The turn ended with a `PromptResponse`.
+    PromptResponse,
+    /// A message from the client ended its own stream.
+    ClientMessage,
+    /// `max_sessions` was reached and this session was the least recent.
+    Eviction,
+    /// Nothing arrived for the session within `session_ttl`.
+    SessionTtl,
+    /// `max_total_bytes` was reached under `OverflowPolicy::Flush`.
+    MemoryLimit,
+    /// A `passthrough_large` chunk went straight through.
+    LargeChunk,
+    /// The session's chunks grew past `auto_passthrough_above` on average.
+    AutoPassthrough,
+    /// The session became a `passthrough_sessions` one.
+    Passthrough,
+    /// A `with_flush_signal` signal arrived.
+    FlushSignal,
+    /// [`DecafControl::drain`](crate::DecafControl::drain) was called.
+    Drain,
+    /// The proxy or `Coalescer` is shutting down.
+    Shutdown,
+}
+
+impl FlushReason {
+    /// The `reason` field logged for this trigger.
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            FlushReason::Timer => "timer",
+            FlushReason::ByteCap => "byte_cap",
+            FlushReason::Newline => "newline",
+            FlushReason::Paragraph => "paragraph",
+            FlushReason::Sentence => "sentence",
+            FlushReason::Pattern => "pattern",
+            FlushReason::ChunkCount => "chunk_count",
+            FlushReason::TokenCount => "token_count",
+            FlushReason::StreamSwitch => "stream_switch",
+            FlushReason::NonChunkUpdate => "non_chunk_update",
+            FlushReason::PromptResponse => "prompt_response",
+            FlushReason::ClientMessage => "client_message",
+            FlushReason::Eviction => "eviction",
+            FlushReason::SessionTtl => "session_ttl",
+            FlushReason::MemoryLimit => "memory_limit",
+            FlushReason::LargeChunk => "large_chunk",
+            FlushReason::AutoPassthrough => "auto_passthrough",
+            FlushReason::Passthrough => "passthrough",
+            FlushReason::FlushSignal => "flush_signal",
+            FlushReason::Drain => "drain",
+            FlushReason::Shutdown => "shutdown",
+        }
+    }
+}
+
+/// Log a flush of `chunks` chunks into `flushed`, unless nothing was
+/// flushed. `bytes` counts text before any `transform`.
+pub(crate) fn log_flush(reason: FlushReason, chunks: usize, flushed: &[SessionNotification]) {
+    let Some(first) = flushed.first() else {
+        return;
+    };
+    tracing::debug!(
+        target: FLUSH_TARGET,
+        reason = reason.as_str(),
+        session_id = %first.session_id.0,
+        bytes = flushed
+            .iter()
+            .filter_map(|n| crate::chunk_text(&n.update))
+            .map(str::len)
+            .sum::<usize>(),
+        chunks,
+        notifications = flushed.len(),
+        "flush"
+    );
+}
@@ -0,0 +1,88 @@
+//! The most recent notifications sent to the client, kept for debugging.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use sacp::schema::{SessionId, SessionNotification};
+use tokio::time::Instant;
+
+use crate::Clock;
+
+/// A notification Decaf sent to the client, as kept by
+/// [`DecafBuilder::with_history`](crate::DecafBuilder::with_history).
+#[derive(Clone, Debug)]
+pub struct FlushRecord {
+    /// When it was sent, by the proxy's [`Clock`].
+    pub at: Instant,
+    pub notification: SessionNotification,
+}
+
+/// A ring buffer of the last `capacity` [`FlushRecord`]s, shared by the
+/// proxy and its [`DecafControl`](crate::DecafControl)s.
+pub(crate) struct FlushHistory {
+    capacity: usize,
+    records: Mutex<VecDeque<FlushRecord>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl FlushHistory {
+    pub(crate) fn new(capacity: usize, clock: Arc<dyn Clock>) -> Self {
+        FlushHistory {
+            capacity,
+            records: Mutex::new(VecDeque::with_capacity(capacity)),
+            clock,
+        }
+    }
+
+    /// Keep a copy of every notification in `sent`, evicting the oldest
+    /// records beyond capacity.
+    pub(crate) fn record(&self, sent: &[SessionNotification]) {
+        if sent.is_empty() {
+            return;
+        }
+        let at = self.clock.now();
+        let mut records = self.lock();
+        for notification in sent {
+            if records.len() == self.capacity {
+                records.pop_front();
+            }
+            records.push_back(FlushRecord {
+                at,
+                notification: notification.clone(),
+            });
+        }
+    }
+
+    /// Every record held, oldest first.
+    pub(crate) fn all(&self) -> Vec<FlushRecord> {
+        self.lock().iter().cloned().collect()
+    }
+
+    /// The records for `session_id` sent within `within` of now, oldest
+    /// first.
+    pub(crate) fn recent(&self, session_id: &SessionId, within: Duration) -> Vec<FlushRecord> {
+        let now = self.clock.now();
+        self.lock()
+            .iter()
+            .filter(|record| {
+                record.notification.session_id == *session_id
+                    && now.saturating_duration_since(record.at) <= within
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<FlushRecord>> {
+        self.records.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl std::fmt::Debug for FlushHistory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FlushHistory")
+            .field("capacity", &self.capacity)
+            .field("len", &self.lock().len())
+            .finish()
+    }
+}
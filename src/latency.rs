@@ -0,0 +1,140 @@
+//! How long buffered text waits before it is flushed.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Sub-buckets per power of two; percentiles are exact to within
+/// `1 / SUB_BUCKETS` (12.5%).
+const SUB_BUCKETS: u64 = 8;
+
+/// `log2(SUB_BUCKETS)`.
+const SUB_BITS: u32 = 3;
+
+/// Enough buckets for any `u64` count of microseconds.
+const BUCKETS: usize = ((64 - SUB_BITS as usize) + 1) * SUB_BUCKETS as usize;
+
+/// Latency percentiles observed so far, from
+/// [`DecafStats::latency_snapshot`](crate::DecafStats::latency_snapshot),
+/// or since the last [`DecafStats::take_snapshot`](crate::DecafStats::take_snapshot)
+/// in a [`StatsSnapshot`](crate::StatsSnapshot).
+///
+/// Each recorded value is the age of a session's oldest un-flushed chunk
+/// when it was flushed. Percentiles are rounded up to their bucket, so they
+/// may overstate the true value by up to 12.5%, but never exceed `max`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LatencySnapshot {
+    /// How many flushes were recorded.
+    pub count: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    /// The longest wait recorded, exactly.
+    pub max: Duration,
+}
+
+/// A lock-free, log-linear histogram of flush latencies in microseconds.
+pub(crate) struct LatencyHistogram {
+    buckets: [AtomicU64; BUCKETS],
+    max_micros: AtomicU64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            max_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+impl fmt::Debug for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("LatencyHistogram")
+            .field(&self.snapshot())
+            .finish()
+    }
+}
+
+impl LatencyHistogram {
+    pub(crate) fn record(&self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        crate::stats::add(&self.buckets[bucket_of(micros)], 1);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> LatencySnapshot {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        summarize(&counts, self.max_micros.load(Ordering::Relaxed))
+    }
+
+    /// [`snapshot`](Self::snapshot), emptying the histogram as it is read.
+    ///
+    /// Each bucket is swapped out on its own, so a latency recorded
+    /// meanwhile is counted in this snapshot or the next, never both. The
+    /// max is swapped out last and may include a value whose bucket went
+    /// to the next one.
+    pub(crate) fn take_snapshot(&self) -> LatencySnapshot {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|b| b.swap(0, Ordering::Relaxed))
+            .collect();
+        summarize(&counts, self.max_micros.swap(0, Ordering::Relaxed))
+    }
+}
+
+/// Percentiles of the bucket `counts`, capped by `max_micros`.
+fn summarize(counts: &[u64], max_micros: u64) -> LatencySnapshot {
+    let count = counts.iter().fold(0, |sum: u64, &n| sum.saturating_add(n));
+    let percentile = |q: f64| {
+        // The smallest bucket holding at least `q` of the values.
+        let rank = ((q * count as f64).ceil() as u64).max(1);
+        let mut seen: u64 = 0;
+        let bucket = counts
+            .iter()
+            .position(|&n| {
+                seen = seen.saturating_add(n);
+                seen >= rank
+            })
+            .unwrap_or(0);
+        Duration::from_micros(bucket_high(bucket).min(max_micros))
+    };
+    match count {
+        0 => LatencySnapshot::default(),
+        count => LatencySnapshot {
+            count,
+            p50: percentile(0.50),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+            max: Duration::from_micros(max_micros),
+        },
+    }
+}
+
+/// The bucket holding `micros`: exact below [`SUB_BUCKETS`], then
+/// [`SUB_BUCKETS`] equal slices of each power of two.
+fn bucket_of(micros: u64) -> usize {
+    if micros < SUB_BUCKETS {
+        return micros as usize;
+    }
+    let exp = 63 - micros.leading_zeros();
+    let sub = (micros >> (exp - SUB_BITS)) & (SUB_BUCKETS - 1);
+    ((exp - SUB_BITS + 1) as u64 * SUB_BUCKETS + sub) as usize
+}
+
+/// The largest value [`bucket_of`] maps to `bucket`.
+fn bucket_high(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < SUB_BUCKETS {
+        return bucket;
+    }
+    let exp = (bucket / SUB_BUCKETS) as u32 + SUB_BITS - 1;
+    let sub = bucket % SUB_BUCKETS;
+    let width = 1u64 << (exp - SUB_BITS);
+    ((SUB_BUCKETS + sub) << (exp - SUB_BITS)).saturating_add(width - 1)
+}
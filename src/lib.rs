@@ -18,181 +18,3934 @@
 //! # }
 //! ```
 
-use std::collections::HashMap;
+mod builder;
+mod clock;
+mod coalescer;
+#[cfg(feature = "serde")]
+mod config;
+mod control;
+mod error;
+mod events;
+mod flush_log;
+mod history;
+mod latency;
+mod outgoing;
+mod rate;
+#[cfg(feature = "tower")]
+mod service;
+mod sessions;
+mod stats;
+
+pub use builder::{CoalesceMode, DecafBuilder, OverflowPolicy, SessionLimitPolicy};
+pub use clock::{Clock, MockClock, TokioClock};
+pub use coalescer::{Coalescer, into_stream};
+#[cfg(feature = "serde")]
+pub use config::DecafConfig;
+pub use control::DecafControl;
+pub use error::DecafError;
+pub use events::FlushEvent;
+pub use history::FlushRecord;
+pub use latency::LatencySnapshot;
+#[cfg(feature = "tower")]
+pub use service::CoalesceService;
+pub use stats::{DecafStats, StatsSnapshot};
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hasher, RandomState};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::Duration;
 
+use regex::Regex;
 use sacp::schema::{
-    ContentBlock, ContentChunk, PromptRequest, SessionId, SessionNotification, SessionUpdate,
+    Annotations, CancelNotification, ContentBlock, ContentChunk, Meta, PromptRequest, SessionId,
+    SessionNotification, SessionUpdate, StopReason, ToolCallId, ToolCallStatus, ToolKind,
 };
 use sacp::util::MatchDispatch;
-use sacp::{Agent, Client, Conductor, ConnectTo, Dispatch, Proxy};
-use tokio::sync::Mutex;
+use sacp::{Agent, Client, Conductor, ConnectTo, Dispatch, Handled, Proxy, UntypedMessage};
+use tokio::sync::{Mutex, MutexGuard, Notify, broadcast, mpsc, oneshot};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use clock::WallClock;
+use control::{DrainRequest, LiveInterval};
+use events::FlushReports;
+use flush_log::{FlushReason, log_flush};
+use history::FlushHistory;
+use outgoing::{MARK_METHOD, Outgoing};
+use rate::EmitBudget;
+use sessions::Sessions;
 
 /// A debouncing proxy that coalesces `AgentMessageChunk` notifications.
 ///
 /// Instead of forwarding every individual chunk, Decaf buffers text
 /// and flushes it at a configurable interval.
 pub struct Decaf {
-    interval: Duration,
+    name: String,
+    enabled: bool,
+    /// The interval, as [`DecafControl::set_interval`] last left it.
+    interval: Arc<LiveInterval>,
+    min_interval: Duration,
+    adaptive: Option<(Duration, Duration)>,
+    max_latency: Option<Duration>,
+    jitter: Duration,
+    quiet_period: Option<Duration>,
+    leading_edge: bool,
+    coalesce_thoughts: bool,
+    thought_interval: Option<Duration>,
+    first_flush_after: Option<Duration>,
+    coalesce_user_echo: bool,
+    coalesce_tool_calls: bool,
+    tool_call_modes: Vec<(ToolKind, CoalesceMode)>,
+    flush_on_sentence: bool,
+    sentence_terminators: Vec<char>,
+    flush_on_newline: bool,
+    flush_on_paragraph: bool,
+    flush_on_pattern: Option<Regex>,
+    max_buffer_bytes: Option<usize>,
+    passthrough_large: Option<usize>,
+    auto_passthrough_above: Option<usize>,
+    flush_every_chunks: Option<usize>,
+    flush_every_tokens: Option<(usize, TokenCountFn)>,
+    join_with: Arc<str>,
+    skip_empty_chunks: bool,
+    skip_whitespace_chunks: bool,
+    dedupe_repeats: bool,
+    split_on_word_boundary: bool,
+    max_total_bytes: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    max_sessions: Option<usize>,
+    session_limit_policy: SessionLimitPolicy,
+    single_session: bool,
+    session_ttl: Option<Duration>,
+    initial_buffer_capacity: usize,
+    interval_for: Option<IntervalFn>,
+    passthrough_sessions: Option<PassthroughFn>,
+    /// Set by [`DecafControl::pause`]: every session passes through.
+    paused: Arc<AtomicBool>,
+    session_priority: Option<PriorityFn>,
+    session_alias: Option<SessionMapFn>,
+    map_session_id: Option<SessionMapFn>,
+    debounce_client_to_agent: bool,
+    flush_before_response: bool,
+    flush_on_stop_reason: bool,
+    coalesce_across_prompts: bool,
+    mark_coalesced: bool,
+    /// Set when [`DecafBuilder::stamp_timestamps`] is enabled.
+    wall_clock: Option<WallClock>,
+    trim_trailing_on_flush: bool,
+    transform: Option<TransformFn>,
+    can_merge: Option<MergeFn>,
+    merge_fn: Option<ConcatFn>,
+    on_flush: Option<FlushFn>,
+    on_error: Option<ErrorFn>,
+    /// Flushes waiting for `on_flush` and `event_broadcast` subscribers,
+    /// queued under the session locks and reported by [`send_text`] once
+    /// they are released.
+    flush_reports: Arc<FlushReports>,
+    flush_signal: Option<mpsc::Receiver<()>>,
+    tap: Option<mpsc::Sender<SessionNotification>>,
+    /// [`DecafBuilder::max_emit_rate`]'s token bucket.
+    emit_budget: Option<EmitBudget>,
+    max_emit_bytes: Option<usize>,
+    /// [`DecafBuilder::with_history`]'s ring buffer, shared with
+    /// [`DecafControl`].
+    history: Option<Arc<FlushHistory>>,
+    clock: Arc<dyn Clock>,
+    control: DecafControl,
+    drains: Option<mpsc::Receiver<DrainRequest>>,
+    shutdown: CancellationToken,
+    stats: Arc<DecafStats>,
+}
+
+/// Notification `meta` key set to `true` on coalesced notifications when
+/// [`DecafBuilder::mark_coalesced`] is enabled.
+pub const COALESCED_META_KEY: &str = "decaf.coalesced";
+
+/// Notification `meta` key holding how many chunks a coalesced notification
+/// was built from, when [`DecafBuilder::mark_coalesced`] is enabled.
+pub const CHUNK_COUNT_META_KEY: &str = "decaf.chunk_count";
+
+/// Notification `meta` key holding when a coalesced notification's first
+/// chunk arrived, in milliseconds since the Unix epoch, when
+/// [`DecafBuilder::stamp_timestamps`] is enabled.
+pub const FIRST_CHUNK_AT_META_KEY: &str = "decaf.first_chunk_at";
+
+/// Notification `meta` key holding when a coalesced notification's last
+/// chunk arrived, like [`FIRST_CHUNK_AT_META_KEY`].
+pub const LAST_CHUNK_AT_META_KEY: &str = "decaf.last_chunk_at";
+
+/// Text content `meta` key listing the annotation-only chunks merged into a
+/// coalesced notification, each as `{"offset": N, "annotations": {...}}`
+/// with `N` the byte offset in the text where it arrived.
+pub const INLINE_ANNOTATIONS_META_KEY: &str = "decaf.annotations";
+
+/// How soon a repeated chunk must follow the original to be dropped, with
+/// [`DecafBuilder::dedupe_repeats`].
+pub const REPEAT_WINDOW: Duration = Duration::from_millis(50);
+
+/// The characters that end a sentence for [`DecafBuilder::flush_on_sentence`]
+/// unless [`DecafBuilder::sentence_terminators`] replaces them: ASCII `.`,
+/// `!` and `?`, and the ideographic full stop and full-width `！` and `？`
+/// of Chinese and Japanese text.
+pub const DEFAULT_SENTENCE_TERMINATORS: &[char] = &['.', '!', '?', '。', '！', '？'];
+
+type IntervalFn = Box<dyn Fn(&SessionId) -> Duration + Send + Sync>;
+
+type PassthroughFn = Box<dyn Fn(&SessionId) -> bool + Send + Sync>;
+
+type PriorityFn = Box<dyn Fn(&SessionId) -> u8 + Send + Sync>;
+
+type SessionMapFn = Box<dyn Fn(&SessionId) -> SessionId + Send + Sync>;
+
+type TokenCountFn = Box<dyn Fn(&str) -> usize + Send + Sync>;
+
+type TransformFn = Arc<dyn Fn(&str) -> String + Send + Sync>;
+
+type MergeFn = Arc<dyn Fn(&ContentChunk, &ContentChunk) -> bool + Send + Sync>;
+
+type ConcatFn = Arc<dyn Fn(&mut String, &str) + Send + Sync>;
+
+type FlushFn = Box<dyn Fn(&SessionId, usize, usize) + Send + Sync>;
+
+type ErrorFn = Box<dyn Fn(&sacp::Error) + Send + Sync>;
+
+/// The kind of text stream a chunk belongs to. Each kind is buffered
+/// separately so thoughts and messages are never merged into one blob.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum ChunkKind {
+    Message,
+    Thought,
+    /// User message text, streaming from the client toward the agent or
+    /// echoed back by the agent with [`DecafBuilder::coalesce_user_echo`].
+    User,
+}
+
+impl ChunkKind {
+    /// Classify an update from the client as a chunk to buffer toward the
+    /// agent, if it is one.
+    fn of_client(update: &SessionUpdate) -> Option<ChunkKind> {
+        match update {
+            SessionUpdate::UserMessageChunk(_) => Some(ChunkKind::User),
+            _ => None,
+        }
+    }
+
+    /// Classify `update` as a chunk Decaf should buffer, if it is one.
+    ///
+    /// Chunks of any content block belong to their stream: text is
+    /// coalesced, other blocks are held in order between the text around
+    /// them.
+    fn of(update: &SessionUpdate, decaf: &Decaf) -> Option<ChunkKind> {
+        match update {
+            SessionUpdate::AgentMessageChunk(_) => Some(ChunkKind::Message),
+            SessionUpdate::AgentThoughtChunk(_) if decaf.coalesce_thoughts => {
+                Some(ChunkKind::Thought)
+            }
+            SessionUpdate::UserMessageChunk(_) if decaf.coalesce_user_echo => Some(ChunkKind::User),
+            _ => None,
+        }
+    }
 }
 
+/// What to do with a notification from the agent.
+enum Route {
+    /// Buffer it as a chunk of this kind.
+    Chunk(ChunkKind),
+
+    /// Merge it into its tool call's pending update, or for a `ToolCall`,
+    /// note its kind.
+    ToolCall,
+
+    /// Flush its session, then forward it.
+    Forward,
+
+    /// Drop it: a text chunk with nothing worth sending, by
+    /// [`DecafBuilder::skip_empty_chunks`] or
+    /// [`DecafBuilder::skip_whitespace_chunks`].
+    Skip,
+}
+
+impl Route {
+    fn of(notification: &SessionNotification, decaf: &Decaf) -> Route {
+        match Route::of_update(&notification.update, decaf) {
+            Route::Chunk(_) | Route::ToolCall if !has_session(notification) => Route::Forward,
+            route => route,
+        }
+    }
+
+    fn of_update(update: &SessionUpdate, decaf: &Decaf) -> Route {
+        match ChunkKind::of(update, decaf) {
+            Some(_) if decaf.skips(update) => Route::Skip,
+            Some(kind) => Route::Chunk(kind),
+            None if decaf.coalesce_tool_calls
+                && matches!(update, SessionUpdate::ToolCallUpdate(_)) =>
+            {
+                Route::ToolCall
+            }
+            // Only watched for its kind, which the updates may not repeat.
+            None if decaf.coalesce_tool_calls
+                && !decaf.tool_call_modes.is_empty()
+                && matches!(update, SessionUpdate::ToolCall(_)) =>
+            {
+                Route::ToolCall
+            }
+            // Including variants added to ACP after this was written.
+            None => Route::Forward,
+        }
+    }
+}
+
+/// Buffering state for one session.
 struct BufferedSession {
-    /// Accumulated text chunks.
+    /// One accumulator per chunk kind seen in this session.
+    buffers: HashMap<ChunkKind, ChunkBuffer>,
+
+    /// Merged `ToolCallUpdate`s per tool call, so several calls in flight
+    /// in one session are kept apart.
+    tool_calls: HashMap<ToolCallId, PendingToolCall>,
+
+    /// The kind each running tool call announced, kept while
+    /// [`tool_call_mode`](DecafBuilder::tool_call_mode) needs it.
+    tool_kinds: HashMap<ToolCallId, ToolKind>,
+
+    /// This session's coalescing window, if
+    /// [`interval_for`](DecafBuilder::interval_for) picked one when the
+    /// entry was created; otherwise the proxy's current interval.
+    interval: Option<Duration>,
+
+    /// Added to the interval to stagger this session's deadline, drawn from
+    /// `jitter` when the entry is created.
+    jitter: Duration,
+
+    /// When the most recent chunk was buffered. While a buffer is non-empty
+    /// it is never older than [`oldest_chunk_at`](Self::oldest_chunk_at);
+    /// it also picks the session to evict at `max_sessions`.
+    last_chunk_at: Option<Instant>,
+
+    /// When an update last came through this entry, buffered or not, or
+    /// when it was created; `session_ttl` counts from here.
+    updated_at: Instant,
+
+    /// Whether this session's chunks bypass buffering, resolved when the
+    /// entry is created.
+    passthrough: bool,
+
+    /// `session` span entered while buffering or flushing, so every event
+    /// in this entry's lifetime carries its session id.
+    span: tracing::Span,
+
+    /// Smoothed gap between this session's chunks, for the adaptive
+    /// interval. `None` until a second chunk arrives.
+    chunk_gap: Option<Duration>,
+
+    /// Smoothed length of this session's text chunks, for
+    /// `auto_passthrough_above`. `None` until a text chunk arrives.
+    chunk_size: Option<usize>,
+
+    /// Text chunks buffered since this session last flushed, for
+    /// `flush_every_chunks`.
+    chunks_buffered: usize,
+
+    /// Tokens in the text chunks buffered since this session last flushed,
+    /// for `flush_every_tokens`.
+    tokens_buffered: usize,
+
+    /// The proxy's stats, where each flush records how long its text waited.
+    stats: Arc<DecafStats>,
+
+    /// The proxy's clock, for timestamps taken outside a handler's `now`.
+    clock: Arc<dyn Clock>,
+
+    /// Set, under the lock, when the entry is removed from its map, after
+    /// its final flush. See [`buffer_into`].
+    retired: bool,
+
+    /// The session's previous text chunk, for `dedupe_repeats`. Cleared by
+    /// anything else arriving for the session.
+    last_text: Option<LastText>,
+
+    /// Whether this entry has sent coalesced text yet; until it has,
+    /// `first_flush_after` shortens its windows.
+    flushed: bool,
+
+    /// Numbers each chunk and tool call update as it is buffered, so a
+    /// flush can put buffers and tool calls back in arrival order even when
+    /// they arrived at the same instant.
+    arrivals: u64,
+}
+
+/// A text chunk as [`BufferedSession::is_repeat`] remembers it.
+struct LastText {
+    kind: ChunkKind,
+    text: String,
+    at: Instant,
+}
+
+struct ChunkBuffer {
+    /// The session this buffer belongs to, for error context.
+    session_id: SessionId,
+
+    /// Notifications ready to emit ahead of `text`, in arrival order: each
+    /// non-text block, preceded by the text run it interrupted.
+    queued: Vec<SessionNotification>,
+
+    /// Accumulated text chunks since the last non-text block.
     text: String,
 
-    /// The most recent notification, used as a template when flushing
-    /// (preserves session_id, meta, annotations, etc).
-    template: SessionNotification,
+    /// Where each chunk's text starts in `text`, with its arrival number,
+    /// so text left over by a split knows when its first byte arrived.
+    /// Only kept with `coalesce_tool_calls`: pending tool calls are all a
+    /// remainder is ever ordered against.
+    text_arrivals: Vec<(usize, u64)>,
+
+    /// [`DecafBuilder::coalesce_tool_calls`], to keep `text_arrivals`.
+    coalesce_tool_calls: bool,
+
+    /// When the oldest un-flushed chunk arrived. `None` while the buffer is empty.
+    first_chunk_at: Option<Instant>,
+
+    /// The session's arrival number of the oldest un-flushed chunk, cleared
+    /// with `first_chunk_at`.
+    first_arrival: Option<u64>,
+
+    /// The first text chunk, with its text and `meta` moved out, used as a
+    /// template when flushing (preserves session_id, annotations, etc).
+    /// Captured once so later chunks can be consumed without replacing it.
+    template: Option<SessionNotification>,
+
+    /// `meta` from every chunk since the last flush, merged.
+    meta: MergedMeta,
+
+    /// Annotations of annotation-only chunks (empty text) in the current
+    /// run, with the byte offset in `text` where each arrived.
+    inline_annotations: Vec<(usize, Annotations)>,
+
+    /// Text chunks pushed since this buffer last emitted a notification.
+    chunks_since_flush: usize,
+
+    /// Text chunks emitted since the last flush was logged.
+    chunks_flushed: usize,
+
+    /// [`DecafBuilder::join_with`], put between consecutive chunks.
+    join_with: Arc<str>,
+
+    /// Whether emitted notifications carry the coalescing markers.
+    mark_coalesced: bool,
+
+    /// [`DecafBuilder::stamp_timestamps`], to turn `stamps` into meta.
+    wall_clock: Option<WallClock>,
+
+    /// When the first and last text chunks pushed since this buffer last
+    /// emitted a notification arrived.
+    stamps: Option<(Instant, Instant)>,
+
+    /// [`DecafBuilder::trim_trailing_on_flush`].
+    trim_trailing: bool,
+
+    /// Whitespace trimmed off the end of the last notification, to lead
+    /// the next one.
+    carry: String,
+
+    /// [`DecafBuilder::transform`], applied to the text of each emitted
+    /// notification.
+    transform: Option<TransformFn>,
+
+    /// [`DecafBuilder::can_merge`], asked before each text chunk joins the
+    /// current run.
+    can_merge: Option<MergeFn>,
+
+    /// [`DecafBuilder::merge_fn`], appending each text chunk to `text` in
+    /// place of `push_str`.
+    merge_fn: Option<ConcatFn>,
+
+    /// The text chunk pushed last, as it arrived, for `can_merge`. Only kept
+    /// when `can_merge` is set.
+    previous: Option<ContentChunk>,
+
+    /// Where emitted notifications are recorded for
+    /// [`DecafBuilder::on_flush`] and [`Decaf::event_broadcast`].
+    flush_reports: Arc<FlushReports>,
+}
+
+/// `ToolCallUpdate`s for one tool call since the last flush, merged into one.
+struct PendingToolCall {
+    /// The first update, with every later one merged in.
+    notification: SessionNotification,
+
+    /// When the first of the merged updates arrived.
+    first_update_at: Instant,
+
+    /// The session's arrival number of the first of the merged updates.
+    first_arrival: u64,
+}
+
+/// The `meta` maps of buffered chunks, merged key by key with the last
+/// writer winning. Each level (notification, content chunk, text content)
+/// is merged separately and written back to the same level on flush.
+#[derive(Default)]
+struct MergedMeta {
+    notification: Option<Meta>,
+    chunk: Option<Meta>,
+    text: Option<Meta>,
+}
+
+/// State shared between one direction's handler and the flush task.
+///
+/// Each session has its own lock; the map lock is only held to look up,
+/// insert or snapshot entries, so work on one session never waits on another.
+#[derive(Default)]
+struct Shared {
+    /// Where this state's flushed notifications are sent.
+    toward: Toward,
+
+    sessions: Mutex<Sessions<SessionEntry>>,
+
+    /// Signalled when a session's flush deadline moves, so the flush task
+    /// re-evaluates which deadline to sleep until.
+    deadline_changed: Notify,
+
+    /// Text held across all of this state's sessions, kept up to date by
+    /// [`SessionGuard`].
+    buffered_bytes: AtomicUsize,
+
+    /// Signalled whenever `buffered_bytes` shrinks, for
+    /// [`OverflowPolicy::Block`].
+    drained: Notify,
+
+    /// Sessions whose turn has ended, until they are prompted again.
+    ended_turns: std::sync::Mutex<EndedTurns>,
+}
+
+/// How many sessions [`EndedTurns`] remembers. A late chunk comes right
+/// after its turn's response, not after a thousand other turns.
+const ENDED_TURNS: usize = 1024;
+
+/// The sessions whose last turn ended most recently, so chunks an agent
+/// sends after its `PromptResponse` are forwarded rather than buffered into
+/// a session nothing will end.
+///
+/// Bounded to [`ENDED_TURNS`], oldest forgotten first, so sessions that are
+/// never prompted again don't pile up. Each end is numbered: a session that
+/// ends, is prompted and ends again leaves a stale entry in `order` that
+/// must not forget the newer end.
+///
+/// Also counts each session's prompts still waiting for a response, for
+/// [`DecafBuilder::coalesce_across_prompts`], and maps their request ids to
+/// their session for `$/cancel_request`.
+#[derive(Default)]
+struct EndedTurns {
+    ended: HashMap<SessionId, u64>,
+    order: VecDeque<(SessionId, u64)>,
+    next: u64,
+    outstanding: HashMap<SessionId, usize>,
+
+    /// Prompts waiting for a response, by the id the proxy received each
+    /// with as JSON text: the session and the id it was forwarded with.
+    prompts: HashMap<String, (SessionId, serde_json::Value)>,
+}
+
+impl EndedTurns {
+    fn end(&mut self, session_id: &SessionId) {
+        // An end number only has to differ from the `ENDED_TURNS` before
+        // it, so wrapping around is harmless.
+        self.next = self.next.wrapping_add(1);
+        self.ended.insert(session_id.clone(), self.next);
+        self.order.push_back((session_id.clone(), self.next));
+        while self.order.len() > ENDED_TURNS {
+            if let Some((oldest, end)) = self.order.pop_front() {
+                if self.ended.get(&oldest) == Some(&end) {
+                    self.ended.remove(&oldest);
+                }
+            }
+        }
+    }
+
+    fn start(&mut self, session_id: &SessionId) {
+        self.ended.remove(session_id);
+        let outstanding = self.outstanding.entry(session_id.clone()).or_default();
+        *outstanding = outstanding.saturating_add(1);
+    }
+
+    /// Count a response to one of `session_id`'s prompts, returning how
+    /// many are still outstanding.
+    fn answered(&mut self, session_id: &SessionId) -> usize {
+        let Some(outstanding) = self.outstanding.get_mut(session_id) else {
+            return 0;
+        };
+        *outstanding = outstanding.saturating_sub(1);
+        let remaining = *outstanding;
+        if remaining == 0 {
+            self.outstanding.remove(session_id);
+        }
+        remaining
+    }
+
+    fn has_ended(&self, session_id: &SessionId) -> bool {
+        self.ended.contains_key(session_id)
+    }
+
+    /// Remember a prompt for `session_id` received as `received` and
+    /// forwarded as `forwarded`, until [`untrack`](Self::untrack).
+    fn track(
+        &mut self,
+        session_id: &SessionId,
+        received: &serde_json::Value,
+        forwarded: &serde_json::Value,
+    ) {
+        self.prompts.insert(
+            received.to_string(),
+            (session_id.clone(), forwarded.clone()),
+        );
+    }
+
+    fn untrack(&mut self, received: &serde_json::Value) {
+        self.prompts.remove(&received.to_string());
+    }
+
+    /// The session and forwarded id of the prompt received as `request_id`,
+    /// while it waits for its response.
+    fn prompt(&self, request_id: &serde_json::Value) -> Option<(SessionId, serde_json::Value)> {
+        self.prompts.get(&request_id.to_string()).cloned()
+    }
+}
+
+/// A locked session that keeps its state's `buffered_bytes` in step with
+/// whatever was buffered or flushed while it was held.
+struct SessionGuard<'a> {
+    session: MutexGuard<'a, BufferedSession>,
+    state: &'a Shared,
+    decaf: &'a Decaf,
+    bytes_before: usize,
+}
+
+/// The peer a [`Shared`] state flushes to.
+#[derive(Clone, Copy, Debug, Default)]
+enum Toward {
+    #[default]
+    Client,
+    Agent,
+}
+
+/// Where a session's next update goes, from [`Shared::admit`].
+enum Admission {
+    /// Buffer into `entry`, after sending `evicted`: what was flushed from
+    /// the session evicted to make room, if any.
+    Entry {
+        entry: SessionEntry,
+        evicted: Vec<SessionNotification>,
+    },
+
+    /// [`DecafBuilder::max_sessions`] is reached and the policy is
+    /// [`SessionLimitPolicy::PassThrough`]: forward the update untouched.
+    PassThrough,
 }
 
-type State = Arc<Mutex<HashMap<SessionId, BufferedSession>>>;
+type SessionEntry = Arc<Mutex<BufferedSession>>;
+
+type State = Arc<Shared>;
 
 impl Decaf {
+    /// A proxy flushing every `interval` with all other options at their
+    /// defaults. Shorthand for `Decaf::builder().interval(interval).build()`.
+    ///
+    /// # Panics
+    ///
+    /// If `interval` is zero or under a millisecond; see
+    /// [`DecafBuilder::with_min_interval`] for tighter intervals.
     pub fn new(interval: Duration) -> Self {
-        Decaf { interval }
+        Decaf::builder().interval(interval).build()
+    }
+
+    /// A proxy whose interval adapts to each session's chunk rate between
+    /// `min_interval` and `max_interval`. Shorthand for
+    /// `Decaf::builder().adaptive_interval(min_interval, max_interval).build()`;
+    /// see [`DecafBuilder::adaptive_interval`] for the scaling.
+    ///
+    /// # Panics
+    ///
+    /// If `min_interval` is zero or greater than `max_interval`.
+    pub fn adaptive(min_interval: Duration, max_interval: Duration) -> Self {
+        Decaf::builder()
+            .adaptive_interval(min_interval, max_interval)
+            .build()
+    }
+
+    /// Start configuring a proxy.
+    pub fn builder() -> DecafBuilder {
+        DecafBuilder::default()
+    }
+
+    /// This proxy's name, as set by [`DecafBuilder::named`].
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The coalescing interval, as set by [`DecafBuilder::interval`] or
+    /// [`Decaf::new`], or since changed through
+    /// [`DecafControl::set_interval`]. Sessions given their own by
+    /// [`DecafBuilder::interval_for`] or
+    /// [`adaptive_interval`](DecafBuilder::adaptive_interval) don't use it.
+    pub fn interval(&self) -> Duration {
+        self.interval.get()
+    }
+
+    /// A shared handle to this proxy's [`DecafStats`], readable from any
+    /// task while the proxy runs.
+    pub fn stats_handle(&self) -> Arc<DecafStats> {
+        self.stats.clone()
+    }
+
+    /// Flush latency percentiles recorded so far. `run` consumes the proxy,
+    /// so read them through [`stats_handle`](Self::stats_handle) while it
+    /// runs.
+    pub fn latency_snapshot(&self) -> LatencySnapshot {
+        self.stats.latency_snapshot()
+    }
+
+    /// Subscribe to a [`FlushEvent`] for every coalesced notification this
+    /// proxy sends, e.g. to drive a typing indicator.
+    ///
+    /// Events are published in the order their notifications were sent,
+    /// once sent, with no session locked. Publishing never waits: a
+    /// subscriber that falls 256 events behind gets
+    /// [`RecvError::Lagged`](tokio::sync::broadcast::error::RecvError::Lagged)
+    /// with the number it missed and resumes from the oldest event still
+    /// held, so a slow subscriber never slows the proxy. Subscribe before
+    /// [`run`](Self::run), which consumes the proxy, and use
+    /// [`resubscribe`](tokio::sync::broadcast::Receiver::resubscribe) for
+    /// more receivers later; they see `Closed` once the proxy is dropped.
+    /// Like [`on_flush`](DecafBuilder::on_flush), chunks forwarded as they
+    /// are aren't reported.
+    pub fn event_broadcast(&self) -> broadcast::Receiver<FlushEvent> {
+        self.flush_reports.subscribe()
+    }
+
+    /// A [`DecafControl`] for flushing this proxy on demand while it runs.
+    pub fn control_handle(&self) -> DecafControl {
+        self.control.clone()
+    }
+
+    /// A proxy that forwards everything untouched, for measuring the
+    /// uncoalesced baseline with the same binary. Shorthand for
+    /// `Decaf::builder().enabled(false).build()`.
+    pub fn disabled() -> Self {
+        Decaf::builder().enabled(false).build()
     }
 
-    pub async fn run(self, transport: impl ConnectTo<Proxy> + 'static) -> Result<(), sacp::Error> {
-        let state: State = Arc::new(Mutex::new(HashMap::new()));
-        let interval = self.interval;
+    pub async fn run(
+        mut self,
+        transport: impl ConnectTo<Proxy> + 'static,
+    ) -> Result<(), sacp::Error> {
+        if let Some(tap) = self.tap.take() {
+            return self.run_tapped(transport, tap).await;
+        }
+        if !self.enabled {
+            return self.run_disabled(transport).await;
+        }
+
+        let state: State = Arc::new(Shared::new(Toward::Client, &self));
+        // `run` consumes the proxy, so nothing has set this before.
+        let _ = self.control.sessions.set(Arc::downgrade(&state));
+        let to_agent: State = Arc::new(Shared::new(Toward::Agent, &self));
+        let flush_signal = self.flush_signal.take();
+        let drains = self.drains.take();
+        let decaf = Arc::new(self);
+        let (transport, sent) = Outgoing::new(transport);
 
         Proxy
             .builder()
-            .name("decaf")
+            .name(decaf.name.clone())
             .on_receive_dispatch_from(
                 Agent,
                 {
                     let state = state.clone();
+                    let decaf = decaf.clone();
                     async move |dispatch: Dispatch, cx| {
                         MatchDispatch::new(dispatch)
-                            .if_notification(async |notification: SessionNotification| {
-                                let is_text_chunk = matches!(
-                                    &notification.update,
-                                    SessionUpdate::AgentMessageChunk(ContentChunk {
-                                        content: ContentBlock::Text(_),
-                                        ..
-                                    })
-                                );
-
-                                if is_text_chunk {
-                                    // Buffer the text chunk
-                                    let mut sessions = state.lock().await;
-                                    let text = match &notification.update {
-                                        SessionUpdate::AgentMessageChunk(ContentChunk {
-                                            content: ContentBlock::Text(tc),
-                                            ..
-                                        }) => tc.text.clone(),
-                                        _ => unreachable!(),
-                                    };
-
-                                    match sessions.get_mut(&notification.session_id) {
-                                        Some(buffered) => {
-                                            buffered.text.push_str(&text);
-                                            buffered.template = notification;
-                                        }
-                                        None => {
-                                            sessions.insert(
-                                                notification.session_id.clone(),
-                                                BufferedSession {
-                                                    text,
-                                                    template: notification,
-                                                },
-                                            );
-                                        }
+                            .if_notification(async |mut notification: SessionNotification| {
+                                decaf.map_session(&mut notification.session_id);
+                                match Route::of(&notification, &decaf) {
+                                    Route::Chunk(kind) => {
+                                        handle_chunk(&state, &decaf, kind, notification, &cx)
+                                            .await?;
+                                    }
+                                    Route::ToolCall => {
+                                        handle_tool_call_update(&state, &decaf, notification, &cx)
+                                            .await?;
+                                    }
+                                    Route::Forward => {
+                                        // Non-chunk message: flush buffer first, then forward.
+                                        // The update goes on even if the flush fails.
+                                        decaf.flush_failed(
+                                            flush_session(
+                                                &state,
+                                                &decaf,
+                                                &notification.session_id,
+                                                FlushReason::NonChunkUpdate,
+                                                &cx,
+                                            )
+                                            .await,
+                                        );
+                                        cx.send_notification_to(Client, notification)?;
+                                    }
+                                    Route::Skip => {
+                                        tracing::debug!(session_id = %notification.session_id.0, "skipping blank chunk");
                                     }
-                                } else {
-                                    // Non-chunk message: flush buffer first, then forward
-                                    flush_session(&state, &notification.session_id, &cx).await?;
-                                    cx.send_notification_to(Client, notification)?;
                                 }
 
                                 Ok(())
                             })
                             .await
-                            .if_response_to::<PromptRequest, _>(async |result, router| {
-                                // Flush any remaining buffered text before
-                                // the prompt response reaches the client.
-                                flush_all(&state, &cx).await?;
-                                router.respond_with_result(result)
+                            .done()
+                    }
+                },
+                sacp::on_receive_dispatch!(),
+            )
+            .on_receive_dispatch_from(
+                Client,
+                {
+                    let state = state.clone();
+                    let to_agent = to_agent.clone();
+                    let decaf = decaf.clone();
+                    async move |dispatch: Dispatch, cx| {
+                        // Text buffered for a cancelled turn is stale: drop it
+                        // instead of letting the cancellation flush it out.
+                        let dispatch = match MatchDispatch::new(dispatch)
+                            .if_notification(async |cancel: CancelNotification| {
+                                let mut session_id = cancel.session_id.clone();
+                                decaf.map_session(&mut session_id);
+                                state.discard(&session_id, &decaf).await;
+                                let flushed = flush_all(&to_agent, &decaf, FlushReason::ClientMessage).await?;
+                                send_text(&to_agent, &decaf, &cx, flushed)?;
+                                cx.send_notification_to(Agent, cancel)
+                            })
+                            .await
+                            .if_notification(async |mut cancel: UntypedMessage| {
+                                let prompt = match cancelled_request(&cancel) {
+                                    Some(request_id) => state.ended_turns().prompt(request_id),
+                                    None => None,
+                                };
+                                let Some((session_id, forwarded)) = prompt else {
+                                    return Ok(Handled::No {
+                                        message: cancel,
+                                        retry: false,
+                                    });
+                                };
+                                state.discard(&session_id, &decaf).await;
+                                let flushed = flush_all(&to_agent, &decaf, FlushReason::ClientMessage).await?;
+                                send_text(&to_agent, &decaf, &cx, flushed)?;
+                                // The agent only knows the prompt by the id we sent it with.
+                                cancel.params[CANCEL_REQUEST_ID] = forwarded;
+                                cx.send_notification_to(Agent, cancel)?;
+                                Ok(Handled::Yes)
                             })
                             .await
-                            .done()
+                            .done()?
+                        {
+                            Handled::Yes => return Ok(Handled::Yes),
+                            Handled::No { message, .. } => message,
+                        };
+
+                        let dispatch =
+                            match forward_prompt(dispatch, &state, &to_agent, &decaf, &cx).await? {
+                                Handled::Yes => return Ok(Handled::Yes),
+                                Handled::No { message, .. } => message,
+                            };
+
+                        if !decaf.debounce_client_to_agent {
+                            return Ok(Handled::No {
+                                message: dispatch,
+                                retry: false,
+                            });
+                        }
+
+                        let handled = MatchDispatch::new(dispatch)
+                            .if_notification(async |notification: SessionNotification| {
+                                match ChunkKind::of_client(&notification.update) {
+                                    Some(kind) if has_session(&notification) => {
+                                        handle_chunk(&to_agent, &decaf, kind, notification, &cx)
+                                            .await
+                                    }
+                                    _ => {
+                                        flush_session(
+                                            &to_agent,
+                                            &decaf,
+                                            &notification.session_id,
+                                            FlushReason::NonChunkUpdate,
+                                            &cx,
+                                        )
+                                        .await?;
+                                        cx.send_notification_to(Agent, notification)
+                                    }
+                                }
+                            })
+                            .await
+                            .done()?;
+
+                        // Any other message (a prompt, say) ends the client's
+                        // stream for now: flush and free everything buffered
+                        // before it is forwarded.
+                        if let Handled::No { .. } = handled {
+                            let flushed = flush_all(&to_agent, &decaf, FlushReason::ClientMessage).await?;
+                            send_text(&to_agent, &decaf, &cx, flushed)?;
+                        }
+                        Ok(handled)
                     }
                 },
                 sacp::on_receive_dispatch!(),
             )
             .with_spawned({
                 let state = state.clone();
+                let to_agent = to_agent.clone();
+                let decaf = decaf.clone();
                 move |cx| async move {
-                    let mut ticker = tokio::time::interval(interval);
-                    loop {
-                        ticker.tick().await;
-                        flush_all(&state, &cx).await?;
-                    }
+                    let send = |state: &Shared, flushed| send_text(state, &decaf, &cx, flushed);
+                    flush_task(&state, &to_agent, &decaf, flush_signal, drains, send).await;
+                    Ok(())
                 }
             })
-            .connect_to(transport)
+            .connect_with(transport, async |cx| {
+                decaf.shutdown.cancelled().await;
+                shutdown(&[&state, &to_agent], &decaf, &cx, sent).await
+            })
             .await
     }
 }
 
-impl ConnectTo<Conductor> for Decaf {
-    async fn connect_to(self, transport: impl ConnectTo<Proxy>) -> Result<(), sacp::Error> {
-        self.run(transport).await
+impl Decaf {
+    /// Run without any handlers or flush task, so sacp forwards every
+    /// message as-is. Nothing is ever buffered, so drains complete at once.
+    async fn run_disabled(
+        mut self,
+        transport: impl ConnectTo<Proxy> + 'static,
+    ) -> Result<(), sacp::Error> {
+        let drains = self.drains.take();
+        Proxy
+            .builder()
+            .name(self.name.clone())
+            .connect_with(transport, async |_cx| self.answer_drains(drains).await)
+            .await
     }
-}
 
-/// Flush a single session's buffer, sending a coalesced chunk to the client.
-async fn flush_session(
-    state: &State,
-    session_id: &SessionId,
-    cx: &sacp::ConnectionTo<Conductor>,
-) -> Result<(), sacp::Error> {
-    let flushed = {
-        let mut sessions = state.lock().await;
-        match sessions.get_mut(session_id) {
-            Some(buffered) if !buffered.text.is_empty() => {
-                let text = std::mem::take(&mut buffered.text);
-                let mut notification = buffered.template.clone();
-
-                // Replace the text content with the coalesced text
-                if let SessionUpdate::AgentMessageChunk(ContentChunk {
-                    content: ContentBlock::Text(tc),
-                    ..
-                }) = &mut notification.update
-                {
-                    tc.text = text;
+    /// Like [`run_disabled`](Self::run_disabled), but with one handler
+    /// copying every `SessionNotification` from the agent into the tap
+    /// before forwarding it unchanged.
+    async fn run_tapped(
+        mut self,
+        transport: impl ConnectTo<Proxy> + 'static,
+        tap: mpsc::Sender<SessionNotification>,
+    ) -> Result<(), sacp::Error> {
+        let drains = self.drains.take();
+        let stats = self.stats.clone();
+        Proxy
+            .builder()
+            .name(self.name.clone())
+            .on_receive_dispatch_from(
+                Agent,
+                async move |dispatch: Dispatch, cx| {
+                    MatchDispatch::new(dispatch)
+                        .if_notification(async |notification: SessionNotification| {
+                            record(&tap, &stats, &notification);
+                            cx.send_notification_to(Client, notification)
+                        })
+                        .await
+                        .done()
+                },
+                sacp::on_receive_dispatch!(),
+            )
+            .connect_with(transport, async |_cx| self.answer_drains(drains).await)
+            .await
+    }
+
+    /// Answer drains at once until shutdown, for a proxy that never buffers.
+    async fn answer_drains(
+        &self,
+        mut drains: Option<mpsc::Receiver<DrainRequest>>,
+    ) -> Result<(), sacp::Error> {
+        loop {
+            tokio::select! {
+                _ = self.shutdown.cancelled() => return Ok(()),
+                Some(done) = recv(&mut drains) => {
+                    let _ = done.send(());
                 }
+            }
+        }
+    }
+
+    /// When a timer flush due at `deadline` may go out, once
+    /// [`max_emit_rate`](DecafBuilder::max_emit_rate) has a token for it.
+    fn emit_deadline(&self, deadline: Instant) -> Instant {
+        match &self.emit_budget {
+            Some(budget) => budget.ready_at(deadline),
+            None => deadline,
+        }
+    }
 
-                Some(notification)
+    /// `notifications` ready to send, with any text over
+    /// [`max_emit_bytes`](DecafBuilder::max_emit_bytes) split into pieces.
+    fn frame(&self, notifications: Vec<SessionNotification>) -> Vec<SessionNotification> {
+        let Some(max_bytes) = self.max_emit_bytes else {
+            return notifications;
+        };
+        let mut framed = Vec::with_capacity(notifications.len());
+        for notification in notifications {
+            let Some(text) = chunk_text(&notification.update).filter(|t| t.len() > max_bytes)
+            else {
+                framed.push(notification);
+                continue;
+            };
+            for piece in frame_pieces(text, max_bytes) {
+                let mut piece_notification = notification.clone();
+                if let Some(tc) = chunk_text_mut(&mut piece_notification.update) {
+                    *tc = piece.to_owned();
+                }
+                framed.push(piece_notification);
             }
-            _ => None,
         }
-    };
+        framed
+    }
 
-    if let Some(notification) = flushed {
-        cx.send_notification_to(Client, notification)?;
+    fn session_interval(&self, session_id: &SessionId) -> Option<Duration> {
+        self.interval_for
+            .as_ref()
+            .map(|interval_for| interval_for(session_id).max(self.min_interval))
     }
 
-    Ok(())
+    /// How updates to a tool call of `kind` merge.
+    fn tool_call_mode(&self, kind: Option<ToolKind>) -> CoalesceMode {
+        kind.and_then(|kind| {
+            self.tool_call_modes
+                .iter()
+                .rev()
+                .find(|(k, _)| *k == kind)
+                .map(|(_, mode)| *mode)
+        })
+        .unwrap_or_default()
+    }
+
+    /// Reorder `sessions` highest [`session_priority`](DecafBuilder::session_priority)
+    /// first, keeping their order otherwise. Left alone without one.
+    fn by_priority<T>(&self, sessions: &mut [(SessionId, T)]) {
+        if let Some(priority) = &self.session_priority {
+            sessions.sort_by_cached_key(|(session_id, _)| std::cmp::Reverse(priority(session_id)));
+        }
+    }
+
+    /// Normalize `session_id` with [`DecafBuilder::session_alias`], then
+    /// rewrite it with [`DecafBuilder::map_session_id`], each if set.
+    fn map_session(&self, session_id: &mut SessionId) {
+        if let Some(alias) = &self.session_alias {
+            let canonical = alias(session_id);
+            if canonical != *session_id {
+                tracing::debug!(alias = %session_id.0, session_id = %canonical.0, "merging aliased session id");
+                *session_id = canonical;
+            }
+        }
+        if let Some(map) = &self.map_session_id {
+            *session_id = map(session_id);
+        }
+    }
+
+    /// Report a failed flush from the flush task to
+    /// [`DecafBuilder::on_error`], or log it without one.
+    fn flush_failed(&self, result: Result<(), sacp::Error>) {
+        let Err(error) = result else { return };
+        match &self.on_error {
+            Some(on_error) => on_error(&error),
+            None => tracing::error!(%error, "flush failed"),
+        }
+    }
+
+    /// Whether `update` is a text chunk to drop unbuffered. Annotated
+    /// chunks are kept even with no text, for their annotations.
+    fn skips(&self, update: &SessionUpdate) -> bool {
+        text_annotations(update).is_none()
+            && chunk_text(update).is_some_and(|text| {
+                (self.skip_empty_chunks && text.is_empty())
+                    || (self.skip_whitespace_chunks && text.trim().is_empty())
+            })
+    }
+
+    /// Whether [`DecafControl::pause`] is in effect.
+    fn paused(&self) -> bool {
+        self.paused.load(Ordering::Acquire)
+    }
+
+    fn session_passthrough(&self, session_id: &SessionId) -> bool {
+        self.passthrough_sessions
+            .as_ref()
+            .is_some_and(|passthrough| passthrough(session_id))
+    }
 }
 
-/// Flush all sessions that have buffered data.
-async fn flush_all(state: &State, cx: &sacp::ConnectionTo<Conductor>) -> Result<(), sacp::Error> {
-    // Collect session IDs that need flushing while holding the lock briefly
-    let session_ids: Vec<SessionId> = {
-        let sessions = state.lock().await;
-        sessions
-            .iter()
-            .filter(|(_, b)| !b.text.is_empty())
-            .map(|(id, _)| id.clone())
-            .collect()
-    };
+impl ConnectTo<Conductor> for Decaf {
+    async fn connect_to(self, transport: impl ConnectTo<Proxy>) -> Result<(), sacp::Error> {
+        self.run(transport).await
+    }
+}
 
-    for session_id in session_ids {
-        flush_session(state, &session_id, cx).await?;
+impl BufferedSession {
+    fn new(session_id: &SessionId, decaf: &Decaf) -> Self {
+        BufferedSession {
+            buffers: HashMap::new(),
+            tool_calls: HashMap::new(),
+            tool_kinds: HashMap::new(),
+            interval: decaf.session_interval(session_id),
+            jitter: random_offset(decaf.jitter),
+            last_chunk_at: None,
+            updated_at: decaf.clock.now(),
+            passthrough: decaf.session_passthrough(session_id),
+            span: tracing::debug_span!(
+                "session",
+                proxy = %decaf.name,
+                session_id = %session_id.0,
+            ),
+            chunk_gap: None,
+            chunk_size: None,
+            chunks_buffered: 0,
+            tokens_buffered: 0,
+            stats: decaf.stats.clone(),
+            clock: decaf.clock.clone(),
+            retired: false,
+            last_text: None,
+            flushed: false,
+            arrivals: 0,
+        }
     }
 
-    Ok(())
+    /// Whether a `kind` chunk of `text` arriving at `now` repeats the
+    /// previous chunk, remembering it as the previous chunk if not.
+    fn is_repeat(&mut self, kind: ChunkKind, text: Option<&str>, now: Instant) -> bool {
+        let Some(text) = text else {
+            self.last_text = None;
+            return false;
+        };
+        match &mut self.last_text {
+            Some(last)
+                if last.kind == kind
+                    && last.text == text
+                    && now.saturating_duration_since(last.at) <= REPEAT_WINDOW =>
+            {
+                true
+            }
+            Some(last) => {
+                // Reuse the allocation: this runs for every chunk.
+                last.kind = kind;
+                last.text.clear();
+                last.text.push_str(text);
+                last.at = now;
+                false
+            }
+            None => {
+                self.last_text = Some(LastText {
+                    kind,
+                    text: text.to_string(),
+                    at: now,
+                });
+                false
+            }
+        }
+    }
+
+    /// When this session must next be flushed: its oldest un-flushed chunk
+    /// plus its interval and jitter (capped by `max_latency`), or its latest
+    /// chunk plus `quiet_period` if that comes first. `None` while empty.
+    ///
+    /// Thoughts count from their own oldest chunk with `thought_interval`,
+    /// so the earlier of the two windows decides.
+    fn deadline(&self, decaf: &Decaf) -> Option<Instant> {
+        let interval = match decaf.adaptive {
+            Some((min, max)) => adaptive_interval(min, max, self.chunk_gap),
+            None => self.interval.unwrap_or_else(|| decaf.interval.get()),
+        };
+        let window = |interval: Duration| {
+            let interval = match decaf.first_flush_after {
+                Some(first_flush_after) if !self.flushed => interval.min(first_flush_after),
+                _ => interval,
+            };
+            match decaf.max_latency {
+                Some(max_latency) => (interval + self.jitter).min(max_latency),
+                None => interval + self.jitter,
+            }
+        };
+        let chunks = self.buffers.iter().filter_map(|(kind, buffer)| {
+            let interval = match kind {
+                ChunkKind::Thought => decaf.thought_interval.unwrap_or(interval),
+                _ => interval,
+            };
+            Some(buffer.first_chunk_at? + window(interval))
+        });
+        let tool_calls = self
+            .tool_calls
+            .values()
+            .map(|t| t.first_update_at + window(interval));
+        let deadline = chunks.chain(tool_calls).min()?;
+        match (decaf.quiet_period, self.last_chunk_at) {
+            (Some(quiet_period), Some(last)) => Some(deadline.min(last + quiet_period)),
+            _ => Some(deadline),
+        }
+    }
+
+    /// When this entry is dropped for going without updates, with
+    /// `session_ttl`.
+    fn expires_at(&self, decaf: &Decaf) -> Option<Instant> {
+        decaf.session_ttl.map(|ttl| self.updated_at + ttl)
+    }
+
+    /// Arrival time of the oldest un-flushed chunk or tool call update.
+    fn oldest_chunk_at(&self) -> Option<Instant> {
+        let chunks = self.buffers.values().filter_map(|b| b.first_chunk_at);
+        let tool_calls = self.tool_calls.values().map(|t| t.first_update_at);
+        chunks.chain(tool_calls).min()
+    }
+
+    /// Bytes of text held across all kinds.
+    fn buffered_bytes(&self) -> usize {
+        self.buffers.values().map(ChunkBuffer::buffered_bytes).sum()
+    }
+
+    /// Whether any buffer or tool call is waiting to be flushed.
+    fn has_pending(&self) -> bool {
+        !self.tool_calls.is_empty() || self.buffers.values().any(|b| !b.is_empty())
+    }
+
+    fn discard(&mut self) {
+        let _span = self.span.clone().entered();
+        for buffer in self.buffers.values_mut() {
+            tracing::debug!(bytes = buffer.text.len(), "discarding buffered text");
+            buffer.discard();
+        }
+        self.tool_calls.clear();
+    }
+
+    /// Take everything pending for the last time, as the entry leaves its
+    /// map.
+    fn retire(&mut self, reason: FlushReason) -> Result<Vec<SessionNotification>, DecafError> {
+        self.retired = true;
+        self.take_for(reason, Self::take_flush)
+    }
+
+    /// Take a flush with `take`, logging it under `reason`.
+    fn take_for(
+        &mut self,
+        reason: FlushReason,
+        take: impl FnOnce(&mut Self) -> Result<Vec<SessionNotification>, DecafError>,
+    ) -> Result<Vec<SessionNotification>, DecafError> {
+        let flushed = take(self)?;
+        let chunks = self
+            .buffers
+            .values_mut()
+            .map(|b| std::mem::take(&mut b.chunks_flushed))
+            .fold(0, usize::saturating_add);
+        log_flush(reason, chunks, &flushed);
+        Ok(flushed)
+    }
+
+    /// Take everything buffered ahead of a non-chunk update, which also
+    /// ends any run of repeated chunks.
+    fn take_before_update(&mut self) -> Result<Vec<SessionNotification>, DecafError> {
+        self.last_text = None;
+        self.take_flush()
+    }
+
+    /// Take every non-empty buffer and pending tool call as coalesced
+    /// notifications, in the order their oldest un-flushed update arrived.
+    fn take_flush(&mut self) -> Result<Vec<SessionNotification>, DecafError> {
+        self.take_flush_with(ChunkBuffer::take)
+    }
+
+    /// Take every non-empty buffer of a kind other than `kind`, oldest
+    /// first, with any pending tool call that arrived before the newest of
+    /// them. Later tool calls stay pending.
+    fn take_other_kinds(
+        &mut self,
+        kind: ChunkKind,
+    ) -> Result<Vec<SessionNotification>, DecafError> {
+        self.take_for(FlushReason::StreamSwitch, |session| {
+            let mut pending = Vec::new();
+            for (other, buffer) in &mut session.buffers {
+                if *other != kind && !buffer.is_empty() {
+                    tracing::debug!(from = ?other, to = ?kind, "stream switched, flushing");
+                    if let Some(oldest) = buffer.first_chunk_at {
+                        let latency = session.clock.now().saturating_duration_since(oldest);
+                        session.stats.record_flush_latency(latency);
+                    }
+                    pending.push((buffer.first_arrival, buffer.take()?));
+                }
+            }
+            if !pending.is_empty() {
+                session.chunks_buffered = 0;
+                session.tokens_buffered = 0;
+            }
+            if let Some(newest) = pending.iter().filter_map(|(arrival, _)| *arrival).max() {
+                pending.extend(session.take_tool_calls_before(newest));
+            }
+            pending.sort_by_key(|(first_arrival, _)| *first_arrival);
+            Ok(pending
+                .into_iter()
+                .flat_map(|(_, flushed)| flushed)
+                .collect())
+        })
+    }
+
+    /// Like [`take_flush`](Self::take_flush), but with
+    /// `split_on_word_boundary` a trailing partial word stays buffered.
+    ///
+    /// What stays gets a fresh window from now: left with its original
+    /// deadline, which has already passed, it would be flushed straight
+    /// away and split the word anyway.
+    fn take_timed_flush(&mut self, decaf: &Decaf) -> Result<Vec<SessionNotification>, DecafError> {
+        self.take_for(FlushReason::Timer, |session| {
+            if !decaf.split_on_word_boundary {
+                return session.take_flush();
+            }
+            let now = session.clock.now();
+            let mut held = false;
+            let flushed = session.take_flush_with(|buffer| match buffer.partial_word_start() {
+                Some(end) => {
+                    let mut flushed = buffer.take_queued();
+                    flushed.extend(buffer.take_prefix(end)?);
+                    tracing::debug!(held = buffer.text.len(), "holding partial word");
+                    buffer.first_chunk_at = Some(now);
+                    held = true;
+                    Ok(flushed)
+                }
+                None => buffer.take(),
+            })?;
+            if held {
+                session.last_chunk_at = Some(now);
+            }
+            Ok(flushed)
+        })
+    }
+
+    /// Take every non-empty buffer with `take`, and every pending tool call,
+    /// in the order their oldest un-flushed update arrived.
+    fn take_flush_with(
+        &mut self,
+        mut take: impl FnMut(&mut ChunkBuffer) -> Result<Vec<SessionNotification>, DecafError>,
+    ) -> Result<Vec<SessionNotification>, DecafError> {
+        let _span = self.span.clone().entered();
+        self.chunks_buffered = 0;
+        self.tokens_buffered = 0;
+        if let Some(oldest) = self.oldest_chunk_at() {
+            let latency = self.clock.now().saturating_duration_since(oldest);
+            self.stats.record_flush_latency(latency);
+        }
+        let mut pending = Vec::new();
+        for buffer in self.buffers.values_mut().filter(|b| !b.is_empty()) {
+            pending.push((buffer.first_arrival, take(buffer)?));
+        }
+        pending.extend(self.take_tool_calls_before(u64::MAX));
+        pending.sort_by_key(|(first_arrival, _)| *first_arrival);
+        self.flushed |= !pending.is_empty();
+        Ok(pending
+            .into_iter()
+            .flat_map(|(_, flushed)| flushed)
+            .collect())
+    }
+
+    /// Take the pending tool calls whose first update arrived before
+    /// `arrival`, each with its arrival number, so text sent ahead of a
+    /// flush never overtakes an older tool call.
+    fn take_tool_calls_before(
+        &mut self,
+        arrival: u64,
+    ) -> Vec<(Option<u64>, Vec<SessionNotification>)> {
+        let older: Vec<ToolCallId> = self
+            .tool_calls
+            .iter()
+            .filter(|(_, tool_call)| tool_call.first_arrival < arrival)
+            .map(|(id, _)| id.clone())
+            .collect();
+        older
+            .iter()
+            .filter_map(|id| self.tool_calls.remove(id))
+            .map(|tool_call| (Some(tool_call.first_arrival), vec![tool_call.notification]))
+            .collect()
+    }
+
+    /// Number the next chunk or tool call update to arrive.
+    ///
+    /// Wraps rather than saturating: a session would need 2^64 updates
+    /// between two flushes for the order to suffer.
+    fn next_arrival(&mut self) -> u64 {
+        let arrival = self.arrivals;
+        self.arrivals = self.arrivals.wrapping_add(1);
+        arrival
+    }
+
+    /// Merge a `ToolCallUpdate` into the pending update for its tool call,
+    /// or note a `ToolCall`'s kind and send it on after everything pending.
+    /// Returns the notifications to forward immediately.
+    fn buffer_tool_call(
+        &mut self,
+        notification: SessionNotification,
+        decaf: &Decaf,
+    ) -> Result<Vec<SessionNotification>, DecafError> {
+        let _span = self.span.clone().entered();
+        self.last_text = None;
+        if let SessionUpdate::ToolCall(call) = &notification.update {
+            self.tool_kinds.insert(call.tool_call_id.clone(), call.kind);
+            let mut forward =
+                self.take_for(FlushReason::NonChunkUpdate, Self::take_before_update)?;
+            forward.push(notification);
+            return Ok(forward);
+        }
+        let SessionUpdate::ToolCallUpdate(update) = &notification.update else {
+            return Ok(vec![notification]);
+        };
+        if self.passthrough || decaf.paused() {
+            let mut forward = self.take_for(FlushReason::Passthrough, Self::take_flush)?;
+            forward.push(notification);
+            return Ok(forward);
+        }
+
+        let now = self.clock.now();
+        let arrival = self.next_arrival();
+        self.last_chunk_at = Some(now);
+        // The last update of a call no longer needs its kind.
+        let kind = match update.fields.status {
+            Some(ToolCallStatus::Completed | ToolCallStatus::Failed) => {
+                self.tool_kinds.remove(&update.tool_call_id)
+            }
+            _ => self.tool_kinds.get(&update.tool_call_id).copied(),
+        };
+        match self.tool_calls.get_mut(&update.tool_call_id) {
+            Some(pending) => {
+                tracing::debug!(tool_call_id = %update.tool_call_id.0, "merging tool call update");
+                let kind = tool_call_kind(&pending.notification, &notification).or(kind);
+                let mode = decaf.tool_call_mode(kind);
+                merge_tool_call_update(&mut pending.notification, notification, mode);
+            }
+            None => {
+                self.tool_calls.insert(
+                    update.tool_call_id.clone(),
+                    PendingToolCall {
+                        notification,
+                        first_update_at: now,
+                        first_arrival: arrival,
+                    },
+                );
+            }
+        }
+        Ok(Vec::new())
+    }
+}
+
+impl ChunkBuffer {
+    fn new(
+        notification: SessionNotification,
+        arrival: u64,
+        decaf: &Decaf,
+    ) -> Result<Self, DecafError> {
+        let mut buffer = ChunkBuffer::empty(&notification.session_id, decaf);
+        buffer.push(notification, decaf.clock.now(), arrival)?;
+        Ok(buffer)
+    }
+
+    fn empty(session_id: &SessionId, decaf: &Decaf) -> Self {
+        ChunkBuffer {
+            session_id: session_id.clone(),
+            queued: Vec::new(),
+            text: String::with_capacity(decaf.initial_buffer_capacity),
+            text_arrivals: Vec::new(),
+            coalesce_tool_calls: decaf.coalesce_tool_calls,
+            first_chunk_at: None,
+            first_arrival: None,
+            template: None,
+            meta: MergedMeta::default(),
+            inline_annotations: Vec::new(),
+            chunks_since_flush: 0,
+            chunks_flushed: 0,
+            join_with: decaf.join_with.clone(),
+            mark_coalesced: decaf.mark_coalesced,
+            wall_clock: decaf.wall_clock,
+            stamps: None,
+            trim_trailing: decaf.trim_trailing_on_flush,
+            carry: String::new(),
+            transform: decaf.transform.clone(),
+            can_merge: decaf.can_merge.clone(),
+            merge_fn: decaf.merge_fn.clone(),
+            previous: None,
+            flush_reports: decaf.flush_reports.clone(),
+        }
+    }
+
+    /// Buffer a chunk that arrived at `now`, the session's `arrival`th.
+    fn push(
+        &mut self,
+        mut notification: SessionNotification,
+        now: Instant,
+        arrival: u64,
+    ) -> Result<(), DecafError> {
+        self.first_chunk_at.get_or_insert(now);
+        self.first_arrival.get_or_insert(arrival);
+        // Asked before anything is moved out, so `can_merge` sees the chunk
+        // as it arrived.
+        let mergeable = self.mergeable(&notification);
+        // An annotation-only chunk marks a place in the text rather than
+        // starting a block of its own, so it joins whatever run is open.
+        let inline = take_inline_annotations(&mut notification.update)
+            .map(|annotations| self.inline_annotations.push((self.text.len(), annotations)))
+            .is_some();
+
+        let Some(text) = chunk_text_mut(&mut notification.update) else {
+            // A non-text block: seal the text run before it so both keep
+            // their place when the buffer is flushed.
+            if self.holds_text() {
+                let text = self.take_text();
+                let sealed = self.notification_with(text)?;
+                self.queued.extend(sealed);
+            }
+            self.queued.push(notification);
+            return Ok(());
+        };
+        let text = std::mem::take(text);
+        let annotations = text_annotations(&notification.update);
+        let template = self.template.as_ref().map(|t| text_annotations(&t.update));
+        if !mergeable || (!inline && template.is_some_and(|template| template != annotations)) {
+            // Annotations describe a whole text block, so text annotated
+            // differently (or that `can_merge` keeps apart) starts a run of
+            // its own with its own template.
+            tracing::debug!("chunk not mergeable, starting a new text run");
+            if !self.text.is_empty() {
+                let text = self.take_text();
+                let sealed = self.notification_with(text)?;
+                self.queued.extend(sealed);
+            }
+            self.template = None;
+        }
+        if !self.text.is_empty() && !text.is_empty() {
+            self.text.push_str(&self.join_with);
+        }
+        let start = self.text.len();
+        match &self.merge_fn {
+            Some(merge_fn) => {
+                merge_fn(&mut self.text, &text);
+                self.clamp_offsets();
+            }
+            None => self.text.push_str(&text),
+        }
+        if self.coalesce_tool_calls {
+            self.text_arrivals
+                .push((start.min(self.text.len()), arrival));
+        }
+        self.chunks_since_flush = self.chunks_since_flush.saturating_add(1);
+        self.stamps = Some((self.stamps.map_or(now, |(first, _)| first), now));
+        self.meta.absorb(&mut notification);
+        self.template.get_or_insert(notification);
+        Ok(())
+    }
+
+    /// Move offsets into `text` that a [`DecafBuilder::merge_fn`] left past
+    /// its end back to the end. A merge should only append, but one that
+    /// shortened the text must not strand an inline annotation that no
+    /// flush would ever reach.
+    fn clamp_offsets(&mut self) {
+        let len = self.text.len();
+        for (offset, _) in &mut self.text_arrivals {
+            *offset = (*offset).min(len);
+        }
+        for (offset, _) in &mut self.inline_annotations {
+            *offset = (*offset).min(len);
+        }
+    }
+
+    /// Whether a text chunk may join the current run by
+    /// [`DecafBuilder::can_merge`], remembering it for the next one. Without
+    /// a predicate, and for non-text blocks, always true.
+    fn mergeable(&mut self, notification: &SessionNotification) -> bool {
+        let (Some(can_merge), Some(chunk)) = (&self.can_merge, content_chunk(&notification.update))
+        else {
+            return true;
+        };
+        if !matches!(chunk.content, ContentBlock::Text(_)) {
+            return true;
+        }
+        let mergeable = self
+            .previous
+            .as_ref()
+            .is_none_or(|previous| can_merge(previous, chunk));
+        self.previous = Some(chunk.clone());
+        mergeable
+    }
+
+    fn discard(&mut self) {
+        self.queued.clear();
+        self.text.clear();
+        self.text_arrivals.clear();
+        self.carry.clear();
+        self.inline_annotations.clear();
+        self.first_chunk_at = None;
+        self.first_arrival = None;
+        self.meta = MergedMeta::default();
+        self.chunks_since_flush = 0;
+        self.stamps = None;
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queued.is_empty() && !self.holds_text()
+    }
+
+    /// Whether the template, if any, is a text chunk that coalesced text
+    /// can be put into, as it always is unless the buffering has a bug.
+    fn template_is_text(&self) -> bool {
+        self.template
+            .as_ref()
+            .is_none_or(|template| chunk_text(&template.update).is_some())
+    }
+
+    /// Whether a text run is open: text, or annotation-only chunks waiting
+    /// for it.
+    fn holds_text(&self) -> bool {
+        !self.text.is_empty() || !self.inline_annotations.is_empty()
+    }
+
+    /// Bytes of text held, including sealed runs in `queued`.
+    fn buffered_bytes(&self) -> usize {
+        let queued: usize = self
+            .queued
+            .iter()
+            .filter_map(|n| chunk_text(&n.update))
+            .map(str::len)
+            .sum();
+        queued + self.text.len()
+    }
+
+    /// Build the coalesced notifications and reset the buffer.
+    fn take(&mut self) -> Result<Vec<SessionNotification>, DecafError> {
+        let mut flushed = std::mem::take(&mut self.queued);
+        // Cleared first so text that fails to flush leaves no deadline
+        // behind for the flush task to wake on again and again.
+        self.first_chunk_at = None;
+        self.first_arrival = None;
+        if self.holds_text() {
+            let text = self.take_text();
+            flushed.extend(self.notification_with(text)?);
+        }
+        Ok(flushed)
+    }
+
+    /// Copy the buffered text out, keeping the buffer's allocation for the
+    /// chunks that follow.
+    fn take_text(&mut self) -> String {
+        let text = self.text.as_str().to_owned();
+        self.text.clear();
+        self.text_arrivals.clear();
+        text
+    }
+
+    /// Take the queued notifications, which precede anything split off the
+    /// front of `text`.
+    fn take_queued(&mut self) -> Vec<SessionNotification> {
+        if !self.holds_text() {
+            self.first_chunk_at = None;
+            self.first_arrival = None;
+        }
+        std::mem::take(&mut self.queued)
+    }
+
+    /// Emit the first `len` bytes of the buffer, keeping the rest buffered.
+    /// A `len` inside a character is rounded down to its start.
+    ///
+    /// The remainder keeps the original `first_chunk_at`: it may have arrived
+    /// with an older chunk, so its age is never understated. Its
+    /// `first_arrival` becomes that of the chunk its first byte came with,
+    /// so it is ordered after tool calls that arrived before that chunk.
+    fn take_prefix(&mut self, len: usize) -> Result<Option<SessionNotification>, DecafError> {
+        let text = split_at_char_boundary(&self.text, len).0.to_owned();
+        self.text.drain(..text.len());
+        let split = self
+            .text_arrivals
+            .iter()
+            .rposition(|(start, _)| *start <= text.len())
+            .unwrap_or(0);
+        self.text_arrivals.drain(..split);
+        for (start, _) in &mut self.text_arrivals {
+            *start = start.saturating_sub(text.len());
+        }
+        if let Some((_, arrival)) = self.text_arrivals.first() {
+            self.first_arrival = Some(*arrival);
+        }
+        if self.is_empty() {
+            self.first_chunk_at = None;
+            self.first_arrival = None;
+        }
+        let notification = self.notification_with(text)?;
+        if !self.text.is_empty() {
+            // The rest came from (at least) the chunk that was just split.
+            self.chunks_since_flush = 1;
+            self.stamps = self.stamps.map(|(_, last)| (last, last));
+        }
+        Ok(notification)
+    }
+
+    /// Emit everything through the last match of `pattern` that the text
+    /// appended from byte `appended_from` onwards could have completed.
+    ///
+    /// Only the appended text plus [`PATTERN_LOOKBACK`] bytes before it are
+    /// searched, so a long buffer is not rescanned on every chunk.
+    fn take_through_pattern(
+        &mut self,
+        pattern: &Regex,
+        appended_from: usize,
+    ) -> Result<Option<SessionNotification>, DecafError> {
+        let (before, _) =
+            split_at_char_boundary(&self.text, appended_from.saturating_sub(PATTERN_LOOKBACK));
+        let start = before.len();
+        // `find_at` searches from `start` but sees the whole buffer, so
+        // anchors and word boundaries behave as if it were scanned in full.
+        let mut end = 0;
+        let mut at = start;
+        while let Some(found) = pattern.find_at(&self.text, at) {
+            end = found.end();
+            at = match self.text[found.end()..].chars().next() {
+                _ if !found.is_empty() => found.end(),
+                Some(c) => found.end() + c.len_utf8(),
+                None => break,
+            };
+        }
+        match end {
+            0 => Ok(None),
+            end => self.take_prefix(end),
+        }
+    }
+
+    /// Split text off the front with `take`, logging it under `reason`.
+    fn split_for(
+        &mut self,
+        reason: FlushReason,
+        take: impl FnOnce(&mut Self) -> Result<Vec<SessionNotification>, DecafError>,
+    ) -> Result<Vec<SessionNotification>, DecafError> {
+        let flushed = take(self)?;
+        log_flush(reason, std::mem::take(&mut self.chunks_flushed), &flushed);
+        Ok(flushed)
+    }
+
+    /// Emit every complete line in the buffer as a single notification.
+    fn take_lines(&mut self) -> Result<Option<SessionNotification>, DecafError> {
+        match self.text.rfind('\n') {
+            Some(newline) => self.take_prefix(newline + 1),
+            None => Ok(None),
+        }
+    }
+
+    /// Emit every complete paragraph in the buffer as a single notification.
+    fn take_paragraphs(&mut self) -> Result<Option<SessionNotification>, DecafError> {
+        match paragraph_end(&self.text) {
+            Some(end) => self.take_prefix(end),
+            None => Ok(None),
+        }
+    }
+
+    /// Emit each complete sentence in the buffer as its own notification.
+    fn take_sentences(
+        &mut self,
+        terminators: &[char],
+    ) -> Result<Vec<SessionNotification>, DecafError> {
+        let mut flushed = Vec::new();
+        let mut taken = 0;
+        for end in sentence_ends(&self.text, terminators) {
+            flushed.extend(self.take_prefix(end - taken)?);
+            taken = end;
+        }
+        Ok(flushed)
+    }
+
+    /// Emit cap-sized pieces while the buffer holds more than `max_bytes`,
+    /// each ending after its last whitespace if `on_word` and it has any.
+    fn take_over_cap(
+        &mut self,
+        max_bytes: usize,
+        on_word: bool,
+    ) -> Result<Vec<SessionNotification>, DecafError> {
+        let mut flushed = Vec::new();
+        while self.text.len() > max_bytes {
+            let (within, _) = split_at_char_boundary(&self.text, max_bytes);
+            let mut end = within.len();
+            if on_word {
+                // Only whitespace within the cap counts: the cap wins.
+                end = word_end(within).unwrap_or(end);
+            }
+            if end == 0 {
+                // The first character alone is wider than the cap.
+                end = self.text.chars().next().map_or(0, char::len_utf8);
+            }
+            flushed.extend(self.take_prefix(end)?);
+        }
+        Ok(flushed)
+    }
+
+    /// Where a timer flush should stop to keep a trailing partial word
+    /// buffered: just past the last whitespace, or `None` to take everything
+    /// (the text ends on whitespace, or has none at all).
+    fn partial_word_start(&self) -> Option<usize> {
+        if self.text.ends_with(char::is_whitespace) {
+            return None;
+        }
+        word_end(&self.text)
+    }
+
+    /// A copy of the template carrying `text` as its content and the merged
+    /// meta of the chunks since the last flush, which is then reset.
+    ///
+    /// With `trim_trailing`, the text goes out after the whitespace carried
+    /// from the last notification and without its own trailing whitespace,
+    /// which is carried instead. `None` if that leaves nothing to send.
+    fn notification_with(
+        &mut self,
+        text: String,
+    ) -> Result<Option<SessionNotification>, DecafError> {
+        let len = text.len();
+        let (text, carried) = match self.trim_trailing {
+            true => self.carry_trailing_whitespace(text),
+            false => (text, 0),
+        };
+        let annotated = self
+            .inline_annotations
+            .first()
+            .is_some_and(|(offset, _)| *offset <= len);
+        if text.is_empty() && len > 0 && !annotated {
+            tracing::debug!(
+                carried = self.carry.len(),
+                "only whitespace to flush, carrying it"
+            );
+            return Ok(None);
+        }
+        let chunks = std::mem::take(&mut self.chunks_since_flush);
+        let stamps = self.stamps.take();
+        self.chunks_flushed = self.chunks_flushed.saturating_add(chunks);
+        tracing::debug!(bytes = text.len(), chunks, "flushing coalesced chunk");
+
+        // Text is only buffered from a chunk that sets the template, so
+        // either failure here is a bug in the buffering itself.
+        let mut notification =
+            self.template
+                .clone()
+                .ok_or_else(|| DecafError::TemplateMissing {
+                    session_id: self.session_id.clone(),
+                })?;
+        std::mem::take(&mut self.meta).apply(&mut notification);
+
+        // Replace the text content with the coalesced text
+        let tc = chunk_text_mut(&mut notification.update).ok_or_else(|| {
+            DecafError::TemplateNotChunk {
+                session_id: self.session_id.clone(),
+            }
+        })?;
+        let len = text.len();
+        *tc = match &self.transform {
+            Some(transform) if !text.is_empty() => transform(&text),
+            _ => text,
+        };
+        let byte_len = tc.len();
+        self.apply_inline_annotations(&mut notification, len, carried);
+
+        self.flush_reports.record(|| FlushEvent {
+            session_id: self.session_id.clone(),
+            byte_len,
+            chunk_count: chunks,
+        });
+
+        if self.mark_coalesced {
+            let meta = notification.meta.get_or_insert_with(Meta::new);
+            meta.insert(COALESCED_META_KEY.to_string(), true.into());
+            meta.insert(CHUNK_COUNT_META_KEY.to_string(), chunks.into());
+        }
+        if let (Some(wall_clock), Some((first, last))) = (self.wall_clock, stamps) {
+            let meta = notification.meta.get_or_insert_with(Meta::new);
+            meta.insert(
+                FIRST_CHUNK_AT_META_KEY.to_string(),
+                wall_clock.unix_ms(first).into(),
+            );
+            meta.insert(
+                LAST_CHUNK_AT_META_KEY.to_string(),
+                wall_clock.unix_ms(last).into(),
+            );
+        }
+
+        Ok(Some(notification))
+    }
+
+    /// `carry` followed by `text`, with its trailing whitespace moved into
+    /// `carry`, and how many bytes of it led the text.
+    fn carry_trailing_whitespace(&mut self, text: String) -> (String, usize) {
+        let carried = self.carry.len();
+        let mut text = match carried {
+            0 => text,
+            _ => std::mem::take(&mut self.carry) + &text,
+        };
+        let end = text.trim_end().len();
+        self.carry = text.split_off(end);
+        (text, carried)
+    }
+
+    /// Merge the annotation-only chunks that arrived within the first `len`
+    /// bytes of the text into `notification`'s annotations, listing each with
+    /// its offset under [`INLINE_ANNOTATIONS_META_KEY`], moved on by the
+    /// `carried` whitespace leading the text. Later ones stay for the rest
+    /// of the text, their offsets moved back by `len`.
+    fn apply_inline_annotations(
+        &mut self,
+        notification: &mut SessionNotification,
+        len: usize,
+        carried: usize,
+    ) {
+        let split = self
+            .inline_annotations
+            .partition_point(|(offset, _)| *offset <= len);
+        if split == 0 {
+            for (offset, _) in &mut self.inline_annotations {
+                *offset -= len;
+            }
+            return;
+        }
+        let rest = self.inline_annotations.split_off(split);
+        let inline = std::mem::replace(
+            &mut self.inline_annotations,
+            rest.into_iter()
+                .map(|(offset, annotations)| (offset - len, annotations))
+                .collect(),
+        );
+        let Some(ContentChunk {
+            content: ContentBlock::Text(tc),
+            ..
+        }) = content_chunk_mut(&mut notification.update)
+        else {
+            return;
+        };
+        let mut listed = Vec::with_capacity(inline.len());
+        let text_len = tc.text.len();
+        for (offset, annotations) in inline {
+            let offset = (offset + carried).min(text_len);
+            let mut entry = Meta::new();
+            entry.insert("offset".to_string(), offset.into());
+            entry.insert(
+                "annotations".to_string(),
+                serde_json::to_value(&annotations).unwrap_or_default(),
+            );
+            listed.push(entry);
+            merge_annotations(&mut tc.annotations, annotations);
+        }
+        tc.meta
+            .get_or_insert_with(Meta::new)
+            .insert(INLINE_ANNOTATIONS_META_KEY.to_string(), listed.into());
+    }
+}
+
+impl MergedMeta {
+    /// Move `notification`'s meta maps into the merge.
+    fn absorb(&mut self, notification: &mut SessionNotification) {
+        merge_meta(&mut self.notification, notification.meta.take());
+        if let Some(chunk) = content_chunk_mut(&mut notification.update) {
+            merge_meta(&mut self.chunk, chunk.meta.take());
+            if let ContentBlock::Text(tc) = &mut chunk.content {
+                merge_meta(&mut self.text, tc.meta.take());
+            }
+        }
+    }
+
+    fn apply(self, notification: &mut SessionNotification) {
+        notification.meta = self.notification;
+        if let Some(chunk) = content_chunk_mut(&mut notification.update) {
+            chunk.meta = self.chunk;
+            if let ContentBlock::Text(tc) = &mut chunk.content {
+                tc.meta = self.text;
+            }
+        }
+    }
+}
+
+/// The latest kind set by `pending` or the `update` merging into it.
+fn tool_call_kind(pending: &SessionNotification, update: &SessionNotification) -> Option<ToolKind> {
+    let kind = |notification: &SessionNotification| match &notification.update {
+        SessionUpdate::ToolCallUpdate(update) => update.fields.kind,
+        _ => None,
+    };
+    kind(update).or_else(|| kind(pending))
+}
+
+/// Apply the `ToolCallUpdate` notification `from` on top of `into`. Every
+/// field of an update replaces the previous value (`content` too, if
+/// `mode` is [`CoalesceMode::LatestWins`]; otherwise it is appended), so
+/// the later update wins wherever it sets a field.
+fn merge_tool_call_update(
+    into: &mut SessionNotification,
+    from: SessionNotification,
+    mode: CoalesceMode,
+) {
+    fn replace<T>(into: &mut Option<T>, from: Option<T>) {
+        if from.is_some() {
+            *into = from;
+        }
+    }
+
+    merge_meta(&mut into.meta, from.meta);
+    let (SessionUpdate::ToolCallUpdate(into), SessionUpdate::ToolCallUpdate(from)) =
+        (&mut into.update, from.update)
+    else {
+        return;
+    };
+    let (into_fields, fields) = (&mut into.fields, from.fields);
+    replace(&mut into_fields.kind, fields.kind);
+    replace(&mut into_fields.status, fields.status);
+    replace(&mut into_fields.title, fields.title);
+    match (mode, &mut into_fields.content, fields.content) {
+        (CoalesceMode::Concat, Some(content), Some(more)) => content.extend(more),
+        (_, content, more) => replace(content, more),
+    }
+    replace(&mut into_fields.locations, fields.locations);
+    replace(&mut into_fields.raw_input, fields.raw_input);
+    replace(&mut into_fields.raw_output, fields.raw_output);
+    merge_meta(&mut into.meta, from.meta);
+}
+
+fn merge_meta(into: &mut Option<Meta>, from: Option<Meta>) {
+    match (into, from) {
+        (Some(into), Some(from)) => into.extend(from),
+        (into @ None, from) => *into = from,
+        (Some(_), None) => {}
+    }
+}
+
+/// The `ContentChunk` of a buffered chunk kind's update.
+fn content_chunk(update: &SessionUpdate) -> Option<&ContentChunk> {
+    match update {
+        SessionUpdate::AgentMessageChunk(chunk)
+        | SessionUpdate::AgentThoughtChunk(chunk)
+        | SessionUpdate::UserMessageChunk(chunk) => Some(chunk),
+        _ => None,
+    }
+}
+
+fn content_chunk_mut(update: &mut SessionUpdate) -> Option<&mut ContentChunk> {
+    match update {
+        SessionUpdate::AgentMessageChunk(chunk)
+        | SessionUpdate::AgentThoughtChunk(chunk)
+        | SessionUpdate::UserMessageChunk(chunk) => Some(chunk),
+        _ => None,
+    }
+}
+
+/// The text of a buffered chunk kind's update.
+fn chunk_text(update: &SessionUpdate) -> Option<&str> {
+    match content_chunk(update)? {
+        ContentChunk {
+            content: ContentBlock::Text(tc),
+            ..
+        } => Some(&tc.text),
+        _ => None,
+    }
+}
+
+/// Whether `notification` names a session its update can be buffered
+/// under. A malformed one with an empty or blank session id is logged and
+/// forwarded as it is instead, since nothing would ever flush or free an
+/// entry keyed on it.
+fn has_session(notification: &SessionNotification) -> bool {
+    if !notification.session_id.0.trim().is_empty() {
+        return true;
+    }
+    tracing::warn!(
+        session_id = ?&*notification.session_id.0,
+        "forwarding an update without a session id unbuffered"
+    );
+    false
+}
+
+/// Take the annotations off a text chunk with empty text, if it has any.
+fn take_inline_annotations(update: &mut SessionUpdate) -> Option<Annotations> {
+    match content_chunk_mut(update)? {
+        ContentChunk {
+            content: ContentBlock::Text(tc),
+            ..
+        } if tc.text.is_empty() => tc.annotations.take(),
+        _ => None,
+    }
+}
+
+/// Merge `from` into `into`: the audience is the union of both, the higher
+/// priority and the later `last_modified` win, and `meta` merges like chunk
+/// meta.
+fn merge_annotations(into: &mut Option<Annotations>, from: Annotations) {
+    let Some(into) = into else {
+        *into = Some(from);
+        return;
+    };
+    if let Some(audience) = from.audience {
+        let merged = into.audience.get_or_insert_with(Vec::new);
+        for role in audience {
+            if !merged.contains(&role) {
+                merged.push(role);
+            }
+        }
+    }
+    into.priority = match (into.priority, from.priority) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    };
+    if from.last_modified.is_some() {
+        into.last_modified = from.last_modified;
+    }
+    merge_meta(&mut into.meta, from.meta);
+}
+
+/// The annotations on a text chunk's content, if any.
+fn text_annotations(update: &SessionUpdate) -> Option<&Annotations> {
+    match content_chunk(update)? {
+        ContentChunk {
+            content: ContentBlock::Text(tc),
+            ..
+        } => tc.annotations.as_ref(),
+        _ => None,
+    }
+}
+
+fn chunk_text_mut(update: &mut SessionUpdate) -> Option<&mut String> {
+    match content_chunk_mut(update)? {
+        ContentChunk {
+            content: ContentBlock::Text(tc),
+            ..
+        } => Some(&mut tc.text),
+        _ => None,
+    }
+}
+
+impl Shared {
+    fn new(toward: Toward, decaf: &Decaf) -> Self {
+        Shared {
+            toward,
+            sessions: Mutex::new(Sessions::new(decaf.single_session)),
+            deadline_changed: Notify::new(),
+            buffered_bytes: AtomicUsize::new(0),
+            drained: Notify::new(),
+            ended_turns: std::sync::Mutex::default(),
+        }
+    }
+
+    /// The ended-turn record, which is only held briefly and never across
+    /// an await.
+    fn ended_turns(&self) -> std::sync::MutexGuard<'_, EndedTurns> {
+        self.ended_turns.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// How many sessions still hold anything, and the bytes of text among
+    /// them. Entries locked elsewhere at the time are skipped.
+    fn unflushed(&self) -> (usize, usize) {
+        let Ok(sessions) = self.sessions.try_lock() else {
+            return (0, 0);
+        };
+        sessions
+            .values()
+            .filter_map(|entry| entry.try_lock().ok())
+            .filter(|session| session.has_pending())
+            .fold((0, 0), |(sessions, bytes), session| {
+                (sessions + 1, bytes + session.buffered_bytes())
+            })
+    }
+
+    /// The earliest flush deadline across this state's sessions.
+    async fn next_deadline(&self, decaf: &Decaf) -> Option<Instant> {
+        let mut next_deadline = None;
+        for (_, entry) in self.snapshot().await {
+            // Empty entries have no deadline; `Option::min` would let them
+            // hide the others, since `None` sorts first.
+            if let Some(deadline) = entry.lock().await.deadline(decaf) {
+                next_deadline =
+                    Some(next_deadline.map_or(deadline, |next: Instant| next.min(deadline)));
+            }
+        }
+        next_deadline
+    }
+
+    /// When the first of this state's sessions expires under
+    /// `session_ttl`.
+    async fn next_expiry(&self, decaf: &Decaf) -> Option<Instant> {
+        decaf.session_ttl?;
+        let mut next_expiry = None;
+        for (_, entry) in self.snapshot().await {
+            let expires_at = entry.lock().await.expires_at(decaf);
+            next_expiry = next_expiry.into_iter().chain(expires_at).min();
+        }
+        next_expiry
+    }
+
+    /// Lock `entry`, accounting for the text it gains or loses until the
+    /// guard is dropped.
+    async fn lock<'a>(&'a self, entry: &'a SessionEntry, decaf: &'a Decaf) -> SessionGuard<'a> {
+        let session = entry.lock().await;
+        let bytes_before = session.buffered_bytes();
+        SessionGuard {
+            session,
+            state: self,
+            decaf,
+            bytes_before,
+        }
+    }
+
+    /// Wait until this state holds no more than `max_bytes` of text.
+    async fn wait_for_room(&self, max_bytes: usize) {
+        loop {
+            // Registered before the check, so a drain in between still wakes us.
+            let drained = self.drained.notified();
+            if self.buffered_bytes.load(Ordering::Acquire) <= max_bytes {
+                return;
+            }
+            tracing::debug!(max_bytes, "buffers full, waiting for a flush");
+            drained.await;
+        }
+    }
+
+    /// The entry for `session_id`, created on first sight unless
+    /// `max_sessions` are already buffered and the policy says otherwise.
+    ///
+    /// Entries are locked under the map lock here; nothing takes the map
+    /// lock while holding an entry's, so this cannot deadlock.
+    async fn admit(&self, session_id: &SessionId, decaf: &Decaf) -> Result<Admission, DecafError> {
+        let mut sessions = self.sessions.lock().await;
+        let mut evicted = Vec::new();
+        if let Some(entry) = sessions.get(session_id) {
+            return Ok(Admission::Entry {
+                entry: entry.clone(),
+                evicted,
+            });
+        }
+
+        if let Some(max_sessions) = decaf.max_sessions {
+            if sessions.len() >= max_sessions {
+                if decaf.session_limit_policy == SessionLimitPolicy::PassThrough {
+                    tracing::debug!(session_id = %session_id.0, max_sessions, "too many sessions, passing through");
+                    return Ok(Admission::PassThrough);
+                }
+                let mut least_recent = None;
+                for (id, entry) in sessions.iter() {
+                    let updated = entry.lock().await.last_chunk_at;
+                    if least_recent.as_ref().is_none_or(|(_, at)| updated < *at) {
+                        least_recent = Some((id.clone(), updated));
+                    }
+                }
+                if let Some((id, _)) = least_recent {
+                    tracing::debug!(session_id = %id.0, max_sessions, "too many sessions, evicting");
+                    if let Some(entry) = sessions.remove(&id) {
+                        decaf.stats.record_sessions_closed(1);
+                        evicted = self
+                            .lock(&entry, decaf)
+                            .await
+                            .retire(FlushReason::Eviction)?;
+                    }
+                }
+            }
+        }
+
+        let entry: SessionEntry = Arc::new(Mutex::new(BufferedSession::new(session_id, decaf)));
+        sessions.insert(session_id.clone(), entry.clone());
+        decaf.stats.record_sessions_opened(1);
+        if decaf.session_ttl.is_some() {
+            // The flush task must wake for this entry's expiry too.
+            self.deadline_changed.notify_one();
+        }
+        Ok(Admission::Entry { entry, evicted })
+    }
+
+    /// Drop everything buffered for `session_id` without sending it.
+    ///
+    /// The entry is removed, and also cleared under its lock in case the
+    /// flush task took it from a snapshot just before.
+    async fn discard(&self, session_id: &SessionId, decaf: &Decaf) {
+        let entry = self.sessions.lock().await.remove(session_id);
+        if let Some(entry) = entry {
+            decaf.stats.record_sessions_closed(1);
+            let mut session = self.lock(&entry, decaf).await;
+            session.retired = true;
+            session.discard();
+        }
+    }
+
+    async fn existing(&self, session_id: &SessionId) -> Option<SessionEntry> {
+        self.sessions.lock().await.get(session_id).cloned()
+    }
+
+    /// Whether `session_id` holds anything, and its bytes of text, or
+    /// `None` if it has no entry. The map lock is released before the
+    /// entry is locked.
+    async fn pending(&self, session_id: &SessionId) -> Option<(bool, usize)> {
+        let entry = self.existing(session_id).await?;
+        let session = entry.lock().await;
+        Some((session.has_pending(), session.buffered_bytes()))
+    }
+
+    /// All current entries, so callers can visit them without the map lock.
+    async fn snapshot(&self) -> Vec<(SessionId, SessionEntry)> {
+        let sessions = self.sessions.lock().await;
+        sessions
+            .iter()
+            .map(|(id, entry)| (id.clone(), entry.clone()))
+            .collect()
+    }
+}
+
+impl Drop for Shared {
+    /// Text still buffered here can no longer be sent (the connection is
+    /// gone once the last handler drops its state), but at least log it:
+    /// a clean shutdown flushes everything, so this means a panic, an abort
+    /// or the transport closing mid-turn.
+    fn drop(&mut self) {
+        let (sessions, bytes) = self.unflushed();
+        if sessions > 0 {
+            tracing::warn!(
+                toward = ?self.toward,
+                sessions,
+                bytes,
+                "dropping unflushed text"
+            );
+        }
+    }
+}
+
+impl std::ops::Deref for SessionGuard<'_> {
+    type Target = BufferedSession;
+
+    fn deref(&self) -> &BufferedSession {
+        &self.session
+    }
+}
+
+impl std::ops::DerefMut for SessionGuard<'_> {
+    fn deref_mut(&mut self) -> &mut BufferedSession {
+        &mut self.session
+    }
+}
+
+impl Drop for SessionGuard<'_> {
+    fn drop(&mut self) {
+        let bytes_after = self.session.buffered_bytes();
+        let total = &self.state.buffered_bytes;
+        if bytes_after > self.bytes_before {
+            let grown = bytes_after - self.bytes_before;
+            let now = total.fetch_add(grown, Ordering::AcqRel) + grown;
+            self.decaf.stats.record_pending(now);
+        } else if bytes_after < self.bytes_before {
+            total.fetch_sub(self.bytes_before - bytes_after, Ordering::AcqRel);
+            self.state.drained.notify_waiters();
+        }
+    }
+}
+
+/// Buffer a text chunk. Returns the notifications to forward immediately.
+fn buffer_chunk(
+    session: &mut BufferedSession,
+    kind: ChunkKind,
+    notification: SessionNotification,
+    decaf: &Decaf,
+) -> Result<Vec<SessionNotification>, DecafError> {
+    let _span = session.span.clone().entered();
+    if session.passthrough || decaf.paused() {
+        // Anything buffered before the session passed through goes first.
+        let mut forward =
+            session.take_for(FlushReason::Passthrough, BufferedSession::take_flush)?;
+        forward.push(notification);
+        return Ok(forward);
+    }
+
+    if session
+        .buffers
+        .get(&kind)
+        .is_some_and(|buffer| !buffer.template_is_text())
+    {
+        // Coalescing into this buffer could only fail; its text is lost
+        // either way, but the chunk and the ones after it need not be.
+        let lost = session
+            .buffers
+            .remove(&kind)
+            .map_or(0, |b| b.buffered_bytes());
+        tracing::error!(
+            ?kind,
+            lost,
+            "buffer template is not a text chunk, forwarding chunk untouched"
+        );
+        return Ok(vec![notification]);
+    }
+
+    if let Some(text) = chunk_text(&notification.update) {
+        decaf.stats.record_chunk(text.len());
+    }
+    let now = decaf.clock.now();
+    if decaf.dedupe_repeats && session.is_repeat(kind, chunk_text(&notification.update), now) {
+        tracing::debug!(?kind, "dropping repeated chunk");
+        return Ok(Vec::new());
+    }
+    if let Some(last) = session.last_chunk_at {
+        let gap = now - last;
+        session.chunk_gap = Some(match session.chunk_gap {
+            Some(smoothed) => (smoothed * 3 + gap) / 4,
+            None => gap,
+        });
+    }
+    session.last_chunk_at = Some(now);
+    if let Some(text) = chunk_text(&notification.update) {
+        session.chunk_size = Some(match session.chunk_size {
+            Some(smoothed) => (smoothed * 3 + text.len()) / 4,
+            None => text.len(),
+        });
+    }
+
+    if let (Some(threshold), Some(text)) =
+        (decaf.passthrough_large, chunk_text(&notification.update))
+    {
+        if text.len() > threshold {
+            tracing::debug!(?kind, bytes = text.len(), "forwarding large chunk");
+            let mut forward =
+                session.take_for(FlushReason::LargeChunk, BufferedSession::take_flush)?;
+            forward.push(notification);
+            return Ok(forward);
+        }
+    }
+    if let (Some(threshold), Some(average)) = (decaf.auto_passthrough_above, session.chunk_size) {
+        if average > threshold {
+            // Chunks this size already come few and far between, so waiting
+            // to coalesce them would only add latency.
+            tracing::debug!(?kind, average, "forwarding chunk of a large-chunk stream");
+            let mut forward =
+                session.take_for(FlushReason::AutoPassthrough, BufferedSession::take_flush)?;
+            forward.push(notification);
+            return Ok(forward);
+        }
+    }
+
+    // Only the new chunk is tokenized; the session keeps the running sum.
+    let tokens = match (&decaf.flush_every_tokens, chunk_text(&notification.update)) {
+        (Some((_, count_tokens)), Some(text)) => count_tokens(text),
+        _ => 0,
+    };
+
+    // Switching streams sends the other kinds' text first, so messages and
+    // thoughts reach the client in the order the agent produced them.
+    let mut switched = session.take_other_kinds(kind)?;
+
+    let buffered_before = session.buffers.get(&kind).map_or(0, |b| b.text.len());
+    let arrival = session.next_arrival();
+    let buffer = match session.buffers.get_mut(&kind) {
+        Some(buffer) => {
+            buffer.push(notification, now, arrival)?;
+            buffer
+        }
+        None if decaf.leading_edge => {
+            // Leading edge: forward this chunk now and keep an empty
+            // buffer for the ones that follow.
+            let buffer = ChunkBuffer::empty(&notification.session_id, decaf);
+            session.buffers.insert(kind, buffer);
+            tracing::debug!(?kind, "forwarding leading-edge chunk");
+            switched.push(notification);
+            return Ok(switched);
+        }
+        None => {
+            let buffer = ChunkBuffer::new(notification, arrival, decaf)?;
+            session.buffers.entry(kind).or_insert(buffer)
+        }
+    };
+
+    tracing::debug!(?kind, buffered = buffer.text.len(), "buffered chunk");
+    let oldest = buffer.first_chunk_at;
+    let first_arrival = buffer.first_arrival;
+
+    let mut flushed = Vec::new();
+    if let Some(pattern) = &decaf.flush_on_pattern {
+        // A non-text chunk seals the text, so what was buffered before may
+        // be gone.
+        let appended_from = buffered_before.min(buffer.text.len());
+        flushed.extend(buffer.split_for(FlushReason::Pattern, |buffer| {
+            Ok(buffer
+                .take_through_pattern(pattern, appended_from)?
+                .into_iter()
+                .collect())
+        })?);
+    }
+    if decaf.flush_on_paragraph {
+        flushed.extend(buffer.split_for(FlushReason::Paragraph, |buffer| {
+            Ok(buffer.take_paragraphs()?.into_iter().collect())
+        })?);
+    }
+    if decaf.flush_on_newline {
+        flushed.extend(buffer.split_for(FlushReason::Newline, |buffer| {
+            Ok(buffer.take_lines()?.into_iter().collect())
+        })?);
+    }
+    if decaf.flush_on_sentence {
+        flushed.extend(buffer.split_for(FlushReason::Sentence, |buffer| {
+            buffer.take_sentences(&decaf.sentence_terminators)
+        })?);
+    }
+    if let Some(max_bytes) = decaf.max_buffer_bytes {
+        flushed.extend(buffer.split_for(FlushReason::ByteCap, |buffer| {
+            buffer.take_over_cap(max_bytes, decaf.split_on_word_boundary)
+        })?);
+    }
+    if !flushed.is_empty() {
+        if let Some(oldest) = oldest {
+            decaf
+                .stats
+                .record_flush_latency(now.saturating_duration_since(oldest));
+        }
+        session.flushed = true;
+        // Text split off the front still follows any queued blocks, and
+        // any tool call that arrived before them.
+        let mut queued = buffer.take_queued();
+        queued.append(&mut flushed);
+        flushed = session
+            .take_tool_calls_before(first_arrival.unwrap_or(arrival))
+            .into_iter()
+            .flat_map(|(_, tool_call)| tool_call)
+            .chain(queued)
+            .collect();
+    }
+    switched.append(&mut flushed);
+
+    if let Some(every) = decaf.flush_every_chunks {
+        session.chunks_buffered = session.chunks_buffered.saturating_add(1);
+        if session.chunks_buffered >= every {
+            tracing::debug!(chunks = every, "chunk count reached, flushing");
+            switched
+                .extend(session.take_for(FlushReason::ChunkCount, BufferedSession::take_flush)?);
+        }
+    }
+    if let Some((every, _)) = decaf.flush_every_tokens {
+        session.tokens_buffered = session.tokens_buffered.saturating_add(tokens);
+        if session.tokens_buffered >= every {
+            tracing::debug!(
+                tokens = session.tokens_buffered,
+                "token count reached, flushing"
+            );
+            switched
+                .extend(session.take_for(FlushReason::TokenCount, BufferedSession::take_flush)?);
+        }
+    }
+    Ok(switched)
+}
+
+/// How many bytes of already-buffered text [`ChunkBuffer::take_through_pattern`]
+/// searches before the newly appended text. Matches must fit in this window
+/// plus the chunk that completes them.
+const PATTERN_LOOKBACK: usize = 256;
+
+/// The coalescing window for a session whose chunks arrive `gap` apart:
+/// from `max` for back-to-back chunks down to `min` once the gap reaches
+/// `max`.
+fn adaptive_interval(min: Duration, max: Duration, gap: Option<Duration>) -> Duration {
+    let Some(gap) = gap else {
+        return min;
+    };
+    let slowness = (gap.as_secs_f64() / max.as_secs_f64()).min(1.0);
+    max - (max - min).mul_f64(slowness)
+}
+
+/// A uniformly random duration in `[0, window)`, or zero for a zero window.
+///
+/// Each `RandomState` is keyed afresh from the OS's randomness (per thread,
+/// then stepped), which is plenty for staggering deadlines and saves a
+/// dependency.
+fn random_offset(window: Duration) -> Duration {
+    if window.is_zero() {
+        return Duration::ZERO;
+    }
+    let bits = RandomState::new().build_hasher().finish() >> 11;
+    window.mul_f64(bits as f64 / (1u64 << 53) as f64)
+}
+
+/// Split `text` at byte `index`, rounded down to the nearest char boundary,
+/// so no partial flush can cut a multi-byte character in two. Every split
+/// of buffered text goes through here.
+fn split_at_char_boundary(text: &str, index: usize) -> (&str, &str) {
+    let mut index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    text.split_at(index)
+}
+
+/// `text` cut into consecutive pieces of at most `max_bytes`, each ending
+/// after its last whitespace if it has any, else on a char boundary. A
+/// character wider than `max_bytes` makes a piece of its own.
+fn frame_pieces(mut text: &str, max_bytes: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    while text.len() > max_bytes {
+        let (within, _) = split_at_char_boundary(text, max_bytes);
+        let mut end = word_end(within).unwrap_or(within.len());
+        if end == 0 {
+            end = text.chars().next().map_or(0, char::len_utf8);
+        }
+        let (piece, rest) = text.split_at(end);
+        pieces.push(piece);
+        text = rest;
+    }
+    if !text.is_empty() {
+        pieces.push(text);
+    }
+    pieces
+}
+
+/// The byte offset just past the last whitespace character in `text`.
+fn word_end(text: &str) -> Option<usize> {
+    let (i, c) = text.char_indices().rfind(|(_, c)| c.is_whitespace())?;
+    Some(i + c.len_utf8())
+}
+
+/// Just past the last blank line in `text`: a `\n` ending a line of only
+/// whitespace that itself follows a `\n`.
+fn paragraph_end(text: &str) -> Option<usize> {
+    let mut end = None;
+    let mut blank_line = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            '\n' if blank_line => end = Some(i + 1),
+            '\n' => blank_line = true,
+            c if c.is_whitespace() => {}
+            _ => blank_line = false,
+        }
+    }
+    end
+}
+
+/// Byte offsets just past each sentence end in `text`, with more text after
+/// it: an ASCII terminator followed by one whitespace character, or any
+/// other terminator followed by whitespace (taken along) or by text that
+/// doesn't start with another terminator.
+///
+/// Scripts with wide terminators such as `。` put no space after them, and
+/// don't use them inside words or numbers the way `.` is, so they need no
+/// whitespace to confirm the boundary.
+fn sentence_ends(text: &str, terminators: &[char]) -> Vec<usize> {
+    let mut ends = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        if !terminators.contains(&c) {
+            continue;
+        }
+        if let Some(&(i, next)) = chars.peek() {
+            let end = if next.is_whitespace() {
+                i + next.len_utf8()
+            } else if c.is_ascii() || terminators.contains(&next) {
+                continue;
+            } else {
+                i
+            };
+            if end < text.len() {
+                ends.push(end);
+            }
+        }
+    }
+    ends
+}
+
+/// Buffer a chunk, forwarding anything it causes to be
+/// flushed immediately and waking the flush task if a new deadline appeared.
+async fn handle_chunk(
+    state: &State,
+    decaf: &Decaf,
+    kind: ChunkKind,
+    notification: SessionNotification,
+    cx: &sacp::ConnectionTo<Conductor>,
+) -> Result<(), sacp::Error> {
+    if let (Some(max_bytes), OverflowPolicy::Block) = (decaf.max_total_bytes, decaf.overflow_policy)
+    {
+        state.wait_for_room(max_bytes).await;
+    }
+
+    let forward = buffer_into(state, decaf, notification, |session, notification| {
+        buffer_chunk(session, kind, notification, decaf)
+    })
+    .await?;
+    send_text(state, decaf, cx, forward)?;
+
+    if let (Some(max_bytes), OverflowPolicy::Flush) = (decaf.max_total_bytes, decaf.overflow_policy)
+    {
+        if state.buffered_bytes.load(Ordering::Acquire) > max_bytes {
+            tracing::debug!(max_bytes, "buffers full, flushing every session");
+            flush_buffered(state, decaf, FlushReason::MemoryLimit, &|state, flushed| {
+                send_text(state, decaf, cx, flushed)
+            })
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Forward a `PromptRequest` to the agent, ending that session's turn when
+/// the response comes back.
+///
+/// Only the prompting session is flushed: another session's turn is still
+/// running and keeps its buffer. The response is correlated here, where the
+/// request and its `session_id` are at hand; the agent handler only sees the
+/// response. The callback runs before the next message from the agent is
+/// dispatched, so every chunk sent ahead of the response is buffered and no
+/// chunk can slip in while it runs.
+///
+/// With `flush_before_response(false)` the text goes out after the
+/// response. Responding through the agent handler would only hand it to
+/// sacp's forwarding task, which sends it after anything flushed alongside,
+/// so the response is sent from here, directly: it then shares the
+/// connection's one outgoing queue with the text sent after it.
+async fn forward_prompt(
+    dispatch: Dispatch,
+    state: &State,
+    to_agent: &State,
+    decaf: &Arc<Decaf>,
+    cx: &sacp::ConnectionTo<Conductor>,
+) -> Result<Handled<Dispatch>, sacp::Error> {
+    MatchDispatch::new(dispatch)
+        .if_request(async |prompt: PromptRequest, responder| {
+            // As for any other client message, text typed ahead goes first.
+            let flushed = flush_all(to_agent, decaf, FlushReason::ClientMessage).await?;
+            send_text(to_agent, decaf, cx, flushed)?;
+
+            let mut session_id = prompt.session_id.clone();
+            decaf.map_session(&mut session_id);
+            state.ended_turns().start(&session_id);
+            let sent = cx.send_request_to(Agent, prompt);
+            let (received, forwarded) = (responder.id(), sent.id());
+            state
+                .ended_turns()
+                .track(&session_id, &received, &forwarded);
+            let (state, decaf, cx2) = (state.clone(), decaf.clone(), cx.clone());
+            sent.on_receiving_result(async move |result| {
+                state.ended_turns().untrack(&received);
+                let stop_reason = result.as_ref().ok().map(|response| response.stop_reason);
+                end_turn(
+                    &state,
+                    &decaf,
+                    &session_id,
+                    stop_reason,
+                    |flushed| send_text(&state, &decaf, &cx2, flushed),
+                    || responder.respond_with_result(result),
+                )
+                .await
+            })
+        })
+        .await
+        .done()
+}
+
+/// Buffer `notification` into its session's entry with `buffer`, returning
+/// what to forward right away: text evicted to make room for the session,
+/// then whatever `buffer` returns, or just the notification if the session
+/// passes through. Wakes the flush task if a new deadline appeared.
+///
+/// An entry removed from the map between [`Shared::admit`] and its lock (by
+/// a turn end, an eviction, a discard or [`flush_all`]) has been flushed for
+/// the last time and is marked `retired`; text buffered there would never
+/// be sent, so the session is admitted again into a fresh entry.
+///
+/// A session whose turn has ended and that hasn't been prompted since gets
+/// no entry at all: the agent is out of spec sending anything then, and
+/// buffering it would hold it back until some later flush (or keep an entry
+/// alive that no turn end will free), so it is forwarded as it is.
+async fn buffer_into(
+    state: &Shared,
+    decaf: &Decaf,
+    notification: SessionNotification,
+    buffer: impl FnOnce(
+        &mut BufferedSession,
+        SessionNotification,
+    ) -> Result<Vec<SessionNotification>, DecafError>,
+) -> Result<Vec<SessionNotification>, DecafError> {
+    if state.ended_turns().has_ended(&notification.session_id) {
+        tracing::debug!(session_id = %notification.session_id.0, "forwarding update after turn end");
+        return Ok(vec![notification]);
+    }
+    let mut forward = Vec::new();
+    loop {
+        let entry = match state.admit(&notification.session_id, decaf).await? {
+            Admission::Entry { entry, evicted } => {
+                forward.extend(evicted);
+                entry
+            }
+            Admission::PassThrough => {
+                forward.push(notification);
+                return Ok(forward);
+            }
+        };
+        let mut session = state.lock(&entry, decaf).await;
+        if session.retired {
+            tracing::debug!("session entry retired while admitting, retrying");
+            continue;
+        }
+        session.updated_at = decaf.clock.now();
+        let before = session.deadline(decaf);
+        forward.extend(buffer(&mut session, notification)?);
+        let after = session.deadline(decaf);
+        if after.is_some() && after != before {
+            state.deadline_changed.notify_one();
+        }
+        return Ok(forward);
+    }
+}
+
+/// End `session_id`'s turn: deliver the prompt's response with `respond`,
+/// and the turn's remaining text with `send`, before or after it as
+/// `flush_before_response` and, for a turn that didn't `stop_reason` with
+/// `EndTurn` (or ended in an error), `flush_on_stop_reason` say.
+///
+/// With `coalesce_across_prompts`, a response while the session has more
+/// prompts outstanding is only delivered: the turn goes on for those, and
+/// its text keeps coalescing on the timer until the last of them is
+/// answered.
+///
+/// Failing to take or send the text is logged rather than returned: the
+/// turn is over either way, and a response lost to a flush error would
+/// leave the client waiting on a prompt that already finished. Only an
+/// error delivering the response itself is returned.
+async fn end_turn(
+    state: &State,
+    decaf: &Decaf,
+    session_id: &SessionId,
+    stop_reason: Option<StopReason>,
+    send: impl Fn(Vec<SessionNotification>) -> Result<(), sacp::Error>,
+    respond: impl FnOnce() -> Result<(), sacp::Error>,
+) -> Result<(), sacp::Error> {
+    let outstanding = state.ended_turns().answered(session_id);
+    if decaf.coalesce_across_prompts && outstanding > 0 {
+        tracing::debug!(session_id = %session_id.0, outstanding, "more prompts outstanding, not flushing");
+        return respond();
+    }
+    state.ended_turns().end(session_id);
+    let flushed = finish_turn(state, decaf, session_id)
+        .await
+        .unwrap_or_else(|error| {
+            tracing::error!(session_id = %session_id.0, %error, "failed to flush at turn end");
+            Vec::new()
+        });
+    let send = |flushed| {
+        if let Err(error) = send(flushed) {
+            tracing::error!(session_id = %session_id.0, %error, "failed to send text at turn end");
+        }
+    };
+    let stopped_early = stop_reason != Some(StopReason::EndTurn);
+    if decaf.flush_before_response || (decaf.flush_on_stop_reason && stopped_early) {
+        send(flushed);
+        return respond();
+    }
+    respond()?;
+    send(flushed);
+    Ok(())
+}
+
+/// Merge a `ToolCallUpdate` into its session, waking the flush task if a new
+/// deadline appeared.
+async fn handle_tool_call_update(
+    state: &State,
+    decaf: &Decaf,
+    notification: SessionNotification,
+    cx: &sacp::ConnectionTo<Conductor>,
+) -> Result<(), sacp::Error> {
+    let forward = buffer_into(state, decaf, notification, |session, notification| {
+        session.buffer_tool_call(notification, decaf)
+    })
+    .await?;
+    send_text(state, decaf, cx, forward)
+}
+
+/// Send coalesced (or passed-through) text notifications on to `state`'s
+/// peer. Each send only queues the notification for sacp's outgoing actor,
+/// so a slow peer never holds this up, however many sessions flush at once.
+fn send_text(
+    state: &Shared,
+    decaf: &Decaf,
+    cx: &sacp::ConnectionTo<Conductor>,
+    notifications: Vec<SessionNotification>,
+) -> Result<(), sacp::Error> {
+    let notifications = decaf.frame(notifications);
+    if let (Toward::Client, Some(history)) = (state.toward, &decaf.history) {
+        history.record(&notifications);
+    }
+    for notification in notifications {
+        match state.toward {
+            Toward::Client => cx.send_notification_to(Client, notification)?,
+            Toward::Agent => cx.send_notification_to(Agent, notification)?,
+        }
+        decaf.stats.record_forwarded(1);
+        if let (Toward::Client, Some(budget)) = (state.toward, &decaf.emit_budget) {
+            budget.spend(decaf.clock.now());
+        }
+    }
+    report_flushes(decaf);
+    Ok(())
+}
+
+/// Copy `notification` into the tap without waiting: if the tap is full
+/// the copy is dropped and counted, and once it is closed nothing more is
+/// recorded. Forwarding never depends on the tap.
+fn record(
+    tap: &mpsc::Sender<SessionNotification>,
+    stats: &DecafStats,
+    notification: &SessionNotification,
+) {
+    match tap.try_send(notification.clone()) {
+        Ok(()) => {}
+        Err(mpsc::error::TrySendError::Full(_)) => {
+            tracing::debug!(session_id = %notification.session_id.0, "tap full, dropping a copy");
+            stats.record_tap_dropped();
+        }
+        Err(mpsc::error::TrySendError::Closed(_)) => {}
+    }
+}
+
+/// Hand every queued flush to `on_flush` and publish it to subscribers.
+/// Called with no session locked, so a slow callback only delays this task.
+fn report_flushes(decaf: &Decaf) {
+    for event in decaf.flush_reports.take() {
+        if let Some(on_flush) = &decaf.on_flush {
+            on_flush(&event.session_id, event.chunk_count, event.byte_len);
+        }
+        decaf.flush_reports.publish(event);
+    }
+}
+
+/// End `session_id`'s turn: take its pending flush and free its entry.
+///
+/// A `PromptResponse` is the only session-end signal we see, so the buffers
+/// are dropped once the turn's text is out; a later chunk simply starts a
+/// fresh entry. Other sessions are left alone, mid-turn as they may be.
+/// This runs in the response callback, before the agent's next message is
+/// dispatched, so no chunk can slip in between the flush and the removal.
+async fn finish_turn(
+    state: &State,
+    decaf: &Decaf,
+    session_id: &SessionId,
+) -> Result<Vec<SessionNotification>, DecafError> {
+    let entry = state.sessions.lock().await.remove(session_id);
+    match entry {
+        Some(entry) => {
+            decaf.stats.record_sessions_closed(1);
+            state
+                .lock(&entry, decaf)
+                .await
+                .retire(FlushReason::PromptResponse)
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Take every pending flush, removing all session entries.
+///
+/// The map is drained in one step, then each entry is locked and retired.
+/// A chunk buffered into an entry before that lock is part of this flush; a
+/// chunk whose handler locks the entry afterwards finds it retired and goes
+/// into a fresh entry, flushed later on its own deadline or trigger. Either
+/// way each chunk is sent exactly once.
+///
+/// The entries are locked concurrently, so a session whose lock is held
+/// (a handler buffering into it, say) doesn't hold up the others. The
+/// flushes still come back in priority order, each session's in its own
+/// order.
+async fn flush_all(
+    state: &State,
+    decaf: &Decaf,
+    reason: FlushReason,
+) -> Result<Vec<SessionNotification>, DecafError> {
+    let mut entries: Vec<_> = state.sessions.lock().await.drain().collect();
+    decaf.stats.record_sessions_closed(entries.len());
+    decaf.by_priority(&mut entries);
+    let retired = futures::future::join_all(
+        entries
+            .iter()
+            .map(|(_, entry)| async { state.lock(entry, decaf).await.retire(reason) }),
+    )
+    .await;
+    let mut flushed = Vec::new();
+    for session in retired {
+        flushed.extend(session?);
+    }
+    Ok(flushed)
+}
+
+/// Flush every session one final time before `run` returns.
+///
+/// sacp drops the connection's background actors as soon as the main
+/// future completes, which would discard notifications still queued for
+/// the transport. A [`MARK_METHOD`] notification sent after them reaches
+/// the [`Outgoing`] transport only once they have, and `sent` fires then.
+async fn shutdown(
+    states: &[&State],
+    decaf: &Decaf,
+    cx: &sacp::ConnectionTo<Conductor>,
+    sent: oneshot::Receiver<()>,
+) -> Result<(), sacp::Error> {
+    for state in states {
+        let flushed = flush_all(state, decaf, FlushReason::Shutdown).await?;
+        send_text(state, decaf, cx, flushed)?;
+    }
+
+    cx.send_notification_to(
+        Client,
+        UntypedMessage::new(MARK_METHOD, serde_json::json!({}))?,
+    )?;
+    // An error only means the transport closed first: nothing left to wait for.
+    let _ = sent.await;
+    Ok(())
+}
+
+/// Flush a single session's buffers, sending coalesced chunks on.
+async fn flush_session(
+    state: &State,
+    decaf: &Decaf,
+    session_id: &SessionId,
+    reason: FlushReason,
+    cx: &sacp::ConnectionTo<Conductor>,
+) -> Result<(), sacp::Error> {
+    let flushed = match state.existing(session_id).await {
+        Some(entry) => state
+            .lock(&entry, decaf)
+            .await
+            .take_for(reason, BufferedSession::take_before_update)?,
+        None => Vec::new(),
+    };
+
+    send_text(state, decaf, cx, flushed)
+}
+
+/// The method of ACP's request-level cancel, which sacp has no type for.
+const CANCEL_REQUEST_METHOD: &str = "$/cancel_request";
+
+/// The field of a [`CANCEL_REQUEST_METHOD`] notification naming the request.
+const CANCEL_REQUEST_ID: &str = "requestId";
+
+/// The request id `message` cancels, if it is a `$/cancel_request`.
+fn cancelled_request(message: &UntypedMessage) -> Option<&serde_json::Value> {
+    if message.method() != CANCEL_REQUEST_METHOD {
+        return None;
+    }
+    message.params().get(CANCEL_REQUEST_ID)
+}
+
+/// The next message on `receiver`, or never if there is none.
+async fn recv<T>(receiver: &mut Option<mpsc::Receiver<T>>) -> Option<T> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The flush task: sleep until the earliest session deadline in either
+/// direction, re-evaluating whenever a new window opens, and serve flush
+/// signals and drains in between. A flush signal replaces the deadlines
+/// entirely; `session_ttl` expiries are kept either way.
+///
+/// A failed flush is handed to [`Decaf::flush_failed`] and the loop goes
+/// on, so one error can't silently stop all later coalescing.
+async fn flush_task(
+    state: &State,
+    to_agent: &State,
+    decaf: &Decaf,
+    mut flush_signal: Option<mpsc::Receiver<()>>,
+    mut drains: Option<mpsc::Receiver<DrainRequest>>,
+    send: impl Fn(&Shared, Vec<SessionNotification>) -> Result<(), sacp::Error>,
+) {
+    let timed = flush_signal.is_none();
+    loop {
+        let next_deadline = match timed {
+            true => state
+                .next_deadline(decaf)
+                .await
+                .map(|deadline| decaf.emit_deadline(deadline))
+                .into_iter()
+                .chain(to_agent.next_deadline(decaf).await)
+                .min(),
+            false => None,
+        };
+        let next_deadline = next_deadline
+            .into_iter()
+            .chain(state.next_expiry(decaf).await)
+            .chain(to_agent.next_expiry(decaf).await)
+            .min();
+        let deadline = async {
+            match next_deadline {
+                Some(deadline) => decaf.clock.sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = deadline => {
+                let now = decaf.clock.now();
+                decaf.flush_failed(flush_due(state, decaf, now, &send).await);
+                decaf.flush_failed(flush_due(to_agent, decaf, now, &send).await);
+                decaf.flush_failed(evict_idle(state, decaf, now, &send).await);
+                decaf.flush_failed(evict_idle(to_agent, decaf, now, &send).await);
+            }
+            _ = state.deadline_changed.notified() => {}
+            _ = to_agent.deadline_changed.notified() => {}
+            _ = decaf.interval.changed.notified() => {}
+            Some(()) = recv(&mut flush_signal) => {
+                decaf.flush_failed(flush_buffered(state, decaf, FlushReason::FlushSignal, &send).await);
+                decaf.flush_failed(flush_buffered(to_agent, decaf, FlushReason::FlushSignal, &send).await);
+            }
+            Some(done) = recv(&mut drains) => {
+                decaf.flush_failed(flush_buffered(state, decaf, FlushReason::Drain, &send).await);
+                decaf.flush_failed(flush_buffered(to_agent, decaf, FlushReason::Drain, &send).await);
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// Flush every session whose deadline is at or before `now`.
+async fn flush_due(
+    state: &State,
+    decaf: &Decaf,
+    now: Instant,
+    send: &impl Fn(&Shared, Vec<SessionNotification>) -> Result<(), sacp::Error>,
+) -> Result<(), sacp::Error> {
+    if let (Toward::Client, Some(budget)) = (state.toward, &decaf.emit_budget) {
+        return flush_due_within(state, decaf, budget, now, send).await;
+    }
+    flush_where(
+        state,
+        decaf,
+        send,
+        |deadline| deadline <= now,
+        |session| session.take_timed_flush(decaf),
+    )
+    .await
+}
+
+/// Flush and remove every session that has expired under `session_ttl`
+/// by `now`.
+///
+/// Expired entries are picked and removed under the map lock (locking each
+/// one under it, as [`Shared::admit`] does), then flushed one at a time
+/// with the map released. An update that got hold of an entry before it
+/// was removed is flushed with it.
+async fn evict_idle(
+    state: &State,
+    decaf: &Decaf,
+    now: Instant,
+    send: &impl Fn(&Shared, Vec<SessionNotification>) -> Result<(), sacp::Error>,
+) -> Result<(), sacp::Error> {
+    if decaf.session_ttl.is_none() {
+        return Ok(());
+    }
+    let mut idle = Vec::new();
+    {
+        let mut sessions = state.sessions.lock().await;
+        let mut expired = Vec::new();
+        for (id, entry) in sessions.iter() {
+            if entry
+                .lock()
+                .await
+                .expires_at(decaf)
+                .is_some_and(|expires_at| expires_at <= now)
+            {
+                expired.push(id.clone());
+            }
+        }
+        for id in expired {
+            tracing::debug!(session_id = %id.0, "session idle past its ttl, evicting");
+            idle.extend(sessions.remove(&id));
+        }
+    }
+    if !idle.is_empty() {
+        decaf.stats.record_sessions_closed(idle.len());
+    }
+    for entry in idle {
+        let flushed = state
+            .lock(&entry, decaf)
+            .await
+            .retire(FlushReason::SessionTtl)?;
+        send(state, flushed)?;
+    }
+    Ok(())
+}
+
+/// Like [`flush_due`], but only while `budget` has tokens: oldest deadline
+/// first, so a busy session can't keep the others waiting, and the rest
+/// stay buffered, taking in new chunks, until the next token.
+async fn flush_due_within(
+    state: &State,
+    decaf: &Decaf,
+    budget: &EmitBudget,
+    now: Instant,
+    send: &impl Fn(&Shared, Vec<SessionNotification>) -> Result<(), sacp::Error>,
+) -> Result<(), sacp::Error> {
+    let mut due = Vec::new();
+    for (session_id, entry) in state.snapshot().await {
+        let deadline = entry.lock().await.deadline(decaf);
+        if let Some(deadline) = deadline.filter(|deadline| *deadline <= now) {
+            due.push((session_id, (deadline, entry)));
+        }
+    }
+    due.sort_by_key(|(_, (deadline, _))| *deadline);
+    decaf.by_priority(&mut due);
+    for (_, (_, entry)) in due {
+        if budget.ready_at(now) > now {
+            tracing::debug!("emit rate reached, deferring flushes");
+            break;
+        }
+        let flushed = {
+            let mut session = state.lock(&entry, decaf).await;
+            match session.deadline(decaf) {
+                Some(deadline) if deadline <= now => session.take_timed_flush(decaf)?,
+                _ => Vec::new(),
+            }
+        };
+        send(state, flushed)?;
+    }
+    Ok(())
+}
+
+/// Flush every session with anything buffered, whatever its deadline.
+async fn flush_buffered(
+    state: &State,
+    decaf: &Decaf,
+    reason: FlushReason,
+    send: &impl Fn(&Shared, Vec<SessionNotification>) -> Result<(), sacp::Error>,
+) -> Result<(), sacp::Error> {
+    flush_where(
+        state,
+        decaf,
+        send,
+        |_| true,
+        |session| session.take_for(reason, BufferedSession::take_flush),
+    )
+    .await
+}
+
+/// Flush every session whose deadline satisfies `due` with `take`, keeping
+/// the entries.
+async fn flush_where(
+    state: &State,
+    decaf: &Decaf,
+    send: &impl Fn(&Shared, Vec<SessionNotification>) -> Result<(), sacp::Error>,
+    due: impl Fn(Instant) -> bool,
+    take: impl Fn(&mut BufferedSession) -> Result<Vec<SessionNotification>, DecafError>,
+) -> Result<(), sacp::Error> {
+    let mut entries = state.snapshot().await;
+    decaf.by_priority(&mut entries);
+    for (_, entry) in entries {
+        let flushed = {
+            let mut session = state.lock(&entry, decaf).await;
+            match session.deadline(decaf) {
+                Some(deadline) if due(deadline) => take(&mut session)?,
+                _ => Vec::new(),
+            }
+        };
+        send(state, flushed)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sacp::schema::{TextContent, ToolCall};
+
+    fn chunk(session_id: &SessionId, text: &str) -> SessionNotification {
+        SessionNotification::new(
+            session_id.clone(),
+            SessionUpdate::AgentMessageChunk(ContentChunk::new(ContentBlock::Text(
+                TextContent::new(text),
+            ))),
+        )
+    }
+
+    /// Counts heap allocations made on the current thread while enabled.
+    mod allocations {
+        use std::alloc::{GlobalAlloc, Layout, System};
+        use std::cell::Cell;
+
+        struct Counting;
+
+        thread_local! {
+            static COUNT: Cell<Option<usize>> = const { Cell::new(None) };
+        }
+
+        unsafe impl GlobalAlloc for Counting {
+            unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+                let _ = COUNT.try_with(|count| count.set(count.get().map(|n| n + 1)));
+                unsafe { System.alloc(layout) }
+            }
+
+            unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+                unsafe { System.dealloc(ptr, layout) }
+            }
+        }
+
+        #[global_allocator]
+        static GLOBAL: Counting = Counting;
+
+        /// Allocations (including reallocations) made by `f`.
+        pub fn count(f: impl FnOnce()) -> usize {
+            COUNT.with(|count| count.set(Some(0)));
+            f();
+            COUNT.with(|count| count.take()).unwrap_or(0)
+        }
+    }
+
+    /// Buffering a long stream only allocates as the text grows: each
+    /// chunk's meta is moved into the merge and the template is kept from
+    /// the first chunk, so nothing is cloned per chunk.
+    #[test]
+    fn test_buffering_does_not_allocate_per_chunk() {
+        let session_id = SessionId::new("s");
+        let chunks: Vec<_> = (0..1000)
+            .map(|n| {
+                chunk(&session_id, "word ").meta(Meta::from_iter([(
+                    "seq".to_string(),
+                    serde_json::Value::from(n),
+                )]))
+            })
+            .collect();
+
+        let mut buffer = ChunkBuffer::empty(
+            &session_id,
+            &Decaf::builder().initial_buffer_capacity(0).build(),
+        );
+        let pushes = allocations::count(|| {
+            for chunk in chunks {
+                buffer.push(chunk, Instant::now(), 0).unwrap();
+            }
+        });
+        assert!(pushes < 50, "{pushes} allocations for 1000 chunks");
+
+        let flushed = buffer.take().unwrap();
+        assert_eq!(chunk_text(&flushed[0].update).map(str::len), Some(5000));
+        assert_eq!(flushed[0].meta.as_ref().unwrap()["seq"], 999);
+    }
+
+    /// Allocations for a 1000-chunk stream flushed every 100 chunks.
+    fn stream_allocations(capacity: usize) -> usize {
+        let session_id = SessionId::new("s");
+        let chunks: Vec<_> = (0..1000).map(|_| chunk(&session_id, "word ")).collect();
+
+        let mut buffer = ChunkBuffer::empty(
+            &session_id,
+            &Decaf::builder().initial_buffer_capacity(capacity).build(),
+        );
+        let mut flushes = 0;
+        let count = allocations::count(|| {
+            for (n, chunk) in chunks.into_iter().enumerate() {
+                buffer.push(chunk, Instant::now(), 0).unwrap();
+                if n % 100 == 99 {
+                    flushes += buffer.take().unwrap().len();
+                }
+            }
+        });
+        assert_eq!(flushes, 10);
+        count
+    }
+
+    /// The buffer keeps its allocation across flushes: with enough capacity
+    /// reserved only the flushes allocate (the copied-out text and the
+    /// returned `Vec`), and a buffer starting empty only grows in its first
+    /// window rather than in every one.
+    #[test]
+    fn test_buffer_capacity_is_reused_across_flushes() {
+        let reserved = stream_allocations(1024);
+        let growing = stream_allocations(0);
+        assert!(reserved <= 2 * 10, "{reserved} allocations with capacity");
+        assert!(
+            growing - reserved < 10,
+            "{growing} allocations without capacity vs {reserved} with"
+        );
+    }
+
+    /// The byte cap flushes on a char boundary and keeps the overflow.
+    #[test]
+    fn test_byte_cap_splits_on_char_boundary() {
+        let decaf = Decaf::builder().max_buffer_bytes(5).build();
+        let mut session = BufferedSession::new(&SessionId::new("s"), &decaf);
+
+        // "añb" is 4 bytes, so the cap is exceeded inside the final "ñ".
+        let notification = chunk(&SessionId::new("s"), "añbñ");
+        let kind = ChunkKind::of(&notification.update, &decaf).unwrap();
+        let flushed = buffer_chunk(&mut session, kind, notification, &decaf).unwrap();
+
+        let texts: Vec<_> = flushed.iter().map(|n| chunk_text(&n.update)).collect();
+        assert_eq!(texts, vec![Some("añb")]);
+        assert_eq!(session.buffers[&kind].text, "ñ");
+    }
+
+    #[test]
+    fn test_split_at_char_boundary_rounds_down() {
+        // "🦀" is four bytes, starting at 1.
+        let text = "a🦀b";
+        for index in 1..5 {
+            assert_eq!(split_at_char_boundary(text, index), ("a", "🦀b"), "{index}");
+        }
+        assert_eq!(split_at_char_boundary(text, 5), ("a🦀", "b"));
+        assert_eq!(split_at_char_boundary(text, 99), ("a🦀b", ""));
+        assert_eq!(split_at_char_boundary(text, 0), ("", "a🦀b"));
+    }
+
+    /// A cap landing mid-emoji, even one narrower than the emoji, flushes
+    /// whole characters only.
+    #[test]
+    fn test_byte_cap_never_splits_an_emoji() {
+        for max_bytes in 1..=6 {
+            let decaf = Decaf::builder().max_buffer_bytes(max_bytes).build();
+            let mut session = BufferedSession::new(&SessionId::new("s"), &decaf);
+            let mut flushed = Vec::new();
+            for text in ["ab", "🦀🦀", "c"] {
+                let notification = chunk(&SessionId::new("s"), text);
+                let kind = ChunkKind::of(&notification.update, &decaf).unwrap();
+                flushed.extend(buffer_chunk(&mut session, kind, notification, &decaf).unwrap());
+            }
+            flushed.extend(session.take_flush().unwrap());
+
+            let text: String = flushed
+                .iter()
+                .map(|n| chunk_text(&n.update).unwrap())
+                .collect();
+            assert_eq!(text, "ab🦀🦀c", "cap {max_bytes}");
+        }
+    }
+
+    #[test]
+    fn test_byte_cap_backs_off_to_word_boundary() {
+        let decaf = Decaf::builder()
+            .max_buffer_bytes(8)
+            .split_on_word_boundary(true)
+            .build();
+        let mut session = BufferedSession::new(&SessionId::new("s"), &decaf);
+
+        let notification = chunk(&SessionId::new("s"), "hello world again");
+        let kind = ChunkKind::of(&notification.update, &decaf).unwrap();
+        let flushed = buffer_chunk(&mut session, kind, notification, &decaf).unwrap();
+
+        let texts: Vec<_> = flushed.iter().map(|n| chunk_text(&n.update)).collect();
+        assert_eq!(texts, vec![Some("hello "), Some("world ")]);
+        assert_eq!(session.buffers[&kind].text, "again");
+    }
+
+    /// A word longer than the cap is still cut at the cap.
+    #[test]
+    fn test_byte_cap_wins_over_word_boundary() {
+        let decaf = Decaf::builder()
+            .max_buffer_bytes(4)
+            .split_on_word_boundary(true)
+            .build();
+        let mut session = BufferedSession::new(&SessionId::new("s"), &decaf);
+
+        let notification = chunk(&SessionId::new("s"), "abcdefghij");
+        let kind = ChunkKind::of(&notification.update, &decaf).unwrap();
+        let flushed = buffer_chunk(&mut session, kind, notification, &decaf).unwrap();
+
+        let texts: Vec<_> = flushed.iter().map(|n| chunk_text(&n.update)).collect();
+        assert_eq!(texts, vec![Some("abcd"), Some("efgh")]);
+        assert_eq!(session.buffers[&kind].text, "ij");
+    }
+
+    #[test]
+    fn test_latency_percentiles() {
+        let histogram = latency::LatencyHistogram::default();
+        assert_eq!(histogram.snapshot(), LatencySnapshot::default());
+        for ms in 1..=100 {
+            histogram.record(Duration::from_millis(ms));
+        }
+
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 100);
+        assert_eq!(snapshot.max, Duration::from_millis(100));
+        // Rounded up to the bucket, at most 12.5% over the true value.
+        for (percentile, exact) in [(snapshot.p50, 50), (snapshot.p95, 95), (snapshot.p99, 99)] {
+            let exact = Duration::from_millis(exact);
+            assert!(percentile >= exact, "{percentile:?} < {exact:?}");
+            assert!(percentile <= exact * 9 / 8, "{percentile:?} > {exact:?}");
+        }
+        assert!(snapshot.p99 <= snapshot.max);
+    }
+
+    /// Counters at their limit saturate rather than overflow, which would
+    /// panic in a debug build.
+    #[test]
+    fn test_counters_saturate() {
+        let decaf = Decaf::builder()
+            .flush_every_chunks(usize::MAX)
+            .flush_every_tokens(usize::MAX, |text| text.len())
+            .build();
+        let session_id = SessionId::new("s");
+        let mut session = BufferedSession::new(&session_id, &decaf);
+        let notification = chunk(&session_id, "a");
+        let kind = ChunkKind::of(&notification.update, &decaf).unwrap();
+        assert!(
+            buffer_chunk(&mut session, kind, notification, &decaf)
+                .unwrap()
+                .is_empty()
+        );
+
+        session.chunks_buffered = usize::MAX;
+        session.tokens_buffered = usize::MAX;
+        let buffer = session.buffers.get_mut(&kind).unwrap();
+        buffer.chunks_since_flush = usize::MAX;
+        buffer.chunks_flushed = usize::MAX;
+        let flushed = buffer_chunk(&mut session, kind, chunk(&session_id, "b"), &decaf).unwrap();
+        let texts: Vec<_> = flushed.iter().map(|n| chunk_text(&n.update)).collect();
+        assert_eq!(texts, vec![Some("ab")]);
+
+        decaf.stats.record_chunk(usize::MAX);
+        decaf.stats.record_chunk(usize::MAX);
+        decaf.stats.record_forwarded(usize::MAX);
+        decaf.stats.record_forwarded(usize::MAX);
+        decaf.stats.record_sessions_closed(1);
+        assert_eq!(decaf.stats.bytes_buffered(), u64::MAX);
+        assert_eq!(decaf.stats.notifications_forwarded(), u64::MAX);
+        assert_eq!(decaf.stats.active_sessions(), 0);
+    }
+
+    /// A template that can't carry text is reported rather than dropping
+    /// the buffered text silently.
+    #[test]
+    fn test_template_not_chunk_is_an_error() {
+        let session_id = SessionId::new("s");
+        let mut buffer =
+            ChunkBuffer::new(chunk(&session_id, "lost"), 0, &Decaf::builder().build()).unwrap();
+        buffer.template = Some(SessionNotification::new(
+            session_id.clone(),
+            SessionUpdate::Plan(sacp::schema::Plan::new(vec![])),
+        ));
+
+        let error = buffer.take().unwrap_err();
+        assert!(
+            matches!(&error, DecafError::TemplateNotChunk { session_id: id } if *id == session_id),
+            "{error:?}"
+        );
+        let error = sacp::Error::from(error);
+        assert_eq!(error.code, sacp::Error::internal_error().code);
+    }
+
+    /// Past `max_sessions`, each new session evicts the least recently
+    /// updated one, whose text is returned rather than lost.
+    #[tokio::test(start_paused = true)]
+    async fn test_max_sessions_bounds_the_map() {
+        let decaf = Decaf::builder().max_sessions(3).build();
+        let state = State::default();
+
+        let mut sent = Vec::new();
+        for n in 0..10 {
+            let session_id = SessionId::new(format!("session-{n}"));
+            let Admission::Entry { entry, evicted } =
+                state.admit(&session_id, &decaf).await.unwrap()
+            else {
+                panic!("the default policy evicts");
+            };
+            if n >= 3 {
+                // The oldest of the three buffered sessions makes room.
+                let evicted: Vec<_> = evicted.iter().map(|n| n.session_id.clone()).collect();
+                assert_eq!(evicted, vec![SessionId::new(format!("session-{}", n - 3))]);
+            }
+            sent.extend(evicted);
+            let notification = chunk(&session_id, "text");
+            let kind = ChunkKind::of(&notification.update, &decaf).unwrap();
+            buffer_chunk(
+                &mut *state.lock(&entry, &decaf).await,
+                kind,
+                notification,
+                &decaf,
+            )
+            .unwrap();
+            assert!(state.sessions.lock().await.len() <= 3);
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+
+        sent.extend(
+            flush_all(&state, &decaf, FlushReason::Shutdown)
+                .await
+                .unwrap(),
+        );
+        assert_eq!(sent.len(), 10);
+        assert_eq!(state.buffered_bytes.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn test_adaptive_interval_scaling() {
+        let (min, max) = (Duration::from_millis(20), Duration::from_millis(220));
+        assert_eq!(adaptive_interval(min, max, None), min);
+        assert_eq!(adaptive_interval(min, max, Some(Duration::ZERO)), max);
+        assert_eq!(
+            adaptive_interval(min, max, Some(Duration::from_millis(110))),
+            Duration::from_millis(120)
+        );
+        assert_eq!(
+            adaptive_interval(min, max, Some(Duration::from_secs(5))),
+            min
+        );
+    }
+
+    /// Text left behind when the state is dropped is counted for the
+    /// warning; a flushed state has nothing to report.
+    #[tokio::test]
+    async fn test_unflushed_text_is_counted() {
+        let decaf = Decaf::new(Duration::from_millis(100));
+        let state = State::default();
+        for (session, text) in [("a", "hello"), ("b", "hi")] {
+            let session_id = SessionId::new(session);
+            let Admission::Entry { entry, .. } = state.admit(&session_id, &decaf).await.unwrap()
+            else {
+                panic!("sessions are unlimited by default");
+            };
+            let notification = chunk(&session_id, text);
+            let kind = ChunkKind::of(&notification.update, &decaf).unwrap();
+            buffer_chunk(
+                &mut *state.lock(&entry, &decaf).await,
+                kind,
+                notification,
+                &decaf,
+            )
+            .unwrap();
+        }
+        assert_eq!(state.unflushed(), (2, 7));
+
+        flush_all(&state, &decaf, FlushReason::Shutdown)
+            .await
+            .unwrap();
+        assert_eq!(state.unflushed(), (0, 0));
+    }
+
+    /// A session whose lock is held doesn't keep `flush_all` from the
+    /// others: here the first session's lock is only let go once the second
+    /// has been retired, which would never happen one session at a time.
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_all_does_not_wait_in_line() {
+        let decaf = Decaf::builder()
+            .session_priority(|session_id| u8::from(&*session_id.0 == "first"))
+            .build();
+        let state = State::default();
+        let mut entries = Vec::new();
+        for session in ["first", "second"] {
+            let session_id = SessionId::new(session);
+            let Admission::Entry { entry, .. } = state.admit(&session_id, &decaf).await.unwrap()
+            else {
+                panic!("sessions are unlimited by default");
+            };
+            let notification = chunk(&session_id, session);
+            let kind = ChunkKind::of(&notification.update, &decaf).unwrap();
+            buffer_chunk(
+                &mut *state.lock(&entry, &decaf).await,
+                kind,
+                notification,
+                &decaf,
+            )
+            .unwrap();
+            entries.push(entry);
+        }
+
+        let held = entries[0].clone().lock_owned().await;
+        let second = entries[1].clone();
+        let holder = tokio::spawn(async move {
+            while !second.lock().await.retired {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            drop(held);
+        });
+
+        let flushed = tokio::time::timeout(
+            Duration::from_secs(1),
+            flush_all(&state, &decaf, FlushReason::Shutdown),
+        )
+        .await
+        .expect("flush_all waited on the held session")
+        .unwrap();
+        holder.await.unwrap();
+        let texts: Vec<_> = flushed
+            .iter()
+            .map(|n| chunk_text(&n.update).unwrap())
+            .collect();
+        assert_eq!(texts, vec!["first", "second"]);
+    }
+
+    /// Chunks buffered while `flush_all` drains the map land either in that
+    /// flush or in a fresh entry, never in a retired one, so no text is lost
+    /// or sent twice.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_chunks_racing_flush_all_are_kept() {
+        const WRITERS: usize = 8;
+        const CHUNKS: usize = 500;
+        let decaf = Arc::new(Decaf::new(Duration::from_secs(60)));
+        let state = State::default();
+
+        let writers: Vec<_> = (0..WRITERS)
+            .map(|n| {
+                let (state, decaf) = (state.clone(), decaf.clone());
+                tokio::spawn(async move {
+                    // Two writers per session, so entries are shared.
+                    let session_id = SessionId::new(format!("session-{}", n / 2));
+                    let mut forwarded = Vec::new();
+                    for _ in 0..CHUNKS {
+                        let notification = chunk(&session_id, "x");
+                        let kind = ChunkKind::of(&notification.update, &decaf).unwrap();
+                        forwarded.extend(
+                            buffer_into(&state, &decaf, notification, |session, notification| {
+                                buffer_chunk(session, kind, notification, &decaf)
+                            })
+                            .await
+                            .unwrap(),
+                        );
+                    }
+                    forwarded
+                })
+            })
+            .collect();
+        let flusher = {
+            let (state, decaf) = (state.clone(), decaf.clone());
+            tokio::spawn(async move {
+                let mut flushed = Vec::new();
+                for _ in 0..CHUNKS {
+                    flushed.extend(
+                        flush_all(&state, &decaf, FlushReason::Shutdown)
+                            .await
+                            .unwrap(),
+                    );
+                    tokio::task::yield_now().await;
+                }
+                flushed
+            })
+        };
+
+        let mut sent = flusher.await.unwrap();
+        for writer in writers {
+            sent.extend(writer.await.unwrap());
+        }
+        sent.extend(
+            flush_all(&state, &decaf, FlushReason::Shutdown)
+                .await
+                .unwrap(),
+        );
+
+        let text: usize = sent
+            .iter()
+            .map(|n| chunk_text(&n.update).unwrap().len())
+            .sum();
+        assert_eq!(text, WRITERS * CHUNKS);
+        assert!(state.sessions.lock().await.is_empty());
+        assert_eq!(state.buffered_bytes.load(Ordering::Acquire), 0);
+    }
+
+    /// A session whose buffer can't be flushed, or whose text can't be
+    /// sent, still gets its prompt response.
+    #[tokio::test]
+    async fn test_turn_end_responds_despite_flush_errors() {
+        let decaf = Decaf::new(Duration::from_millis(100));
+        let state = State::default();
+        let session_id = SessionId::new("broken");
+
+        // Text without a template: taking it fails.
+        let Admission::Entry { entry, .. } = state.admit(&session_id, &decaf).await.unwrap() else {
+            panic!("sessions are unlimited by default");
+        };
+        let mut buffer = ChunkBuffer::empty(&session_id, &decaf);
+        buffer.text.push_str("orphan");
+        entry
+            .lock()
+            .await
+            .buffers
+            .insert(ChunkKind::Message, buffer);
+
+        let mut responded = false;
+        end_turn(
+            &state,
+            &decaf,
+            &session_id,
+            Some(StopReason::EndTurn),
+            |_| Ok(()),
+            || {
+                responded = true;
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+        assert!(responded);
+
+        // Sending fails, in either order.
+        for flush_before_response in [true, false] {
+            let decaf = Decaf::builder()
+                .flush_before_response(flush_before_response)
+                .build();
+            let Admission::Entry { entry, .. } = state.admit(&session_id, &decaf).await.unwrap()
+            else {
+                panic!("sessions are unlimited by default");
+            };
+            let notification = chunk(&session_id, "text");
+            let kind = ChunkKind::of(&notification.update, &decaf).unwrap();
+            buffer_chunk(
+                &mut *state.lock(&entry, &decaf).await,
+                kind,
+                notification,
+                &decaf,
+            )
+            .unwrap();
+
+            let (sends, mut responded) = (std::cell::Cell::new(0), false);
+            end_turn(
+                &state,
+                &decaf,
+                &session_id,
+                Some(StopReason::EndTurn),
+                |_| {
+                    sends.set(sends.get() + 1);
+                    Err(sacp::Error::internal_error())
+                },
+                || {
+                    responded = true;
+                    Ok(())
+                },
+            )
+            .await
+            .unwrap();
+            assert_eq!((sends.get(), responded), (1, true));
+        }
+    }
+
+    /// A buffer whose template isn't a text chunk fails to flush with an
+    /// error rather than a panic, and the next chunk for it is forwarded
+    /// untouched, after which the stream coalesces as usual again.
+    #[tokio::test]
+    async fn test_template_mismatch_degrades_gracefully() {
+        let decaf = Decaf::new(Duration::from_secs(60));
+        let session_id = SessionId::new("s");
+        let mut session = BufferedSession::new(&session_id, &decaf);
+        let kind = ChunkKind::of(&chunk(&session_id, "").update, &decaf).unwrap();
+        buffer_chunk(&mut session, kind, chunk(&session_id, "lost"), &decaf).unwrap();
+        let buffer = session.buffers.get_mut(&kind).unwrap();
+        buffer.template = Some(SessionNotification::new(
+            session_id.clone(),
+            SessionUpdate::ToolCall(ToolCall::new("call", "run")),
+        ));
+        assert!(matches!(
+            buffer.take(),
+            Err(DecafError::TemplateNotChunk { .. })
+        ));
+
+        let forwarded =
+            buffer_chunk(&mut session, kind, chunk(&session_id, "untouched"), &decaf).unwrap();
+        let texts: Vec<_> = forwarded
+            .iter()
+            .map(|n| chunk_text(&n.update).unwrap())
+            .collect();
+        assert_eq!(texts, vec!["untouched"]);
+
+        for text in ["coalesced ", "again"] {
+            assert!(
+                buffer_chunk(&mut session, kind, chunk(&session_id, text), &decaf)
+                    .unwrap()
+                    .is_empty()
+            );
+        }
+        let flushed = session.take_flush().unwrap();
+        assert_eq!(chunk_text(&flushed[0].update), Some("coalesced again"));
+    }
+
+    /// A flush that fails is reported to `on_error` and the flush task goes
+    /// on, so text buffered afterwards is still flushed at its deadline.
+    #[tokio::test(start_paused = true)]
+    async fn test_flush_task_survives_flush_errors() {
+        let errors = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let decaf = Decaf::builder()
+            .on_error({
+                let errors = errors.clone();
+                move |_| {
+                    errors.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            .build();
+        let state = State::default();
+        let to_agent: State = Arc::new(Shared::new(Toward::Agent, &decaf));
+        let session_id = SessionId::new("s");
+
+        // The first send fails, as a transient transport error would.
+        let (failed, sent) = (
+            std::cell::Cell::new(false),
+            std::cell::RefCell::new(Vec::new()),
+        );
+        let send = |_: &Shared, flushed: Vec<SessionNotification>| {
+            if flushed.is_empty() {
+                return Ok(());
+            }
+            if !failed.replace(true) {
+                return Err(sacp::Error::internal_error());
+            }
+            let texts = flushed
+                .iter()
+                .map(|n| chunk_text(&n.update).unwrap().to_owned());
+            sent.borrow_mut().extend(texts);
+            Ok(())
+        };
+        let buffer = async |text: &str| {
+            let notification = chunk(&session_id, text);
+            let kind = ChunkKind::of(&notification.update, &decaf).unwrap();
+            let forward = buffer_into(&state, &decaf, notification, |session, notification| {
+                buffer_chunk(session, kind, notification, &decaf)
+            })
+            .await
+            .unwrap();
+            assert!(forward.is_empty());
+        };
+
+        tokio::select! {
+            _ = flush_task(&state, &to_agent, &decaf, None, None, send) => unreachable!(),
+            _ = async {
+                buffer("lost").await;
+                tokio::time::sleep(Duration::from_millis(150)).await;
+                assert_eq!(errors.load(Ordering::SeqCst), 1);
+                assert!(sent.borrow().is_empty());
+
+                buffer("kept").await;
+                tokio::time::sleep(Duration::from_millis(150)).await;
+                assert_eq!(*sent.borrow(), ["kept"]);
+                assert_eq!(errors.load(Ordering::SeqCst), 1);
+            } => {}
+        }
+    }
+
+    /// Sequential sessions don't accumulate entries in the state map.
+    #[tokio::test]
+    async fn test_finished_sessions_are_freed() {
+        let decaf = Decaf::new(Duration::from_millis(100));
+        let state = State::default();
+
+        for n in 0..10 {
+            let session_id = SessionId::new(format!("session-{n}"));
+            let Admission::Entry { entry, .. } = state.admit(&session_id, &decaf).await.unwrap()
+            else {
+                panic!("sessions are unlimited by default");
+            };
+            for word in ["hello ", "world"] {
+                let kind = ChunkKind::of(&chunk(&session_id, word).update, &decaf).unwrap();
+                buffer_chunk(
+                    &mut *state.lock(&entry, &decaf).await,
+                    kind,
+                    chunk(&session_id, word),
+                    &decaf,
+                )
+                .unwrap();
+            }
+            assert_eq!(state.sessions.lock().await.len(), 1);
+
+            let flushed = finish_turn(&state, &decaf, &session_id).await.unwrap();
+            assert_eq!(flushed.len(), 1);
+            assert_eq!(chunk_text(&flushed[0].update), Some("hello world"));
+            assert!(state.sessions.lock().await.is_empty());
+            assert_eq!(state.buffered_bytes.load(Ordering::Acquire), 0);
+        }
+    }
+
+    /// Ended turns are forgotten oldest first, and a session that ended
+    /// again since is not forgotten on account of its older end.
+    #[test]
+    fn test_ended_turns_are_bounded() {
+        let mut ended = EndedTurns::default();
+        let first = SessionId::new("first");
+        ended.end(&first);
+        ended.start(&first);
+        ended.end(&first);
+        for n in 0..ENDED_TURNS - 1 {
+            ended.end(&SessionId::new(format!("other-{n}")));
+        }
+        assert!(ended.has_ended(&first));
+        assert!(ended.has_ended(&SessionId::new("other-0")));
+
+        ended.end(&SessionId::new("newest"));
+        assert!(!ended.has_ended(&first));
+        assert_eq!(ended.ended.len(), ENDED_TURNS);
+    }
 }
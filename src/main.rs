@@ -10,7 +10,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .and_then(|arg| arg.parse().ok())
         .unwrap_or(100);
 
-    Decaf::new(Duration::from_millis(interval_ms))
+    // stdout carries the ACP connection, so logs go to stderr.
+    #[cfg(feature = "json-log")]
+    tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
+    // An interval of 0 forwards everything untouched, for baseline runs.
+    let decaf = match interval_ms {
+        0 => Decaf::disabled(),
+        interval_ms => Decaf::new(Duration::from_millis(interval_ms)),
+    };
+    // A config file, when given, replaces the interval argument.
+    #[cfg(feature = "serde")]
+    let decaf = match config_path() {
+        Some(path) => {
+            let config = serde_json::from_slice(&std::fs::read(path)?)?;
+            Decaf::from_config(config)?
+        }
+        None => decaf,
+    };
+    decaf
         .connect_to(sacp::ByteStreams::new(
             tokio::io::stdout().compat_write(),
             tokio::io::stdin().compat(),
@@ -19,3 +41,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+/// The JSON config named by `--config <path>` or, failing that, the
+/// `DECAF_CONFIG` environment variable.
+#[cfg(feature = "serde")]
+fn config_path() -> Option<std::path::PathBuf> {
+    let mut args = std::env::args_os().skip(1);
+    match args.next() {
+        Some(flag) if flag == "--config" => args.next().map(Into::into),
+        _ => std::env::var_os("DECAF_CONFIG").map(Into::into),
+    }
+}
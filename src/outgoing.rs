@@ -0,0 +1,73 @@
+//! Knowing when what the proxy sent has reached its transport.
+//!
+//! sacp queues outgoing messages for a background actor and drops that
+//! actor as soon as the connection's main future returns, so shutdown has
+//! to wait for its final flush to get through. [`Outgoing`] sits between
+//! the connection and the real transport and answers a [`MARK_METHOD`]
+//! notification, which follows everything sent before it through the same
+//! queue, instead of passing it on.
+
+use std::pin::pin;
+
+use futures::StreamExt;
+use futures::channel::mpsc;
+use futures::future::{self, BoxFuture, Either};
+use sacp::jsonrpcmsg::Message;
+use sacp::{Channel, Conductor, ConnectTo, Proxy};
+use tokio::sync::oneshot;
+
+/// The notification [`Outgoing`] answers rather than forwards.
+pub(crate) const MARK_METHOD: &str = "_decaf/outgoing_mark";
+
+/// A transport that reports, once, when a [`MARK_METHOD`] notification
+/// reaches it.
+pub(crate) struct Outgoing<T> {
+    transport: T,
+    marked: oneshot::Sender<()>,
+}
+
+impl<T> Outgoing<T> {
+    /// Wrap `transport`, returning the receiver the mark is reported on.
+    /// It errors instead if the transport closes first.
+    pub(crate) fn new(transport: T) -> (Self, oneshot::Receiver<()>) {
+        let (marked, receiver) = oneshot::channel();
+        (Outgoing { transport, marked }, receiver)
+    }
+}
+
+impl<T: ConnectTo<Proxy>> ConnectTo<Proxy> for Outgoing<T> {
+    async fn connect_to(self, client: impl ConnectTo<Conductor>) -> Result<(), sacp::Error> {
+        let (channel, serve) = self.into_channel_and_future();
+        match future::select(pin!(client.connect_to(channel)), serve).await {
+            Either::Left((result, _)) | Either::Right((result, _)) => result,
+        }
+    }
+
+    fn into_channel_and_future(self) -> (Channel, BoxFuture<'static, Result<(), sacp::Error>>) {
+        let (inner, serve) = self.transport.into_channel_and_future();
+        let (tx, mut rx) = mpsc::unbounded();
+        let mut marked = Some(self.marked);
+        let copy = async move {
+            while let Some(message) = rx.next().await {
+                if let Ok(Message::Request(request)) = &message {
+                    if request.method == MARK_METHOD {
+                        if let Some(marked) = marked.take() {
+                            let _ = marked.send(());
+                        }
+                        continue;
+                    }
+                }
+                inner
+                    .tx
+                    .unbounded_send(message)
+                    .map_err(sacp::util::internal_error)?;
+            }
+            Ok(())
+        };
+        let future = async move {
+            futures::try_join!(serve, copy)?;
+            Ok(())
+        };
+        (Channel { rx: inner.rx, tx }, Box::pin(future))
+    }
+}
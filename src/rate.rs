@@ -0,0 +1,45 @@
+//! Capping how many notifications per second reach the client.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// A token bucket over notifications sent to the client, for
+/// [`DecafBuilder::max_emit_rate`](crate::DecafBuilder::max_emit_rate).
+///
+/// The bucket holds a single token and earns one every `1 / rate` seconds,
+/// kept as the time the next token is due (the "theoretical arrival time"
+/// of GCRA), so no fractional tokens drift. Every notification sent spends
+/// one, even when the bucket is empty, so text that can't wait (a flush
+/// ahead of another update, a turn end...) leaves a debt the timer pays off
+/// before flushing again. Only the timer waits for a token.
+#[derive(Debug)]
+pub(crate) struct EmitBudget {
+    spacing: Duration,
+    next_token_at: Mutex<Option<Instant>>,
+}
+
+impl EmitBudget {
+    pub(crate) fn new(rate: u32) -> Self {
+        EmitBudget {
+            spacing: Duration::from_secs(1) / rate,
+            next_token_at: Mutex::new(None),
+        }
+    }
+
+    /// When the timer may next send: `now` if a token is available.
+    pub(crate) fn ready_at(&self, now: Instant) -> Instant {
+        (*self.lock()).map_or(now, |next| next.max(now))
+    }
+
+    /// Spend a token on a notification sent at `now`.
+    pub(crate) fn spend(&self, now: Instant) {
+        let mut next = self.lock();
+        *next = Some(next.map_or(now, |next| next.max(now)) + self.spacing);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Option<Instant>> {
+        self.next_token_at.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
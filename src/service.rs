@@ -0,0 +1,190 @@
+//! A [`Coalescer`] as a `tower` service, with the `tower` feature.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+
+use sacp::schema::{SessionId, SessionNotification};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore, mpsc};
+use tokio_util::sync::PollSemaphore;
+use tower_service::Service;
+
+use crate::{Coalescer, DecafError};
+
+/// A [`Coalescer`] behind `tower::Service<SessionNotification>`, for
+/// pipelines built from middleware.
+///
+/// [`call`](Service::call) pushes one notification from the agent and
+/// resolves to whatever must be sent on right away, as
+/// [`Coalescer::push`] returns it. Text that waits for its deadline comes
+/// out of [`timer`](Self::timer) instead: spawn it once, with the sending
+/// half of a channel, and it sleeps until the next deadline, ticks the
+/// coalescer and sends what was flushed. Without it, buffered text only
+/// goes out on early triggers, [`end_turn`](Self::end_turn) and
+/// [`flush`](Self::flush).
+///
+/// The timer and the service take turns: while the timer is sending,
+/// [`poll_ready`](Service::poll_ready) stays pending, so a slow reader of
+/// the channel holds back new notifications instead of letting them pile
+/// up, and text a call returns never overtakes text flushed before it.
+/// Forward each call's output before the next call, and the channel's as
+/// it arrives, to keep each session in order.
+///
+/// Clones share the coalescer; each waits for its own turn.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use decaf_mod::{Coalescer, CoalesceService, Decaf};
+/// # use sacp::schema::{ContentBlock, ContentChunk, SessionNotification, SessionUpdate};
+/// # use tower_service::Service;
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<(), decaf_mod::DecafError> {
+/// let mut service = CoalesceService::new(Coalescer::new(Decaf::new(Duration::from_millis(50))));
+/// let (timed, mut flushed) = tokio::sync::mpsc::channel(16);
+/// tokio::spawn(service.timer(timed));
+///
+/// let chunk = SessionNotification::new(
+///     "session",
+///     SessionUpdate::AgentMessageChunk(ContentChunk::new(ContentBlock::from("Hello"))),
+/// );
+/// std::future::poll_fn(|cx| service.poll_ready(cx)).await?;
+/// assert!(service.call(chunk).await?.is_empty());
+/// assert!(flushed.recv().await.is_some());
+/// # Ok(())
+/// # }
+/// ```
+pub struct CoalesceService {
+    shared: Arc<ServiceShared>,
+    turn: PollSemaphore,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+struct ServiceShared {
+    coalescer: Mutex<Coalescer>,
+    /// One permit, held by whoever is producing output.
+    turn: Arc<Semaphore>,
+    /// Wakes the timer when a call may have moved the next deadline.
+    pushed: Notify,
+}
+
+impl CoalesceService {
+    pub fn new(coalescer: Coalescer) -> Self {
+        let turn = Arc::new(Semaphore::new(1));
+        CoalesceService {
+            shared: Arc::new(ServiceShared {
+                coalescer: Mutex::new(coalescer),
+                turn: turn.clone(),
+                pushed: Notify::new(),
+            }),
+            turn: PollSemaphore::new(turn),
+            permit: None,
+        }
+    }
+
+    /// Drive the coalescer's timer, sending each timed flush on `timed`.
+    ///
+    /// Runs until `timed` is closed; spawn it. A failure to flush is
+    /// reported to [`on_error`](crate::DecafBuilder::on_error) (or logged)
+    /// and the timer goes on.
+    pub fn timer(
+        &self,
+        timed: mpsc::Sender<SessionNotification>,
+    ) -> impl Future<Output = ()> + Send + 'static {
+        let shared = self.shared.clone();
+        async move {
+            loop {
+                let (clock, next_deadline) = {
+                    let coalescer = shared.lock();
+                    (coalescer.decaf.clock.clone(), coalescer.next_deadline())
+                };
+                let deadline = async {
+                    match next_deadline {
+                        Some(deadline) => clock.sleep_until(deadline).await,
+                        None => std::future::pending().await,
+                    }
+                };
+                tokio::select! {
+                    () = deadline => {}
+                    () = shared.pushed.notified() => continue,
+                    () = timed.closed() => return,
+                }
+                let Ok(_turn) = shared.turn.clone().acquire_owned().await else {
+                    return;
+                };
+                let out = {
+                    let mut coalescer = shared.lock();
+                    coalescer.tick().map_err(|error| {
+                        coalescer.decaf.flush_failed(Err(error.into()));
+                    })
+                };
+                for notification in out.unwrap_or_default() {
+                    if timed.send(notification).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// [`Coalescer::end_turn`], once the timer isn't sending.
+    pub async fn end_turn(
+        &self,
+        session_id: &SessionId,
+    ) -> Result<Vec<SessionNotification>, DecafError> {
+        let _turn = self.shared.turn.acquire().await;
+        let out = self.shared.lock().end_turn(session_id);
+        self.shared.pushed.notify_one();
+        out
+    }
+
+    /// [`Coalescer::flush`], once the timer isn't sending.
+    pub async fn flush(&self) -> Result<Vec<SessionNotification>, DecafError> {
+        let _turn = self.shared.turn.acquire().await;
+        let out = self.shared.lock().flush();
+        self.shared.pushed.notify_one();
+        out
+    }
+}
+
+impl ServiceShared {
+    fn lock(&self) -> MutexGuard<'_, Coalescer> {
+        self.coalescer.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Clone for CoalesceService {
+    fn clone(&self) -> Self {
+        CoalesceService {
+            shared: self.shared.clone(),
+            turn: self.turn.clone(),
+            permit: None,
+        }
+    }
+}
+
+impl Service<SessionNotification> for CoalesceService {
+    type Response = Vec<SessionNotification>;
+    type Error = DecafError;
+    type Future = std::future::Ready<Result<Vec<SessionNotification>, DecafError>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), DecafError>> {
+        if self.permit.is_none() {
+            // The semaphore is never closed, so this always yields a permit.
+            self.permit = std::task::ready!(self.turn.poll_acquire(cx));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// # Panics
+    ///
+    /// If [`poll_ready`](Service::poll_ready) hasn't returned ready since
+    /// the last call.
+    fn call(&mut self, notification: SessionNotification) -> Self::Future {
+        let _turn = self
+            .permit
+            .take()
+            .expect("CoalesceService::call before poll_ready");
+        let out = self.shared.lock().push(notification);
+        self.shared.pushed.notify_one();
+        std::future::ready(out)
+    }
+}
@@ -0,0 +1,139 @@
+//! Where per-session state is kept: a map, or a single slot for
+//! embeddings that only ever stream one session at a time.
+
+use std::collections::HashMap;
+
+use sacp::schema::SessionId;
+
+/// Per-session state by session id, for
+/// [`DecafBuilder::single_session`](crate::DecafBuilder::single_session).
+///
+/// `Single` holds at most one session and never hashes its id. A second
+/// distinct session moves both into a `Map`, which is kept from then on:
+/// an embedding that had two sessions at once may well have them again.
+#[derive(Debug)]
+pub(crate) enum Sessions<T> {
+    Single(Option<(SessionId, T)>),
+    Map(HashMap<SessionId, T>),
+}
+
+impl<T> Default for Sessions<T> {
+    fn default() -> Self {
+        Sessions::Map(HashMap::new())
+    }
+}
+
+impl<T> Sessions<T> {
+    pub(crate) fn new(single_session: bool) -> Self {
+        match single_session {
+            true => Sessions::Single(None),
+            false => Sessions::default(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Sessions::Single(single) => usize::from(single.is_some()),
+            Sessions::Map(map) => map.len(),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn contains_key(&self, session_id: &SessionId) -> bool {
+        self.get(session_id).is_some()
+    }
+
+    pub(crate) fn get(&self, session_id: &SessionId) -> Option<&T> {
+        match self {
+            Sessions::Single(single) => single
+                .as_ref()
+                .filter(|(id, _)| id == session_id)
+                .map(|(_, value)| value),
+            Sessions::Map(map) => map.get(session_id),
+        }
+    }
+
+    pub(crate) fn get_mut(&mut self, session_id: &SessionId) -> Option<&mut T> {
+        match self {
+            Sessions::Single(single) => single
+                .as_mut()
+                .filter(|(id, _)| id == session_id)
+                .map(|(_, value)| value),
+            Sessions::Map(map) => map.get_mut(session_id),
+        }
+    }
+
+    /// Add `session_id`'s state, moving to a map if another session is
+    /// already held in the single slot.
+    pub(crate) fn insert(&mut self, session_id: SessionId, value: T) {
+        match self {
+            Sessions::Single(single) => match single.take() {
+                Some((held, held_value)) if held != session_id => {
+                    tracing::debug!(
+                        session_id = %session_id.0,
+                        held = %held.0,
+                        "second session in single-session mode, switching to a map"
+                    );
+                    *self = Sessions::Map(HashMap::from([(held, held_value), (session_id, value)]));
+                }
+                _ => *single = Some((session_id, value)),
+            },
+            Sessions::Map(map) => {
+                map.insert(session_id, value);
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, session_id: &SessionId) -> Option<T> {
+        match self {
+            Sessions::Single(single) => {
+                if single.as_ref().is_some_and(|(id, _)| id == session_id) {
+                    single.take().map(|(_, value)| value)
+                } else {
+                    None
+                }
+            }
+            Sessions::Map(map) => map.remove(session_id),
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&SessionId, &T)> {
+        let (single, map) = match self {
+            Sessions::Single(single) => (single.as_ref(), None),
+            Sessions::Map(map) => (None, Some(map)),
+        };
+        single
+            .map(|(id, value)| (id, value))
+            .into_iter()
+            .chain(map.into_iter().flatten())
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = (&SessionId, &mut T)> {
+        let (single, map) = match self {
+            Sessions::Single(single) => (single.as_mut(), None),
+            Sessions::Map(map) => (None, Some(map)),
+        };
+        single
+            .map(|(id, value)| (&*id, value))
+            .into_iter()
+            .chain(map.into_iter().flatten())
+    }
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = &T> {
+        self.iter().map(|(_, value)| value)
+    }
+
+    /// Take every session's state out, leaving the same kind of store
+    /// empty.
+    pub(crate) fn drain(&mut self) -> impl Iterator<Item = (SessionId, T)> {
+        let (single, map) = match self {
+            Sessions::Single(single) => (single.take(), None),
+            Sessions::Map(map) => (None, Some(map.drain())),
+        };
+        single.into_iter().chain(map.into_iter().flatten())
+    }
+}
@@ -0,0 +1,269 @@
+//! Runtime counters describing how well Decaf is coalescing.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::latency::{LatencyHistogram, LatencySnapshot};
+
+/// Counters updated as chunks are buffered and flushed.
+///
+/// Obtain a shared handle with [`Decaf::stats_handle`](crate::Decaf::stats_handle)
+/// before running the proxy. All counters are plain atomics, so reading them
+/// never contends with the flush path. They saturate at `u64::MAX` rather
+/// than wrap, however long the proxy runs, unless
+/// [`take_snapshot`](Self::take_snapshot) resets them.
+#[derive(Debug, Default)]
+pub struct DecafStats {
+    name: String,
+    metrics_prefix: String,
+    chunks_received: AtomicU64,
+    notifications_forwarded: AtomicU64,
+    bytes_buffered: AtomicU64,
+    peak_pending_bytes: AtomicU64,
+    active_sessions: AtomicU64,
+    tap_dropped: AtomicU64,
+    flush_latency: LatencyHistogram,
+}
+
+impl DecafStats {
+    pub(crate) fn new(name: String, metrics_prefix: String) -> Self {
+        DecafStats {
+            name,
+            metrics_prefix,
+            ..DecafStats::default()
+        }
+    }
+
+    /// The name of the proxy these counters belong to, to tell instances
+    /// apart when several run in one pipeline.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Text chunks received from the agent that were eligible for coalescing.
+    pub fn chunks_received(&self) -> u64 {
+        self.chunks_received.load(Ordering::Relaxed)
+    }
+
+    /// Text notifications sent to the client (coalesced flushes plus chunks
+    /// forwarded immediately, e.g. by leading-edge mode).
+    pub fn notifications_forwarded(&self) -> u64 {
+        self.notifications_forwarded.load(Ordering::Relaxed)
+    }
+
+    /// Total bytes of text that went through the buffers.
+    pub fn bytes_buffered(&self) -> u64 {
+        self.bytes_buffered.load(Ordering::Relaxed)
+    }
+
+    /// The most text held in the buffers at once, toward either peer.
+    pub fn peak_pending_bytes(&self) -> u64 {
+        self.peak_pending_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Sessions with a buffer entry right now, toward either peer. Entries
+    /// are freed at turn ends, evictions and shutdown.
+    pub fn active_sessions(&self) -> u64 {
+        self.active_sessions.load(Ordering::Relaxed)
+    }
+
+    /// Notifications left out of the [`tap`](crate::DecafBuilder::tap)
+    /// recording because its channel was full. They were still forwarded.
+    pub fn tap_dropped(&self) -> u64 {
+        self.tap_dropped.load(Ordering::Relaxed)
+    }
+
+    /// How long text waited before being flushed: p50/p95/p99 and max of
+    /// the age of each session's oldest un-flushed chunk at every flush.
+    ///
+    /// Compare it with the configured interval to see whether text is
+    /// mostly flushed by the timer or sooner, by turn ends and other
+    /// triggers. Early splits (sentences, lines, patterns, the byte cap)
+    /// are recorded too.
+    pub fn latency_snapshot(&self) -> LatencySnapshot {
+        self.flush_latency.snapshot()
+    }
+
+    /// `chunks_received / notifications_forwarded`, or `None` before anything
+    /// has been forwarded.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        ratio(self.chunks_received(), self.notifications_forwarded())
+    }
+
+    /// Read every counter and reset the cumulative ones to zero, for
+    /// reporting in windows: each snapshot covers what happened since the
+    /// previous one.
+    ///
+    /// `chunks_received`, `notifications_forwarded`, `bytes_buffered`,
+    /// `tap_dropped` and the latency histogram are reset; the
+    /// `peak_pending_bytes` and `active_sessions` gauges are only read.
+    /// Each counter is swapped out on its own, so an update racing the
+    /// snapshot lands in this window or the next, never in both or
+    /// neither, but the counters are not read at one instant.
+    ///
+    /// [`render_prometheus`](Self::render_prometheus) shows the reset
+    /// counters starting over, which Prometheus takes for a restart; use
+    /// one or the other.
+    pub fn take_snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            chunks_received: self.chunks_received.swap(0, Ordering::Relaxed),
+            notifications_forwarded: self.notifications_forwarded.swap(0, Ordering::Relaxed),
+            bytes_buffered: self.bytes_buffered.swap(0, Ordering::Relaxed),
+            tap_dropped: self.tap_dropped.swap(0, Ordering::Relaxed),
+            latency: self.flush_latency.take_snapshot(),
+            peak_pending_bytes: self.peak_pending_bytes(),
+            active_sessions: self.active_sessions(),
+        }
+    }
+
+    /// The counters in the Prometheus text exposition format, ready to be
+    /// appended to a scrape response.
+    ///
+    /// Metric names start with the
+    /// [`metrics_prefix`](crate::DecafBuilder::metrics_prefix) (default
+    /// `decaf_`), and every sample carries a `proxy` label with the
+    /// [`name`](Self::name) so stacked instances stay apart:
+    ///
+    /// - `decaf_chunks_received_total`, `decaf_notifications_forwarded_total`,
+    ///   `decaf_bytes_buffered_total` and `decaf_tap_dropped_total` are
+    ///   counters;
+    /// - `decaf_peak_pending_bytes` and `decaf_active_sessions` are gauges.
+    pub fn render_prometheus(&self) -> String {
+        let metrics = [
+            (
+                "chunks_received_total",
+                "counter",
+                "Text chunks received that were eligible for coalescing.",
+                self.chunks_received(),
+            ),
+            (
+                "notifications_forwarded_total",
+                "counter",
+                "Text notifications sent on, coalesced or not.",
+                self.notifications_forwarded(),
+            ),
+            (
+                "bytes_buffered_total",
+                "counter",
+                "Bytes of text that went through the buffers.",
+                self.bytes_buffered(),
+            ),
+            (
+                "tap_dropped_total",
+                "counter",
+                "Notifications left out of the tap recording because it was full.",
+                self.tap_dropped(),
+            ),
+            (
+                "peak_pending_bytes",
+                "gauge",
+                "The most text held in the buffers at once.",
+                self.peak_pending_bytes(),
+            ),
+            (
+                "active_sessions",
+                "gauge",
+                "Sessions currently holding a buffer entry.",
+                self.active_sessions(),
+            ),
+        ];
+        let proxy = escape_label(&self.name);
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            let name = format!("{}{name}", self.metrics_prefix);
+            // Writing to a `String` cannot fail.
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            let _ = writeln!(out, "{name}{{proxy=\"{proxy}\"}} {value}");
+        }
+        out
+    }
+
+    pub(crate) fn record_chunk(&self, bytes: usize) {
+        add(&self.chunks_received, 1);
+        add(&self.bytes_buffered, bytes as u64);
+    }
+
+    pub(crate) fn record_pending(&self, bytes: usize) {
+        self.peak_pending_bytes
+            .fetch_max(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_forwarded(&self, notifications: usize) {
+        add(&self.notifications_forwarded, notifications as u64);
+    }
+
+    pub(crate) fn record_sessions_opened(&self, sessions: usize) {
+        add(&self.active_sessions, sessions as u64);
+    }
+
+    pub(crate) fn record_sessions_closed(&self, sessions: usize) {
+        // Saturating too: a gauge stuck at zero beats one wrapped around.
+        let _ = self
+            .active_sessions
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| {
+                Some(n.saturating_sub(sessions as u64))
+            });
+    }
+
+    pub(crate) fn record_tap_dropped(&self) {
+        add(&self.tap_dropped, 1);
+    }
+
+    pub(crate) fn record_flush_latency(&self, latency: Duration) {
+        self.flush_latency.record(latency);
+    }
+}
+
+/// The counters of one reporting window, from
+/// [`DecafStats::take_snapshot`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// Text chunks received since the previous snapshot.
+    pub chunks_received: u64,
+    /// Text notifications sent on since the previous snapshot.
+    pub notifications_forwarded: u64,
+    /// Bytes of text buffered since the previous snapshot.
+    pub bytes_buffered: u64,
+    /// Notifications left out of the tap since the previous snapshot.
+    pub tap_dropped: u64,
+    /// Flush latencies recorded since the previous snapshot.
+    pub latency: LatencySnapshot,
+    /// The most text ever held in the buffers at once; not reset.
+    pub peak_pending_bytes: u64,
+    /// Sessions with a buffer entry when the snapshot was taken.
+    pub active_sessions: u64,
+}
+
+impl StatsSnapshot {
+    /// `chunks_received / notifications_forwarded` over the window, or
+    /// `None` if nothing was forwarded in it.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        ratio(self.chunks_received, self.notifications_forwarded)
+    }
+}
+
+fn ratio(chunks: u64, forwarded: u64) -> Option<f64> {
+    match forwarded {
+        0 => None,
+        forwarded => Some(chunks as f64 / forwarded as f64),
+    }
+}
+
+/// Add `n` to `counter`, stopping at `u64::MAX`.
+pub(crate) fn add(counter: &AtomicU64, n: u64) {
+    // The closure always returns `Some`, so this cannot fail.
+    let _ = counter.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+        Some(count.saturating_add(n))
+    });
+}
+
+/// `value` escaped for a Prometheus label: backslash, double quote and
+/// newline.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,95 @@
+//! Coalescing across prompts sent before the previous one is answered.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Event, Script, ScriptedAgent, Step, message_chunk, run};
+use decaf_mod::Decaf;
+use sacp::schema::{ContentBlock, SessionNotification, SessionUpdate};
+
+/// The first prompt's chunks, then its response once the second prompt is
+/// in flight; the second prompt's chunks come a little later.
+fn agent() -> ScriptedAgent {
+    ScriptedAgent::with(|request| match &request.prompt[0] {
+        ContentBlock::Text(tc) if tc.text == "first" => Script::new(vec![
+            Step::Send(message_chunk("one ")),
+            Step::Send(message_chunk("two ")),
+            Step::Sleep(Duration::from_millis(20)),
+        ]),
+        _ => Script::new(vec![
+            Step::Sleep(Duration::from_millis(50)),
+            Step::Send(message_chunk("three ")),
+            Step::Send(message_chunk("four")),
+        ]),
+    })
+}
+
+/// What the client saw: each message's text, and `|` for each response.
+fn seen(events: &[Event]) -> Vec<String> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Notification(SessionNotification {
+                update: SessionUpdate::AgentMessageChunk(chunk),
+                ..
+            }) => match &chunk.content {
+                ContentBlock::Text(tc) => Some(tc.text.clone()),
+                _ => None,
+            },
+            Event::Response(..) => Some("|".to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+async fn prompt_twice(decaf: Decaf) -> Result<Vec<Event>, sacp::Error> {
+    run(decaf, agent(), async |client| {
+        let session = client.new_session().await?;
+        tokio::try_join!(client.prompt(&session, "first"), async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            client.prompt(&session, "second").await
+        })?;
+        Ok(())
+    })
+    .await
+}
+
+/// With prompts outstanding, the first response leaves the text buffered;
+/// the last response flushes all of it.
+#[tokio::test(start_paused = true)]
+async fn test_text_coalesces_until_last_response() -> Result<(), sacp::Error> {
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .coalesce_across_prompts(true)
+        .build();
+
+    let events = prompt_twice(decaf).await?;
+
+    assert_eq!(seen(&events), vec!["|", "one two three four", "|"]);
+    Ok(())
+}
+
+/// Off by default: every response flushes its session and ends its turn,
+/// so the second prompt's chunks pass through as stragglers.
+#[tokio::test(start_paused = true)]
+async fn test_each_response_flushes_by_default() -> Result<(), sacp::Error> {
+    let events = prompt_twice(Decaf::new(Duration::from_secs(60))).await?;
+
+    assert_eq!(seen(&events), vec!["one two ", "|", "three ", "four", "|"]);
+    Ok(())
+}
+
+/// Text still goes out on the timer while the prompts are outstanding.
+#[tokio::test(start_paused = true)]
+async fn test_timer_still_flushes_across_prompts() -> Result<(), sacp::Error> {
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(30))
+        .coalesce_across_prompts(true)
+        .build();
+
+    let events = prompt_twice(decaf).await?;
+
+    assert_eq!(seen(&events), vec!["|", "one two ", "three four", "|"]);
+    Ok(())
+}
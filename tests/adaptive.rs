@@ -0,0 +1,60 @@
+//! Scaling the interval with the chunk rate via `Decaf::adaptive`.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::Decaf;
+
+/// `count` chunks sent `gap` apart.
+fn stream(count: usize, gap: Duration) -> Vec<Step> {
+    (0..count)
+        .flat_map(|n| {
+            [
+                Step::Send(message_chunk(&format!("{n} "))),
+                Step::Sleep(gap),
+            ]
+        })
+        .collect()
+}
+
+async fn notifications(decaf: Decaf, steps: Vec<Step>) -> Result<usize, sacp::Error> {
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(steps)),
+        async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            Ok(())
+        },
+    )
+    .await?;
+    Ok(message_texts(&events).len())
+}
+
+/// A fast burst widens the window towards `max_interval`, coalescing far
+/// more than a fixed `min_interval` would.
+#[tokio::test(start_paused = true)]
+async fn test_fast_burst_widens_window() -> Result<(), sacp::Error> {
+    let (min, max) = (Duration::from_millis(10), Duration::from_millis(200));
+    let burst = || stream(40, Duration::from_millis(2));
+
+    let fixed = notifications(Decaf::new(min), burst()).await?;
+    let adaptive = notifications(Decaf::adaptive(min, max), burst()).await?;
+
+    assert!(fixed >= 7, "fixed interval sent {fixed}");
+    assert_eq!(adaptive, 1);
+    Ok(())
+}
+
+/// Chunks arriving slower than `max_interval` apart are each flushed after
+/// the short window instead of waiting for the next one.
+#[tokio::test(start_paused = true)]
+async fn test_slow_stream_narrows_window() -> Result<(), sacp::Error> {
+    let (min, max) = (Duration::from_millis(10), Duration::from_millis(200));
+    let slow = stream(5, Duration::from_millis(250));
+
+    assert_eq!(notifications(Decaf::adaptive(min, max), slow).await?, 5);
+    Ok(())
+}
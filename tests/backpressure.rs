@@ -0,0 +1,74 @@
+//! Bounding the text held across sessions with `max_total_bytes`.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, message_texts, run, words};
+use decaf_mod::{Decaf, OverflowPolicy};
+
+const LIMIT: usize = 64;
+
+/// 200 five-byte words, far more than [`LIMIT`].
+fn flood() -> Vec<&'static str> {
+    vec!["word "; 200]
+}
+
+async fn stream(decaf: Decaf) -> Result<Vec<String>, sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(&flood())));
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+    Ok(message_texts(&events))
+}
+
+/// The agent outpaces a long interval; flushing early keeps what is held
+/// within one chunk of the limit.
+#[tokio::test]
+async fn test_overflow_flushes_everything() -> Result<(), sacp::Error> {
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .max_total_bytes(LIMIT)
+        .build();
+    let stats = decaf.stats_handle();
+
+    let texts = stream(decaf).await?;
+
+    assert_eq!(texts.concat(), flood().concat());
+    assert!(texts.len() > 1);
+    assert!(stats.peak_pending_bytes() <= (LIMIT + "word ".len()) as u64);
+    Ok(())
+}
+
+/// With `Block`, the handler waits for deadline flushes to make room.
+#[tokio::test(start_paused = true)]
+async fn test_overflow_blocks_until_drained() -> Result<(), sacp::Error> {
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(10))
+        .max_total_bytes(LIMIT)
+        .overflow_policy(OverflowPolicy::Block)
+        .build();
+    let stats = decaf.stats_handle();
+
+    let texts = stream(decaf).await?;
+
+    assert_eq!(texts.concat(), flood().concat());
+    assert!(stats.peak_pending_bytes() <= (LIMIT + "word ".len()) as u64);
+    Ok(())
+}
+
+/// Without a limit the whole turn is held until the response.
+#[tokio::test]
+async fn test_unbounded_by_default() -> Result<(), sacp::Error> {
+    let decaf = Decaf::new(Duration::from_secs(60));
+    let stats = decaf.stats_handle();
+
+    let texts = stream(decaf).await?;
+
+    assert_eq!(texts, vec![flood().concat()]);
+    assert_eq!(stats.peak_pending_bytes(), 1000);
+    Ok(())
+}
@@ -0,0 +1,122 @@
+//! Builder validation.
+
+use std::time::Duration;
+
+use decaf_mod::Decaf;
+
+#[test]
+#[should_panic(expected = "Decaf interval must be non-zero")]
+fn test_zero_interval_is_rejected() {
+    Decaf::builder().interval(Duration::ZERO).build();
+}
+
+#[test]
+#[should_panic(expected = "Decaf max_sessions must be non-zero")]
+fn test_zero_max_sessions_is_rejected() {
+    Decaf::builder().max_sessions(0).build();
+}
+
+#[test]
+#[should_panic(expected = "Decaf flush_every_chunks must be non-zero")]
+fn test_zero_flush_every_chunks_is_rejected() {
+    Decaf::builder().flush_every_chunks(0).build();
+}
+
+#[test]
+#[should_panic(expected = "Decaf history capacity must be non-zero")]
+fn test_zero_history_is_rejected() {
+    Decaf::builder().with_history(0).build();
+}
+
+#[test]
+#[should_panic(expected = "Decaf adaptive interval needs 0 < min_interval <= max_interval")]
+fn test_inverted_adaptive_interval_is_rejected() {
+    Decaf::adaptive(Duration::from_millis(200), Duration::from_millis(10));
+}
+
+#[test]
+#[should_panic(expected = "Decaf metrics_prefix must match [a-zA-Z_:][a-zA-Z0-9_:]*")]
+fn test_invalid_metrics_prefix_is_rejected() {
+    Decaf::builder().metrics_prefix("decaf-").build();
+}
+
+#[test]
+#[should_panic(expected = "Decaf flush_every_tokens must be non-zero")]
+fn test_zero_flush_every_tokens_is_rejected() {
+    Decaf::builder()
+        .flush_every_tokens(0, |text| text.len())
+        .build();
+}
+
+#[test]
+#[should_panic(
+    expected = "Decaf interval must be at least min_interval (1ms unless set with with_min_interval)"
+)]
+fn test_sub_millisecond_interval_is_rejected() {
+    Decaf::new(Duration::from_micros(100));
+}
+
+#[test]
+#[should_panic(
+    expected = "Decaf interval must be at least min_interval (1ms unless set with with_min_interval)"
+)]
+fn test_sub_millisecond_adaptive_interval_is_rejected() {
+    Decaf::adaptive(Duration::from_micros(100), Duration::from_millis(100));
+}
+
+#[test]
+fn test_min_interval_allows_tighter_intervals() {
+    Decaf::builder()
+        .interval(Duration::from_micros(100))
+        .with_min_interval(Duration::from_micros(50))
+        .build();
+}
+
+#[test]
+#[should_panic(expected = "Decaf min_interval must be non-zero")]
+fn test_zero_min_interval_is_rejected() {
+    Decaf::builder()
+        .interval(Duration::from_micros(100))
+        .with_min_interval(Duration::ZERO)
+        .build();
+}
+
+#[test]
+#[should_panic(expected = "Decaf max_emit_bytes must be non-zero")]
+fn test_zero_max_emit_bytes_is_rejected() {
+    Decaf::builder().max_emit_bytes(0).build();
+}
+
+/// A built proxy reports the name and interval it was configured with,
+/// and the interval follows `set_interval`.
+#[test]
+fn test_configuration_reads_back() {
+    let decaf = Decaf::builder()
+        .named("decaf-inner")
+        .interval(Duration::from_millis(250))
+        .build();
+    assert_eq!(decaf.name(), "decaf-inner");
+    assert_eq!(decaf.interval(), Duration::from_millis(250));
+
+    decaf
+        .control_handle()
+        .set_interval(Duration::from_millis(40))
+        .unwrap();
+    assert_eq!(decaf.interval(), Duration::from_millis(40));
+
+    let decaf = Decaf::new(Duration::from_secs(1));
+    assert_eq!(
+        (decaf.name(), decaf.interval()),
+        ("decaf", Duration::from_secs(1))
+    );
+}
+
+#[test]
+#[should_panic(
+    expected = "Decaf interval must be at least min_interval (1ms unless set with with_min_interval)"
+)]
+fn test_sub_millisecond_first_flush_is_rejected() {
+    Decaf::builder()
+        .first_flush_after(Duration::from_micros(100))
+        .build();
+}
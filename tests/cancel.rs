@@ -0,0 +1,145 @@
+//! Cancelling a prompt mid-stream.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Event, Script, ScriptedAgent, Step, message_chunk, message_texts, recv, run, words};
+use decaf_mod::Decaf;
+use futures::{StreamExt, channel::mpsc};
+use sacp::schema::{
+    CancelNotification, PromptRequest, PromptResponse, SessionId, SessionNotification, StopReason,
+    SuccessorMessage,
+};
+use sacp::{Conductor, Responder, UntypedMessage};
+
+/// Text still buffered when the client cancels is dropped, not flushed,
+/// while the cancellation itself reaches the agent.
+#[tokio::test(start_paused = true)]
+async fn test_cancel_discards_buffered_text() -> Result<(), sacp::Error> {
+    let mut steps = words(&["this ", "answer ", "is ", "stale"]);
+    steps.push(Step::Sleep(Duration::from_millis(100)));
+    let agent = ScriptedAgent::new(Script::new(steps).stop_reason(StopReason::Cancelled));
+
+    let events = run(
+        Decaf::new(Duration::from_secs(60)),
+        agent.clone(),
+        async |client| {
+            let session_id = client.new_session().await?;
+            tokio::try_join!(client.prompt(&session_id, "go"), async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                client
+                    .cx
+                    .send_notification(CancelNotification::new(session_id.clone()))
+            })?;
+            Ok(())
+        },
+    )
+    .await?;
+
+    assert!(message_texts(&events).is_empty());
+    assert!(matches!(
+        events.as_slice(),
+        [Event::Response(_, StopReason::Cancelled)]
+    ));
+    assert_eq!(agent.cancelled().len(), 1);
+    Ok(())
+}
+
+/// A `$/cancel_request` for a prompt in flight drops its session's
+/// buffered text like a `session/cancel` does, and reaches the agent under
+/// the id the proxy forwarded the prompt with.
+///
+/// The conductor gives every hop a fresh request id without translating
+/// `$/cancel_request`, so this drives the proxy directly, playing the
+/// conductor on both sides.
+#[tokio::test(start_paused = true)]
+async fn test_cancel_request_discards_buffered_text() -> Result<(), sacp::Error> {
+    let (prompts_tx, mut prompts_rx) = mpsc::unbounded();
+    let (notifications_tx, mut notifications_rx) = mpsc::unbounded();
+    let (cancels_tx, mut cancels_rx) = mpsc::unbounded();
+
+    let stop_reason = Conductor
+        .builder()
+        .name("decaf-test-conductor")
+        .on_receive_request(
+            async move |_prompt: SuccessorMessage<PromptRequest>,
+                        responder: Responder<PromptResponse>,
+                        _cx| {
+                prompts_tx
+                    .unbounded_send(responder)
+                    .map_err(|_| sacp::Error::internal_error())
+            },
+            sacp::on_receive_request!(),
+        )
+        .on_receive_notification(
+            async move |notification: SessionNotification, _cx| {
+                notifications_tx
+                    .unbounded_send(notification)
+                    .map_err(|_| sacp::Error::internal_error())
+            },
+            sacp::on_receive_notification!(),
+        )
+        .on_receive_notification(
+            async move |cancel: SuccessorMessage<UntypedMessage>, _cx| {
+                cancels_tx
+                    .unbounded_send(cancel.message)
+                    .map_err(|_| sacp::Error::internal_error())
+            },
+            sacp::on_receive_notification!(),
+        )
+        .connect_with(Decaf::new(Duration::from_secs(60)), async |cx| {
+            let session_id = SessionId::new("session");
+            let prompt = cx.send_request(PromptRequest::new(session_id.clone(), vec![]));
+            let prompt_id = prompt.id();
+            let forwarded = prompts_rx.next().await.expect("prompt reaches the agent");
+            for word in ["this ", "answer ", "is ", "stale"] {
+                cx.send_notification(SuccessorMessage {
+                    message: SessionNotification::new(session_id.clone(), message_chunk(word)),
+                    meta: None,
+                })?;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            cx.send_notification(UntypedMessage::new(
+                "$/cancel_request",
+                serde_json::json!({ "requestId": prompt_id }),
+            )?)?;
+            let cancel = cancels_rx.next().await.expect("cancel reaches the agent");
+            assert_eq!(cancel.method(), "$/cancel_request");
+            assert_eq!(cancel.params()["requestId"], forwarded.id());
+
+            forwarded.respond(PromptResponse::new(StopReason::Cancelled))?;
+            Ok(recv(prompt).await?.stop_reason)
+        })
+        .await?;
+
+    assert_eq!(stop_reason, StopReason::Cancelled);
+    assert!(notifications_rx.try_recv().is_err());
+    Ok(())
+}
+
+/// A `$/cancel_request` naming no prompt in flight drops nothing: the text
+/// is flushed with the response as usual.
+#[tokio::test(start_paused = true)]
+async fn test_unknown_cancel_request_keeps_buffered_text() -> Result<(), sacp::Error> {
+    let mut steps = words(&["still ", "sent"]);
+    steps.push(Step::Sleep(Duration::from_millis(100)));
+    let agent = ScriptedAgent::new(Script::new(steps));
+
+    let events = run(Decaf::new(Duration::from_secs(60)), agent, async |client| {
+        let session_id = client.new_session().await?;
+        tokio::try_join!(client.prompt(&session_id, "go"), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.cx.send_notification(UntypedMessage::new(
+                "$/cancel_request",
+                serde_json::json!({ "requestId": "unknown" }),
+            )?)
+        })?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["still sent"]);
+    Ok(())
+}
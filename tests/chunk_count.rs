@@ -0,0 +1,52 @@
+//! Flushing every N chunks with `flush_every_chunks`.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run, words};
+use decaf_mod::Decaf;
+
+async fn run_script(steps: Vec<Step>, decaf: Decaf) -> Result<Vec<String>, sacp::Error> {
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(steps)),
+        async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            Ok(())
+        },
+    )
+    .await?;
+    Ok(message_texts(&events))
+}
+
+/// Exactly 2N chunks with a long interval make exactly two notifications.
+#[tokio::test]
+async fn test_flushes_every_n_chunks() -> Result<(), sacp::Error> {
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .flush_every_chunks(5)
+        .build();
+    let steps = words(&["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"]);
+
+    assert_eq!(run_script(steps, decaf).await?, vec!["01234", "56789"]);
+    Ok(())
+}
+
+/// The timer still flushes a short batch, and the count starts over after
+/// it.
+#[tokio::test(start_paused = true)]
+async fn test_timer_flush_resets_the_count() -> Result<(), sacp::Error> {
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(100))
+        .flush_every_chunks(3)
+        .build();
+    let mut steps = words(&["a", "b"]);
+    steps.push(Step::Sleep(Duration::from_millis(150)));
+    steps.extend(words(&["c", "d", "e"]));
+    steps.push(Step::Send(message_chunk("f")));
+
+    assert_eq!(run_script(steps, decaf).await?, vec!["ab", "cde", "f"]);
+    Ok(())
+}
@@ -0,0 +1,71 @@
+//! Debouncing user message chunks streamed from the client to the agent.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, TestClient, run, user_chunk};
+use decaf_mod::Decaf;
+use sacp::schema::{ContentBlock, ContentChunk, SessionNotification, SessionUpdate};
+
+/// The text of every `UserMessageChunk` the agent received, in order.
+fn user_texts(notifications: &[SessionNotification]) -> Vec<String> {
+    notifications
+        .iter()
+        .filter_map(|notification| match &notification.update {
+            SessionUpdate::UserMessageChunk(ContentChunk {
+                content: ContentBlock::Text(tc),
+                ..
+            }) => Some(tc.text.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Stream `words` as user chunks, then prompt so the stream is forwarded.
+async fn dictate(client: &TestClient, words: &[&str]) -> Result<(), sacp::Error> {
+    let session_id = client.new_session().await?;
+    for word in words {
+        client.cx.send_notification(SessionNotification::new(
+            session_id.clone(),
+            user_chunk(word),
+        ))?;
+    }
+    client.prompt(&session_id, "go").await?;
+    Ok(())
+}
+
+/// With the option on, word-by-word dictation reaches the agent as one
+/// chunk, flushed ahead of the prompt.
+#[tokio::test]
+async fn test_user_chunks_are_coalesced() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![]));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .debounce_client_to_agent(true)
+        .build();
+
+    run(decaf, agent.clone(), async |client| {
+        dictate(&client, &["turn ", "on ", "the ", "lights"]).await
+    })
+    .await?;
+
+    assert_eq!(user_texts(&agent.received()), vec!["turn on the lights"]);
+    Ok(())
+}
+
+/// Off by default: user chunks pass through untouched.
+#[tokio::test]
+async fn test_user_chunks_pass_through_by_default() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![]));
+
+    run(
+        Decaf::new(Duration::from_secs(60)),
+        agent.clone(),
+        async |client| dictate(&client, &["turn ", "on"]).await,
+    )
+    .await?;
+
+    assert_eq!(user_texts(&agent.received()), vec!["turn ", "on"]);
+    Ok(())
+}
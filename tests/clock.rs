@@ -0,0 +1,70 @@
+//! Driving the flush timer with an injected `MockClock`.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::{Decaf, MockClock};
+
+fn steps() -> Vec<Step> {
+    vec![
+        Step::Send(message_chunk("a ")),
+        Step::Send(message_chunk("b ")),
+        Step::Sleep(Duration::from_millis(200)),
+        Step::Send(message_chunk("c")),
+    ]
+}
+
+/// Advancing the mock clock past the interval flushes, mid-turn.
+#[tokio::test(start_paused = true)]
+async fn test_advancing_mock_clock_flushes() -> Result<(), sacp::Error> {
+    let clock = Arc::new(MockClock::new());
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(100))
+        .with_clock(clock.clone())
+        .build();
+
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(steps())),
+        async |client| {
+            let session = client.new_session().await?;
+            tokio::try_join!(client.prompt(&session, "go"), async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                clock.advance(Duration::from_millis(100));
+                Ok(())
+            })?;
+            Ok(())
+        },
+    )
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a b ", "c"]);
+    Ok(())
+}
+
+/// Tokio time passing is not enough: without an advance nothing flushes
+/// until the turn ends.
+#[tokio::test(start_paused = true)]
+async fn test_mock_clock_ignores_tokio_time() -> Result<(), sacp::Error> {
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(100))
+        .with_clock(Arc::new(MockClock::new()))
+        .build();
+
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(steps())),
+        async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            Ok(())
+        },
+    )
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a b c"]);
+    Ok(())
+}
@@ -0,0 +1,95 @@
+//! Driving the buffering directly with a `Coalescer`, no connection needed.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::message_chunk;
+use decaf_mod::{Coalescer, Decaf, DecafError, MockClock};
+use sacp::schema::{
+    ContentBlock, ContentChunk, SessionId, SessionNotification, SessionUpdate, ToolCall,
+};
+
+fn coalescer(clock: &Arc<MockClock>) -> Coalescer {
+    Coalescer::new(
+        Decaf::builder()
+            .interval(Duration::from_millis(100))
+            .with_clock(clock.clone())
+            .build(),
+    )
+}
+
+fn chunk(session: &str, text: &str) -> SessionNotification {
+    SessionNotification::new(SessionId::new(session), message_chunk(text))
+}
+
+/// What each notification is, with the text of message chunks.
+fn describe(notifications: &[SessionNotification]) -> Vec<String> {
+    notifications
+        .iter()
+        .map(|notification| match &notification.update {
+            SessionUpdate::AgentMessageChunk(ContentChunk {
+                content: ContentBlock::Text(tc),
+                ..
+            }) => tc.text.clone(),
+            SessionUpdate::ToolCall(_) => "tool call".to_string(),
+            _ => "other".to_string(),
+        })
+        .collect()
+}
+
+#[test]
+fn test_chunks_flush_on_tick_after_deadline() -> Result<(), DecafError> {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = coalescer(&clock);
+    assert!(coalescer.next_deadline().is_none());
+    assert!(coalescer.push(chunk("s", "Hello, "))?.is_empty());
+    assert!(coalescer.push(chunk("s", "world"))?.is_empty());
+
+    clock.advance(Duration::from_millis(50));
+    assert!(coalescer.tick()?.is_empty());
+    assert!(coalescer.next_deadline().is_some());
+
+    clock.advance(Duration::from_millis(50));
+    assert_eq!(describe(&coalescer.tick()?), vec!["Hello, world"]);
+    assert!(coalescer.next_deadline().is_none());
+    assert!(coalescer.tick()?.is_empty());
+    Ok(())
+}
+
+/// A non-chunk update comes back behind the text buffered before it.
+#[test]
+fn test_other_updates_flush_their_session_first() -> Result<(), DecafError> {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = coalescer(&clock);
+    coalescer.push(chunk("a", "Let me look"))?;
+    coalescer.push(chunk("b", "elsewhere"))?;
+    let tool_call = SessionNotification::new(
+        SessionId::new("a"),
+        SessionUpdate::ToolCall(ToolCall::new("call-1", "Read file")),
+    );
+    assert_eq!(
+        describe(&coalescer.push(tool_call)?),
+        vec!["Let me look", "tool call"]
+    );
+    assert_eq!(describe(&coalescer.flush()?), vec!["elsewhere"]);
+    Ok(())
+}
+
+#[test]
+fn test_end_turn_flushes_only_that_session() -> Result<(), DecafError> {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = coalescer(&clock);
+    coalescer.push(chunk("a", "done"))?;
+    coalescer.push(chunk("b", "still going"))?;
+    assert_eq!(
+        describe(&coalescer.end_turn(&SessionId::new("a"))?),
+        vec!["done"]
+    );
+    assert!(coalescer.end_turn(&SessionId::new("a"))?.is_empty());
+
+    clock.advance(Duration::from_millis(100));
+    assert_eq!(describe(&coalescer.tick()?), vec!["still going"]);
+    Ok(())
+}
@@ -0,0 +1,472 @@
+//! Shared harness for the decaf integration tests.
+//!
+//! [`ScriptedAgent`] replays a per-prompt script of session updates (with
+//! optional pauses), and [`run`] wires it through a conductor with the given
+//! `Decaf` in front, recording everything the client observes in order.
+
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use decaf_mod::Decaf;
+use futures::{StreamExt, channel::mpsc};
+use sacp::schema::{
+    AgentCapabilities, CancelNotification, ContentBlock, ContentChunk, InitializeRequest,
+    InitializeResponse, NewSessionRequest, NewSessionResponse, PromptRequest, PromptResponse,
+    ProtocolVersion, SessionId, SessionNotification, SessionUpdate, StopReason, TextContent,
+};
+use sacp::{Agent, Client, ConnectTo, ConnectionTo, Responder};
+use sacp_conductor::{ConductorImpl, ProxiesAndAgent};
+use tokio::io::duplex;
+use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+// ---------------------------------------------------------------------------
+// ScriptedAgent — replays a script of updates for every prompt
+// ---------------------------------------------------------------------------
+
+/// One step of an agent script.
+#[derive(Clone)]
+pub enum Step {
+    /// Send an update for the prompting session.
+    Send(SessionUpdate),
+    /// Send a fully-formed notification (custom session id, meta, ...).
+    Notify(SessionNotification),
+    /// Pause before the next step.
+    Sleep(Duration),
+}
+
+/// What the agent does in response to one prompt.
+#[derive(Clone)]
+pub struct Script {
+    pub steps: Vec<Step>,
+    pub stop_reason: StopReason,
+    /// Played after the response has been sent, as an out-of-spec agent
+    /// might.
+    pub late: Vec<Step>,
+}
+
+impl Script {
+    pub fn new(steps: Vec<Step>) -> Self {
+        Script {
+            steps,
+            stop_reason: StopReason::EndTurn,
+            late: Vec::new(),
+        }
+    }
+
+    pub fn stop_reason(mut self, stop_reason: StopReason) -> Self {
+        self.stop_reason = stop_reason;
+        self
+    }
+
+    pub fn late(mut self, late: Vec<Step>) -> Self {
+        self.late = late;
+        self
+    }
+}
+
+type ScriptFn = dyn Fn(&PromptRequest) -> Script + Send + Sync;
+
+#[derive(Clone)]
+pub struct ScriptedAgent {
+    script: Arc<ScriptFn>,
+    sessions: Arc<AtomicUsize>,
+    received: Arc<Mutex<Vec<SessionNotification>>>,
+    cancelled: Arc<Mutex<Vec<SessionId>>>,
+}
+
+impl ScriptedAgent {
+    /// An agent that plays the same script for every prompt.
+    pub fn new(script: Script) -> Self {
+        Self::with(move |_| script.clone())
+    }
+
+    /// An agent that picks its script based on the incoming prompt.
+    pub fn with(script: impl Fn(&PromptRequest) -> Script + Send + Sync + 'static) -> Self {
+        ScriptedAgent {
+            script: Arc::new(script),
+            sessions: Arc::new(AtomicUsize::new(0)),
+            received: Arc::default(),
+            cancelled: Arc::default(),
+        }
+    }
+
+    /// Every notification the agent has received from the client, in order.
+    /// Clone the agent before handing it to [`run`] to read this afterwards.
+    pub fn received(&self) -> Vec<SessionNotification> {
+        self.received.lock().unwrap().clone()
+    }
+
+    /// Every session the client has sent a cancellation for, in order.
+    pub fn cancelled(&self) -> Vec<SessionId> {
+        self.cancelled.lock().unwrap().clone()
+    }
+}
+
+impl ConnectTo<Client> for ScriptedAgent {
+    async fn connect_to(self, client: impl ConnectTo<Agent>) -> Result<(), sacp::Error> {
+        let sessions = self.sessions.clone();
+        let script = self.script.clone();
+        let received = self.received.clone();
+        let cancelled = self.cancelled.clone();
+        Agent
+            .builder()
+            .name("scripted-agent")
+            .on_receive_notification(
+                async move |notification: SessionNotification, _cx| {
+                    received.lock().unwrap().push(notification);
+                    Ok(())
+                },
+                sacp::on_receive_notification!(),
+            )
+            .on_receive_notification(
+                async move |cancel: CancelNotification, _cx| {
+                    cancelled.lock().unwrap().push(cancel.session_id);
+                    Ok(())
+                },
+                sacp::on_receive_notification!(),
+            )
+            .on_receive_request(
+                async |init: InitializeRequest, responder: Responder<InitializeResponse>, _cx| {
+                    responder.respond(
+                        InitializeResponse::new(init.protocol_version)
+                            .agent_capabilities(AgentCapabilities::new()),
+                    )
+                },
+                sacp::on_receive_request!(),
+            )
+            .on_receive_request(
+                async move |_req: NewSessionRequest,
+                            responder: Responder<NewSessionResponse>,
+                            _cx| {
+                    let n = sessions.fetch_add(1, Ordering::SeqCst) + 1;
+                    responder.respond(NewSessionResponse::new(SessionId::new(format!(
+                        "session-{n}"
+                    ))))
+                },
+                sacp::on_receive_request!(),
+            )
+            .on_receive_request(
+                async move |request: PromptRequest,
+                            responder: Responder<PromptResponse>,
+                            cx: ConnectionTo<Client>| {
+                    let script = script(&request);
+                    let cx2 = cx.clone();
+                    cx.spawn(async move {
+                        let play = async |steps: Vec<Step>| -> Result<(), sacp::Error> {
+                            for step in steps {
+                                match step {
+                                    Step::Send(update) => {
+                                        cx2.send_notification(SessionNotification::new(
+                                            request.session_id.clone(),
+                                            update,
+                                        ))?
+                                    }
+                                    Step::Notify(notification) => {
+                                        cx2.send_notification(notification)?
+                                    }
+                                    Step::Sleep(duration) => tokio::time::sleep(duration).await,
+                                }
+                            }
+                            Ok(())
+                        };
+                        play(script.steps).await?;
+                        responder.respond(PromptResponse::new(script.stop_reason))?;
+                        play(script.late).await
+                    })
+                },
+                sacp::on_receive_request!(),
+            )
+            .connect_to(client)
+            .await
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Client side
+// ---------------------------------------------------------------------------
+
+/// Something the client observed, in arrival order.
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum Event {
+    Notification(SessionNotification),
+    Response(SessionId, StopReason),
+}
+
+/// Handle given to test bodies for driving the client.
+#[derive(Clone)]
+pub struct TestClient {
+    pub cx: ConnectionTo<Agent>,
+    events: mpsc::UnboundedSender<Event>,
+}
+
+impl TestClient {
+    pub async fn new_session(&self) -> Result<SessionId, sacp::Error> {
+        let session = recv(
+            self.cx
+                .send_request(NewSessionRequest::new(PathBuf::from("/"))),
+        )
+        .await?;
+        Ok(session.session_id)
+    }
+
+    /// Send a prompt and wait for the response, recording it as an [`Event`].
+    ///
+    /// The response is recorded from the client's dispatch loop, so its
+    /// position among the notifications is the order they arrived in.
+    pub async fn prompt(
+        &self,
+        session_id: &SessionId,
+        text: &str,
+    ) -> Result<StopReason, sacp::Error> {
+        let events = self.events.clone();
+        let session = session_id.clone();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        self.cx
+            .send_request(PromptRequest::new(
+                session_id.clone(),
+                vec![ContentBlock::Text(TextContent::new(text.to_string()))],
+            ))
+            .on_receiving_result(async move |result| {
+                if let Ok(response) = &result {
+                    events
+                        .unbounded_send(Event::Response(session, response.stop_reason))
+                        .map_err(|_| sacp::Error::internal_error())?;
+                }
+                tx.send(result).map_err(|_| sacp::Error::internal_error())
+            })?;
+        let response = rx.await.map_err(|_| sacp::Error::internal_error())??;
+        Ok(response.stop_reason)
+    }
+}
+
+pub async fn recv<T: sacp::JsonRpcResponse + Send>(
+    response: sacp::SentRequest<T>,
+) -> Result<T, sacp::Error> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    response.on_receiving_result(async move |result| {
+        tx.send(result).map_err(|_| sacp::Error::internal_error())
+    })?;
+    rx.await.map_err(|_| sacp::Error::internal_error())?
+}
+
+/// Run `body` as a client talking to `agent` through `decaf`, returning every
+/// event the client observed.
+pub async fn run(
+    decaf: Decaf,
+    agent: ScriptedAgent,
+    body: impl AsyncFnOnce(TestClient) -> Result<(), sacp::Error>,
+) -> Result<Vec<Event>, sacp::Error> {
+    run_chain(vec![decaf], agent, body).await
+}
+
+/// Like [`run`], but with several proxies stacked in front of `agent`; the
+/// first is closest to the client.
+pub async fn run_chain(
+    proxies: Vec<Decaf>,
+    agent: ScriptedAgent,
+    body: impl AsyncFnOnce(TestClient) -> Result<(), sacp::Error>,
+) -> Result<Vec<Event>, sacp::Error> {
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_test_writer()
+        .try_init();
+
+    let (events_tx, mut events_rx) = mpsc::unbounded::<Event>();
+
+    let (client_write, conductor_read) = duplex(8192);
+    let (conductor_write, client_read) = duplex(8192);
+
+    let conductor_handle = tokio::spawn(async move {
+        ConductorImpl::new_agent(
+            "decaf-test-conductor".to_string(),
+            ProxiesAndAgent::new(agent).proxies(proxies),
+            Default::default(),
+        )
+        .run(sacp::ByteStreams::new(
+            conductor_write.compat_write(),
+            conductor_read.compat(),
+        ))
+        .await
+    });
+
+    let result = tokio::time::timeout(Duration::from_secs(10), {
+        let events_tx = events_tx.clone();
+        async move {
+            sacp::Client
+                .builder()
+                .name("decaf-test-client")
+                .on_receive_notification(
+                    {
+                        let events_tx = events_tx.clone();
+                        async move |notification: SessionNotification, _cx: ConnectionTo<Agent>| {
+                            events_tx
+                                .unbounded_send(Event::Notification(notification))
+                                .map_err(|_| sacp::Error::internal_error())
+                        }
+                    },
+                    sacp::on_receive_notification!(),
+                )
+                .connect_with(
+                    sacp::ByteStreams::new(client_write.compat_write(), client_read.compat()),
+                    async |cx| {
+                        recv(cx.send_request(InitializeRequest::new(ProtocolVersion::LATEST)))
+                            .await?;
+                        body(TestClient {
+                            cx,
+                            events: events_tx,
+                        })
+                        .await
+                    },
+                )
+                .await
+        }
+    })
+    .await
+    .expect("Test timed out");
+
+    conductor_handle.abort();
+    result?;
+
+    drop(events_tx);
+    let mut events = Vec::new();
+    while let Some(event) = events_rx.next().await {
+        events.push(event);
+    }
+    Ok(events)
+}
+
+// ---------------------------------------------------------------------------
+// Wire — the proxy's own transport, with the test in the conductor's seat
+// ---------------------------------------------------------------------------
+
+/// The conductor's end of a proxy's transport: raw JSON-RPC messages, read
+/// in exactly the order the proxy wrote them.
+pub struct Wire {
+    channel: sacp::Channel,
+}
+
+impl Wire {
+    /// Send a raw message to the proxy.
+    pub fn send(&self, message: serde_json::Value) {
+        let message = serde_json::from_value(message).expect("a JSON-RPC message");
+        self.channel
+            .tx
+            .unbounded_send(Ok(message))
+            .expect("proxy is running");
+    }
+
+    /// Send `message` as coming from the agent, wrapped for the proxy's
+    /// successor.
+    pub fn send_from_agent(&self, method: &str, params: serde_json::Value) {
+        self.send(serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": "_proxy/successor",
+            "params": { "method": method, "params": params },
+        }));
+    }
+
+    /// The next message the proxy wrote.
+    pub async fn next(&mut self) -> serde_json::Value {
+        let message = tokio::time::timeout(Duration::from_secs(10), self.channel.rx.next())
+            .await
+            .expect("proxy wrote nothing")
+            .expect("proxy closed its transport")
+            .expect("proxy sent an error");
+        serde_json::to_value(message).expect("a JSON-RPC message")
+    }
+}
+
+/// Spawn `decaf` on a bare transport, returning its other end and the
+/// task running the proxy.
+pub fn wire(decaf: Decaf) -> (Wire, tokio::task::JoinHandle<Result<(), sacp::Error>>) {
+    let (channel, proxy) = sacp::Channel::duplex();
+    (Wire { channel }, tokio::spawn(decaf.run(proxy)))
+}
+
+/// Run `decaf` on a bare transport and hand its other end to `body`, so
+/// tests see the proxy's output in its own order, before any conductor.
+pub async fn run_wire(
+    decaf: Decaf,
+    body: impl AsyncFnOnce(&mut Wire) -> Result<(), sacp::Error>,
+) -> Result<(), sacp::Error> {
+    let (mut wire, proxy) = wire(decaf);
+    let result = body(&mut wire).await;
+    proxy.abort();
+    result
+}
+
+// ---------------------------------------------------------------------------
+// Builders and extractors
+// ---------------------------------------------------------------------------
+
+pub fn message_chunk(text: &str) -> SessionUpdate {
+    SessionUpdate::AgentMessageChunk(ContentChunk::new(ContentBlock::Text(TextContent::new(
+        text.to_string(),
+    ))))
+}
+
+pub fn user_chunk(text: &str) -> SessionUpdate {
+    SessionUpdate::UserMessageChunk(ContentChunk::new(ContentBlock::Text(TextContent::new(
+        text.to_string(),
+    ))))
+}
+
+pub fn thought_chunk(text: &str) -> SessionUpdate {
+    SessionUpdate::AgentThoughtChunk(ContentChunk::new(ContentBlock::Text(TextContent::new(
+        text.to_string(),
+    ))))
+}
+
+/// Script steps sending each word as its own message chunk.
+pub fn words(words: &[&str]) -> Vec<Step> {
+    words.iter().map(|w| Step::Send(message_chunk(w))).collect()
+}
+
+/// The text of every `AgentMessageChunk` the client saw, in order.
+pub fn message_texts(events: &[Event]) -> Vec<String> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Notification(SessionNotification {
+                update:
+                    SessionUpdate::AgentMessageChunk(ContentChunk {
+                        content: ContentBlock::Text(tc),
+                        ..
+                    }),
+                ..
+            }) => Some(tc.text.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Every text chunk the client saw as `("message" | "thought" | "user", text)`,
+/// in order.
+pub fn chunk_texts(events: &[Event]) -> Vec<(&'static str, String)> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Notification(SessionNotification { update, .. }) => match update {
+                SessionUpdate::AgentMessageChunk(ContentChunk {
+                    content: ContentBlock::Text(tc),
+                    ..
+                }) => Some(("message", tc.text.clone())),
+                SessionUpdate::AgentThoughtChunk(ContentChunk {
+                    content: ContentBlock::Text(tc),
+                    ..
+                }) => Some(("thought", tc.text.clone())),
+                SessionUpdate::UserMessageChunk(ContentChunk {
+                    content: ContentBlock::Text(tc),
+                    ..
+                }) => Some(("user", tc.text.clone())),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
@@ -0,0 +1,175 @@
+//! Many sessions streaming at once.
+
+mod common;
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use common::{Event, Script, ScriptedAgent, Step, message_chunk, run, wire};
+use decaf_mod::Decaf;
+use sacp::schema::{ContentBlock, ContentChunk, SessionNotification, SessionUpdate};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// Every message chunk's text, grouped by session in arrival order.
+fn texts_by_session(events: &[Event]) -> HashMap<String, Vec<String>> {
+    let mut texts: HashMap<String, Vec<String>> = HashMap::new();
+    for event in events {
+        if let Event::Notification(SessionNotification {
+            session_id,
+            update:
+                SessionUpdate::AgentMessageChunk(ContentChunk {
+                    content: ContentBlock::Text(tc),
+                    ..
+                }),
+            ..
+        }) = event
+        {
+            texts
+                .entry(session_id.0.to_string())
+                .or_default()
+                .push(tc.text.clone());
+        }
+    }
+    texts
+}
+
+const SESSIONS: usize = 100;
+const WORDS: usize = 20;
+
+/// Every session's text arrives complete and in order while 100 sessions
+/// stream interleaved chunks and the flush task fires between them.
+#[tokio::test(start_paused = true)]
+async fn test_many_concurrent_sessions() -> Result<(), sacp::Error> {
+    let steps = (0..WORDS)
+        .flat_map(|n| {
+            [
+                Step::Send(message_chunk(&format!("{n} "))),
+                Step::Sleep(Duration::from_millis(7)),
+            ]
+        })
+        .collect();
+    let agent = ScriptedAgent::new(Script::new(steps));
+
+    let events = run(
+        Decaf::new(Duration::from_millis(20)),
+        agent,
+        async |client| {
+            let mut sessions = Vec::new();
+            for _ in 0..SESSIONS {
+                sessions.push(client.new_session().await?);
+            }
+            futures::future::try_join_all(sessions.iter().map(|id| client.prompt(id, "go")))
+                .await?;
+            Ok(())
+        },
+    )
+    .await?;
+
+    let mut texts: HashMap<String, String> = HashMap::new();
+    let mut notifications: HashMap<String, usize> = HashMap::new();
+    for event in &events {
+        if let Event::Notification(SessionNotification {
+            session_id,
+            update:
+                SessionUpdate::AgentMessageChunk(ContentChunk {
+                    content: ContentBlock::Text(tc),
+                    ..
+                }),
+            ..
+        }) = event
+        {
+            texts
+                .entry(session_id.0.to_string())
+                .or_default()
+                .push_str(&tc.text);
+            *notifications.entry(session_id.0.to_string()).or_default() += 1;
+        }
+    }
+
+    let expected: String = (0..WORDS).map(|n| format!("{n} ")).collect();
+    assert_eq!(texts.len(), SESSIONS);
+    for (session_id, text) in &texts {
+        assert_eq!(text, &expected, "{session_id}");
+        assert!(
+            notifications[session_id] < WORDS,
+            "{session_id} was not coalesced"
+        );
+    }
+    Ok(())
+}
+
+/// A prompt finishing in one session doesn't flush another session that is
+/// still mid-turn.
+#[tokio::test(start_paused = true)]
+async fn test_response_flushes_only_its_session() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::with(|prompt| match &prompt.prompt[..] {
+        [ContentBlock::Text(tc)] if tc.text == "slow" => Script::new(vec![
+            Step::Send(message_chunk("still ")),
+            Step::Sleep(Duration::from_millis(500)),
+            Step::Send(message_chunk("going")),
+        ]),
+        _ => Script::new(vec![Step::Send(message_chunk("done"))]),
+    });
+
+    let (mut fast, mut slow) = (None, None);
+    let events = run(Decaf::new(Duration::from_secs(60)), agent, async |client| {
+        let slow_id = client.new_session().await?;
+        let fast_id = client.new_session().await?;
+        tokio::try_join!(client.prompt(&slow_id, "slow"), async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            client.prompt(&fast_id, "fast").await
+        })?;
+        (fast, slow) = (Some(fast_id), Some(slow_id));
+        Ok(())
+    })
+    .await?;
+
+    let texts = texts_by_session(&events);
+    let (fast, slow) = (fast.unwrap(), slow.unwrap());
+    assert_eq!(texts[fast.0.as_ref()], vec!["done"]);
+    assert_eq!(texts[slow.0.as_ref()], vec!["still going"]);
+    Ok(())
+}
+
+/// Flushing every session doesn't wait on a slow client: each send only
+/// queues the notification for the transport, so the shutdown flush of 100
+/// sessions is done before a client reading one notification per 100ms has
+/// read the first, rather than after all 100 reads.
+#[tokio::test(start_paused = true)]
+async fn test_flush_all_does_not_wait_for_a_slow_client() -> Result<(), sacp::Error> {
+    const READ_DELAY: Duration = Duration::from_millis(100);
+
+    let shutdown = CancellationToken::new();
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .with_cancellation(shutdown.clone())
+        .build();
+    let stats = decaf.stats_handle();
+    let (mut wire, proxy) = wire(decaf);
+    for n in 0..SESSIONS {
+        let chunk = SessionNotification::new(format!("session-{n}"), message_chunk("text"));
+        wire.send_from_agent("session/update", serde_json::to_value(chunk)?);
+    }
+    while stats.chunks_received() < SESSIONS as u64 {
+        tokio::task::yield_now().await;
+    }
+
+    let started = Instant::now();
+    shutdown.cancel();
+    proxy.await.expect("proxy task")?;
+    let flushed_in = started.elapsed();
+
+    let mut sessions = HashSet::new();
+    for _ in 0..SESSIONS {
+        tokio::time::sleep(READ_DELAY).await;
+        let message = wire.next().await;
+        sessions.insert(message["params"]["sessionId"].to_string());
+    }
+    let read_in = started.elapsed();
+
+    assert_eq!(sessions.len(), SESSIONS);
+    assert!(flushed_in < READ_DELAY, "flush took {flushed_in:?}");
+    assert!(read_in >= READ_DELAY * SESSIONS as u32);
+    Ok(())
+}
@@ -0,0 +1,105 @@
+//! Loading a proxy from a `DecafConfig`, with the `serde` feature.
+
+#![cfg(feature = "serde")]
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, message_texts, run, words};
+use decaf_mod::{Decaf, DecafConfig, DecafError};
+
+/// A config survives a trip through JSON, and the proxy it builds uses it.
+#[tokio::test(start_paused = true)]
+async fn test_config_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+    let config = DecafConfig {
+        name: Some("configured".to_string()),
+        interval_ms: Some(250),
+        max_latency_ms: Some(1000),
+        max_buffer_bytes: Some(4096),
+        max_total_bytes: Some(65536),
+        flush_on_sentence: Some(true),
+        join_with: Some(" ".to_string()),
+        ..DecafConfig::default()
+    };
+    let json = serde_json::to_string(&config)?;
+    assert_eq!(serde_json::from_str::<DecafConfig>(&json)?, config);
+
+    let decaf = Decaf::from_config(config)?;
+    assert_eq!(decaf.name(), "configured");
+    assert_eq!(
+        decaf.control_handle().interval(),
+        Duration::from_millis(250)
+    );
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(words(&["One.", "Two", "three"]))),
+        async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            Ok(())
+        },
+    )
+    .await?;
+    assert_eq!(message_texts(&events), vec!["One. ", "Two three"]);
+    Ok(())
+}
+
+/// An empty config is the builder's defaults, and unknown fields are
+/// rejected rather than ignored.
+#[test]
+fn test_config_defaults_and_unknown_fields() -> Result<(), Box<dyn std::error::Error>> {
+    let config: DecafConfig = serde_json::from_str("{}")?;
+    assert_eq!(config, DecafConfig::default());
+    let decaf = Decaf::from_config(config)?;
+    assert_eq!(
+        decaf.control_handle().interval(),
+        Duration::from_millis(100)
+    );
+
+    let error = serde_json::from_str::<DecafConfig>(r#"{ "interval": 100 }"#).unwrap_err();
+    assert!(
+        error.to_string().contains("unknown field `interval`"),
+        "{error}"
+    );
+    Ok(())
+}
+
+/// Out-of-range values fail with the field named instead of panicking.
+#[test]
+fn test_invalid_config_is_rejected() {
+    let cases = [
+        (
+            DecafConfig {
+                interval_ms: Some(0),
+                ..DecafConfig::default()
+            },
+            "invalid config: interval_ms must be non-zero",
+        ),
+        (
+            DecafConfig {
+                max_emit_bytes: Some(0),
+                ..DecafConfig::default()
+            },
+            "invalid config: max_emit_bytes must be non-zero",
+        ),
+        (
+            DecafConfig {
+                max_buffer_bytes: Some(2048),
+                max_total_bytes: Some(1024),
+                ..DecafConfig::default()
+            },
+            "invalid config: max_buffer_bytes must not exceed max_total_bytes",
+        ),
+    ];
+    for (config, message) in cases {
+        let Err(error) = Decaf::from_config(config) else {
+            panic!("{message}: accepted");
+        };
+        assert!(
+            matches!(error, DecafError::InvalidConfig { .. }),
+            "{error:?}"
+        );
+        assert_eq!(error.to_string(), message);
+    }
+}
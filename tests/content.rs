@@ -0,0 +1,434 @@
+//! Non-text content blocks inside message chunks.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Event, Script, ScriptedAgent, Step, message_chunk, run};
+use decaf_mod::{Decaf, INLINE_ANNOTATIONS_META_KEY};
+use sacp::schema::{
+    Annotations, ContentBlock, ContentChunk, ImageContent, ResourceLink, Role, SessionId,
+    SessionNotification, SessionUpdate, TextContent,
+};
+
+fn image_chunk() -> SessionUpdate {
+    SessionUpdate::AgentMessageChunk(ContentChunk::new(ContentBlock::Image(ImageContent::new(
+        "aGk=",
+        "image/png",
+    ))))
+}
+
+/// Each message-chunk notification the client saw: its text, or `[image]`.
+fn blocks(events: &[Event]) -> Vec<String> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Notification(SessionNotification {
+                update: SessionUpdate::AgentMessageChunk(ContentChunk { content, .. }),
+                ..
+            }) => Some(match content {
+                ContentBlock::Text(tc) => tc.text.clone(),
+                ContentBlock::Image(_) => "[image]".to_string(),
+                ContentBlock::ResourceLink(link) => format!("[{}]", link.name),
+                other => panic!("unexpected block: {other:?}"),
+            }),
+            _ => None,
+        })
+        .collect()
+}
+
+/// An image between text chunks is held in place rather than forcing the
+/// text before it out early.
+#[tokio::test]
+async fn test_image_keeps_its_place() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("Here ")),
+        Step::Send(message_chunk("it is: ")),
+        Step::Send(image_chunk()),
+        Step::Send(message_chunk("nice, ")),
+        Step::Send(message_chunk("right?")),
+    ]));
+
+    let events = run(Decaf::new(Duration::from_secs(60)), agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        blocks(&events),
+        vec!["Here it is: ", "[image]", "nice, right?"]
+    );
+    Ok(())
+}
+
+fn link_chunk(name: &str) -> SessionUpdate {
+    SessionUpdate::AgentMessageChunk(ContentChunk::new(ContentBlock::ResourceLink(
+        ResourceLink::new(name, format!("file:///{name}")),
+    )))
+}
+
+/// A resource link is held in its own session's stream like any other
+/// block: it neither splits that session's text early nor flushes a
+/// session streaming alongside it.
+#[tokio::test]
+async fn test_resource_link_leaves_other_sessions_alone() -> Result<(), sacp::Error> {
+    let other = |update| {
+        Step::Notify(SessionNotification::new(
+            SessionId::new("session-2"),
+            update,
+        ))
+    };
+    let agent = ScriptedAgent::with(move |prompt| match &*prompt.session_id.0 {
+        "session-1" => Script::new(vec![
+            Step::Send(message_chunk("See ")),
+            other(message_chunk("Meanwhile, ")),
+            Step::Send(link_chunk("main.rs")),
+            other(message_chunk("elsewhere")),
+            Step::Send(message_chunk(" for details.")),
+        ]),
+        _ => Script::new(vec![]),
+    });
+
+    let events = run(Decaf::new(Duration::from_secs(60)), agent, async |client| {
+        let linking = client.new_session().await?;
+        let streaming = client.new_session().await?;
+        client.prompt(&linking, "go").await?;
+        client.prompt(&streaming, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    let seen: Vec<String> = events
+        .iter()
+        .map(|event| match event {
+            Event::Response(session_id, _) => format!("{}: done", session_id.0),
+            Event::Notification(notification) => format!(
+                "{}: {}",
+                notification.session_id.0,
+                blocks(std::slice::from_ref(event)).concat()
+            ),
+        })
+        .collect();
+    assert_eq!(
+        seen,
+        vec![
+            "session-1: See ",
+            "session-1: [main.rs]",
+            "session-1:  for details.",
+            "session-1: done",
+            "session-2: Meanwhile, elsewhere",
+            "session-2: done",
+        ]
+    );
+    Ok(())
+}
+
+/// Text split off early (here by sentence) still follows the blocks queued
+/// ahead of it.
+#[tokio::test]
+async fn test_early_split_follows_queued_image() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("Look")),
+        Step::Send(image_chunk()),
+        Step::Send(message_chunk(" here. Then")),
+        Step::Send(message_chunk(" more")),
+    ]));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .flush_on_sentence(true)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        blocks(&events),
+        vec!["Look", "[image]", " here. ", "Then more"]
+    );
+    Ok(())
+}
+
+fn annotated_chunk(text: &str, annotations: Annotations) -> SessionUpdate {
+    SessionUpdate::AgentMessageChunk(ContentChunk::new(ContentBlock::Text(
+        TextContent::new(text).annotations(annotations),
+    )))
+}
+
+/// Text and annotations of each message-chunk notification the client saw.
+fn annotated_texts(events: &[Event]) -> Vec<(String, Option<Annotations>)> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Notification(SessionNotification {
+                update:
+                    SessionUpdate::AgentMessageChunk(ContentChunk {
+                        content: ContentBlock::Text(tc),
+                        ..
+                    }),
+                ..
+            }) => Some((tc.text.clone(), tc.annotations.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Chunks coalesce only with chunks annotated the same way, so every
+/// notification's annotations hold for all of its text.
+#[tokio::test]
+async fn test_annotations_split_text_runs() -> Result<(), sacp::Error> {
+    let for_user = Annotations::new().audience(vec![Role::User]);
+    let urgent = Annotations::new().priority(1.0);
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(annotated_chunk("Shown ", for_user.clone())),
+        Step::Send(annotated_chunk("to you. ", for_user.clone())),
+        Step::Send(annotated_chunk("Important!", urgent.clone())),
+        Step::Send(message_chunk(" Plain ")),
+        Step::Send(message_chunk("text.")),
+    ]));
+
+    let events = run(Decaf::new(Duration::from_secs(60)), agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        annotated_texts(&events),
+        vec![
+            ("Shown to you. ".to_string(), Some(for_user)),
+            ("Important!".to_string(), Some(urgent)),
+            (" Plain text.".to_string(), None),
+        ]
+    );
+    Ok(())
+}
+
+/// A buffer's template outlives a flush; the next run still takes the
+/// annotations of its own first chunk.
+#[tokio::test(start_paused = true)]
+async fn test_annotations_change_after_flush() -> Result<(), sacp::Error> {
+    let urgent = Annotations::new().priority(1.0);
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("plain")),
+        Step::Sleep(Duration::from_millis(200)),
+        Step::Send(annotated_chunk("urgent", urgent.clone())),
+    ]));
+
+    let events = run(
+        Decaf::new(Duration::from_millis(100)),
+        agent,
+        async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            Ok(())
+        },
+    )
+    .await?;
+
+    assert_eq!(
+        annotated_texts(&events),
+        vec![
+            ("plain".to_string(), None),
+            ("urgent".to_string(), Some(urgent)),
+        ]
+    );
+    Ok(())
+}
+
+/// The `INLINE_ANNOTATIONS_META_KEY` list on each message-chunk notification
+/// the client saw.
+fn inline_annotations(events: &[Event]) -> Vec<Option<serde_json::Value>> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Notification(SessionNotification {
+                update:
+                    SessionUpdate::AgentMessageChunk(ContentChunk {
+                        content: ContentBlock::Text(tc),
+                        ..
+                    }),
+                ..
+            }) => Some(
+                tc.meta
+                    .as_ref()
+                    .and_then(|meta| meta.get(INLINE_ANNOTATIONS_META_KEY).cloned()),
+            ),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Annotation-only chunks don't split the text around them: their
+/// annotations are merged into the one notification, and each is listed
+/// with the offset in its text where it arrived.
+#[tokio::test]
+async fn test_annotation_only_chunks_merge_into_the_text() -> Result<(), sacp::Error> {
+    let for_user = Annotations::new().audience(vec![Role::User]);
+    let urgent = Annotations::new().priority(1.0);
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("Note: ")),
+        Step::Send(annotated_chunk("", for_user.clone())),
+        Step::Send(message_chunk("this ")),
+        Step::Send(message_chunk("matters")),
+        Step::Send(annotated_chunk("", urgent.clone())),
+        Step::Send(message_chunk(".")),
+    ]));
+
+    let events = run(Decaf::new(Duration::from_secs(60)), agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        annotated_texts(&events),
+        vec![(
+            "Note: this matters.".to_string(),
+            Some(Annotations::new().audience(vec![Role::User]).priority(1.0)),
+        )]
+    );
+    assert_eq!(
+        inline_annotations(&events),
+        vec![Some(serde_json::json!([
+            { "offset": 6, "annotations": { "audience": ["user"] } },
+            { "offset": 18, "annotations": { "priority": 1.0 } },
+        ]))]
+    );
+    Ok(())
+}
+
+/// When the text around annotation-only chunks is split, each one goes with
+/// the text before it, at an offset into its own notification's text.
+#[tokio::test]
+async fn test_annotation_offsets_follow_splits() -> Result<(), sacp::Error> {
+    let urgent = Annotations::new().priority(1.0);
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("one ")),
+        Step::Send(annotated_chunk("", urgent.clone())),
+        Step::Send(message_chunk("two\nthree ")),
+        Step::Send(annotated_chunk("", urgent.clone())),
+        Step::Send(message_chunk("four")),
+    ]));
+
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .flush_on_newline(true)
+        .build();
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        annotated_texts(&events),
+        vec![
+            ("one two\n".to_string(), Some(urgent.clone())),
+            ("three four".to_string(), Some(urgent.clone())),
+        ]
+    );
+    let listed = |offset| {
+        Some(serde_json::json!([{ "offset": offset, "annotations": { "priority": 1.0 } }]))
+    };
+    assert_eq!(inline_annotations(&events), vec![listed(4), listed(6)]);
+    Ok(())
+}
+
+/// Annotated text through `merge_fn`, with the inline annotations the
+/// client saw.
+async fn merged_annotations(
+    merge_fn: fn(&mut String, &str),
+    steps: Vec<Step>,
+) -> Result<
+    (
+        Vec<(String, Option<Annotations>)>,
+        Vec<Option<serde_json::Value>>,
+    ),
+    sacp::Error,
+> {
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .merge_fn(merge_fn)
+        .build();
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(steps)),
+        async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            Ok(())
+        },
+    )
+    .await?;
+    Ok((annotated_texts(&events), inline_annotations(&events)))
+}
+
+/// A `merge_fn` that only appends, here dropping doubled spaces, keeps an
+/// annotation-only chunk at the offset where the next text starts.
+#[tokio::test]
+async fn test_annotation_offsets_with_merge_fn() -> Result<(), sacp::Error> {
+    let urgent = Annotations::new().priority(1.0);
+    let (texts, inline) = merged_annotations(
+        |buffered, chunk| {
+            for c in chunk.chars() {
+                if c != ' ' || !buffered.ends_with(' ') {
+                    buffered.push(c);
+                }
+            }
+        },
+        vec![
+            Step::Send(message_chunk("Note:  ")),
+            Step::Send(annotated_chunk("", urgent.clone())),
+            Step::Send(message_chunk("  this")),
+        ],
+    )
+    .await?;
+
+    assert_eq!(texts, vec![("Note: this".to_string(), Some(urgent))]);
+    assert_eq!(
+        inline,
+        vec![Some(serde_json::json!([
+            { "offset": 6, "annotations": { "priority": 1.0 } },
+        ]))]
+    );
+    Ok(())
+}
+
+/// A `merge_fn` that shortens the text before an annotation-only chunk
+/// moves its offset back to the end of what is left, rather than losing it.
+#[tokio::test]
+async fn test_merge_fn_shortening_text_keeps_annotations() -> Result<(), sacp::Error> {
+    let urgent = Annotations::new().priority(1.0);
+    let (texts, inline) = merged_annotations(
+        |buffered, chunk| {
+            if chunk.starts_with('.') {
+                buffered.truncate(buffered.trim_end().len());
+            }
+            buffered.push_str(chunk);
+        },
+        vec![
+            Step::Send(message_chunk("Done   ")),
+            Step::Send(annotated_chunk("", urgent.clone())),
+            Step::Send(message_chunk(".")),
+        ],
+    )
+    .await?;
+
+    assert_eq!(texts, vec![("Done.".to_string(), Some(urgent))]);
+    assert_eq!(
+        inline,
+        vec![Some(serde_json::json!([
+            { "offset": 5, "annotations": { "priority": 1.0 } },
+        ]))]
+    );
+    Ok(())
+}
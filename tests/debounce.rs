@@ -2,13 +2,14 @@
 //!
 //! Creates a fast word-dumping agent that sends 20 words as individual
 //! `AgentMessageChunk` notifications with no delay, runs them through
-//! decaf, and verifies the client receives fewer (coalesced) notifications
+//! decaf, and verifies the client receives a single coalesced notification
 //! containing all the original text.
 
 use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
 
-use decaf_mod::Decaf;
+use decaf_mod::{Decaf, MockClock};
 use futures::{SinkExt, StreamExt, channel::mpsc};
 use sacp::schema::{
     AgentCapabilities, ContentBlock, ContentChunk, InitializeRequest, InitializeResponse,
@@ -131,11 +132,16 @@ async fn test_decaf_coalesces_chunks() -> Result<(), sacp::Error> {
     let (client_write, conductor_read) = duplex(8192);
     let (conductor_write, client_read) = duplex(8192);
 
-    // Spawn conductor: FastWordAgent -> Decaf (100ms) -> client
+    // Spawn conductor: FastWordAgent -> Decaf -> client. The mock clock is
+    // never advanced, so only the prompt response flushes and the count is
+    // exact however slowly the test runs.
+    let decaf = Decaf::builder()
+        .with_clock(Arc::new(MockClock::new()))
+        .build();
     let conductor_handle = tokio::spawn(async move {
         ConductorImpl::new_agent(
             "decaf-test-conductor".to_string(),
-            ProxiesAndAgent::new(FastWordAgent).proxy(Decaf::new(Duration::from_millis(100))),
+            ProxiesAndAgent::new(FastWordAgent).proxy(decaf),
             Default::default(),
         )
         .run(sacp::ByteStreams::new(
@@ -216,17 +222,9 @@ async fn test_decaf_coalesces_chunks() -> Result<(), sacp::Error> {
         "Debounced text should contain all original words"
     );
 
-    // Debouncing should coalesce — fewer notifications than words sent.
-    // The agent sends 20 words with no delay, so they should all land
-    // within a single 100ms tick (or at most a few ticks).
-    assert!(
-        texts.len() < WORDS.len(),
-        "Expected fewer notifications ({}) than words sent ({}), \
-         meaning debouncing coalesced chunks. Individual chunks: {:?}",
-        texts.len(),
-        WORDS.len(),
-        texts,
-    );
+    // With no timer, all 20 words are coalesced into the one flush the
+    // prompt response triggers.
+    assert_eq!(texts.len(), 1, "Individual chunks: {texts:?}");
 
     tracing::info!(
         words_sent = WORDS.len(),
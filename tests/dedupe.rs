@@ -0,0 +1,71 @@
+//! Dropping chunks an agent repeats by mistake, with `dedupe_repeats`.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::{Decaf, REPEAT_WINDOW};
+
+async fn texts(decaf: Decaf, steps: Vec<Step>) -> Result<Vec<String>, sacp::Error> {
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(steps)),
+        async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            Ok(())
+        },
+    )
+    .await?;
+    Ok(message_texts(&events))
+}
+
+fn deduping() -> Decaf {
+    Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .dedupe_repeats(true)
+        .build()
+}
+
+fn retried() -> Vec<Step> {
+    vec![
+        Step::Send(message_chunk("Hello, ")),
+        Step::Send(message_chunk("the")),
+        Step::Send(message_chunk("the")),
+        Step::Send(message_chunk(" end")),
+    ]
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_back_to_back_repeat_is_dropped() -> Result<(), sacp::Error> {
+    assert_eq!(texts(deduping(), retried()).await?, vec!["Hello, the end"]);
+    Ok(())
+}
+
+/// Off by default, the repeat is coalesced like any other chunk.
+#[tokio::test(start_paused = true)]
+async fn test_repeats_are_kept_by_default() -> Result<(), sacp::Error> {
+    let decaf = Decaf::new(Duration::from_secs(60));
+    assert_eq!(texts(decaf, retried()).await?, vec!["Hello, thethe end"]);
+    Ok(())
+}
+
+/// Identical chunks further apart than the window, or not consecutive, are
+/// taken to be real text.
+#[tokio::test(start_paused = true)]
+async fn test_spaced_out_repeats_are_kept() -> Result<(), sacp::Error> {
+    let steps = vec![
+        Step::Send(message_chunk("that ")),
+        Step::Sleep(REPEAT_WINDOW * 2),
+        Step::Send(message_chunk("that ")),
+        Step::Send(message_chunk("is ")),
+        Step::Send(message_chunk("it, ")),
+        Step::Send(message_chunk("is ")),
+    ];
+    assert_eq!(
+        texts(deduping(), steps).await?,
+        vec!["that that is it, is "]
+    );
+    Ok(())
+}
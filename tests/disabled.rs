@@ -0,0 +1,58 @@
+//! Forwarding everything untouched with `Decaf::disabled`.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Event, Script, ScriptedAgent, Step, message_texts, run, words};
+use decaf_mod::Decaf;
+
+/// The client sees exactly the notifications the agent sent, one for one.
+#[tokio::test]
+async fn test_disabled_forwards_every_chunk() -> Result<(), sacp::Error> {
+    let sent: Vec<String> = (0..50).map(|i| format!("w{i} ")).collect();
+    let sent_refs: Vec<&str> = sent.iter().map(String::as_str).collect();
+    let agent = ScriptedAgent::new(Script::new(words(&sent_refs)));
+    let decaf = Decaf::disabled();
+    let stats = decaf.stats_handle();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    let notifications = events
+        .iter()
+        .filter(|event| matches!(event, Event::Notification(_)))
+        .count();
+    assert_eq!(notifications, sent.len());
+    assert_eq!(message_texts(&events), sent);
+    assert_eq!(stats.chunks_received(), 0);
+    Ok(())
+}
+
+/// Pauses that would normally flush change nothing, and a drain returns at
+/// once since nothing is ever held.
+#[tokio::test(start_paused = true)]
+async fn test_disabled_ignores_timing_and_drains_at_once() -> Result<(), sacp::Error> {
+    let mut steps = words(&["a ", "b "]);
+    steps.push(Step::Sleep(Duration::from_millis(500)));
+    steps.extend(words(&["c"]));
+    let agent = ScriptedAgent::new(Script::new(steps));
+    let decaf = Decaf::disabled();
+    let control = decaf.control_handle();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        tokio::try_join!(client.prompt(&session, "go"), async {
+            Ok(control.drain().await?)
+        })?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a ", "b ", "c"]);
+    Ok(())
+}
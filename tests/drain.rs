@@ -0,0 +1,73 @@
+//! Flushing from outside the proxy with `DecafControl::drain`.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::{Decaf, DecafError};
+
+fn steps() -> Vec<Step> {
+    vec![
+        Step::Send(message_chunk("a ")),
+        Step::Send(message_chunk("b ")),
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Send(message_chunk("c")),
+    ]
+}
+
+/// A drain flushes mid-turn, long before the interval would.
+#[tokio::test(start_paused = true)]
+async fn test_drain_flushes_immediately() -> Result<(), sacp::Error> {
+    let decaf = Decaf::new(Duration::from_secs(60));
+    let control = decaf.control_handle();
+
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(steps())),
+        async |client| {
+            let session = client.new_session().await?;
+            tokio::try_join!(client.prompt(&session, "go"), async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                Ok(control.drain().await?)
+            })?;
+            Ok(())
+        },
+    )
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a b ", "c"]);
+    Ok(())
+}
+
+/// Drains landing on the same instant as the deadline send nothing twice.
+#[tokio::test(start_paused = true)]
+async fn test_drain_racing_timer_does_not_duplicate() -> Result<(), sacp::Error> {
+    let decaf = Decaf::new(Duration::from_millis(50));
+    let control = decaf.control_handle();
+
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(steps())),
+        async |client| {
+            let session = client.new_session().await?;
+            tokio::try_join!(client.prompt(&session, "go"), async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                tokio::try_join!(control.drain(), control.drain())?;
+                Ok(())
+            })?;
+            Ok(())
+        },
+    )
+    .await?;
+
+    assert_eq!(message_texts(&events).concat(), "a b c");
+    Ok(())
+}
+
+/// Once the proxy is gone, draining reports it.
+#[tokio::test]
+async fn test_drain_after_stop_fails() {
+    let control = Decaf::new(Duration::from_secs(1)).control_handle();
+    assert!(matches!(control.drain().await, Err(DecafError::Stopped)));
+}
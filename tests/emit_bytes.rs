@@ -0,0 +1,75 @@
+//! Splitting large outgoing notifications with `max_emit_bytes`.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{Event, Script, ScriptedAgent, message_chunk, message_texts, run, words};
+use decaf_mod::{Coalescer, Decaf, DecafError, MockClock};
+use sacp::schema::{SessionId, SessionNotification};
+
+/// A whole turn coalesced into one buffer goes out in pieces under the
+/// limit, which the client joins back into the exact text.
+#[tokio::test(start_paused = true)]
+async fn test_client_reassembles_split_flush() -> Result<(), sacp::Error> {
+    let sent: Vec<String> = (0..200).map(|i| format!("word{i} ")).collect();
+    let sent: Vec<&str> = sent.iter().map(String::as_str).collect();
+    let agent = ScriptedAgent::new(Script::new(words(&sent)));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .max_emit_bytes(64)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    let texts = message_texts(&events);
+    assert!(texts.len() > 1, "{texts:?}");
+    assert!(texts.iter().all(|text| text.len() <= 64), "{texts:?}");
+    assert!(texts.iter().all(|text| text.ends_with(' ')), "{texts:?}");
+    assert_eq!(texts.concat(), sent.concat());
+    Ok(())
+}
+
+/// Pieces end after whitespace where there is some, and otherwise on a
+/// character boundary, each a copy of the coalesced notification. Text
+/// under the limit, and the timer, are unchanged.
+#[test]
+fn test_pieces_fall_on_word_then_char_boundaries() -> Result<(), DecafError> {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = Coalescer::new(
+        Decaf::builder()
+            .interval(Duration::from_millis(100))
+            .max_emit_bytes(8)
+            .with_clock(clock.clone())
+            .build(),
+    );
+    let session_id = SessionId::new("s");
+    let chunk = |text| SessionNotification::new(session_id.clone(), message_chunk(text));
+
+    coalescer.push(chunk("a bc def "))?;
+    coalescer.push(chunk("ééééé"))?;
+    assert!(coalescer.tick()?.is_empty());
+    clock.advance(Duration::from_millis(100));
+    let out = coalescer.tick()?;
+    assert!(out.iter().all(|n| n.session_id == session_id));
+    assert_eq!(texts(out), vec!["a bc ", "def ", "éééé", "é"]);
+
+    coalescer.push(chunk("short"))?;
+    coalescer.push(chunk(" and long"))?;
+    assert_eq!(
+        texts(coalescer.end_turn(&session_id)?),
+        vec!["short ", "and long"]
+    );
+    Ok(())
+}
+
+fn texts(out: Vec<SessionNotification>) -> Vec<String> {
+    let events: Vec<_> = out.into_iter().map(Event::Notification).collect();
+    message_texts(&events)
+}
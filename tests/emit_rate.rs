@@ -0,0 +1,82 @@
+//! Capping timer flushes with `max_emit_rate`.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::{Coalescer, Decaf, DecafError, MockClock};
+use sacp::schema::{SessionId, SessionNotification};
+
+/// A two-second stream that would flush every 20ms gets at most one flush
+/// per 200ms, plus the turn end, and loses no text.
+#[tokio::test(start_paused = true)]
+async fn test_emit_rate_caps_timer_flushes() -> Result<(), sacp::Error> {
+    let mut steps = Vec::new();
+    for n in 0..200 {
+        steps.push(Step::Send(message_chunk(&format!("{n} "))));
+        steps.push(Step::Sleep(Duration::from_millis(10)));
+    }
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(20))
+        .max_emit_rate(5)
+        .build();
+
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(steps)),
+        async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            Ok(())
+        },
+    )
+    .await?;
+
+    let texts = message_texts(&events);
+    assert!(
+        texts.len() <= 1 + 5 * 2 + 1,
+        "{} notifications",
+        texts.len()
+    );
+    let expected: String = (0..200).map(|n| format!("{n} ")).collect();
+    assert_eq!(texts.concat(), expected);
+    Ok(())
+}
+
+/// Sessions coming due together are flushed one token at a time, oldest
+/// first, and the rest merge what arrives meanwhile.
+#[test]
+fn test_emit_rate_spreads_sessions_due_together() -> Result<(), DecafError> {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = Coalescer::new(
+        Decaf::builder()
+            .interval(Duration::from_millis(100))
+            .max_emit_rate(10)
+            .with_clock(clock.clone())
+            .build(),
+    );
+    let chunk = |session: usize, text: &str| {
+        SessionNotification::new(SessionId::new(format!("{session}")), message_chunk(text))
+    };
+    for session in 0..20 {
+        coalescer.push(chunk(session, "a"))?;
+        clock.advance(Duration::from_micros(10));
+    }
+
+    let mut flushed = Vec::new();
+    for _ in 0..100 {
+        clock.advance(Duration::from_millis(10));
+        for session in 0..20 {
+            coalescer.push(chunk(session, "b"))?;
+        }
+        flushed.extend(coalescer.tick()?);
+    }
+
+    // One second past the deadline: one flush straight away, then one per
+    // 100ms.
+    let sessions: Vec<_> = flushed.iter().map(|n| n.session_id.0.to_string()).collect();
+    assert_eq!(sessions, (0..10).map(|n| n.to_string()).collect::<Vec<_>>());
+    Ok(())
+}
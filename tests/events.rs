@@ -0,0 +1,85 @@
+//! Subscribing to flushes with `Decaf::event_broadcast`.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run, words};
+use decaf_mod::{Coalescer, Decaf, DecafError, FlushEvent, MockClock};
+use sacp::schema::{SessionId, SessionNotification};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+/// Every subscriber sees one event per coalesced notification, in the order
+/// the client received them.
+#[tokio::test(start_paused = true)]
+async fn test_events_arrive_in_flush_order() -> Result<(), sacp::Error> {
+    let mut steps = words(&["a ", "bb ", "ccc "]);
+    steps.push(Step::Sleep(Duration::from_millis(150)));
+    steps.extend(words(&["dddd ", "eeeee"]));
+    let decaf = Decaf::new(Duration::from_millis(100));
+    let mut first = decaf.event_broadcast();
+    let mut second = first.resubscribe();
+
+    let mut prompted = None;
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(steps)),
+        async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            prompted = Some(session);
+            Ok(())
+        },
+    )
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a bb ccc ", "dddd eeeee"]);
+    let session_id = prompted.expect("prompted");
+    let expected = vec![
+        FlushEvent {
+            session_id: session_id.clone(),
+            byte_len: 9,
+            chunk_count: 3,
+        },
+        FlushEvent {
+            session_id,
+            byte_len: 10,
+            chunk_count: 2,
+        },
+    ];
+    for subscriber in [&mut first, &mut second] {
+        let mut seen = Vec::new();
+        while let Ok(event) = subscriber.recv().await {
+            seen.push(event);
+        }
+        assert_eq!(seen, expected);
+    }
+    Ok(())
+}
+
+/// A subscriber that falls behind loses the oldest events instead of
+/// holding up the flush.
+#[tokio::test]
+async fn test_slow_subscriber_lags() -> Result<(), DecafError> {
+    let decaf = Decaf::builder()
+        .flush_every_chunks(1)
+        .with_clock(Arc::new(MockClock::new()))
+        .build();
+    let mut events = decaf.event_broadcast();
+    let mut coalescer = Coalescer::new(decaf);
+    let session_id = SessionId::new("s");
+    for _ in 0..300 {
+        let chunk = SessionNotification::new(session_id.clone(), message_chunk("x"));
+        assert_eq!(coalescer.push(chunk)?.len(), 1);
+    }
+
+    assert!(matches!(events.recv().await, Err(RecvError::Lagged(44))));
+    let mut held = 0;
+    while events.try_recv().is_ok() {
+        held += 1;
+    }
+    assert_eq!(held, 256);
+    assert!(matches!(events.try_recv(), Err(TryRecvError::Empty)));
+    Ok(())
+}
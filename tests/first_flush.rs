@@ -0,0 +1,52 @@
+//! A shorter window for each reply's first flush.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::{Decaf, DecafBuilder};
+
+/// Twelve words, one every 20ms.
+fn steady_words() -> Script {
+    let mut steps = Vec::new();
+    for word in [
+        "a ", "b ", "c ", "d ", "e ", "f ", "g ", "h ", "i ", "j ", "k ", "l ",
+    ] {
+        steps.push(Step::Send(message_chunk(word)));
+        steps.push(Step::Sleep(Duration::from_millis(20)));
+    }
+    Script::new(steps)
+}
+
+async fn replies(builder: DecafBuilder) -> Result<Vec<String>, sacp::Error> {
+    let decaf = builder.interval(Duration::from_millis(150)).build();
+    let events = run(decaf, ScriptedAgent::new(steady_words()), async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "one").await?;
+        client.prompt(&session, "two").await?;
+        Ok(())
+    })
+    .await?;
+    Ok(message_texts(&events))
+}
+
+/// The first flush of each reply comes after 30ms, well before the 150ms
+/// interval the rest of the reply is coalesced over.
+#[tokio::test(start_paused = true)]
+async fn test_first_flush_lands_early() -> Result<(), sacp::Error> {
+    let reply = ["a b ", "c d e f g h i j ", "k l "];
+    assert_eq!(
+        replies(Decaf::builder().first_flush_after(Duration::from_millis(30))).await?,
+        [reply, reply].concat()
+    );
+    Ok(())
+}
+
+/// Without it, the first flush waits for the whole interval.
+#[tokio::test(start_paused = true)]
+async fn test_first_flush_waits_by_default() -> Result<(), sacp::Error> {
+    let reply = ["a b c d e f g h ", "i j k l "];
+    assert_eq!(replies(Decaf::builder()).await?, [reply, reply].concat());
+    Ok(())
+}
@@ -0,0 +1,80 @@
+//! Logging each flush decision under `decaf_mod::flush`.
+
+mod common;
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::message_chunk;
+use decaf_mod::{Coalescer, Decaf, DecafError, MockClock};
+use sacp::schema::{SessionId, SessionNotification};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Collects everything logged, for the test to read back.
+#[derive(Clone, Default)]
+struct Captured(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Captured {
+    type Writer = Captured;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+/// A newline split, a timer flush and a turn end each log their own reason,
+/// with the text and chunks that went out.
+#[test]
+fn test_flushes_are_logged_with_their_reason() -> Result<(), DecafError> {
+    let captured = Captured::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter("decaf_mod::flush=debug")
+        .with_writer(captured.clone())
+        .with_ansi(false)
+        .finish();
+
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = Coalescer::new(
+        Decaf::builder()
+            .interval(Duration::from_millis(100))
+            .flush_on_newline(true)
+            .with_clock(clock.clone())
+            .build(),
+    );
+    let session_id = SessionId::new("s");
+    let chunk = |text| SessionNotification::new(session_id.clone(), message_chunk(text));
+    tracing::subscriber::with_default(subscriber, || -> Result<(), DecafError> {
+        coalescer.push(chunk("one "))?;
+        coalescer.push(chunk("line\nand "))?;
+        coalescer.push(chunk("more"))?;
+        clock.advance(Duration::from_millis(100));
+        coalescer.tick()?;
+        coalescer.push(chunk("done"))?;
+        coalescer.end_turn(&session_id)?;
+        Ok(())
+    })?;
+
+    let log = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+    let lines: Vec<_> = log.lines().collect();
+    assert_eq!(lines.len(), 3, "{log}");
+    for (line, fields) in lines.iter().zip([
+        r#"reason="newline" session_id=s bytes=9 chunks=2"#,
+        r#"reason="timer" session_id=s bytes=8 chunks=2"#,
+        r#"reason="prompt_response" session_id=s bytes=4 chunks=1"#,
+    ]) {
+        assert!(line.contains(fields), "{line}");
+    }
+    Ok(())
+}
@@ -0,0 +1,67 @@
+//! Driving flushes by hand with `with_flush_signal`.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::Decaf;
+use tokio::sync::mpsc;
+
+/// Pauses far longer than the interval don't flush; only the signal and the
+/// prompt response do.
+#[tokio::test(start_paused = true)]
+async fn test_flush_signal_replaces_timer() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("a ")),
+        Step::Send(message_chunk("b ")),
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Send(message_chunk("c ")),
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Send(message_chunk("d")),
+    ]));
+    let (flush, flush_signal) = mpsc::channel(1);
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(10))
+        .with_flush_signal(flush_signal)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        tokio::try_join!(client.prompt(&session, "go"), async {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            flush
+                .send(())
+                .await
+                .map_err(|_| sacp::Error::internal_error())
+        })?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a b c ", "d"]);
+    Ok(())
+}
+
+/// Dropping the sender stops manual flushes but not the turn-end flush.
+#[tokio::test(start_paused = true)]
+async fn test_closed_flush_signal_still_flushes_turn() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("a ")),
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Send(message_chunk("b")),
+    ]));
+    let (flush, flush_signal) = mpsc::channel(1);
+    drop(flush);
+    let decaf = Decaf::builder().with_flush_signal(flush_signal).build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a b"]);
+    Ok(())
+}
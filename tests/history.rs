@@ -0,0 +1,106 @@
+//! Recent flushes kept with `with_history` and read from `DecafControl`.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{Event, Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::{Coalescer, Decaf, DecafControl, DecafError, FlushRecord, MockClock};
+use sacp::schema::{SessionId, SessionNotification};
+
+fn texts(records: Vec<FlushRecord>) -> Vec<String> {
+    let events: Vec<_> = records
+        .into_iter()
+        .map(|record| Event::Notification(record.notification))
+        .collect();
+    message_texts(&events)
+}
+
+/// A `Coalescer` keeping three notifications, with its control handle and
+/// clock.
+fn coalescer() -> (Coalescer, DecafControl, Arc<MockClock>) {
+    let clock = Arc::new(MockClock::new());
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(100))
+        .with_history(3)
+        .with_clock(clock.clone())
+        .build();
+    let control = decaf.control_handle();
+    (Coalescer::new(decaf), control, clock)
+}
+
+fn flush(
+    coalescer: &mut Coalescer,
+    clock: &MockClock,
+    session: &str,
+    text: &str,
+) -> Result<(), DecafError> {
+    let chunk = SessionNotification::new(SessionId::new(session), message_chunk(text));
+    coalescer.push(chunk)?;
+    clock.advance(Duration::from_millis(100));
+    assert_eq!(coalescer.tick()?.len(), 1);
+    Ok(())
+}
+
+/// Only the last `capacity` flushes are kept, oldest first.
+#[test]
+fn test_history_keeps_the_most_recent() -> Result<(), DecafError> {
+    let (mut coalescer, control, clock) = coalescer();
+    for text in ["one", "two", "three", "four", "five"] {
+        flush(&mut coalescer, &clock, "s", text)?;
+    }
+    assert_eq!(texts(control.history()), vec!["three", "four", "five"]);
+    Ok(())
+}
+
+/// `recent` narrows the history to one session and a window back from now.
+#[test]
+fn test_recent_filters_by_session_and_age() -> Result<(), DecafError> {
+    let (mut coalescer, control, clock) = coalescer();
+    flush(&mut coalescer, &clock, "a", "old")?;
+    clock.advance(Duration::from_secs(60));
+    flush(&mut coalescer, &clock, "b", "other")?;
+    flush(&mut coalescer, &clock, "a", "new")?;
+
+    let a = SessionId::new("a");
+    assert_eq!(
+        texts(control.recent(&a, Duration::from_secs(30))),
+        vec!["new"]
+    );
+    assert_eq!(
+        texts(control.recent(&a, Duration::from_secs(90))),
+        vec!["old", "new"]
+    );
+    Ok(())
+}
+
+/// The proxy records what it sends the client; without `with_history`
+/// nothing is kept.
+#[tokio::test(start_paused = true)]
+async fn test_proxy_history() -> Result<(), sacp::Error> {
+    for (decaf, expected) in [
+        (Decaf::builder().with_history(2), vec!["b", "c"]),
+        (Decaf::builder(), vec![]),
+    ] {
+        let agent = ScriptedAgent::new(Script::new(vec![
+            Step::Send(message_chunk("a")),
+            Step::Sleep(Duration::from_secs(1)),
+            Step::Send(message_chunk("b")),
+            Step::Sleep(Duration::from_secs(1)),
+            Step::Send(message_chunk("c")),
+        ]));
+        let decaf = decaf.interval(Duration::from_millis(100)).build();
+        let control = decaf.control_handle();
+        let events = run(decaf, agent, async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            Ok(())
+        })
+        .await?;
+
+        assert_eq!(message_texts(&events), vec!["a", "b", "c"]);
+        assert_eq!(texts(control.history()), expected);
+    }
+    Ok(())
+}
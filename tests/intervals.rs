@@ -0,0 +1,143 @@
+//! Per-session flush deadlines.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::Decaf;
+
+fn paused_words() -> Script {
+    Script::new(vec![
+        Step::Send(message_chunk("a ")),
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Send(message_chunk("b ")),
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Send(message_chunk("c ")),
+    ])
+}
+
+/// A session with a short interval flushes between pauses; one with a long
+/// interval holds everything until the prompt response.
+#[tokio::test(start_paused = true)]
+async fn test_interval_for_overrides_per_session() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(paused_words());
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(10))
+        .interval_for(|session_id| {
+            if &*session_id.0 == "session-1" {
+                Duration::from_millis(50)
+            } else {
+                Duration::from_secs(10)
+            }
+        })
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let fast = client.new_session().await?;
+        client.prompt(&fast, "go").await?;
+        let slow = client.new_session().await?;
+        client.prompt(&slow, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a ", "b ", "c ", "a b c "]);
+    Ok(())
+}
+
+/// A zero interval from `interval_for` is raised to the minimum rather than
+/// reaching the timer.
+#[tokio::test(start_paused = true)]
+async fn test_interval_for_is_raised_to_min_interval() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(paused_words());
+    let decaf = Decaf::builder().interval_for(|_| Duration::ZERO).build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a ", "b ", "c "]);
+    Ok(())
+}
+
+/// `max_latency` caps the wait even when the interval is much longer.
+#[tokio::test(start_paused = true)]
+async fn test_max_latency_caps_interval() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(paused_words());
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(10))
+        .max_latency(Duration::from_millis(50))
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a ", "b ", "c "]);
+    Ok(())
+}
+
+/// A burst followed by silence flushes after `quiet_period`, long before
+/// the interval.
+#[tokio::test(start_paused = true)]
+async fn test_quiet_period_flushes_idle_session() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("a ")),
+        Step::Send(message_chunk("b ")),
+        Step::Send(message_chunk("c ")),
+        Step::Sleep(Duration::from_millis(200)),
+        Step::Send(message_chunk("d ")),
+    ]));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(10))
+        .quiet_period(Duration::from_millis(50))
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a b c ", "d "]);
+    Ok(())
+}
+
+/// A stream that never pauses for `quiet_period` still flushes on its
+/// interval.
+#[tokio::test(start_paused = true)]
+async fn test_steady_stream_flushes_on_interval() -> Result<(), sacp::Error> {
+    let steps = ["a ", "b ", "c ", "d ", "e "]
+        .into_iter()
+        .flat_map(|word| {
+            [
+                Step::Send(message_chunk(word)),
+                Step::Sleep(Duration::from_millis(30)),
+            ]
+        })
+        .collect();
+    let agent = ScriptedAgent::new(Script::new(steps));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(100))
+        .quiet_period(Duration::from_millis(50))
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    // Chunks at 0, 30, 60 and 90 flush at 100; the prompt response flushes the last.
+    assert_eq!(message_texts(&events), vec!["a b c d ", "e "]);
+    Ok(())
+}
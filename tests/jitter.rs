@@ -0,0 +1,64 @@
+//! Staggering session deadlines with `jitter`.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::message_chunk;
+use decaf_mod::{Coalescer, Decaf, DecafBuilder, DecafError, MockClock};
+use sacp::schema::{SessionId, SessionNotification};
+
+const SESSIONS: usize = 20;
+
+/// Start `SESSIONS` sessions at once, then step the clock 10ms at a time
+/// until `until`, returning how many sessions flushed at each step.
+fn flushes_per_step(builder: DecafBuilder, until: Duration) -> Result<Vec<usize>, DecafError> {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = Coalescer::new(builder.with_clock(clock.clone()).build());
+    for session in 0..SESSIONS {
+        let session_id = SessionId::new(format!("session-{session}"));
+        coalescer.push(SessionNotification::new(session_id, message_chunk("hi")))?;
+    }
+    let step = Duration::from_millis(10);
+    let mut flushes = Vec::new();
+    for _ in 0..until.as_millis() / step.as_millis() {
+        clock.advance(step);
+        flushes.push(coalescer.tick()?.len());
+    }
+    Ok(flushes)
+}
+
+fn builder() -> DecafBuilder {
+    Decaf::builder().interval(Duration::from_millis(100))
+}
+
+/// Without jitter, sessions started together flush together.
+#[test]
+fn test_no_jitter_flushes_together() -> Result<(), DecafError> {
+    let flushes = flushes_per_step(builder(), Duration::from_millis(200))?;
+    assert_eq!(flushes[9], SESSIONS);
+    assert_eq!(flushes.iter().sum::<usize>(), SESSIONS);
+    Ok(())
+}
+
+#[test]
+fn test_jitter_spreads_flushes_over_the_window() -> Result<(), DecafError> {
+    let jittered = builder().jitter(Duration::from_millis(100));
+    let flushes = flushes_per_step(jittered, Duration::from_millis(200))?;
+    // Never early, always within interval + jitter.
+    assert!(flushes[..9].iter().all(|&n| n == 0), "{flushes:?}");
+    assert_eq!(flushes.iter().sum::<usize>(), SESSIONS);
+    assert!(flushes.iter().all(|&n| n < SESSIONS), "{flushes:?}");
+    Ok(())
+}
+
+#[test]
+fn test_max_latency_caps_jitter() -> Result<(), DecafError> {
+    let jittered = builder()
+        .jitter(Duration::from_secs(10))
+        .max_latency(Duration::from_millis(150));
+    let flushes = flushes_per_step(jittered, Duration::from_millis(150))?;
+    assert_eq!(flushes.iter().sum::<usize>(), SESSIONS);
+    Ok(())
+}
@@ -0,0 +1,64 @@
+//! Spacing bare tokens with `join_with`.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{Event, Script, ScriptedAgent, Step, message_chunk, message_texts, run, words};
+use decaf_mod::{Coalescer, Decaf, DecafError, MockClock};
+use sacp::schema::{SessionId, SessionNotification};
+
+/// Space-less tokens come out spaced, with no separator leading a
+/// notification after a timer flush.
+#[tokio::test(start_paused = true)]
+async fn test_join_with_spaces_bare_tokens() -> Result<(), sacp::Error> {
+    let mut steps = words(&["Hello", "there,", "world."]);
+    steps.push(Step::Sleep(Duration::from_millis(150)));
+    steps.extend(words(&["How", "are", "you?"]));
+    let agent = ScriptedAgent::new(Script::new(steps));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(100))
+        .join_with(" ")
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        message_texts(&events),
+        vec!["Hello there, world.", "How are you?"]
+    );
+    Ok(())
+}
+
+/// Empty chunks add no separator, and the rest of a split buffer still
+/// gets one before the next chunk.
+#[test]
+fn test_join_with_skips_empty_chunks() -> Result<(), DecafError> {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = Coalescer::new(
+        Decaf::builder()
+            .interval(Duration::from_millis(100))
+            .join_with(" ")
+            .max_buffer_bytes(6)
+            .with_clock(clock.clone())
+            .build(),
+    );
+    let session_id = SessionId::new("s");
+    let chunk = |text| SessionNotification::new(session_id.clone(), message_chunk(text));
+
+    let mut out = Vec::new();
+    for text in ["", "a", "", "b", "cdefg", "h"] {
+        out.extend(coalescer.push(chunk(text))?);
+    }
+    out.extend(coalescer.end_turn(&session_id)?);
+
+    let events: Vec<_> = out.into_iter().map(Event::Notification).collect();
+    assert_eq!(message_texts(&events), vec!["a b cd", "efg h"]);
+    Ok(())
+}
@@ -0,0 +1,51 @@
+//! Chunks an agent sends after its prompt response.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Event, Script, ScriptedAgent, Step, message_chunk, message_texts, run, words};
+use decaf_mod::Decaf;
+
+/// A chunk after the response is forwarded as it arrives instead of
+/// waiting in a session whose turn is over; the next turn coalesces again.
+#[tokio::test(start_paused = true)]
+async fn test_late_chunk_is_forwarded() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(&["all ", "done"])).late(vec![
+        Step::Sleep(Duration::from_millis(1)),
+        Step::Send(message_chunk("oh, ")),
+        Step::Send(message_chunk("one more thing")),
+    ]));
+
+    let events = run(Decaf::new(Duration::from_secs(60)), agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        // Well short of the interval: only forwarding gets the late chunks
+        // here before the next prompt.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        client.prompt(&session, "again").await?;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        message_texts(&events),
+        vec![
+            "all done",
+            "oh, ",
+            "one more thing",
+            "all done",
+            "oh, ",
+            "one more thing"
+        ]
+    );
+    let responses: Vec<_> = events
+        .iter()
+        .enumerate()
+        .filter(|(_, event)| matches!(event, Event::Response(..)))
+        .map(|(at, _)| at)
+        .collect();
+    assert_eq!(responses, vec![1, 5]);
+    Ok(())
+}
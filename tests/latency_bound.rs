@@ -0,0 +1,254 @@
+//! `max_latency` holds under load: no text waits longer than it from its
+//! own arrival, however fast chunks keep coming.
+//!
+//! The interval is always longer than `max_latency`, so a timer that only
+//! looked at ages on interval boundaries, or restamped a session on every
+//! chunk, would let text wait too long.
+
+mod common;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, run, thought_chunk};
+use decaf_mod::{Clock, Coalescer, Decaf, MockClock};
+use proptest::prelude::*;
+use sacp::schema::{ContentBlock, ContentChunk, SessionId, SessionNotification, SessionUpdate};
+use tokio::time::Instant;
+
+const MAX_LATENCY: Duration = Duration::from_millis(40);
+
+/// The text of a message or thought chunk, and whether it is a thought.
+fn text(update: &SessionUpdate) -> Option<(bool, &str)> {
+    match update {
+        SessionUpdate::AgentMessageChunk(ContentChunk {
+            content: ContentBlock::Text(tc),
+            ..
+        }) => Some((false, &tc.text)),
+        SessionUpdate::AgentThoughtChunk(ContentChunk {
+            content: ContentBlock::Text(tc),
+            ..
+        }) => Some((true, &tc.text)),
+        _ => None,
+    }
+}
+
+/// Through the proxy and its flush task: a chunk every 7ms for 1.4s, each
+/// naming its own index, is sent on within `max_latency` of arriving.
+#[tokio::test(start_paused = true)]
+async fn test_proxy_honors_max_latency_under_load() -> Result<(), sacp::Error> {
+    const CHUNKS: u64 = 200;
+    const GAP: Duration = Duration::from_millis(7);
+    let mut steps = Vec::new();
+    for i in 0..CHUNKS {
+        steps.push(Step::Send(message_chunk(&format!("{i} "))));
+        steps.push(Step::Sleep(GAP));
+    }
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(500))
+        .max_latency(MAX_LATENCY)
+        .with_history(CHUNKS as usize)
+        .build();
+    let control = decaf.control_handle();
+
+    let mut start = None;
+    run(
+        decaf,
+        ScriptedAgent::new(Script::new(steps)),
+        async |client| {
+            let session = client.new_session().await?;
+            start = Some(Instant::now());
+            client.prompt(&session, "go").await?;
+            Ok(())
+        },
+    )
+    .await?;
+
+    let start = start.unwrap();
+    let mut seen = 0;
+    for record in control.history() {
+        let (_, text) = text(&record.notification.update).expect("only text is sent");
+        for index in text.split_whitespace() {
+            let index: u64 = index.parse().unwrap();
+            assert_eq!(index, seen, "chunks out of order");
+            seen += 1;
+            let arrived = start + GAP * index as u32;
+            let waited = record.at - arrived;
+            assert!(
+                waited <= MAX_LATENCY,
+                "chunk {index} waited {waited:?}, over {MAX_LATENCY:?}"
+            );
+        }
+    }
+    assert_eq!(seen, CHUNKS);
+    Ok(())
+}
+
+/// The flush triggers and stream of one run.
+#[derive(Clone, Debug)]
+struct Load {
+    interval: u64,
+    max_latency: u64,
+    flush_on_sentence: bool,
+    flush_on_newline: bool,
+    max_buffer_bytes: Option<usize>,
+    quiet_period: Option<u64>,
+    first_flush_after: Option<u64>,
+    leading_edge: bool,
+    /// Per chunk: the gap before it, its session, whether it is a thought,
+    /// and its text.
+    chunks: Vec<(u64, usize, bool, String)>,
+}
+
+fn load() -> impl Strategy<Value = Load> {
+    (
+        (1..60u64, 1..300u64),
+        (
+            any::<bool>(),
+            any::<bool>(),
+            proptest::option::of(1..16usize),
+        ),
+        (
+            proptest::option::of(1..100u64),
+            proptest::option::of(1..100u64),
+            any::<bool>(),
+        ),
+        proptest::collection::vec(
+            (0..10u64, 0..2usize, any::<bool>(), "[ab .\n]{1,8}"),
+            1..150,
+        ),
+    )
+        .prop_map(
+            |(
+                (max_latency, longer),
+                (flush_on_sentence, flush_on_newline, max_buffer_bytes),
+                (quiet_period, first_flush_after, leading_edge),
+                chunks,
+            )| Load {
+                interval: max_latency + longer,
+                max_latency,
+                flush_on_sentence,
+                flush_on_newline,
+                max_buffer_bytes,
+                quiet_period,
+                first_flush_after,
+                leading_edge,
+                chunks,
+            },
+        )
+}
+
+impl Load {
+    fn build(&self, clock: Arc<MockClock>) -> Decaf {
+        let ms = Duration::from_millis;
+        let mut builder = Decaf::builder()
+            .interval(ms(self.interval))
+            .max_latency(ms(self.max_latency))
+            .flush_on_sentence(self.flush_on_sentence)
+            .flush_on_newline(self.flush_on_newline)
+            .leading_edge(self.leading_edge)
+            .with_clock(clock);
+        if let Some(max_bytes) = self.max_buffer_bytes {
+            builder = builder.max_buffer_bytes(max_bytes);
+        }
+        if let Some(quiet_period) = self.quiet_period {
+            builder = builder.quiet_period(ms(quiet_period));
+        }
+        if let Some(first_flush_after) = self.first_flush_after {
+            builder = builder.first_flush_after(ms(first_flush_after.min(self.interval)));
+        }
+        builder.build()
+    }
+}
+
+/// When each byte still unsent arrived, per session and stream.
+type Arrivals = HashMap<(String, bool), VecDeque<(Instant, usize)>>;
+
+/// Take `sent` off the front of `arrivals`, returning the longest any of
+/// its bytes waited until `now`.
+fn waited(arrivals: &mut Arrivals, sent: &[SessionNotification], now: Instant) -> Duration {
+    let mut longest = Duration::ZERO;
+    for notification in sent {
+        let Some((thought, text)) = text(&notification.update) else {
+            continue;
+        };
+        let queue = arrivals
+            .get_mut(&(notification.session_id.0.to_string(), thought))
+            .expect("text from a stream nothing was pushed to");
+        let mut len = text.len();
+        while len > 0 {
+            let (arrived, left) = queue.front_mut().expect("more text out than in");
+            longest = longest.max(now - *arrived);
+            let taken = len.min(*left);
+            *left -= taken;
+            len -= taken;
+            if *left == 0 {
+                queue.pop_front();
+            }
+        }
+    }
+    longest
+}
+
+/// Run `load` through a `Coalescer`, ticking at exactly each
+/// `next_deadline` as a timer would, and return the longest any byte
+/// waited.
+fn longest_wait(load: &Load) -> Result<Duration, TestCaseError> {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = Coalescer::new(load.build(clock.clone()));
+    let mut arrivals = Arrivals::new();
+    let mut arrive_at = clock.now();
+    let mut chunks = load.chunks.iter().peekable();
+    let mut longest = Duration::ZERO;
+    for _ in 0..10 * load.chunks.len() + 10 {
+        if let Some((gap, ..)) = chunks.peek() {
+            arrive_at = arrive_at.max(clock.now()) + Duration::from_millis(*gap);
+        }
+        let next = match (chunks.peek().map(|_| arrive_at), coalescer.next_deadline()) {
+            (Some(arrival), Some(deadline)) if deadline < arrival => Some((deadline, false)),
+            (Some(arrival), _) => Some((arrival, true)),
+            (None, Some(deadline)) => Some((deadline, false)),
+            (None, None) => None,
+        };
+        let Some((at, is_chunk)) = next else {
+            return Ok(longest);
+        };
+        clock.advance(at.saturating_duration_since(clock.now()));
+        let out = if is_chunk {
+            let (_, session, thought, text) = chunks.next().unwrap();
+            arrivals
+                .entry((format!("s{session}"), *thought))
+                .or_default()
+                .push_back((clock.now(), text.len()));
+            let update = match thought {
+                true => thought_chunk(text),
+                false => message_chunk(text),
+            };
+            let session_id = SessionId::new(format!("s{session}"));
+            coalescer.push(SessionNotification::new(session_id, update))
+        } else {
+            // Push the next arrival back so its gap is measured from here.
+            arrive_at = clock.now();
+            coalescer.tick()
+        };
+        let out = out.map_err(|e| TestCaseError::fail(e.to_string()))?;
+        longest = longest.max(waited(&mut arrivals, &out, clock.now()));
+    }
+    Err(TestCaseError::fail("the timer never ran dry"))
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(256))]
+
+    #[test]
+    fn test_no_text_waits_past_max_latency(load in load()) {
+        let longest = longest_wait(&load)?;
+        prop_assert!(
+            longest <= Duration::from_millis(load.max_latency),
+            "text waited {:?}, over {}ms",
+            longest,
+            load.max_latency
+        );
+    }
+}
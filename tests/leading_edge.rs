@@ -0,0 +1,33 @@
+//! Leading-edge mode: the first chunk of every reply is forwarded at once.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, message_texts, run, words};
+use decaf_mod::Decaf;
+
+/// Each of two back-to-back prompts gets its first chunk forwarded alone,
+/// with the rest of the reply coalesced behind it.
+#[tokio::test]
+async fn test_leading_edge_rearms_per_prompt() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(&["one ", "two ", "three "])));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .leading_edge(true)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "first").await?;
+        client.prompt(&session, "second").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        message_texts(&events),
+        vec!["one ", "two three ", "one ", "two three "]
+    );
+    Ok(())
+}
@@ -0,0 +1,302 @@
+//! Coalescing never loses, duplicates or reorders text, whatever flushes it.
+//!
+//! Random chunk sequences, interleaved with other updates, turn ends and
+//! time passing, go through a `Coalescer` configured with a random mix of
+//! flush triggers. Per session, the text that comes out must be exactly the
+//! text that went in, each stream in order and nothing moved across another
+//! update.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{message_chunk, thought_chunk};
+use decaf_mod::{Coalescer, Decaf, DecafError, MockClock};
+use proptest::prelude::*;
+use regex::Regex;
+use sacp::schema::{
+    ContentBlock, ContentChunk, SessionId, SessionNotification, SessionUpdate, ToolCall,
+};
+
+const SESSIONS: usize = 2;
+
+/// What the agent sends or the caller does next.
+#[derive(Clone, Debug)]
+enum Op {
+    Chunk {
+        session: usize,
+        thought: bool,
+        text: String,
+    },
+    /// Any update that isn't text, which text must never cross.
+    Update {
+        session: usize,
+    },
+    /// Let time pass, then tick.
+    Wait(u64),
+    EndTurn(usize),
+}
+
+/// A session's traffic as the client would render it.
+#[derive(Clone, Debug, PartialEq)]
+enum Item {
+    Text { thought: bool, text: String },
+    Update(String),
+}
+
+/// The flush triggers switched on for one run.
+#[derive(Clone, Debug)]
+struct Config {
+    flush_on_sentence: bool,
+    flush_on_newline: bool,
+    flush_on_paragraph: bool,
+    flush_on_pattern: bool,
+    max_buffer_bytes: Option<usize>,
+    split_on_word_boundary: bool,
+    flush_every_chunks: Option<usize>,
+    flush_every_tokens: Option<usize>,
+    leading_edge: bool,
+    passthrough_large: Option<usize>,
+    auto_passthrough_above: Option<usize>,
+    quiet_period: Option<u64>,
+    max_latency: Option<u64>,
+    thought_interval: Option<u64>,
+    first_flush_after: Option<u64>,
+    max_emit_bytes: Option<usize>,
+    max_emit_rate: Option<u32>,
+    max_sessions: Option<usize>,
+}
+
+impl Config {
+    fn build(&self, clock: Arc<MockClock>) -> Decaf {
+        let mut builder = Decaf::builder()
+            .interval(Duration::from_millis(100))
+            .flush_on_sentence(self.flush_on_sentence)
+            .flush_on_newline(self.flush_on_newline)
+            .flush_on_paragraph(self.flush_on_paragraph)
+            .split_on_word_boundary(self.split_on_word_boundary)
+            .leading_edge(self.leading_edge)
+            .with_clock(clock);
+        if self.flush_on_pattern {
+            builder = builder.flush_on_pattern(Regex::new("(?m)^```").unwrap());
+        }
+        if let Some(max_bytes) = self.max_buffer_bytes {
+            builder = builder.max_buffer_bytes(max_bytes);
+        }
+        if let Some(chunks) = self.flush_every_chunks {
+            builder = builder.flush_every_chunks(chunks);
+        }
+        if let Some(tokens) = self.flush_every_tokens {
+            builder = builder.flush_every_tokens(tokens, |text| text.split_whitespace().count());
+        }
+        if let Some(threshold) = self.passthrough_large {
+            builder = builder.passthrough_large(threshold);
+        }
+        if let Some(avg_bytes) = self.auto_passthrough_above {
+            builder = builder.auto_passthrough_above(avg_bytes);
+        }
+        if let Some(ms) = self.quiet_period {
+            builder = builder.quiet_period(Duration::from_millis(ms));
+        }
+        if let Some(ms) = self.max_latency {
+            builder = builder.max_latency(Duration::from_millis(ms));
+        }
+        if let Some(ms) = self.thought_interval {
+            builder = builder.thought_interval(Duration::from_millis(ms));
+        }
+        if let Some(ms) = self.first_flush_after {
+            builder = builder.first_flush_after(Duration::from_millis(ms));
+        }
+        if let Some(max_bytes) = self.max_emit_bytes {
+            builder = builder.max_emit_bytes(max_bytes);
+        }
+        if let Some(per_second) = self.max_emit_rate {
+            builder = builder.max_emit_rate(per_second);
+        }
+        if let Some(max_sessions) = self.max_sessions {
+            builder = builder.max_sessions(max_sessions);
+        }
+        builder.build()
+    }
+}
+
+fn config() -> impl Strategy<Value = Config> {
+    (
+        (any::<bool>(), any::<bool>(), any::<bool>(), any::<bool>()),
+        (proptest::option::of(1..16usize), any::<bool>()),
+        (
+            proptest::option::of(1..5usize),
+            proptest::option::of(1..5usize),
+        ),
+        (
+            any::<bool>(),
+            proptest::option::of(1..16usize),
+            proptest::option::of(1..16usize),
+        ),
+        (
+            proptest::option::of(1..200u64),
+            proptest::option::of(1..300u64),
+            proptest::option::of(1..300u64),
+            proptest::option::of(1..100u64),
+        ),
+        (
+            proptest::option::of(1..16usize),
+            proptest::option::of(1..20u32),
+            proptest::option::of(1..SESSIONS),
+        ),
+    )
+        .prop_map(
+            |(
+                (flush_on_sentence, flush_on_newline, flush_on_pattern, flush_on_paragraph),
+                (max_buffer_bytes, split_on_word_boundary),
+                (flush_every_chunks, flush_every_tokens),
+                (leading_edge, passthrough_large, auto_passthrough_above),
+                (quiet_period, max_latency, thought_interval, first_flush_after),
+                (max_emit_bytes, max_emit_rate, max_sessions),
+            )| Config {
+                flush_on_sentence,
+                flush_on_newline,
+                flush_on_paragraph,
+                flush_on_pattern,
+                max_buffer_bytes,
+                split_on_word_boundary,
+                flush_every_chunks,
+                flush_every_tokens,
+                leading_edge,
+                passthrough_large,
+                auto_passthrough_above,
+                quiet_period,
+                max_latency,
+                thought_interval,
+                first_flush_after,
+                max_emit_bytes,
+                max_emit_rate,
+                max_sessions,
+            },
+        )
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        6 => (0..SESSIONS, any::<bool>(), "[ab .!?\n`é。😀]{0,12}").prop_map(
+            |(session, thought, text)| Op::Chunk { session, thought, text }
+        ),
+        1 => (0..SESSIONS).prop_map(|session| Op::Update { session }),
+        2 => (0..250u64).prop_map(Op::Wait),
+        1 => (0..SESSIONS).prop_map(Op::EndTurn),
+    ]
+}
+
+fn session_id(session: usize) -> SessionId {
+    SessionId::new(format!("s{session}"))
+}
+
+/// What went in and what came out, per session.
+type Traffic = ([Vec<Item>; SESSIONS], [Vec<Item>; SESSIONS]);
+
+/// Run `ops` through a `Coalescer` set up by `config`.
+fn coalesce(config: &Config, ops: &[Op]) -> Result<Traffic, DecafError> {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = Coalescer::new(config.build(clock.clone()));
+    let mut sent: [Vec<Item>; SESSIONS] = Default::default();
+    let mut received: [Vec<Item>; SESSIONS] = Default::default();
+    let mut updates = 0;
+    for op in ops {
+        let out = match op {
+            Op::Chunk {
+                session,
+                thought,
+                text,
+            } => {
+                sent[*session].push(Item::Text {
+                    thought: *thought,
+                    text: text.clone(),
+                });
+                let chunk = match thought {
+                    true => thought_chunk(text),
+                    false => message_chunk(text),
+                };
+                coalescer.push(SessionNotification::new(session_id(*session), chunk))?
+            }
+            Op::Update { session } => {
+                updates += 1;
+                let id = format!("update-{updates}");
+                sent[*session].push(Item::Update(id.clone()));
+                let update = SessionUpdate::ToolCall(ToolCall::new(id, "run"));
+                coalescer.push(SessionNotification::new(session_id(*session), update))?
+            }
+            Op::Wait(ms) => {
+                clock.advance(Duration::from_millis(*ms));
+                coalescer.tick()?
+            }
+            Op::EndTurn(session) => coalescer.end_turn(&session_id(*session))?,
+        };
+        receive(&mut received, out);
+    }
+    receive(&mut received, coalescer.flush()?);
+    Ok((sent, received))
+}
+
+fn receive(received: &mut [Vec<Item>; SESSIONS], out: Vec<SessionNotification>) {
+    for notification in out {
+        let session = (0..SESSIONS)
+            .find(|&s| session_id(s) == notification.session_id)
+            .expect("unknown session");
+        let item = match notification.update {
+            SessionUpdate::AgentMessageChunk(ContentChunk {
+                content: ContentBlock::Text(tc),
+                ..
+            }) => Item::Text {
+                thought: false,
+                text: tc.text,
+            },
+            SessionUpdate::AgentThoughtChunk(ContentChunk {
+                content: ContentBlock::Text(tc),
+                ..
+            }) => Item::Text {
+                thought: true,
+                text: tc.text,
+            },
+            SessionUpdate::ToolCall(call) => Item::Update(call.tool_call_id.0.to_string()),
+            other => panic!("unexpected update {other:?}"),
+        };
+        received[session].push(item);
+    }
+}
+
+/// `items` with empty text dropped and consecutive text of the same stream
+/// joined, so only the text and its place among the updates are compared.
+fn joined(items: Vec<Item>) -> Vec<Item> {
+    let mut joined: Vec<Item> = Vec::new();
+    for item in items {
+        match (joined.last_mut(), item) {
+            (_, Item::Text { text, .. }) if text.is_empty() => {}
+            (
+                Some(Item::Text { thought, text }),
+                Item::Text {
+                    thought: next,
+                    text: more,
+                },
+            ) if *thought == next => text.push_str(&more),
+            (_, item) => joined.push(item),
+        }
+    }
+    joined
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(512))]
+
+    #[test]
+    fn test_text_out_equals_text_in(
+        config in config(),
+        ops in proptest::collection::vec(op(), 0..80),
+    ) {
+        let (sent, received) =
+            coalesce(&config, &ops).map_err(|e| TestCaseError::fail(e.to_string()))?;
+        for (sent, received) in sent.into_iter().zip(received) {
+            prop_assert_eq!(joined(sent), joined(received));
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Rewriting session ids on the way to the client.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Event, Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::Decaf;
+use sacp::schema::{SessionId, SessionNotification, SessionUpdate, ToolCall};
+
+fn prefixed() -> Decaf {
+    Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .map_session_id(|id| SessionId::new(format!("ext-{}", id.0)))
+        .build()
+}
+
+/// The client sees only mapped ids, on coalesced text and forwarded
+/// updates alike, and the turn still ends on the prompt's (unmapped) id.
+#[tokio::test(start_paused = true)]
+async fn test_client_sees_mapped_ids() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("one ")),
+        Step::Send(message_chunk("two")),
+        Step::Send(SessionUpdate::ToolCall(ToolCall::new("call-1", "run"))),
+        Step::Send(message_chunk("three ")),
+        Step::Send(message_chunk("four")),
+    ]));
+
+    let events = run(prefixed(), agent, async |client| {
+        let session = client.new_session().await?;
+        assert_eq!(session.0.as_ref(), "session-1");
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["one two", "three four"]);
+    let ids: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Notification(notification) => Some(notification.session_id.0.to_string()),
+            Event::Response(..) => None,
+        })
+        .collect();
+    assert_eq!(ids, vec!["ext-session-1"; 3]);
+    assert!(matches!(events.last(), Some(Event::Response(..))));
+    Ok(())
+}
+
+/// Sessions mapped to the same id share a buffer.
+#[tokio::test(start_paused = true)]
+async fn test_mapped_sessions_coalesce_together() -> Result<(), sacp::Error> {
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(100))
+        .map_session_id(|_| SessionId::new("shared"))
+        .build();
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("a")),
+        Step::Notify(SessionNotification::new(
+            SessionId::new("other"),
+            message_chunk("b"),
+        )),
+        Step::Sleep(Duration::from_millis(200)),
+    ]));
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["ab"]);
+    Ok(())
+}
@@ -0,0 +1,70 @@
+//! Joining chunks with a `merge_fn` of one's own.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run, words};
+use decaf_mod::Decaf;
+
+/// Append `chunk`, dropping any space that would follow another.
+fn collapse_spaces(buffered: &mut String, chunk: &str) {
+    for c in chunk.chars() {
+        if c != ' ' || !buffered.ends_with(' ') {
+            buffered.push(c);
+        }
+    }
+}
+
+/// Runs of spaces, within a chunk and across chunk boundaries, come out
+/// as one.
+#[tokio::test]
+async fn test_merge_fn_collapses_spaces() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(&[
+        "Hello  ",
+        " world",
+        "  and   ",
+        " everyone",
+    ])));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .merge_fn(collapse_spaces)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["Hello world and everyone"]);
+    Ok(())
+}
+
+/// The function only sees the current window: text after a timer flush
+/// starts a fresh accumulator, and early triggers see the merged text.
+#[tokio::test(start_paused = true)]
+async fn test_merge_fn_starts_afresh_after_a_flush() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("One.  ")),
+        Step::Send(message_chunk("  Two ")),
+        Step::Sleep(Duration::from_millis(150)),
+        Step::Send(message_chunk("  three")),
+    ]));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(100))
+        .flush_on_sentence(true)
+        .merge_fn(collapse_spaces)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["One. ", "Two ", " three"]);
+    Ok(())
+}
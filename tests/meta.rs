@@ -0,0 +1,203 @@
+//! Merging of `meta` across coalesced chunks.
+
+mod common;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::{Event, Script, ScriptedAgent, Step, message_chunk, message_texts, run, words};
+use decaf_mod::{
+    CHUNK_COUNT_META_KEY, COALESCED_META_KEY, Decaf, FIRST_CHUNK_AT_META_KEY,
+    LAST_CHUNK_AT_META_KEY,
+};
+use sacp::schema::{
+    ContentBlock, ContentChunk, Meta, SessionId, SessionNotification, SessionUpdate, TextContent,
+    ToolCall,
+};
+use serde_json::json;
+
+fn chunk_with_meta(text: &str, meta: serde_json::Value) -> Step {
+    let serde_json::Value::Object(meta) = meta else {
+        panic!("meta must be an object");
+    };
+    Step::Notify(
+        SessionNotification::new(SessionId::new("session-1"), message_chunk(text)).meta(meta),
+    )
+}
+
+/// Distinct keys from every chunk survive; shared keys take the last value.
+#[tokio::test]
+async fn test_meta_merged_across_chunks() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        chunk_with_meta("one ", json!({"first": 1, "seq": 1})),
+        chunk_with_meta("two ", json!({"second": 2, "seq": 2})),
+        chunk_with_meta("three", json!({"third": 3, "seq": 3})),
+    ]));
+    let decaf = Decaf::new(Duration::from_secs(60));
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["one two three"]);
+    let meta: Vec<Option<Meta>> = events
+        .into_iter()
+        .filter_map(|event| match event {
+            Event::Notification(notification) => Some(notification.meta),
+            Event::Response(..) => None,
+        })
+        .collect();
+    let expected = json!({"first": 1, "second": 2, "third": 3, "seq": 3});
+    assert_eq!(meta, vec![expected.as_object().cloned()]);
+    Ok(())
+}
+
+/// Coalesced notifications are tagged with their chunk count; a chunk
+/// forwarded as-is is not.
+#[tokio::test]
+async fn test_mark_coalesced() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(&["first ", "a ", "b ", "c"])));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .leading_edge(true)
+        .mark_coalesced(true)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["first ", "a b c"]);
+    let meta: Vec<Option<Meta>> = events
+        .into_iter()
+        .filter_map(|event| match event {
+            Event::Notification(notification) => Some(notification.meta),
+            Event::Response(..) => None,
+        })
+        .collect();
+    let marked = json!({COALESCED_META_KEY: true, CHUNK_COUNT_META_KEY: 3});
+    assert_eq!(meta, vec![None, marked.as_object().cloned()]);
+    assert_eq!(COALESCED_META_KEY, "decaf.coalesced");
+    assert_eq!(CHUNK_COUNT_META_KEY, "decaf.chunk_count");
+    Ok(())
+}
+
+fn chunk_in_context(text: &str, context: &str) -> Step {
+    let mut meta = Meta::new();
+    meta.insert("context".to_string(), json!(context));
+    Step::Send(SessionUpdate::AgentMessageChunk(
+        ContentChunk::new(ContentBlock::Text(TextContent::new(text))).meta(meta),
+    ))
+}
+
+/// A `can_merge` predicate keeps chunks it rejects out of the run before
+/// them; the runs still arrive in order, each with its own chunk meta.
+#[tokio::test]
+async fn test_can_merge_splits_unmergeable_chunks() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        chunk_in_context("Tool ", "tool"),
+        chunk_in_context("output. ", "tool"),
+        chunk_in_context("My ", "reply"),
+        chunk_in_context("answer.", "reply"),
+    ]));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .can_merge(|previous, next| previous.meta == next.meta)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    let contexts: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Notification(SessionNotification {
+                update:
+                    SessionUpdate::AgentMessageChunk(ContentChunk {
+                        content: ContentBlock::Text(tc),
+                        meta,
+                        ..
+                    }),
+                ..
+            }) => Some((tc.text.clone(), meta.as_ref().map(|m| m["context"].clone()))),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        contexts,
+        vec![
+            ("Tool output. ".to_string(), Some(json!("tool"))),
+            ("My answer.".to_string(), Some(json!("reply"))),
+        ]
+    );
+    Ok(())
+}
+
+/// Each coalesced notification carries when its first and last chunks
+/// arrived; text after another update starts a fresh window.
+#[tokio::test(start_paused = true)]
+async fn test_stamp_timestamps() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("a ")),
+        Step::Sleep(Duration::from_millis(30)),
+        Step::Send(message_chunk("b ")),
+        Step::Sleep(Duration::from_millis(30)),
+        Step::Send(message_chunk("c")),
+        Step::Sleep(Duration::from_millis(30)),
+        Step::Send(SessionUpdate::ToolCall(ToolCall::new("call-1", "run"))),
+        Step::Sleep(Duration::from_millis(30)),
+        Step::Send(message_chunk("d")),
+    ]));
+    let unix_ms = || {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        u64::try_from(now.as_millis()).unwrap()
+    };
+    let before = unix_ms();
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .stamp_timestamps(true)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a b c", "d"]);
+    let stamps: Vec<(u64, u64)> = events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Notification(SessionNotification {
+                update: SessionUpdate::AgentMessageChunk(_),
+                meta: Some(meta),
+                ..
+            }) => Some((
+                meta[FIRST_CHUNK_AT_META_KEY].as_u64().unwrap(),
+                meta[LAST_CHUNK_AT_META_KEY].as_u64().unwrap(),
+            )),
+            _ => None,
+        })
+        .collect();
+    let [(first, last), (d_first, d_last)] = stamps[..] else {
+        panic!("expected two stamped notifications, got {stamps:?}");
+    };
+    // Time is paused, so only the scripted sleeps move the stamps.
+    assert!(
+        first >= before && first <= unix_ms(),
+        "{first} not in [{before}, now]"
+    );
+    assert_eq!(last - first, 60);
+    assert_eq!((d_first - first, d_last - first), (120, 120));
+    Ok(())
+}
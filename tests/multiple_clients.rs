@@ -0,0 +1,54 @@
+//! One `Decaf` per client branch: each instance times the same chunk
+//! stream independently.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Wire, message_chunk, wire};
+use decaf_mod::Decaf;
+use sacp::schema::SessionNotification;
+
+const WORDS: [&str; 10] = ["a ", "b ", "c ", "d ", "e ", "f ", "g ", "h ", "i ", "j "];
+
+/// The text of the next `count` notifications `wire` carries.
+async fn texts(wire: &mut Wire, count: u64) -> Vec<String> {
+    let mut texts = Vec::new();
+    for _ in 0..count {
+        let message = wire.next().await;
+        assert_eq!(message["method"], "session/update");
+        let text = &message["params"]["update"]["content"]["text"];
+        texts.push(text.as_str().expect("a text chunk").to_string());
+    }
+    texts
+}
+
+/// A fast and a slow client, each behind its own `Decaf`, get the same
+/// words at their own pace: the fast one word by word, the slow one in a
+/// single notification.
+#[tokio::test(start_paused = true)]
+async fn test_one_decaf_per_client_branch() -> Result<(), sacp::Error> {
+    let branch = |name: &str, interval| Decaf::builder().named(name).interval(interval).build();
+    let fast = branch("fast", Duration::from_millis(50));
+    let slow = branch("slow", Duration::from_secs(1));
+    let (fast_stats, slow_stats) = (fast.stats_handle(), slow.stats_handle());
+    let (mut fast_wire, fast_proxy) = wire(fast);
+    let (mut slow_wire, slow_proxy) = wire(slow);
+
+    for word in WORDS {
+        let chunk = serde_json::to_value(SessionNotification::new("session", message_chunk(word)))?;
+        fast_wire.send_from_agent("session/update", chunk.clone());
+        slow_wire.send_from_agent("session/update", chunk);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    assert_eq!(fast_stats.notifications_forwarded(), 10);
+    assert_eq!(slow_stats.notifications_forwarded(), 1);
+    assert_eq!(texts(&mut fast_wire, 10).await, WORDS);
+    assert_eq!(texts(&mut slow_wire, 1).await, vec![WORDS.concat()]);
+
+    fast_proxy.abort();
+    slow_proxy.abort();
+    Ok(())
+}
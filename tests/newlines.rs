@@ -0,0 +1,60 @@
+//! Line-boundary flushing.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run, words};
+use decaf_mod::Decaf;
+
+/// Completed lines flush as soon as they arrive, all lines of one chunk
+/// together, while the trailing partial line waits.
+#[tokio::test]
+async fn test_flush_on_newline() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(&[
+        "fn main() {\n    let x",
+        " = 1;\r\n    x\n",
+        "}",
+    ])));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .flush_on_newline(true)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        message_texts(&events),
+        vec!["fn main() {\n", "    let x = 1;\r\n    x\n", "}"]
+    );
+    Ok(())
+}
+
+/// A partial trailing line still flushes when its interval elapses.
+#[tokio::test(start_paused = true)]
+async fn test_partial_line_flushes_on_tick() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("- one\n- tw")),
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Send(message_chunk("o\n")),
+    ]));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(50))
+        .flush_on_newline(true)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["- one\n", "- tw", "o\n"]);
+    Ok(())
+}
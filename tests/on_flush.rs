@@ -0,0 +1,58 @@
+//! Observing each coalesced flush with `DecafBuilder::on_flush`.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run, words};
+use decaf_mod::Decaf;
+use sacp::schema::SessionId;
+
+/// One report per coalesced notification; the chunk counts add up to the
+/// chunks the agent sent and the sizes to the text the client got.
+#[tokio::test(start_paused = true)]
+async fn test_on_flush_reports_every_notification() -> Result<(), sacp::Error> {
+    let mut steps = words(&["a ", "bb ", "ccc "]);
+    steps.push(Step::Sleep(Duration::from_millis(150)));
+    steps.extend(words(&["dddd ", "eeeee"]));
+    steps.push(Step::Send(message_chunk("!")));
+    let reports: Arc<Mutex<Vec<(SessionId, usize, usize)>>> = Arc::default();
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(100))
+        .on_flush({
+            let reports = reports.clone();
+            move |session_id, chunks, bytes| {
+                reports
+                    .lock()
+                    .unwrap()
+                    .push((session_id.clone(), chunks, bytes));
+            }
+        })
+        .build();
+
+    let mut prompted = None;
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(steps)),
+        async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            prompted = Some(session);
+            Ok(())
+        },
+    )
+    .await?;
+
+    let texts = message_texts(&events);
+    assert_eq!(texts, vec!["a bb ccc ", "dddd eeeee!"]);
+    let reports = reports.lock().unwrap();
+    let counts: Vec<_> = reports
+        .iter()
+        .map(|&(_, chunks, bytes)| (chunks, bytes))
+        .collect();
+    assert_eq!(counts, vec![(3, 9), (3, 11)]);
+    assert_eq!(counts.iter().map(|(chunks, _)| chunks).sum::<usize>(), 6);
+    assert!(reports.iter().all(|(id, ..)| Some(id) == prompted.as_ref()));
+    Ok(())
+}
@@ -0,0 +1,48 @@
+//! Paragraph-boundary flushing.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, message_texts, run, words};
+use decaf_mod::Decaf;
+
+async fn paragraphs(chunks: &[&str]) -> Result<Vec<String>, sacp::Error> {
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .flush_on_paragraph(true)
+        .build();
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(words(chunks))),
+        async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            Ok(())
+        },
+    )
+    .await?;
+    Ok(message_texts(&events))
+}
+
+/// A paragraph flushes once a blank line follows it, its lines kept
+/// together, and the partial paragraph after it waits.
+#[tokio::test]
+async fn test_flush_on_paragraph() -> Result<(), sacp::Error> {
+    assert_eq!(
+        paragraphs(&["First line,\nsecond", " line.\n", "\nNext", " paragraph."]).await?,
+        vec!["First line,\nsecond line.\n\n", "Next paragraph."]
+    );
+    Ok(())
+}
+
+/// A line of only whitespace counts as blank, and a single newline
+/// doesn't end a paragraph.
+#[tokio::test]
+async fn test_whitespace_line_ends_paragraph() -> Result<(), sacp::Error> {
+    assert_eq!(
+        paragraphs(&["One\n", " \t", "\r\nTwo\nstill two\n", "\n \n", "Three"]).await?,
+        vec!["One\n \t\r\n", "Two\nstill two\n\n \n", "Three"]
+    );
+    Ok(())
+}
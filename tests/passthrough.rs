@@ -0,0 +1,143 @@
+//! Sessions exempt from debouncing.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run, words};
+use decaf_mod::{Coalescer, Decaf, DecafError, MockClock};
+use sacp::schema::{ContentBlock, ContentChunk, SessionId, SessionNotification, SessionUpdate};
+
+const PARAGRAPH: &str = "A whole paragraph, sent by the agent as a single chunk.";
+
+/// A passthrough session sees every chunk as sent; others are coalesced.
+#[tokio::test]
+async fn test_passthrough_sessions_are_not_buffered() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(&["live ", "typing ", "preview"])));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .passthrough_sessions(|session_id| &*session_id.0 == "session-1")
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let live = client.new_session().await?;
+        client.prompt(&live, "go").await?;
+        let normal = client.new_session().await?;
+        client.prompt(&normal, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        message_texts(&events),
+        vec!["live ", "typing ", "preview", "live typing preview"]
+    );
+    Ok(())
+}
+
+/// Tiny chunks coalesce around a large one, which goes out on its own
+/// behind the text buffered before it.
+#[tokio::test(start_paused = true)]
+async fn test_large_chunks_pass_through() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("Here ")),
+        Step::Send(message_chunk("it is:")),
+        Step::Send(message_chunk(PARAGRAPH)),
+        Step::Sleep(Duration::from_secs(5)),
+        Step::Send(message_chunk("Any ")),
+        Step::Send(message_chunk("questions?")),
+    ]));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .passthrough_large(32)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        message_texts(&events),
+        vec!["Here it is:", PARAGRAPH, "Any questions?"]
+    );
+    Ok(())
+}
+
+/// The large chunk is handed back by the very push that brought it, with
+/// no wait for the interval.
+#[test]
+fn test_large_chunk_is_not_delayed() -> Result<(), DecafError> {
+    let mut coalescer = Coalescer::new(
+        Decaf::builder()
+            .interval(Duration::from_secs(60))
+            .passthrough_large(32)
+            .with_clock(Arc::new(MockClock::new()))
+            .build(),
+    );
+    let chunk = |text| SessionNotification::new(SessionId::new("s"), message_chunk(text));
+    assert!(coalescer.push(chunk("Here it is:"))?.is_empty());
+    assert_eq!(coalescer.push(chunk(PARAGRAPH))?.len(), 2);
+    assert!(coalescer.push(chunk("thanks"))?.is_empty());
+    Ok(())
+}
+
+/// A stream of chunks that are large on average goes through chunk by
+/// chunk, while small chunks in another session still coalesce.
+#[test]
+fn test_large_chunk_streams_pass_through() -> Result<(), DecafError> {
+    let mut coalescer = Coalescer::new(
+        Decaf::builder()
+            .interval(Duration::from_secs(60))
+            .auto_passthrough_above(32)
+            .with_clock(Arc::new(MockClock::new()))
+            .build(),
+    );
+    let chunk = |session: &str, text| {
+        SessionNotification::new(SessionId::new(session), message_chunk(text))
+    };
+    for _ in 0..3 {
+        assert_eq!(coalescer.push(chunk("big", PARAGRAPH))?.len(), 1);
+    }
+    for word in ["small ", "words ", "here"] {
+        assert!(coalescer.push(chunk("small", word))?.is_empty());
+    }
+    let texts: Vec<_> = coalescer
+        .flush()?
+        .into_iter()
+        .map(|n| match n.update {
+            SessionUpdate::AgentMessageChunk(ContentChunk {
+                content: ContentBlock::Text(tc),
+                ..
+            }) => tc.text,
+            other => panic!("unexpected update {other:?}"),
+        })
+        .collect();
+    assert_eq!(texts, vec!["small words here"]);
+    Ok(())
+}
+
+/// Once the chunks shrink, the smoothed size falls back under the
+/// threshold and the session coalesces again.
+#[test]
+fn test_auto_passthrough_follows_the_average() -> Result<(), DecafError> {
+    let mut coalescer = Coalescer::new(
+        Decaf::builder()
+            .interval(Duration::from_secs(60))
+            .auto_passthrough_above(32)
+            .with_clock(Arc::new(MockClock::new()))
+            .build(),
+    );
+    let chunk = |text| SessionNotification::new(SessionId::new("s"), message_chunk(text));
+    assert_eq!(coalescer.push(chunk(PARAGRAPH))?.len(), 1);
+    // 55 bytes smoothed with 4: still 42 on average.
+    assert_eq!(coalescer.push(chunk("tiny"))?.len(), 1);
+    // Then 32, no longer over the threshold.
+    assert!(coalescer.push(chunk("tiny"))?.is_empty());
+    assert!(coalescer.push(chunk("tiny"))?.is_empty());
+    assert_eq!(coalescer.flush()?.len(), 1);
+    Ok(())
+}
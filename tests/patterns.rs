@@ -0,0 +1,63 @@
+//! Pattern-triggered flushing.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, message_texts, run, words};
+use decaf_mod::Decaf;
+use regex::Regex;
+
+/// Code fences flush through the fence as they open and close, including
+/// a fence split across chunks.
+#[tokio::test]
+async fn test_flush_on_code_fence() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(&[
+        "Intro\n``",
+        "`rust\nfn main() {}\n",
+        "``",
+        "`\nDone",
+    ])));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .flush_on_pattern(Regex::new("(?m)^```").unwrap())
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        message_texts(&events),
+        vec!["Intro\n```", "rust\nfn main() {}\n```", "\nDone"]
+    );
+    Ok(())
+}
+
+/// `^` anchors to the real line start, not to where the search window
+/// happens to begin.
+#[tokio::test]
+async fn test_pattern_anchors_see_whole_buffer() -> Result<(), sacp::Error> {
+    let long_line = "x".repeat(300);
+    let agent = ScriptedAgent::new(Script::new(words(&[&long_line, "```", " inline"])));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .flush_on_pattern(Regex::new("(?m)^```").unwrap())
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        message_texts(&events),
+        vec![format!("{long_line}``` inline")]
+    );
+    Ok(())
+}
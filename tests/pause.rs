@@ -0,0 +1,44 @@
+//! Pausing and resuming buffering through `DecafControl`.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::Decaf;
+
+/// Pausing flushes what was buffered and forwards each later chunk on its
+/// own; resuming coalesces again.
+#[tokio::test(start_paused = true)]
+async fn test_pause_and_resume() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("a ")),
+        Step::Send(message_chunk("b ")),
+        Step::Sleep(Duration::from_millis(50)),
+        Step::Send(message_chunk("c ")),
+        Step::Send(message_chunk("d ")),
+        Step::Sleep(Duration::from_millis(50)),
+        Step::Send(message_chunk("e ")),
+        Step::Send(message_chunk("f")),
+    ]));
+    let decaf = Decaf::new(Duration::from_secs(60));
+    let control = decaf.control_handle();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        let (prompted, ()) = tokio::join!(client.prompt(&session, "go"), async {
+            tokio::time::sleep(Duration::from_millis(25)).await;
+            control.pause().await.unwrap();
+            assert!(control.is_paused());
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            control.resume();
+        });
+        prompted?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a b ", "c ", "d ", "e f"]);
+    assert!(!control.is_paused());
+    Ok(())
+}
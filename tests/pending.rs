@@ -0,0 +1,53 @@
+//! Asking a running proxy what a session has buffered, with
+//! `DecafControl::has_pending` and `pending_bytes`.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::Decaf;
+
+/// Buffered text shows as pending without being flushed, and not once a
+/// drain has sent it.
+#[tokio::test(start_paused = true)]
+async fn test_has_pending_until_flushed() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("hello ")),
+        Step::Send(message_chunk("world")),
+        Step::Sleep(Duration::from_millis(100)),
+    ]));
+    let decaf = Decaf::new(Duration::from_secs(60));
+    let control = decaf.control_handle();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        assert!(!control.has_pending(&session).await);
+        tokio::try_join!(client.prompt(&session, "go"), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert!(control.has_pending(&session).await);
+            assert_eq!(control.pending_bytes(&session).await, 11);
+            // Looking flushes nothing.
+            assert!(control.has_pending(&session).await);
+
+            control.drain().await?;
+            assert!(!control.has_pending(&session).await);
+            assert_eq!(control.pending_bytes(&session).await, 0);
+            Ok(())
+        })?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["hello world"]);
+    Ok(())
+}
+
+/// Before the proxy runs there is nothing to see.
+#[tokio::test]
+async fn test_nothing_pending_before_running() {
+    let control = Decaf::new(Duration::from_millis(100)).control_handle();
+    let session = sacp::schema::SessionId::new("s");
+    assert!(!control.has_pending(&session).await);
+    assert_eq!(control.pending_bytes(&session).await, 0);
+}
@@ -0,0 +1,81 @@
+//! Flushing important sessions first with `session_priority`.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{Event, Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::{Coalescer, Decaf, DecafError, MockClock};
+use sacp::schema::{SessionId, SessionNotification};
+
+/// Two sessions due together under a one-per-second rate: the focused one
+/// goes out first, the background one when the next token comes.
+#[tokio::test(start_paused = true)]
+async fn test_priority_orders_rate_limited_flushes() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::with(|prompt| {
+        Script::new(vec![
+            Step::Send(message_chunk(&prompt.session_id.0)),
+            Step::Sleep(Duration::from_secs(3)),
+        ])
+    });
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(100))
+        .max_emit_rate(1)
+        .session_priority(|session_id| u8::from(&*session_id.0 == "session-2"))
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let background = client.new_session().await?;
+        let focused = client.new_session().await?;
+        tokio::try_join!(
+            client.prompt(&background, "go"),
+            client.prompt(&focused, "go")
+        )?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["session-2", "session-1"]);
+    Ok(())
+}
+
+/// Priority wins over deadlines when the rate allows only one flush, and
+/// orders a final flush too.
+#[test]
+fn test_priority_beats_older_deadline() -> Result<(), DecafError> {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = Coalescer::new(
+        Decaf::builder()
+            .interval(Duration::from_millis(100))
+            .max_emit_rate(1)
+            .session_priority(|session_id| match &*session_id.0 {
+                "focused" => 9,
+                _ => 0,
+            })
+            .with_clock(clock.clone())
+            .build(),
+    );
+    let chunk = |session: &str, text: &str| {
+        SessionNotification::new(SessionId::new(session), message_chunk(text))
+    };
+
+    coalescer.push(chunk("background", "old"))?;
+    clock.advance(Duration::from_millis(10));
+    coalescer.push(chunk("focused", "new"))?;
+    clock.advance(Duration::from_millis(100));
+    assert_eq!(texts(coalescer.tick()?), vec!["new"]);
+    clock.advance(Duration::from_secs(1));
+    assert_eq!(texts(coalescer.tick()?), vec!["old"]);
+
+    coalescer.push(chunk("background", "a"))?;
+    clock.advance(Duration::from_millis(10));
+    coalescer.push(chunk("focused", "b"))?;
+    assert_eq!(texts(coalescer.flush()?), vec!["b", "a"]);
+    Ok(())
+}
+
+fn texts(out: Vec<SessionNotification>) -> Vec<String> {
+    let events: Vec<_> = out.into_iter().map(Event::Notification).collect();
+    message_texts(&events)
+}
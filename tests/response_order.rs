@@ -0,0 +1,148 @@
+//! Where a turn's final coalesced chunk lands relative to its response.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{message_chunk, run_wire};
+use decaf_mod::{Decaf, DecafBuilder};
+use sacp::schema::{PromptRequest, PromptResponse, SessionNotification, StopReason};
+use serde_json::json;
+
+async fn final_chunk_order(decaf: Decaf) -> Result<Vec<&'static str>, sacp::Error> {
+    stop_order(decaf, StopReason::EndTurn).await
+}
+
+/// What the proxy writes once the agent answers a prompt with
+/// `stop_reason`, after streaming two words: `"text"` for the coalesced
+/// chunk, `"response"` for the prompt response.
+///
+/// Read straight off the proxy's transport, since a conductor may forward
+/// responses and notifications to the client through different tasks.
+async fn stop_order(
+    decaf: Decaf,
+    stop_reason: StopReason,
+) -> Result<Vec<&'static str>, sacp::Error> {
+    let mut order = Vec::new();
+    run_wire(decaf, async |wire| {
+        let prompt = PromptRequest::new("session", vec![]);
+        wire.send(json!({
+            "jsonrpc": "2.0",
+            "id": "prompt",
+            "method": "session/prompt",
+            "params": serde_json::to_value(prompt)?,
+        }));
+        let forwarded = wire.next().await;
+        assert_eq!(forwarded["params"]["method"], "session/prompt");
+
+        for word in ["all ", "done"] {
+            let chunk = SessionNotification::new("session", message_chunk(word));
+            wire.send_from_agent("session/update", serde_json::to_value(chunk)?);
+        }
+        wire.send(json!({
+            "jsonrpc": "2.0",
+            "id": forwarded["id"],
+            "result": serde_json::to_value(PromptResponse::new(stop_reason))?,
+        }));
+
+        for _ in 0..2 {
+            let message = wire.next().await;
+            if message["id"] == "prompt" {
+                order.push("response");
+            } else {
+                assert_eq!(message["method"], "session/update");
+                order.push("text");
+            }
+        }
+        Ok(())
+    })
+    .await?;
+    Ok(order)
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_final_chunk_precedes_response_by_default() -> Result<(), sacp::Error> {
+    let decaf = Decaf::new(Duration::from_secs(60));
+    assert_eq!(final_chunk_order(decaf).await?, vec!["text", "response"]);
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_final_chunk_follows_response_when_disabled() -> Result<(), sacp::Error> {
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .flush_before_response(false)
+        .build();
+    assert_eq!(final_chunk_order(decaf).await?, vec!["response", "text"]);
+    Ok(())
+}
+
+/// The response and the text leave through the proxy's one outgoing queue,
+/// in the order they were handed to it, so the order holds however a
+/// multi-threaded runtime schedules the tasks around them.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_final_chunk_follows_response_across_threads() -> Result<(), sacp::Error> {
+    for _ in 0..100 {
+        let decaf = text_after_response().build();
+        assert_eq!(final_chunk_order(decaf).await?, vec!["response", "text"]);
+    }
+    Ok(())
+}
+
+fn text_after_response() -> DecafBuilder {
+    Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .flush_before_response(false)
+}
+
+const EARLY_STOPS: [StopReason; 4] = [
+    StopReason::MaxTokens,
+    StopReason::MaxTurnRequests,
+    StopReason::Refusal,
+    StopReason::Cancelled,
+];
+
+/// A turn cut short sends its text ahead of the stop, even when text
+/// normally follows the response; only `EndTurn` keeps that order.
+#[tokio::test(start_paused = true)]
+async fn test_early_stops_flush_before_response() -> Result<(), sacp::Error> {
+    for stop_reason in EARLY_STOPS {
+        assert_eq!(
+            stop_order(text_after_response().build(), stop_reason).await?,
+            vec!["text", "response"],
+            "{stop_reason:?}"
+        );
+    }
+    assert_eq!(
+        stop_order(text_after_response().build(), StopReason::EndTurn).await?,
+        vec!["response", "text"]
+    );
+    Ok(())
+}
+
+/// Every stop reason flushes first by default.
+#[tokio::test(start_paused = true)]
+async fn test_every_stop_reason_flushes_before_response_by_default() -> Result<(), sacp::Error> {
+    for stop_reason in EARLY_STOPS.into_iter().chain([StopReason::EndTurn]) {
+        let decaf = Decaf::new(Duration::from_secs(60));
+        assert_eq!(
+            stop_order(decaf, stop_reason).await?,
+            vec!["text", "response"],
+            "{stop_reason:?}"
+        );
+    }
+    Ok(())
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_flush_on_stop_reason_disabled() -> Result<(), sacp::Error> {
+    for stop_reason in EARLY_STOPS {
+        let decaf = text_after_response().flush_on_stop_reason(false).build();
+        assert_eq!(
+            stop_order(decaf, stop_reason).await?,
+            vec!["response", "text"],
+            "{stop_reason:?}"
+        );
+    }
+    Ok(())
+}
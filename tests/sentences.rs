@@ -0,0 +1,92 @@
+//! Sentence-boundary flushing.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, message_texts, run, words};
+use decaf_mod::{DEFAULT_SENTENCE_TERMINATORS, Decaf};
+
+async fn sentences(decaf: Decaf, chunks: &[&str]) -> Result<Vec<String>, sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(chunks)));
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+    Ok(message_texts(&events))
+}
+
+/// Complete sentences flush as they finish, one per notification, while the
+/// trailing partial sentence waits for the prompt-response flush.
+#[tokio::test]
+async fn test_flush_on_sentence() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(&[
+        "Version 1.2 ",
+        "is out. ",
+        "It works! Then ",
+        "it sleeps",
+    ])));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .flush_on_sentence(true)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        message_texts(&events),
+        vec!["Version 1.2 is out. ", "It works! ", "Then it sleeps"]
+    );
+    Ok(())
+}
+
+/// Japanese puts no space after `。`, `！` or `？`; each still ends its
+/// sentence, splitting between characters rather than inside one.
+#[tokio::test]
+async fn test_flush_on_japanese_sentence() -> Result<(), sacp::Error> {
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .flush_on_sentence(true)
+        .build();
+
+    let texts = sentences(
+        decaf,
+        &[
+            "今日は",
+            "晴れです。明日",
+            "は雨！？",
+            "傘を持って",
+            "いこう",
+        ],
+    )
+    .await?;
+
+    assert_eq!(
+        texts,
+        vec!["今日は晴れです。", "明日は雨！？", "傘を持っていこう"]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_custom_sentence_terminators() -> Result<(), sacp::Error> {
+    let mut terminators = DEFAULT_SENTENCE_TERMINATORS.to_vec();
+    terminators.push('।');
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .flush_on_sentence(true)
+        .sentence_terminators(&terminators)
+        .build();
+
+    let texts = sentences(decaf, &["यह ठीक है। ", "धन्यवाद. ", "Bye"]).await?;
+
+    assert_eq!(texts, vec!["यह ठीक है। ", "धन्यवाद. ", "Bye"]);
+    Ok(())
+}
@@ -0,0 +1,102 @@
+//! `CoalesceService`, a `Coalescer` as a `tower` service, with the `tower`
+//! feature.
+
+#![cfg(feature = "tower")]
+
+mod common;
+
+use std::task::Poll;
+use std::time::Duration;
+
+use common::message_chunk;
+use decaf_mod::{CoalesceService, Coalescer, Decaf};
+use futures::FutureExt;
+use sacp::schema::{ContentBlock, ContentChunk, SessionNotification, SessionUpdate, ToolCall};
+use tokio::sync::mpsc;
+use tower_service::Service;
+
+fn chunk(session: &str, text: &str) -> SessionNotification {
+    SessionNotification::new(session.to_string(), message_chunk(text))
+}
+
+fn text(notification: &SessionNotification) -> &str {
+    match &notification.update {
+        SessionUpdate::AgentMessageChunk(ContentChunk {
+            content: ContentBlock::Text(tc),
+            ..
+        }) => &tc.text,
+        other => panic!("expected a text chunk, got {other:?}"),
+    }
+}
+
+async fn call(
+    service: &mut CoalesceService,
+    notification: SessionNotification,
+) -> Result<Vec<SessionNotification>, decaf_mod::DecafError> {
+    std::future::poll_fn(|cx| service.poll_ready(cx)).await?;
+    service.call(notification).await
+}
+
+/// Chunks are buffered by `call` and come out of the timer, coalesced, on
+/// their deadline.
+#[tokio::test(start_paused = true)]
+async fn test_timer_sends_coalesced_text() -> Result<(), decaf_mod::DecafError> {
+    let mut service = CoalesceService::new(Coalescer::new(Decaf::new(Duration::from_millis(100))));
+    let (timed, mut flushed) = mpsc::channel(16);
+    tokio::spawn(service.timer(timed));
+    let start = tokio::time::Instant::now();
+
+    assert!(call(&mut service, chunk("s", "Hello, ")).await?.is_empty());
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(call(&mut service, chunk("s", "world")).await?.is_empty());
+    assert!(flushed.try_recv().is_err());
+
+    let out = flushed.recv().await.expect("timed flush");
+    assert_eq!(text(&out), "Hello, world");
+    assert_eq!(start.elapsed(), Duration::from_millis(100));
+    Ok(())
+}
+
+/// Any other update returns its session's text ahead of it from `call`.
+#[tokio::test(start_paused = true)]
+async fn test_call_returns_text_before_update() -> Result<(), decaf_mod::DecafError> {
+    let mut service = CoalesceService::new(Coalescer::new(Decaf::new(Duration::from_secs(60))));
+
+    call(&mut service, chunk("s", "a")).await?;
+    call(&mut service, chunk("s", "b")).await?;
+    let update = SessionNotification::new(
+        "s".to_string(),
+        SessionUpdate::ToolCall(ToolCall::new("call-1", "run")),
+    );
+    let out = call(&mut service, update).await?;
+
+    assert_eq!(out.len(), 2);
+    assert_eq!(text(&out[0]), "ab");
+    assert!(matches!(out[1].update, SessionUpdate::ToolCall(_)));
+    assert!(service.flush().await?.is_empty());
+    Ok(())
+}
+
+/// While the timer waits on a full channel, the service isn't ready.
+#[tokio::test(start_paused = true)]
+async fn test_not_ready_while_timer_sends() -> Result<(), decaf_mod::DecafError> {
+    let mut service = CoalesceService::new(Coalescer::new(Decaf::new(Duration::from_millis(100))));
+    let (timed, mut flushed) = mpsc::channel(1);
+    tokio::spawn(service.timer(timed));
+
+    call(&mut service, chunk("a", "one")).await?;
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    call(&mut service, chunk("b", "two")).await?;
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    let mut ready = std::future::poll_fn(|cx| service.poll_ready(cx)).boxed();
+    assert!(futures::poll!(&mut ready).is_pending());
+    assert_eq!(text(&flushed.recv().await.expect("first flush")), "one");
+    tokio::task::yield_now().await;
+    assert!(matches!(futures::poll!(&mut ready), Poll::Ready(Ok(()))));
+    drop(ready);
+
+    assert!(service.call(chunk("a", "three")).await?.is_empty());
+    assert_eq!(text(&flushed.recv().await.expect("second flush")), "two");
+    Ok(())
+}
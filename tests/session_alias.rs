@@ -0,0 +1,90 @@
+//! Merging a stream split across aliased session ids.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Event, Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::Decaf;
+use sacp::schema::{SessionId, SessionNotification};
+
+/// `<id>-dup` is an alias of `<id>`.
+fn canonical(id: &SessionId) -> SessionId {
+    match id.0.strip_suffix("-dup") {
+        Some(canonical) => SessionId::new(canonical),
+        None => id.clone(),
+    }
+}
+
+/// A step sending `text` under the alias of the first session.
+fn aliased(text: &str) -> Step {
+    Step::Notify(SessionNotification::new(
+        SessionId::new("session-1-dup"),
+        message_chunk(text),
+    ))
+}
+
+fn session_ids(events: &[Event]) -> Vec<String> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Notification(notification) => Some(notification.session_id.0.to_string()),
+            Event::Response(..) => None,
+        })
+        .collect()
+}
+
+/// Chunks under the session's id and its alias, interleaved, coalesce
+/// into one buffer in arrival order, sent under the canonical id when the
+/// turn ends.
+#[tokio::test(start_paused = true)]
+async fn test_aliased_ids_share_a_buffer() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("one ")),
+        aliased("two "),
+        aliased("three "),
+        Step::Send(message_chunk("four")),
+    ]));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .session_alias(canonical)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["one two three four"]);
+    assert_eq!(session_ids(&events), vec!["session-1"]);
+    assert!(matches!(events.last(), Some(Event::Response(..))));
+    Ok(())
+}
+
+/// With `map_session_id` too, ids are merged first and the canonical id is
+/// what gets mapped.
+#[tokio::test(start_paused = true)]
+async fn test_alias_applies_before_map_session_id() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        aliased("one "),
+        Step::Send(message_chunk("two")),
+    ]));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .session_alias(canonical)
+        .map_session_id(|id| SessionId::new(format!("ext-{}", id.0)))
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["one two"]);
+    assert_eq!(session_ids(&events), vec!["ext-session-1"]);
+    Ok(())
+}
@@ -0,0 +1,60 @@
+//! Notifications without a usable session id.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Event, Script, ScriptedAgent, Step, message_chunk, run};
+use decaf_mod::{Coalescer, Decaf};
+use sacp::schema::{SessionId, SessionNotification};
+
+fn stray(session_id: &str) -> SessionNotification {
+    SessionNotification::new(SessionId::new(session_id), message_chunk("stray"))
+}
+
+/// A chunk with an empty session id goes straight through, ahead of the
+/// session text still buffered, and leaves no entry behind once the turn
+/// has freed the real session's.
+#[tokio::test(start_paused = true)]
+async fn test_empty_session_id_is_forwarded_untouched() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("a ")),
+        Step::Notify(stray("")),
+        Step::Send(message_chunk("b")),
+    ]));
+    let decaf = Decaf::new(Duration::from_secs(60));
+    let stats = decaf.stats_handle();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    let notifications: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Notification(notification) => Some(notification.clone()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(notifications.len(), 2);
+    assert_eq!(notifications[0], stray(""));
+    assert_eq!(&*notifications[1].session_id.0, "session-1");
+    assert_eq!(stats.active_sessions(), 0);
+    Ok(())
+}
+
+/// The `Coalescer` returns such chunks at once too, blank ids included,
+/// with nothing left to flush.
+#[test]
+fn test_coalescer_forwards_blank_session_ids() -> Result<(), decaf_mod::DecafError> {
+    let mut coalescer = Coalescer::new(Decaf::new(Duration::from_millis(100)));
+    for session_id in ["", "  "] {
+        assert_eq!(coalescer.push(stray(session_id))?, vec![stray(session_id)]);
+    }
+    assert_eq!(coalescer.next_deadline(), None);
+    assert!(coalescer.flush()?.is_empty());
+    Ok(())
+}
@@ -0,0 +1,106 @@
+//! Bounding the number of buffered sessions with `max_sessions`.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Event, Script, ScriptedAgent, Step, message_chunk, run};
+use decaf_mod::{Decaf, SessionLimitPolicy};
+use sacp::schema::{ContentBlock, ContentChunk, SessionId, SessionNotification, SessionUpdate};
+
+/// Chunks for sessions `s0`, `s1`, `s2`, then `s0` again, a millisecond
+/// apart so "least recently updated" is well defined.
+fn steps() -> Vec<Step> {
+    [("s0", "a"), ("s1", "b"), ("s2", "c"), ("s0", "d")]
+        .into_iter()
+        .flat_map(|(session, text)| {
+            [
+                Step::Notify(SessionNotification::new(
+                    SessionId::new(session),
+                    message_chunk(text),
+                )),
+                Step::Sleep(Duration::from_millis(1)),
+            ]
+        })
+        .collect()
+}
+
+/// `(session, text)` of every message chunk the client saw, in order.
+fn session_texts(events: &[Event]) -> Vec<(String, String)> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Notification(SessionNotification {
+                session_id,
+                update:
+                    SessionUpdate::AgentMessageChunk(ContentChunk {
+                        content: ContentBlock::Text(tc),
+                        ..
+                    }),
+                ..
+            }) => Some((session_id.0.to_string(), tc.text.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+async fn run_with(policy: SessionLimitPolicy) -> Result<Vec<(String, String)>, sacp::Error> {
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(1))
+        .max_sessions(2)
+        .session_limit_policy(policy)
+        .build();
+
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(steps())),
+        async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            // The turn end only flushes the prompting session, so wait for
+            // the timer to send the others.
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            Ok(())
+        },
+    )
+    .await?;
+    Ok(session_texts(&events))
+}
+
+fn pair(session: &str, text: &str) -> (String, String) {
+    (session.to_string(), text.to_string())
+}
+
+/// A third session flushes the least recently updated one out; returning
+/// to it later evicts the next. Every chunk still reaches the client.
+#[tokio::test(start_paused = true)]
+async fn test_evicts_least_recent_session() -> Result<(), sacp::Error> {
+    let mut texts = run_with(SessionLimitPolicy::EvictLeastRecent).await?;
+
+    // The timer flushes the two sessions left in no particular order.
+    texts[2..].sort();
+    assert_eq!(
+        texts,
+        vec![
+            pair("s0", "a"),
+            pair("s1", "b"),
+            pair("s0", "d"),
+            pair("s2", "c")
+        ]
+    );
+    Ok(())
+}
+
+/// With `PassThrough` the buffered sessions stay put and the extra session's
+/// chunk is forwarded as soon as it arrives.
+#[tokio::test(start_paused = true)]
+async fn test_passes_extra_session_through() -> Result<(), sacp::Error> {
+    let mut texts = run_with(SessionLimitPolicy::PassThrough).await?;
+
+    texts[1..].sort();
+    assert_eq!(
+        texts,
+        vec![pair("s2", "c"), pair("s0", "ad"), pair("s1", "b")]
+    );
+    Ok(())
+}
@@ -0,0 +1,97 @@
+//! `session_ttl`: sessions that stop receiving updates are flushed and
+//! dropped without waiting for a turn end.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::{Clock, Coalescer, Decaf, DecafError, MockClock};
+use sacp::schema::{ContentBlock, ContentChunk, SessionId, SessionNotification, SessionUpdate};
+
+fn text_of(notifications: &[SessionNotification]) -> Vec<&str> {
+    notifications
+        .iter()
+        .filter_map(|notification| match &notification.update {
+            SessionUpdate::AgentMessageChunk(ContentChunk {
+                content: ContentBlock::Text(tc),
+                ..
+            }) => Some(tc.text.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// A session the agent wrote to outside any prompt, and the prompting one
+/// while the agent goes quiet, are both flushed and evicted once idle past
+/// the TTL, long before their interval or the turn end.
+#[tokio::test(start_paused = true)]
+async fn test_idle_sessions_are_evicted() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Notify(SessionNotification::new(
+            SessionId::new("other"),
+            message_chunk("theirs"),
+        )),
+        Step::Send(message_chunk("mine")),
+        Step::Sleep(Duration::from_secs(2)),
+    ]));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .session_ttl(Duration::from_millis(500))
+        .build();
+    let stats = decaf.stats_handle();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        let watch = async {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            assert_eq!(stats.active_sessions(), 2);
+            assert_eq!(stats.notifications_forwarded(), 0);
+            tokio::time::sleep(Duration::from_millis(400)).await;
+            assert_eq!(stats.active_sessions(), 0);
+            assert_eq!(stats.notifications_forwarded(), 2);
+        };
+        let (stop_reason, ()) = tokio::join!(client.prompt(&session, "go"), watch);
+        stop_reason?;
+        Ok(())
+    })
+    .await?;
+
+    let mut texts = message_texts(&events);
+    texts.sort();
+    assert_eq!(texts, vec!["mine", "theirs"]);
+    Ok(())
+}
+
+/// Each update restarts the TTL; once it runs out, `tick` returns the
+/// session's text and forgets it, and a later chunk starts a fresh entry.
+#[test]
+fn test_coalescer_evicts_after_ttl() -> Result<(), DecafError> {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = Coalescer::new(
+        Decaf::builder()
+            .interval(Duration::from_secs(60))
+            .session_ttl(Duration::from_secs(5))
+            .with_clock(clock.clone())
+            .build(),
+    );
+    let chunk = |text: &str| SessionNotification::new("session", message_chunk(text));
+
+    for text in ["one ", "two ", "three"] {
+        assert!(coalescer.push(chunk(text))?.is_empty());
+        clock.advance(Duration::from_secs(3));
+        assert!(coalescer.tick()?.is_empty());
+    }
+    assert_eq!(
+        coalescer.next_deadline(),
+        Some(clock.now() + Duration::from_secs(2))
+    );
+    clock.advance(Duration::from_secs(2));
+    assert_eq!(text_of(&coalescer.tick()?), vec!["one two three"]);
+    assert!(coalescer.next_deadline().is_none());
+
+    assert!(coalescer.push(chunk("four"))?.is_empty());
+    assert_eq!(text_of(&coalescer.flush()?), vec!["four"]);
+    Ok(())
+}
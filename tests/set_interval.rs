@@ -0,0 +1,75 @@
+//! Retuning the interval of a running proxy with `DecafControl::set_interval`.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::{Coalescer, Decaf, DecafError, MockClock};
+use sacp::schema::{SessionId, SessionNotification};
+
+/// With a minute-long interval the whole turn is one notification; once
+/// the interval drops mid-turn, every pause gets its own.
+#[tokio::test(start_paused = true)]
+async fn test_set_interval_changes_flush_cadence() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("a ")),
+        Step::Send(message_chunk("b ")),
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Send(message_chunk("c ")),
+        Step::Send(message_chunk("d ")),
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Send(message_chunk("e")),
+    ]));
+    let decaf = Decaf::new(Duration::from_secs(60));
+    let control = decaf.control_handle();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        tokio::try_join!(client.prompt(&session, "go"), async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(control.set_interval(Duration::from_millis(20))?)
+        })?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a b ", "c d ", "e"]);
+    Ok(())
+}
+
+/// Text already buffered takes the new interval, with no new chunk needed.
+#[test]
+fn test_set_interval_moves_pending_deadlines() -> Result<(), DecafError> {
+    let clock = Arc::new(MockClock::new());
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(100))
+        .with_clock(clock.clone())
+        .build();
+    let control = decaf.control_handle();
+    let mut coalescer = Coalescer::new(decaf);
+
+    coalescer.push(SessionNotification::new(
+        SessionId::new("s"),
+        message_chunk("hi"),
+    ))?;
+    clock.advance(Duration::from_millis(50));
+    assert!(coalescer.tick()?.is_empty());
+
+    control.set_interval(Duration::from_millis(20))?;
+    assert_eq!(control.interval(), Duration::from_millis(20));
+    assert_eq!(coalescer.tick()?.len(), 1);
+    Ok(())
+}
+
+/// An interval below the minimum is refused and changes nothing.
+#[test]
+fn test_set_interval_rejects_below_minimum() {
+    let control = Decaf::new(Duration::from_millis(100)).control_handle();
+    assert!(matches!(
+        control.set_interval(Duration::from_micros(10)),
+        Err(DecafError::InvalidInterval { .. })
+    ));
+    assert_eq!(control.interval(), Duration::from_millis(100));
+}
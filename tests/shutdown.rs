@@ -0,0 +1,112 @@
+//! Graceful shutdown via a cancellation token.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run, wire, words};
+use decaf_mod::Decaf;
+use sacp::schema::SessionNotification;
+use tokio_util::sync::CancellationToken;
+
+/// Cancelling the token flushes text that no tick has flushed yet.
+#[tokio::test(start_paused = true)]
+async fn test_shutdown_flushes_pending_text() -> Result<(), sacp::Error> {
+    let mut steps = words(&["Hello ", "from ", "the ", "agent"]);
+    steps.push(Step::Sleep(Duration::from_secs(60)));
+    let agent = ScriptedAgent::new(Script::new(steps));
+
+    let shutdown = CancellationToken::new();
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(10))
+        .with_cancellation(shutdown.clone())
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session_id = client.new_session().await?;
+        tokio::select! {
+            result = client.prompt(&session_id, "go") => {
+                panic!("prompt finished before shutdown: {result:?}");
+            }
+            () = async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                shutdown.cancel();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            } => {}
+        }
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["Hello from the agent"]);
+    Ok(())
+}
+
+/// Text a tick already flushed is not sent again on shutdown.
+#[tokio::test(start_paused = true)]
+async fn test_shutdown_after_tick_does_not_resend() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("a ")),
+        Step::Sleep(Duration::from_millis(100)),
+        Step::Send(message_chunk("b ")),
+        Step::Sleep(Duration::from_secs(60)),
+    ]));
+
+    let shutdown = CancellationToken::new();
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(50))
+        .with_cancellation(shutdown.clone())
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session_id = client.new_session().await?;
+        tokio::select! {
+            result = client.prompt(&session_id, "go") => {
+                panic!("prompt finished before shutdown: {result:?}");
+            }
+            () = async {
+                tokio::time::sleep(Duration::from_millis(120)).await;
+                shutdown.cancel();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            } => {}
+        }
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a ", "b "]);
+    Ok(())
+}
+
+/// The final flush is on the proxy's transport by the time `run` returns,
+/// with the proxy's tasks spread over a multi-threaded runtime.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_shutdown_sends_text_before_returning() -> Result<(), sacp::Error> {
+    for _ in 0..100 {
+        let shutdown = CancellationToken::new();
+        let decaf = Decaf::builder()
+            .interval(Duration::from_secs(60))
+            .with_cancellation(shutdown.clone())
+            .build();
+        let stats = decaf.stats_handle();
+        let (mut wire, proxy) = wire(decaf);
+        for word in ["Hello ", "from ", "the ", "agent"] {
+            let chunk = SessionNotification::new("session", message_chunk(word));
+            wire.send_from_agent("session/update", serde_json::to_value(chunk)?);
+        }
+        // Nothing answers a buffered chunk, so wait for the proxy to count it.
+        while stats.chunks_received() < 4 {
+            tokio::task::yield_now().await;
+        }
+        shutdown.cancel();
+        proxy.await.expect("proxy task")?;
+
+        let message = wire.next().await;
+        assert_eq!(message["method"], "session/update");
+        assert_eq!(
+            message["params"]["update"]["content"]["text"],
+            "Hello from the agent"
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! `single_session`: one slot instead of a map, and the move to a map when
+//! a second session shows up.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::{Coalescer, Decaf, DecafError};
+use sacp::schema::{ContentBlock, ContentChunk, SessionId, SessionNotification, SessionUpdate};
+
+fn single_session() -> Decaf {
+    Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .single_session(true)
+        .build()
+}
+
+fn text_of(notifications: &[SessionNotification]) -> Vec<&str> {
+    notifications
+        .iter()
+        .filter_map(|notification| match &notification.update {
+            SessionUpdate::AgentMessageChunk(ContentChunk {
+                content: ContentBlock::Text(tc),
+                ..
+            }) => Some(tc.text.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Sessions one after the other reuse the slot, each turn coalesced.
+#[tokio::test]
+async fn test_sequential_sessions_share_the_slot() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("Hello, ")),
+        Step::Send(message_chunk("world")),
+    ]));
+
+    let events = run(single_session(), agent, async |client| {
+        for _ in 0..3 {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+        }
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["Hello, world"; 3]);
+    Ok(())
+}
+
+/// Sessions streaming at once move to a map and are still kept apart.
+#[tokio::test(start_paused = true)]
+async fn test_concurrent_sessions_fall_back_to_a_map() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::with(|prompt| {
+        let name = match &prompt.prompt[..] {
+            [ContentBlock::Text(tc)] => tc.text.clone(),
+            _ => String::new(),
+        };
+        Script::new(
+            (0..5)
+                .flat_map(|n| {
+                    [
+                        Step::Send(message_chunk(&format!("{name}{n} "))),
+                        Step::Sleep(Duration::from_millis(3)),
+                    ]
+                })
+                .collect(),
+        )
+    });
+
+    let events = run(single_session(), agent, async |client| {
+        let first = client.new_session().await?;
+        let second = client.new_session().await?;
+        tokio::try_join!(client.prompt(&first, "a"), client.prompt(&second, "b"))?;
+        Ok(())
+    })
+    .await?;
+
+    let mut texts = message_texts(&events);
+    texts.sort();
+    assert_eq!(texts, vec!["a0 a1 a2 a3 a4 ", "b0 b1 b2 b3 b4 "]);
+    Ok(())
+}
+
+/// A `Coalescer` falls back the same way, and the first session keeps what
+/// it had buffered.
+#[test]
+fn test_coalescer_falls_back_to_a_map() -> Result<(), DecafError> {
+    let chunk = |session: &str, text: &str| {
+        SessionNotification::new(SessionId::new(session), message_chunk(text))
+    };
+    let mut coalescer = Coalescer::new(single_session());
+    assert!(coalescer.push(chunk("a", "one "))?.is_empty());
+    assert!(coalescer.push(chunk("b", "two "))?.is_empty());
+    assert!(coalescer.push(chunk("a", "three"))?.is_empty());
+
+    assert_eq!(
+        text_of(&coalescer.end_turn(&SessionId::new("a"))?),
+        vec!["one three"]
+    );
+    assert_eq!(
+        text_of(&coalescer.end_turn(&SessionId::new("b"))?),
+        vec!["two "]
+    );
+    assert!(coalescer.next_deadline().is_none());
+    Ok(())
+}
@@ -0,0 +1,55 @@
+//! Dropping blank chunks with `skip_empty_chunks` and
+//! `skip_whitespace_chunks`.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_texts, run, words};
+use decaf_mod::{Decaf, DecafBuilder};
+
+const CHUNKS: &[&str] = &["Hello", "", " ", "world", "\t\n"];
+
+async fn run_with(builder: DecafBuilder) -> Result<(Vec<String>, u64), sacp::Error> {
+    let mut steps = words(CHUNKS);
+    steps.push(Step::Sleep(Duration::from_millis(200)));
+    steps.extend(words(&["", "  "]));
+    let decaf = builder.interval(Duration::from_millis(100)).build();
+    let stats = decaf.stats_handle();
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(steps)),
+        async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            Ok(())
+        },
+    )
+    .await?;
+    Ok((message_texts(&events), stats.chunks_received()))
+}
+
+/// Empty chunks vanish without being counted, while whitespace is kept;
+/// skipping whitespace too drops both, so the trailing blanks open no
+/// buffer and send nothing.
+#[tokio::test(start_paused = true)]
+async fn test_skip_empty_and_whitespace_chunks() -> Result<(), sacp::Error> {
+    assert_eq!(
+        run_with(Decaf::builder().skip_empty_chunks(true)).await?,
+        (vec!["Hello world\t\n".to_string(), "  ".to_string()], 5)
+    );
+    assert_eq!(
+        run_with(Decaf::builder().skip_whitespace_chunks(true)).await?,
+        (vec!["Helloworld".to_string()], 2)
+    );
+    Ok(())
+}
+
+/// Without either option every chunk is buffered as before.
+#[tokio::test(start_paused = true)]
+async fn test_blank_chunks_kept_by_default() -> Result<(), sacp::Error> {
+    let (texts, received) = run_with(Decaf::builder()).await?;
+    assert_eq!(texts, vec!["Hello world\t\n", "  "]);
+    assert_eq!(received, 7);
+    Ok(())
+}
@@ -0,0 +1,214 @@
+//! Runtime statistics exposed through `Decaf::stats_handle`.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run, run_chain, words};
+use decaf_mod::{Decaf, StatsSnapshot};
+use sacp::schema::{SessionId, SessionNotification};
+
+#[tokio::test]
+async fn test_stats_track_coalescing() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(&["a ", "bb ", "ccc ", "dddd"])));
+    let decaf = Decaf::new(Duration::from_secs(60));
+    let stats = decaf.stats_handle();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a bb ccc dddd"]);
+    assert_eq!(stats.chunks_received(), 4);
+    assert_eq!(stats.notifications_forwarded(), 1);
+    assert_eq!(stats.bytes_buffered(), 13);
+    assert_eq!(stats.compression_ratio(), Some(4.0));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_named_proxies_keep_separate_stats() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(&["a ", "bb ", "ccc ", "dddd"])));
+    let outer = Decaf::builder()
+        .named("decaf-slow")
+        .interval(Duration::from_secs(60))
+        .build();
+    let inner = Decaf::builder()
+        .named("decaf-fast")
+        .interval(Duration::from_secs(30))
+        .build();
+    assert_eq!(outer.name(), "decaf-slow");
+    let (outer_stats, inner_stats) = (outer.stats_handle(), inner.stats_handle());
+
+    let events = run_chain(vec![outer, inner], agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["a bb ccc dddd"]);
+    assert_eq!(outer_stats.name(), "decaf-slow");
+    assert_eq!(inner_stats.name(), "decaf-fast");
+    assert_eq!(inner_stats.chunks_received(), 4);
+    assert_eq!(outer_stats.chunks_received(), 1);
+    assert_eq!(Decaf::new(Duration::from_secs(1)).name(), "decaf");
+    Ok(())
+}
+
+/// Each flush records the age of the oldest text it sends.
+#[tokio::test(start_paused = true)]
+async fn test_latency_snapshot_tracks_flush_age() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("a ")),
+        Step::Sleep(Duration::from_millis(150)),
+        Step::Send(message_chunk("b")),
+        Step::Sleep(Duration::from_millis(30)),
+    ]));
+    let decaf = Decaf::new(Duration::from_millis(100));
+    let stats = decaf.stats_handle();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    // "a " waits out the interval; "b" is flushed by the turn end.
+    assert_eq!(message_texts(&events), vec!["a ", "b"]);
+    let latency = stats.latency_snapshot();
+    assert_eq!(latency.count, 2);
+    assert_eq!(latency.max, Duration::from_millis(100));
+    assert_eq!(latency.p99, Duration::from_millis(100));
+    let turn_end = Duration::from_millis(30);
+    assert!(
+        latency.p50 >= turn_end && latency.p50 <= turn_end * 9 / 8,
+        "{latency:?}"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_render_prometheus() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(&["a ", "bb ", "ccc ", "dddd"])));
+    let decaf = Decaf::builder()
+        .named("edge \"1\"")
+        .metrics_prefix("app_decaf_")
+        .interval(Duration::from_secs(60))
+        .build();
+    let stats = decaf.stats_handle();
+
+    run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    let peak = stats.peak_pending_bytes();
+    assert_eq!(
+        stats.render_prometheus(),
+        format!(
+            "\
+# HELP app_decaf_chunks_received_total Text chunks received that were eligible for coalescing.
+# TYPE app_decaf_chunks_received_total counter
+app_decaf_chunks_received_total{{proxy=\"edge \\\"1\\\"\"}} 4
+# HELP app_decaf_notifications_forwarded_total Text notifications sent on, coalesced or not.
+# TYPE app_decaf_notifications_forwarded_total counter
+app_decaf_notifications_forwarded_total{{proxy=\"edge \\\"1\\\"\"}} 1
+# HELP app_decaf_bytes_buffered_total Bytes of text that went through the buffers.
+# TYPE app_decaf_bytes_buffered_total counter
+app_decaf_bytes_buffered_total{{proxy=\"edge \\\"1\\\"\"}} 13
+# HELP app_decaf_tap_dropped_total Notifications left out of the tap recording because it was full.
+# TYPE app_decaf_tap_dropped_total counter
+app_decaf_tap_dropped_total{{proxy=\"edge \\\"1\\\"\"}} 0
+# HELP app_decaf_peak_pending_bytes The most text held in the buffers at once.
+# TYPE app_decaf_peak_pending_bytes gauge
+app_decaf_peak_pending_bytes{{proxy=\"edge \\\"1\\\"\"}} {peak}
+# HELP app_decaf_active_sessions Sessions currently holding a buffer entry.
+# TYPE app_decaf_active_sessions gauge
+app_decaf_active_sessions{{proxy=\"edge \\\"1\\\"\"}} 0
+"
+        )
+    );
+    Ok(())
+}
+
+/// The turn end frees the prompting session; another session the agent
+/// wrote to stays active until it is flushed and freed too.
+#[tokio::test]
+async fn test_active_sessions_gauge() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("mine")),
+        Step::Notify(SessionNotification::new(
+            SessionId::new("other"),
+            message_chunk("theirs"),
+        )),
+    ]));
+    let decaf = Decaf::new(Duration::from_secs(60));
+    let stats = decaf.stats_handle();
+
+    run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        assert_eq!(stats.active_sessions(), 1);
+        assert!(
+            stats
+                .render_prometheus()
+                .contains("\ndecaf_active_sessions{proxy=\"decaf\"} 1\n")
+        );
+        Ok(())
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// `take_snapshot` resets the counters for the next window, but not the
+/// gauges.
+#[tokio::test]
+async fn test_take_snapshot_resets_counters() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("a ")),
+        Step::Send(message_chunk("bb")),
+        Step::Notify(SessionNotification::new(
+            SessionId::new("other"),
+            message_chunk("theirs"),
+        )),
+    ]));
+    let decaf = Decaf::new(Duration::from_secs(60));
+    let stats = decaf.stats_handle();
+
+    run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    let first = stats.take_snapshot();
+    assert_eq!(first.chunks_received, 3);
+    assert_eq!(first.notifications_forwarded, 1);
+    assert_eq!(first.bytes_buffered, 10);
+    assert_eq!(first.latency.count, 1);
+    assert_eq!(first.compression_ratio(), Some(3.0));
+    assert_eq!(first.active_sessions, 1);
+    assert!(first.peak_pending_bytes >= 4);
+
+    let second = stats.take_snapshot();
+    assert_eq!(
+        second,
+        StatsSnapshot {
+            peak_pending_bytes: first.peak_pending_bytes,
+            active_sessions: 1,
+            ..StatsSnapshot::default()
+        }
+    );
+    assert_eq!(second.compression_ratio(), None);
+    assert_eq!(stats.chunks_received(), 0);
+    Ok(())
+}
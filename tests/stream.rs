@@ -0,0 +1,97 @@
+//! Coalescing a stream of notifications with `into_stream`.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::message_chunk;
+use decaf_mod::{Coalescer, Decaf, MockClock, into_stream};
+use futures::{SinkExt, StreamExt, channel::mpsc};
+use sacp::schema::{ContentBlock, ContentChunk, SessionId, SessionNotification, SessionUpdate};
+
+fn chunk(session: &str, text: &str) -> SessionNotification {
+    SessionNotification::new(SessionId::new(session), message_chunk(text))
+}
+
+fn text(notification: &SessionNotification) -> &str {
+    match &notification.update {
+        SessionUpdate::AgentMessageChunk(ContentChunk {
+            content: ContentBlock::Text(tc),
+            ..
+        }) => &tc.text,
+        other => panic!("unexpected update {other:?}"),
+    }
+}
+
+/// Word chunks arriving over time come out coalesced per interval, and the
+/// text still buffered when the input ends is flushed before the output
+/// ends.
+#[tokio::test(start_paused = true)]
+async fn test_word_stream_is_coalesced() {
+    let (mut tx, rx) = mpsc::unbounded();
+    let agent = tokio::spawn(async move {
+        for word in ["The ", "quick ", "brown "] {
+            tx.send(chunk("s", word)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(30)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for word in ["fox ", "jumps"] {
+            tx.send(chunk("s", word)).await.unwrap();
+        }
+    });
+
+    let out: Vec<_> = into_stream(rx, Duration::from_millis(100)).collect().await;
+    agent.await.unwrap();
+
+    let texts: Vec<_> = out.iter().map(text).collect();
+    assert_eq!(texts, vec!["The quick brown ", "fox jumps"]);
+}
+
+/// Text is handed on once its window closes, while the input is still
+/// open, rather than waiting for more input.
+#[tokio::test(start_paused = true)]
+async fn test_output_follows_the_timer() {
+    let (mut tx, rx) = mpsc::unbounded();
+    tx.send(chunk("s", "hello ")).await.unwrap();
+    tx.send(chunk("s", "there")).await.unwrap();
+
+    let mut out = Box::pin(into_stream(rx, Duration::from_millis(100)));
+    let start = tokio::time::Instant::now();
+    let first = out.next().await.unwrap();
+    assert_eq!(text(&first), "hello there");
+    assert_eq!(start.elapsed(), Duration::from_millis(100));
+
+    drop(tx);
+    assert!(out.next().await.is_none());
+}
+
+/// A configured `Coalescer` keeps its options, here splitting sentences,
+/// and sessions stay apart.
+#[tokio::test]
+async fn test_coalescer_into_stream() {
+    let clock = Arc::new(MockClock::new());
+    let coalescer = Coalescer::new(
+        Decaf::builder()
+            .interval(Duration::from_secs(60))
+            .flush_on_sentence(true)
+            .with_clock(clock)
+            .build(),
+    );
+    let input = futures::stream::iter([
+        chunk("a", "One. "),
+        chunk("b", "Other "),
+        chunk("a", "Two"),
+        chunk("b", "session"),
+    ]);
+
+    let out: Vec<_> = coalescer.into_stream(input).collect().await;
+    let texts = |session: &str| -> Vec<_> {
+        out.iter()
+            .filter(|n| &*n.session_id.0 == session)
+            .map(text)
+            .collect()
+    };
+    assert_eq!(texts("a"), vec!["One. ", "Two"]);
+    assert_eq!(texts("b"), vec!["Other session"]);
+}
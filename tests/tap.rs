@@ -0,0 +1,65 @@
+//! Recording every notification with `DecafBuilder::tap` while forwarding
+//! them untouched.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, message_texts, run, words};
+use decaf_mod::Decaf;
+use sacp::schema::{ContentBlock, ContentChunk, SessionNotification, SessionUpdate};
+use tokio::sync::mpsc;
+
+fn text(notification: &SessionNotification) -> &str {
+    match &notification.update {
+        SessionUpdate::AgentMessageChunk(ContentChunk {
+            content: ContentBlock::Text(tc),
+            ..
+        }) => &tc.text,
+        update => panic!("not a message chunk: {update:?}"),
+    }
+}
+
+async fn run_tapped(decaf: Decaf) -> Result<Vec<String>, sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(&["a ", "bb ", "ccc ", "dddd"])));
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+    Ok(message_texts(&events))
+}
+
+/// Chunks are neither coalesced nor changed, and the tap gets each one.
+#[tokio::test]
+async fn test_tap_records_and_forwards_verbatim() -> Result<(), sacp::Error> {
+    let (tap, mut recorded) = mpsc::channel(16);
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .tap(tap)
+        .build();
+
+    assert_eq!(run_tapped(decaf).await?, vec!["a ", "bb ", "ccc ", "dddd"]);
+    let mut texts = Vec::new();
+    while let Ok(notification) = recorded.try_recv() {
+        texts.push(text(&notification).to_string());
+    }
+    assert_eq!(texts, vec!["a ", "bb ", "ccc ", "dddd"]);
+    Ok(())
+}
+
+/// A full tap loses copies, counted in the stats, but never holds up the
+/// client's stream.
+#[tokio::test]
+async fn test_full_tap_drops_copies_not_notifications() -> Result<(), sacp::Error> {
+    let (tap, mut recorded) = mpsc::channel(1);
+    let decaf = Decaf::builder().tap(tap).build();
+    let stats = decaf.stats_handle();
+
+    assert_eq!(run_tapped(decaf).await?, vec!["a ", "bb ", "ccc ", "dddd"]);
+    assert_eq!(text(&recorded.try_recv().unwrap()), "a ");
+    assert!(recorded.try_recv().is_err());
+    assert_eq!(stats.tap_dropped(), 3);
+    Ok(())
+}
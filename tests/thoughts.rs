@@ -0,0 +1,176 @@
+//! Coalescing of `AgentThoughtChunk` text alongside message text.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, chunk_texts, message_chunk, run, thought_chunk};
+use decaf_mod::{Clock, Coalescer, Decaf, DecafError, MockClock};
+use sacp::schema::{SessionId, SessionNotification};
+
+fn reasoning_then_answer() -> Script {
+    Script::new(vec![
+        Step::Send(thought_chunk("Let me ")),
+        Step::Send(thought_chunk("think. ")),
+        Step::Send(message_chunk("The answer ")),
+        Step::Send(message_chunk("is 42.")),
+    ])
+}
+
+/// Thoughts and messages coalesce into separate notifications, thoughts first.
+#[tokio::test]
+async fn test_thoughts_coalesce_separately() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(reasoning_then_answer());
+    let decaf = Decaf::new(Duration::from_secs(60));
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        chunk_texts(&events),
+        vec![
+            ("thought", "Let me think. ".to_string()),
+            ("message", "The answer is 42.".to_string()),
+        ]
+    );
+    Ok(())
+}
+
+/// With `coalesce_thoughts(false)` thoughts pass through one by one.
+#[tokio::test]
+async fn test_thoughts_opt_out() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(reasoning_then_answer());
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .coalesce_thoughts(false)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        chunk_texts(&events),
+        vec![
+            ("thought", "Let me ".to_string()),
+            ("thought", "think. ".to_string()),
+            ("message", "The answer is 42.".to_string()),
+        ]
+    );
+    Ok(())
+}
+
+/// Each switch between message and thought text flushes the stream being
+/// left, so interleaved text keeps the agent's order.
+#[tokio::test]
+async fn test_stream_switch_preserves_order() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(message_chunk("First ")),
+        Step::Send(message_chunk("part. ")),
+        Step::Send(thought_chunk("Hmm, ")),
+        Step::Send(thought_chunk("wait. ")),
+        Step::Send(message_chunk("Second part.")),
+    ]));
+    let decaf = Decaf::new(Duration::from_secs(60));
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        chunk_texts(&events),
+        vec![
+            ("message", "First part. ".to_string()),
+            ("thought", "Hmm, wait. ".to_string()),
+            ("message", "Second part.".to_string()),
+        ]
+    );
+    Ok(())
+}
+
+/// Thoughts coalesce over their own, longer window while messages keep the
+/// message interval.
+#[tokio::test(start_paused = true)]
+async fn test_thought_interval_sets_thought_cadence() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(vec![
+        Step::Send(thought_chunk("Let me ")),
+        Step::Sleep(Duration::from_millis(300)),
+        Step::Send(thought_chunk("think. ")),
+        Step::Sleep(Duration::from_secs(1)),
+        Step::Send(message_chunk("The answer ")),
+        Step::Sleep(Duration::from_millis(300)),
+        Step::Send(message_chunk("is 42.")),
+    ]));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(100))
+        .thought_interval(Duration::from_secs(1))
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(
+        chunk_texts(&events),
+        vec![
+            ("thought", "Let me think. ".to_string()),
+            ("message", "The answer ".to_string()),
+            ("message", "is 42.".to_string()),
+        ]
+    );
+    Ok(())
+}
+
+/// Side by side, each session comes due on its kind's interval.
+#[test]
+fn test_thought_and_message_deadlines() -> Result<(), DecafError> {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = Coalescer::new(
+        Decaf::builder()
+            .interval(Duration::from_millis(100))
+            .thought_interval(Duration::from_millis(500))
+            .with_clock(clock.clone())
+            .build(),
+    );
+    let thinking = SessionId::new("thinking");
+    let answering = SessionId::new("answering");
+    coalescer.push(SessionNotification::new(
+        thinking.clone(),
+        thought_chunk("hmm"),
+    ))?;
+    coalescer.push(SessionNotification::new(
+        answering.clone(),
+        message_chunk("42"),
+    ))?;
+    assert_eq!(
+        coalescer.next_deadline(),
+        Some(clock.now() + Duration::from_millis(100))
+    );
+
+    clock.advance(Duration::from_millis(100));
+    let flushed = coalescer.tick()?;
+    assert_eq!(flushed.len(), 1);
+    assert_eq!(flushed[0].session_id, answering);
+
+    clock.advance(Duration::from_millis(300));
+    assert!(coalescer.tick()?.is_empty());
+    clock.advance(Duration::from_millis(100));
+    let flushed = coalescer.tick()?;
+    assert_eq!(flushed.len(), 1);
+    assert_eq!(flushed[0].session_id, thinking);
+    Ok(())
+}
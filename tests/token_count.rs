@@ -0,0 +1,64 @@
+//! Flushing every N tokens with `flush_every_tokens`.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, message_texts, run, words};
+use decaf_mod::Decaf;
+
+fn whitespace_tokens(text: &str) -> usize {
+    text.split_whitespace().count()
+}
+
+async fn run_words(chunks: &[&str], decaf: Decaf) -> Result<Vec<String>, sacp::Error> {
+    let events = run(
+        decaf,
+        ScriptedAgent::new(Script::new(words(chunks))),
+        async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            Ok(())
+        },
+    )
+    .await?;
+    Ok(message_texts(&events))
+}
+
+/// A flush goes out as soon as the running count reaches the threshold,
+/// however the tokens are spread across chunks.
+#[tokio::test]
+async fn test_flushes_every_n_tokens() -> Result<(), sacp::Error> {
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .flush_every_tokens(4, whitespace_tokens)
+        .build();
+    let chunks = ["one two ", "three ", "four five six ", "seven ", "eight"];
+
+    assert_eq!(
+        run_words(&chunks, decaf).await?,
+        vec!["one two three four five six ", "seven eight"]
+    );
+    Ok(())
+}
+
+/// The counter is handed each chunk's text once, never the whole buffer.
+#[tokio::test]
+async fn test_counts_only_new_chunks() -> Result<(), sacp::Error> {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .flush_every_tokens(100, {
+            let seen = seen.clone();
+            move |text| {
+                seen.lock().unwrap().push(text.to_string());
+                whitespace_tokens(text)
+            }
+        })
+        .build();
+
+    assert_eq!(run_words(&["a ", "b ", "c"], decaf).await?, vec!["a b c"]);
+    assert_eq!(*seen.lock().unwrap(), vec!["a ", "b ", "c"]);
+    Ok(())
+}
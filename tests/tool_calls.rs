@@ -0,0 +1,263 @@
+//! Merging of `ToolCallUpdate`s per tool call.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Event, Script, ScriptedAgent, Step, message_chunk, run};
+use decaf_mod::{CoalesceMode, Decaf};
+use sacp::schema::{
+    ContentBlock, ContentChunk, Plan, SessionNotification, SessionUpdate, TextContent, ToolCall,
+    ToolCallContent, ToolCallStatus, ToolCallUpdate, ToolCallUpdateFields, ToolKind,
+};
+
+fn start(id: &str) -> Step {
+    Step::Send(SessionUpdate::ToolCall(ToolCall::new(
+        id.to_string(),
+        "run",
+    )))
+}
+
+fn start_kind(id: &str, kind: ToolKind) -> Step {
+    Step::Send(SessionUpdate::ToolCall(
+        ToolCall::new(id.to_string(), "run").kind(kind),
+    ))
+}
+
+fn output(id: &str, text: &str, status: Option<ToolCallStatus>) -> Step {
+    let content = ToolCallContent::from(ContentBlock::Text(TextContent::new(text.to_string())));
+    Step::Send(SessionUpdate::ToolCallUpdate(ToolCallUpdate::new(
+        id.to_string(),
+        ToolCallUpdateFields::new()
+            .content(vec![content])
+            .status(status),
+    )))
+}
+
+/// Every update the client saw as `(tool call id, text, status)`, message
+/// text as `message <text>`, and other session updates by name.
+fn updates(events: &[Event]) -> Vec<String> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            Event::Notification(SessionNotification { update, .. }) => Some(match update {
+                SessionUpdate::ToolCall(call) => format!("start {}", call.tool_call_id.0),
+                SessionUpdate::ToolCallUpdate(update) => {
+                    let text: String = update
+                        .fields
+                        .content
+                        .iter()
+                        .flatten()
+                        .filter_map(|content| match content {
+                            ToolCallContent::Content(content) => match &content.content {
+                                ContentBlock::Text(tc) => Some(tc.text.as_str()),
+                                _ => None,
+                            },
+                            _ => None,
+                        })
+                        .collect();
+                    format!(
+                        "{} {text} {:?}",
+                        update.tool_call_id.0, update.fields.status
+                    )
+                }
+                SessionUpdate::AgentMessageChunk(ContentChunk {
+                    content: ContentBlock::Text(tc),
+                    ..
+                }) => format!("message {}", tc.text),
+                SessionUpdate::Plan(_) => "plan".to_string(),
+                _ => "other".to_string(),
+            }),
+            Event::Response(..) => None,
+        })
+        .collect()
+}
+
+async fn stream(decaf: Decaf, steps: Vec<Step>) -> Result<Vec<String>, sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(steps));
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+    Ok(updates(&events))
+}
+
+fn coalescing() -> Decaf {
+    Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .coalesce_tool_calls(true)
+        .build()
+}
+
+/// Interleaved calls are merged separately, each adding up its output
+/// deltas and keeping the latest value of every other field.
+#[tokio::test]
+async fn test_tool_call_updates_merge_per_call() -> Result<(), sacp::Error> {
+    let steps = vec![
+        start("a"),
+        start("b"),
+        output("a", "1", Some(ToolCallStatus::InProgress)),
+        output("b", "x", Some(ToolCallStatus::InProgress)),
+        output("a", "2", None),
+        output("b", "y", Some(ToolCallStatus::Completed)),
+        output("a", "3", Some(ToolCallStatus::Completed)),
+    ];
+
+    assert_eq!(
+        stream(coalescing(), steps).await?,
+        vec![
+            "start a",
+            "start b",
+            "a 123 Some(Completed)",
+            "b xy Some(Completed)",
+        ]
+    );
+    Ok(())
+}
+
+/// Any other notification flushes the pending updates ahead of it.
+#[tokio::test]
+async fn test_other_notification_flushes_tool_calls() -> Result<(), sacp::Error> {
+    let steps = vec![
+        output("a", "1", Some(ToolCallStatus::InProgress)),
+        output("a", "2", None),
+        Step::Send(SessionUpdate::Plan(Plan::new(vec![]))),
+        output("a", "3", Some(ToolCallStatus::Completed)),
+    ];
+
+    assert_eq!(
+        stream(coalescing(), steps).await?,
+        vec!["a 12 Some(InProgress)", "plan", "a 3 Some(Completed)"]
+    );
+    Ok(())
+}
+
+/// Without the option, updates pass through one by one.
+#[tokio::test]
+async fn test_tool_call_updates_forwarded_by_default() -> Result<(), sacp::Error> {
+    let steps = vec![output("a", "1", None), output("a", "12", None)];
+
+    assert_eq!(
+        stream(Decaf::new(Duration::from_secs(60)), steps).await?,
+        vec!["a 1 None", "a 12 None"]
+    );
+    Ok(())
+}
+
+/// A call of a `LatestWins` kind reporting its progress step by step
+/// reaches the client as its final state only, flushed by the timer.
+#[tokio::test(start_paused = true)]
+async fn test_progress_updates_keep_only_the_latest() -> Result<(), sacp::Error> {
+    let mut steps = vec![start_kind("download", ToolKind::Fetch)];
+    for percent in (10..100).step_by(10) {
+        steps.push(output(
+            "download",
+            &format!("{percent}%"),
+            Some(ToolCallStatus::InProgress),
+        ));
+    }
+    steps.push(output("download", "100%", Some(ToolCallStatus::Completed)));
+    steps.push(Step::Sleep(Duration::from_millis(100)));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(50))
+        .coalesce_tool_calls(true)
+        .tool_call_mode(ToolKind::Fetch, CoalesceMode::LatestWins)
+        .build();
+
+    assert_eq!(
+        stream(decaf, steps).await?,
+        vec!["start download", "download 100% Some(Completed)"]
+    );
+    Ok(())
+}
+
+/// With `LatestWins` for one kind, that kind keeps its latest content
+/// while other calls' output deltas still add up.
+#[tokio::test]
+async fn test_latest_wins_mode_replaces_content_per_kind() -> Result<(), sacp::Error> {
+    let steps = vec![
+        start_kind("shell", ToolKind::Execute),
+        start_kind("read", ToolKind::Read),
+        output("shell", "a", Some(ToolCallStatus::InProgress)),
+        output("read", "1", Some(ToolCallStatus::InProgress)),
+        output("shell", "b", None),
+        output("read", "12", Some(ToolCallStatus::Completed)),
+        output("shell", "c", Some(ToolCallStatus::Completed)),
+    ];
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .coalesce_tool_calls(true)
+        .tool_call_mode(ToolKind::Read, CoalesceMode::LatestWins)
+        .build();
+
+    assert_eq!(
+        stream(decaf, steps).await?,
+        vec![
+            "start shell",
+            "start read",
+            "shell abc Some(Completed)",
+            "read 12 Some(Completed)",
+        ]
+    );
+    Ok(())
+}
+
+/// Message text and tool calls buffered side by side come out in the order
+/// their first un-flushed update arrived, even when every update arrived at
+/// the same instant.
+#[tokio::test(start_paused = true)]
+async fn test_interleaved_text_and_tool_calls_keep_arrival_order() -> Result<(), sacp::Error> {
+    let steps = vec![
+        output("a", "1", Some(ToolCallStatus::InProgress)),
+        Step::Send(message_chunk("Hello ")),
+        output("b", "x", Some(ToolCallStatus::InProgress)),
+        Step::Send(message_chunk("world")),
+        output("a", "2", None),
+        output("c", "!", Some(ToolCallStatus::InProgress)),
+    ];
+
+    assert_eq!(
+        stream(coalescing(), steps).await?,
+        vec![
+            "a 12 Some(InProgress)",
+            "message Hello world",
+            "b x Some(InProgress)",
+            "c ! Some(InProgress)",
+        ]
+    );
+    Ok(())
+}
+
+/// Text sent early, here on a sentence end, takes the tool calls that
+/// arrived before it along instead of overtaking them; what is left of it
+/// counts as arriving with the chunk it came in.
+#[tokio::test(start_paused = true)]
+async fn test_early_flush_keeps_older_tool_calls_first() -> Result<(), sacp::Error> {
+    let steps = vec![
+        output("a", "1", Some(ToolCallStatus::InProgress)),
+        Step::Send(message_chunk("Done.")),
+        output("b", "x", Some(ToolCallStatus::InProgress)),
+        Step::Send(message_chunk(" Next")),
+        output("a", "12", None),
+        Step::Send(message_chunk(" step")),
+    ];
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .coalesce_tool_calls(true)
+        .flush_on_sentence(true)
+        .build();
+
+    assert_eq!(
+        stream(decaf, steps).await?,
+        vec![
+            "a 1 Some(InProgress)",
+            "message Done. ",
+            "b x Some(InProgress)",
+            "message Next step",
+            "a 12 None",
+        ]
+    );
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//! Rewriting coalesced text with `DecafBuilder::transform`.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, message_texts, run, words};
+use decaf_mod::Decaf;
+
+/// The client receives the transformed text of the whole batch.
+#[tokio::test]
+async fn test_transform_rewrites_coalesced_text() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(&["hel", "lo ", "wor", "ld"])));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .transform(|text| text.to_uppercase())
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["HELLO WORLD"]);
+    Ok(())
+}
+
+/// The transform runs once per outgoing notification: it sees coalesced
+/// text, never single chunks, and is skipped when nothing is left over.
+#[tokio::test]
+async fn test_transform_sees_only_outgoing_text() -> Result<(), sacp::Error> {
+    let agent = ScriptedAgent::new(Script::new(words(&["One", " two.", " Three", " four."])));
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .flush_on_sentence(true)
+        .transform({
+            let seen = seen.clone();
+            move |text| {
+                seen.lock().unwrap().push(text.to_string());
+                text.trim_end().to_string()
+            }
+        })
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await?;
+
+    assert_eq!(message_texts(&events), vec!["One two.", "Three four."]);
+    assert_eq!(*seen.lock().unwrap(), vec!["One two. ", "Three four."]);
+    Ok(())
+}
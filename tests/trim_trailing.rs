@@ -0,0 +1,105 @@
+//! Trailing whitespace held back from coalesced notifications.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{message_chunk, thought_chunk};
+use decaf_mod::{Coalescer, Decaf, DecafError, MockClock};
+use sacp::schema::{ContentBlock, ContentChunk, SessionId, SessionNotification, SessionUpdate};
+
+fn coalescer(clock: Arc<MockClock>) -> Coalescer {
+    Coalescer::new(
+        Decaf::builder()
+            .interval(Duration::from_millis(100))
+            .trim_trailing_on_flush(true)
+            .with_clock(clock)
+            .build(),
+    )
+}
+
+fn chunk(update: SessionUpdate) -> SessionNotification {
+    SessionNotification::new(SessionId::new("s"), update)
+}
+
+fn texts(out: Vec<SessionNotification>) -> Vec<String> {
+    out.into_iter()
+        .map(|n| match n.update {
+            SessionUpdate::AgentMessageChunk(ContentChunk {
+                content: ContentBlock::Text(tc),
+                ..
+            })
+            | SessionUpdate::AgentThoughtChunk(ContentChunk {
+                content: ContentBlock::Text(tc),
+                ..
+            }) => tc.text,
+            other => panic!("unexpected update {other:?}"),
+        })
+        .collect()
+}
+
+/// Every notification ends on a non-space, and the whitespace cut off one
+/// leads the next, so the text put back together is what the agent sent.
+#[test]
+fn test_trailing_whitespace_leads_the_next_notification() -> Result<(), DecafError> {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = coalescer(clock.clone());
+    let sent = ["fn main() {\n", "    let x = 1; ", "\n", "    x\n", "}"];
+    let mut emitted = Vec::new();
+    for text in sent {
+        emitted.extend(texts(coalescer.push(chunk(message_chunk(text)))?));
+        clock.advance(Duration::from_millis(100));
+        emitted.extend(texts(coalescer.tick()?));
+    }
+    emitted.extend(texts(coalescer.flush()?));
+
+    assert_eq!(
+        emitted,
+        vec!["fn main() {", "\n    let x = 1;", " \n    x", "\n}"]
+    );
+    assert!(
+        emitted
+            .iter()
+            .all(|text| !text.ends_with(char::is_whitespace))
+    );
+    assert_eq!(emitted.concat(), sent.concat());
+    Ok(())
+}
+
+/// A flush of nothing but whitespace sends nothing; it is all held for the
+/// next text.
+#[test]
+fn test_whitespace_only_flush_is_held() -> Result<(), DecafError> {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = coalescer(clock.clone());
+    coalescer.push(chunk(message_chunk("one ")))?;
+    clock.advance(Duration::from_millis(100));
+    assert_eq!(texts(coalescer.tick()?), vec!["one"]);
+
+    coalescer.push(chunk(message_chunk("  ")))?;
+    clock.advance(Duration::from_millis(100));
+    assert!(coalescer.tick()?.is_empty());
+    assert_eq!(coalescer.next_deadline(), None);
+
+    coalescer.push(chunk(message_chunk("two")))?;
+    assert_eq!(texts(coalescer.flush()?), vec!["   two"]);
+    Ok(())
+}
+
+/// Each stream holds back its own whitespace, and what is still held when
+/// the turn ends is dropped.
+#[test]
+fn test_streams_hold_their_own_whitespace() -> Result<(), DecafError> {
+    let clock = Arc::new(MockClock::new());
+    let mut coalescer = coalescer(clock);
+    coalescer.push(chunk(thought_chunk("thinking ")))?;
+    let switched = coalescer.push(chunk(message_chunk("Answer: ")))?;
+    assert_eq!(texts(switched), vec!["thinking"]);
+    coalescer.push(chunk(message_chunk("42 ")))?;
+    assert_eq!(
+        texts(coalescer.end_turn(&SessionId::new("s"))?),
+        vec!["Answer: 42"]
+    );
+    Ok(())
+}
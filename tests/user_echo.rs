@@ -0,0 +1,75 @@
+//! Coalescing `UserMessageChunk` text the agent echoes back to the client.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, chunk_texts, message_chunk, run, user_chunk};
+use decaf_mod::Decaf;
+
+fn echo_then_answer() -> Script {
+    Script::new(vec![
+        Step::Send(user_chunk("What is ")),
+        Step::Send(user_chunk("six times seven?")),
+        Step::Send(message_chunk("It is ")),
+        Step::Send(message_chunk("42. ")),
+        Step::Send(user_chunk("Six ")),
+        Step::Send(user_chunk("times seven")),
+        Step::Send(message_chunk("is 42.")),
+    ])
+}
+
+async fn texts(decaf: Decaf) -> Result<Vec<(&'static str, String)>, sacp::Error> {
+    let events = run(
+        decaf,
+        ScriptedAgent::new(echo_then_answer()),
+        async |client| {
+            let session = client.new_session().await?;
+            client.prompt(&session, "go").await?;
+            Ok(())
+        },
+    )
+    .await?;
+    Ok(chunk_texts(&events))
+}
+
+/// The echo is buffered on its own, and each switch to or from message text
+/// flushes the stream being left, so the agent's order is kept.
+#[tokio::test]
+async fn test_user_echo_coalesces_in_order() -> Result<(), sacp::Error> {
+    let decaf = Decaf::builder()
+        .interval(Duration::from_secs(60))
+        .coalesce_user_echo(true)
+        .build();
+
+    assert_eq!(
+        texts(decaf).await?,
+        vec![
+            ("user", "What is six times seven?".to_string()),
+            ("message", "It is 42. ".to_string()),
+            ("user", "Six times seven".to_string()),
+            ("message", "is 42.".to_string()),
+        ]
+    );
+    Ok(())
+}
+
+/// By default the echo is forwarded chunk by chunk, flushing message text
+/// before it like any other update.
+#[tokio::test]
+async fn test_user_echo_passes_through_by_default() -> Result<(), sacp::Error> {
+    let decaf = Decaf::new(Duration::from_secs(60));
+
+    assert_eq!(
+        texts(decaf).await?,
+        vec![
+            ("user", "What is ".to_string()),
+            ("user", "six times seven?".to_string()),
+            ("message", "It is 42. ".to_string()),
+            ("user", "Six ".to_string()),
+            ("user", "times seven".to_string()),
+            ("message", "is 42.".to_string()),
+        ]
+    );
+    Ok(())
+}
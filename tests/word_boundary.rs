@@ -0,0 +1,66 @@
+//! Holding a trailing partial word back with `split_on_word_boundary`.
+
+mod common;
+
+use std::time::Duration;
+
+use common::{Script, ScriptedAgent, Step, message_chunk, message_texts, run};
+use decaf_mod::Decaf;
+
+async fn run_script(steps: Vec<Step>, split_on_word_boundary: bool) -> Vec<String> {
+    let agent = ScriptedAgent::new(Script::new(steps));
+    let decaf = Decaf::builder()
+        .interval(Duration::from_millis(100))
+        .split_on_word_boundary(split_on_word_boundary)
+        .build();
+
+    let events = run(decaf, agent, async |client| {
+        let session = client.new_session().await?;
+        client.prompt(&session, "go").await?;
+        Ok(())
+    })
+    .await
+    .expect("run failed");
+    message_texts(&events)
+}
+
+/// Each timer flush stops after the last whitespace; the partial word waits
+/// for the next flush, and the turn end sends whatever is left.
+#[tokio::test(start_paused = true)]
+async fn test_timer_flush_keeps_partial_word() {
+    let steps = vec![
+        Step::Send(message_chunk("hello wor")),
+        Step::Sleep(Duration::from_millis(150)),
+        Step::Send(message_chunk("ld again")),
+        Step::Sleep(Duration::from_millis(100)),
+    ];
+
+    assert_eq!(
+        run_script(steps, true).await,
+        vec!["hello ", "world ", "again"]
+    );
+}
+
+/// Text without any whitespace is flushed whole rather than held.
+#[tokio::test(start_paused = true)]
+async fn test_single_token_is_flushed_whole() {
+    let steps = vec![
+        Step::Send(message_chunk("abcdefgh")),
+        Step::Sleep(Duration::from_millis(150)),
+        Step::Send(message_chunk("ij")),
+    ];
+
+    assert_eq!(run_script(steps, true).await, vec!["abcdefgh", "ij"]);
+}
+
+/// Without the option the timer cuts wherever the text happens to end.
+#[tokio::test(start_paused = true)]
+async fn test_disabled_splits_mid_word() {
+    let steps = vec![
+        Step::Send(message_chunk("hello wor")),
+        Step::Sleep(Duration::from_millis(150)),
+        Step::Send(message_chunk("ld")),
+    ];
+
+    assert_eq!(run_script(steps, false).await, vec!["hello wor", "ld"]);
+}